@@ -0,0 +1,428 @@
+use std::process::Command;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use crate::schema::{ExpandedHost, IncusLockfile};
+use crate::secrets::SecretResolver;
+
+/// A host whose commands did not all succeed
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostFailure {
+    pub host: String,
+    pub reason: String,
+}
+
+/// How often, and how patiently, to retry a failing command
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// Extra attempts after the first failure
+    pub retries: u32,
+
+    /// Delay before the first retry, doubling for each one after it
+    pub backoff: Duration,
+}
+
+/// Why a command failed, and whether running it again might help
+#[derive(Debug, Clone, PartialEq)]
+struct CommandError {
+    message: String,
+    retryable: bool,
+}
+
+/// stderr fragments of failures that usually clear up on their own
+const TRANSIENT_ERRORS: &[&str] = &[
+    "timeout",
+    "timed out",
+    "connection refused",
+    "temporarily unavailable",
+    "try again",
+    "not ready",
+    "resource busy",
+];
+
+/// Whether a command's stderr looks like a transient daemon or network hiccup
+fn is_transient(stderr: &str) -> bool {
+    let stderr = stderr.to_lowercase();
+    TRANSIENT_ERRORS
+        .iter()
+        .any(|fragment| stderr.contains(fragment))
+}
+
+/// Call `attempt` until it succeeds, fails fatally or runs out of retries
+///
+/// `sleep` is handed each backoff delay, so tests can run without waiting.
+fn with_retries(
+    policy: &RetryPolicy,
+    mut attempt: impl FnMut() -> Result<(), CommandError>,
+    mut sleep: impl FnMut(Duration),
+) -> Result<(), String> {
+    let mut delay = policy.backoff;
+    let mut retried = 0;
+    loop {
+        match attempt() {
+            Ok(()) => return Ok(()),
+            Err(e) if e.retryable && retried < policy.retries => {
+                sleep(delay);
+                delay *= 2;
+                retried += 1;
+            }
+            Err(e) if retried > 0 => {
+                return Err(format!("{} (gave up after {} retries)", e.message, retried))
+            }
+            Err(e) => return Err(e.message),
+        }
+    }
+}
+
+/// Group hosts into layers that only depend on hosts in earlier layers
+///
+/// Takes `(name, depends_on)` pairs and keeps the given order within a layer.
+pub fn dependency_layers<'a>(
+    hosts: &[(&'a str, &'a [String])],
+) -> Result<Vec<Vec<&'a str>>, String> {
+    let mut remaining = hosts.to_vec();
+    let mut placed: Vec<&str> = Vec::new();
+    let mut layers = Vec::new();
+
+    while !remaining.is_empty() {
+        let (ready, blocked): (Vec<_>, Vec<_>) = remaining
+            .into_iter()
+            .partition(|(_, deps)| deps.iter().all(|d| placed.contains(&d.as_str())));
+        if ready.is_empty() {
+            let names: Vec<&str> = blocked.iter().map(|(name, _)| *name).collect();
+            return Err(format!(
+                "depends_on cycle among hosts: {}",
+                names.join(", ")
+            ));
+        }
+
+        let layer: Vec<&str> = ready.iter().map(|(name, _)| *name).collect();
+        placed.extend(&layer);
+        layers.push(layer);
+        remaining = blocked;
+    }
+
+    Ok(layers)
+}
+
+/// Run command chains in batches of up to `max_parallel`, each chain in order
+///
+/// Chains in a batch run concurrently, so a chain's later commands only ever
+/// follow its earlier ones. The next batch starts once the whole batch is done;
+/// the first failure stops there, after the rest of its batch has finished.
+fn run_chains_in_batches(
+    chains: &[Vec<String>],
+    max_parallel: usize,
+    run: impl Fn(&str) -> Result<(), String> + Sync,
+) -> Result<(), String> {
+    for batch in chains.chunks(max_parallel.max(1)) {
+        let results: Vec<Result<(), String>> = thread::scope(|scope| {
+            let workers: Vec<_> = batch
+                .iter()
+                .map(|chain| scope.spawn(|| chain.iter().try_for_each(|command| run(command))))
+                .collect();
+            workers
+                .into_iter()
+                .map(|worker| worker.join().unwrap())
+                .collect()
+        });
+        results.into_iter().collect::<Result<(), String>>()?;
+    }
+    Ok(())
+}
+
+/// Run the lockfile's commands against incus
+///
+/// Storage pools are created first, then networks, up to `network_parallel`
+/// at a time with each network's settings applied after its create; ACLs and
+/// DNS zones follow. Any failure there aborts. Hosts then run layer by layer
+/// (see `IncusLockfile::host_layers`) with up to `parallel` at once. A failing
+/// host doesn't stop the others; it is reported, and hosts depending on it are
+/// skipped. Commands failing with a transient error are retried according to `retry`.
+pub fn apply(
+    lockfile: &IncusLockfile,
+    secrets: &SecretResolver,
+    parallel: usize,
+    network_parallel: usize,
+    retry: &RetryPolicy,
+    verbose: bool,
+) -> Result<Vec<HostFailure>, Box<dyn std::error::Error>> {
+    for command in lockfile.generate_storage_commands() {
+        run_with_retries(&command, secrets, retry)?;
+    }
+    let networks: Vec<Vec<String>> = lockfile
+        .subnets
+        .iter()
+        .filter(|s| s.is_managed())
+        .map(|subnet| lockfile.generate_subnet_commands(subnet))
+        .collect();
+    run_chains_in_batches(&networks, network_parallel, |command| {
+        run_with_retries(command, secrets, retry)
+    })?;
+    for command in lockfile.generate_acl_and_zone_commands() {
+        run_with_retries(&command, secrets, retry)?;
+    }
+
+    let dependencies = lockfile.host_dependencies();
+
+    let failures: Mutex<Vec<HostFailure>> = Mutex::new(Vec::new());
+    for layer in lockfile.host_layers() {
+        let queue = Mutex::new(layer.into_iter());
+        thread::scope(|scope| {
+            for _ in 0..parallel.max(1) {
+                scope.spawn(|| loop {
+                    let Some(host) = queue.lock().unwrap().next() else {
+                        break;
+                    };
+                    let name = host.name.as_str();
+
+                    let failed_dependency = {
+                        let failures = failures.lock().unwrap();
+                        dependencies
+                            .get(name)
+                            .into_iter()
+                            .flatten()
+                            .find(|dep| failures.iter().any(|f| &f.host == *dep))
+                            .cloned()
+                    };
+                    let result = match failed_dependency {
+                        Some(dep) => Err(format!("skipped, dependency '{}' failed", dep)),
+                        None => apply_host(lockfile, host, secrets, retry),
+                    };
+
+                    match result {
+                        Ok(()) if verbose => println!("  ✓ Applied host: {}", name),
+                        Ok(()) => {}
+                        Err(reason) => failures.lock().unwrap().push(HostFailure {
+                            host: name.to_string(),
+                            reason,
+                        }),
+                    }
+                });
+            }
+        });
+    }
+
+    // Report in lockfile order regardless of which worker finished first
+    let mut failures = failures.into_inner().unwrap();
+    failures.sort_by_key(|f| lockfile.hosts.iter().position(|h| h.name == f.host));
+    Ok(failures)
+}
+
+/// Run commands in order, stopping at the first failure
+///
+/// Used for `--teardown --apply`, whose deletes must happen in sequence.
+pub fn run_commands(
+    commands: &[String],
+    secrets: &SecretResolver,
+    retry: &RetryPolicy,
+) -> Result<(), String> {
+    for command in commands {
+        run_with_retries(command, secrets, retry)?;
+    }
+    Ok(())
+}
+
+/// Run every command for one host, stopping at the first failure
+fn apply_host(
+    lockfile: &IncusLockfile,
+    host: &ExpandedHost,
+    secrets: &SecretResolver,
+    retry: &RetryPolicy,
+) -> Result<(), String> {
+    for command in lockfile.generate_host_commands(host) {
+        run_with_retries(&command, secrets, retry)?;
+    }
+    Ok(())
+}
+
+/// Run a single generated command, retrying transient failures
+fn run_with_retries(
+    command: &str,
+    secrets: &SecretResolver,
+    retry: &RetryPolicy,
+) -> Result<(), String> {
+    with_retries(retry, || run_command(command, secrets), thread::sleep)
+}
+
+/// Run a single generated command through the shell, skipping comments
+fn run_command(command: &str, secrets: &SecretResolver) -> Result<(), CommandError> {
+    if command.starts_with('#') {
+        return Ok(());
+    }
+
+    let fatal = |message: String| CommandError {
+        message,
+        retryable: false,
+    };
+    let expanded = secrets.expand(command).map_err(|e| fatal(e.to_string()))?;
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(expanded)
+        .output()
+        .map_err(|e| fatal(format!("failed to run '{}': {}", command, e)))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        // Report the unresolved command so secrets don't end up in logs
+        return Err(CommandError {
+            message: format!("'{}' failed: {}", command, stderr.trim()),
+            retryable: is_transient(&stderr),
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_backoff() {
+        let policy = RetryPolicy {
+            retries: 3,
+            backoff: Duration::from_millis(100),
+        };
+        let failure = |stderr: &str| CommandError {
+            message: format!("failed: {}", stderr),
+            retryable: is_transient(stderr),
+        };
+
+        // Two transient failures, then success
+        let mut attempts = 0;
+        let mut delays = Vec::new();
+        let result = with_retries(
+            &policy,
+            || {
+                attempts += 1;
+                match attempts {
+                    1 => Err(failure("Error: context deadline exceeded (Client.Timeout)")),
+                    2 => Err(failure("Error: connection refused")),
+                    _ => Ok(()),
+                }
+            },
+            |delay| delays.push(delay),
+        );
+        assert_eq!(result, Ok(()));
+        assert_eq!(
+            delays,
+            vec![Duration::from_millis(100), Duration::from_millis(200)]
+        );
+
+        // Fatal errors are not retried
+        let mut attempts = 0;
+        let result = with_retries(
+            &policy,
+            || {
+                attempts += 1;
+                Err(failure("Error: Invalid config key"))
+            },
+            |_| panic!("fatal error retried"),
+        );
+        assert_eq!(result, Err("failed: Error: Invalid config key".to_string()));
+        assert_eq!(attempts, 1);
+
+        // Transient errors give up once the retries run out
+        let mut attempts = 0;
+        let result = with_retries(
+            &policy,
+            || {
+                attempts += 1;
+                Err(failure("Error: operation timed out"))
+            },
+            |_| {},
+        );
+        assert_eq!(
+            result,
+            Err("failed: Error: operation timed out (gave up after 3 retries)".to_string())
+        );
+        assert_eq!(attempts, 4);
+    }
+
+    #[test]
+    fn test_network_batches_keep_set_after_create() {
+        let chains: Vec<Vec<String>> = ["net1", "net2", "net3", "net4", "net5"]
+            .iter()
+            .map(|name| {
+                vec![
+                    format!("incus network create {} --type=bridge", name),
+                    format!("incus network set {} ipv4.address=auto", name),
+                    format!("incus network set {} ipv4.dhcp=false", name),
+                ]
+            })
+            .collect();
+
+        let log = Mutex::new(Vec::new());
+        run_chains_in_batches(&chains, 2, |command| {
+            // Give the other chains in the batch a chance to interleave
+            thread::sleep(Duration::from_millis(1));
+            log.lock().unwrap().push(command.to_string());
+            Ok(())
+        })
+        .unwrap();
+        let log = log.into_inner().unwrap();
+        assert_eq!(log.len(), 15);
+
+        let position = |command: &String| log.iter().position(|c| c == command).unwrap();
+        for chain in &chains {
+            for set in &chain[1..] {
+                assert!(
+                    position(&chain[0]) < position(set),
+                    "{} ran before its create",
+                    set
+                );
+            }
+        }
+        // Nothing from a batch starts before the previous batch is done
+        let batches: Vec<Vec<usize>> = chains
+            .chunks(2)
+            .map(|batch| batch.iter().flatten().map(position).collect())
+            .collect();
+        for pair in batches.windows(2) {
+            assert!(pair[0].iter().max() < pair[1].iter().min());
+        }
+
+        // A failure stops before the next batch
+        let ran = Mutex::new(Vec::new());
+        let err = run_chains_in_batches(&chains, 2, |command| {
+            ran.lock().unwrap().push(command.to_string());
+            if command.starts_with("incus network create net2") {
+                Err("boom".to_string())
+            } else {
+                Ok(())
+            }
+        })
+        .unwrap_err();
+        assert_eq!(err, "boom");
+        let ran = ran.into_inner().unwrap();
+        assert_eq!(ran.len(), 4);
+        assert!(!ran.iter().any(|c| c.contains("net3")));
+    }
+
+    #[test]
+    fn test_dependency_layers() {
+        let none: Vec<String> = Vec::new();
+        let on_db = vec!["db".to_string()];
+        let on_api_and_cache = vec!["api".to_string(), "cache".to_string()];
+        let hosts: Vec<(&str, &[String])> = vec![
+            ("web", &on_api_and_cache),
+            ("api", &on_db),
+            ("db", &none),
+            ("cache", &none),
+        ];
+        assert_eq!(
+            dependency_layers(&hosts).unwrap(),
+            vec![vec!["db", "cache"], vec!["api"], vec!["web"]]
+        );
+
+        let on_web = vec!["web".to_string()];
+        let on_api = vec!["api".to_string()];
+        let cyclic: Vec<(&str, &[String])> =
+            vec![("db", &none), ("api", &on_web), ("web", &on_api)];
+        assert_eq!(
+            dependency_layers(&cyclic).unwrap_err(),
+            "depends_on cycle among hosts: api, web"
+        );
+    }
+}
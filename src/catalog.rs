@@ -0,0 +1,198 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::schema::{Flavor, Image, RoleTemplate};
+
+/// How long to wait for a remote catalog before giving up
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Flavor, image and role definitions pulled in through `include`
+#[derive(Debug, Default, Deserialize)]
+pub struct Catalog {
+    #[serde(default)]
+    pub flavors: BTreeMap<String, Flavor>,
+
+    #[serde(default)]
+    pub images: BTreeMap<String, Image>,
+
+    #[serde(default)]
+    pub roles: BTreeMap<String, RoleTemplate>,
+}
+
+/// Whether an include entry names an HTTP(S) URL rather than a file
+pub fn is_url(source: &str) -> bool {
+    source.starts_with("http://") || source.starts_with("https://")
+}
+
+/// Read an include entry, fetching URLs and falling back to `cache_dir` when that fails
+///
+/// Every successful fetch refreshes the cached copy, so a catalog server being
+/// down only matters the first time a URL is used.
+pub fn read_source(
+    source: &str,
+    cache_dir: Option<&Path>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    if !is_url(source) {
+        return Ok(fs::read_to_string(source).map_err(|e| format!("include '{}': {}", source, e))?);
+    }
+
+    let cached = cache_dir.map(|dir| cache_path(dir, source));
+    match fetch(source) {
+        Ok(content) => {
+            if let Some(ref cached) = cached {
+                fs::create_dir_all(cached.parent().unwrap_or(Path::new(".")))?;
+                fs::write(cached, &content)?;
+            }
+            Ok(content)
+        }
+        Err(e) => match cached.filter(|path| path.is_file()) {
+            Some(cached) => {
+                eprintln!(
+                    "⚠ include '{}': {}; using cached copy {}",
+                    source,
+                    e,
+                    cached.display()
+                );
+                Ok(fs::read_to_string(cached)?)
+            }
+            None => Err(format!("include '{}': {}", source, e).into()),
+        },
+    }
+}
+
+/// GET a URL, treating non-2xx responses as failures
+fn fetch(url: &str) -> Result<String, String> {
+    let client = reqwest::blocking::Client::builder()
+        .timeout(FETCH_TIMEOUT)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let response = client
+        .get(url)
+        .send()
+        .map_err(|e| format!("fetch failed: {}", e))?;
+    if !response.status().is_success() {
+        return Err(format!("fetch failed: HTTP {}", response.status()));
+    }
+    response
+        .text()
+        .map_err(|e| format!("reading response failed: {}", e))
+}
+
+/// File in `cache_dir` holding the last fetched copy of `url`
+fn cache_path(cache_dir: &Path, url: &str) -> PathBuf {
+    let name: String = url
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    cache_dir.join(format!("{}.yaml", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::IncusCompose;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Serve one HTTP response on a local port, returning its URL
+    fn serve_once(status: &'static str, body: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/catalog.yaml", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0u8; 1024];
+            let _ = stream.read(&mut request);
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            stream.write_all(response.as_bytes()).unwrap();
+        });
+        url
+    }
+
+    #[test]
+    fn test_fetch_catalog_from_url() {
+        let url = serve_once(
+            "200 OK",
+            "flavors:\n  shared_small:\n    name: shared_small\n    cpu:\n      cores: 1\n    memory:\n      limit: 1GB\n",
+        );
+        let cache_dir = std::env::temp_dir().join(format!(
+            "incus-composer-{}-catalog-cache",
+            std::process::id()
+        ));
+        let yaml = format!(
+            r#"
+include: ["{}"]
+catalog_cache: {}
+hosts:
+  - name: web
+    flavor: shared_small
+    image: base_image
+    subnets: [frontend]
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+"#,
+            url,
+            cache_dir.display()
+        );
+
+        let compose = IncusCompose::load_from_reader(yaml.as_bytes(), "test.yaml").unwrap();
+        assert_eq!(compose.flavors["shared_small"].cpu.cores, 1);
+        assert_eq!(compose.include, vec![url.clone()]);
+
+        // The server is gone now, so the second load comes from the cache
+        let compose = IncusCompose::load_from_reader(yaml.as_bytes(), "test.yaml").unwrap();
+        assert!(compose.flavors.contains_key("shared_small"));
+        fs::remove_dir_all(&cache_dir).unwrap();
+
+        let missing = serve_once("404 Not Found", "");
+        let err = read_source(&missing, None).unwrap_err().to_string();
+        assert!(err.contains("HTTP 404"), "{}", err);
+    }
+
+    #[test]
+    fn test_relative_include_resolves_against_config_dir() {
+        let dir = std::env::temp_dir().join(format!(
+            "incus-composer-{}-relative-include",
+            std::process::id()
+        ));
+        let sub = dir.join("sub");
+        fs::create_dir_all(&sub).unwrap();
+        fs::write(
+            sub.join("catalog.yaml"),
+            "flavors:\n  shared_small:\n    name: shared_small\n    cpu:\n      cores: 1\n    memory:\n      limit: 1GB\n",
+        )
+        .unwrap();
+        let config = sub.join("compose.yaml");
+        fs::write(
+            &config,
+            "include: [catalog.yaml]\nhosts:\n  - name: web\n    flavor: shared_small\n    image: base_image\nsubnets: []\n",
+        )
+        .unwrap();
+        let overlay = sub.join("override.yaml");
+        fs::write(&overlay, "dns_domain: example.internal\n").unwrap();
+
+        // Found next to the configuration, whatever the current directory is
+        let loaded = IncusCompose::load_from_file(&config);
+        let with_overrides = IncusCompose::load_with_overrides(&config, &[&overlay]);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(loaded.unwrap().flavors.contains_key("shared_small"));
+        assert!(with_overrides.is_ok());
+    }
+}
@@ -0,0 +1,63 @@
+use std::error::Error;
+use std::io;
+
+use crate::schema::ValidationError;
+
+/// Why a run failed, each kind with a stable process exit code for scripts
+///
+/// 2 matches the code clap uses for command-line usage errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// Any failure without a more specific kind, e.g. a stale --check script
+    Other,
+    /// Options that cannot be combined as given
+    Usage,
+    /// The configuration file does not exist
+    ConfigNotFound,
+    /// The configuration (or an override or include) is not valid YAML for the schema
+    Parse,
+    /// The configuration parsed but breaks a constraint, or cannot be allocated
+    Validation,
+    /// The lockfile could not be read or written
+    LockfileIo,
+    /// One or more hosts failed to apply
+    Apply,
+}
+
+impl ErrorKind {
+    /// Process exit code for this kind
+    pub fn code(self) -> i32 {
+        match self {
+            ErrorKind::Other => 1,
+            ErrorKind::Usage => 2,
+            ErrorKind::ConfigNotFound => 3,
+            ErrorKind::Parse => 4,
+            ErrorKind::Validation => 5,
+            ErrorKind::LockfileIo => 6,
+            ErrorKind::Apply => 7,
+        }
+    }
+
+    /// Kind of an error from loading the configuration
+    pub fn of_config_error(err: &(dyn Error + 'static)) -> Self {
+        if err.is::<ValidationError>() {
+            ErrorKind::Validation
+        } else if err
+            .downcast_ref::<io::Error>()
+            .is_some_and(|e| e.kind() == io::ErrorKind::NotFound)
+        {
+            ErrorKind::ConfigNotFound
+        } else {
+            ErrorKind::Parse
+        }
+    }
+
+    /// Kind of an error from generating, merging or saving the lockfile
+    pub fn of_lockfile_error(err: &(dyn Error + 'static)) -> Self {
+        if err.is::<io::Error>() {
+            ErrorKind::LockfileIo
+        } else {
+            ErrorKind::Validation
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use ipnet::IpNet;
+use std::fmt;
+
+/// Name of the role whose values are firewall rules
+pub const FIREWALL_ROLE: &str = "firewall";
+
+/// A single packet filtering rule from a `firewall` role value
+///
+/// Rules read as `<allow|deny|reject> [in|out] [<proto>] [port <ports>] [from <cidr>] [to <cidr>]`,
+/// e.g. `allow tcp port 443 from 0.0.0.0/0`. Direction defaults to `in`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FirewallRule {
+    pub action: String,
+    pub direction: String,
+    pub protocol: Option<String>,
+    pub port: Option<String>,
+    pub source: Option<String>,
+    pub destination: Option<String>,
+}
+
+impl FirewallRule {
+    /// Parse a rule, describing what is wrong on failure
+    pub fn parse(rule: &str) -> Result<Self, String> {
+        let mut tokens = rule.split_whitespace();
+        let action = match tokens.next() {
+            Some("allow") => "allow",
+            Some("deny") => "drop",
+            Some("reject") => "reject",
+            Some(other) => {
+                return Err(format!(
+                    "rule '{}': unknown action '{}' (expected allow, deny or reject)",
+                    rule, other
+                ))
+            }
+            None => return Err("empty firewall rule".to_string()),
+        };
+
+        let mut parsed = FirewallRule {
+            action: action.to_string(),
+            direction: "ingress".to_string(),
+            protocol: None,
+            port: None,
+            source: None,
+            destination: None,
+        };
+
+        while let Some(token) = tokens.next() {
+            match token {
+                "in" => parsed.direction = "ingress".to_string(),
+                "out" => parsed.direction = "egress".to_string(),
+                "tcp" | "udp" | "icmp4" | "icmp6" => parsed.protocol = Some(token.to_string()),
+                "port" => {
+                    let port = tokens
+                        .next()
+                        .ok_or_else(|| format!("rule '{}': 'port' needs a value", rule))?;
+                    if !valid_ports(port) {
+                        return Err(format!("rule '{}': invalid port '{}'", rule, port));
+                    }
+                    parsed.port = Some(port.to_string());
+                }
+                "from" | "to" => {
+                    let cidr = tokens
+                        .next()
+                        .ok_or_else(|| format!("rule '{}': '{}' needs a CIDR", rule, token))?;
+                    if cidr.parse::<IpNet>().is_err() {
+                        return Err(format!("rule '{}': invalid CIDR '{}'", rule, cidr));
+                    }
+                    if token == "from" {
+                        parsed.source = Some(cidr.to_string());
+                    } else {
+                        parsed.destination = Some(cidr.to_string());
+                    }
+                }
+                other => return Err(format!("rule '{}': unexpected '{}'", rule, other)),
+            }
+        }
+
+        if parsed.port.is_some() && !matches!(parsed.protocol.as_deref(), Some("tcp") | Some("udp"))
+        {
+            return Err(format!("rule '{}': a port requires tcp or udp", rule));
+        }
+
+        Ok(parsed)
+    }
+}
+
+impl fmt::Display for FirewallRule {
+    /// Arguments for `incus network acl rule add <acl> ...`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} action={}", self.direction, self.action)?;
+        if let Some(ref protocol) = self.protocol {
+            write!(f, " protocol={}", protocol)?;
+        }
+        if let Some(ref port) = self.port {
+            write!(f, " destination_port={}", port)?;
+        }
+        if let Some(ref source) = self.source {
+            write!(f, " source={}", source)?;
+        }
+        if let Some(ref destination) = self.destination {
+            write!(f, " destination={}", destination)?;
+        }
+        Ok(())
+    }
+}
+
+/// A port, port range (`8000-8080`) or comma-separated list of either
+fn valid_ports(ports: &str) -> bool {
+    ports.split(',').all(|part| {
+        let mut bounds = part.splitn(2, '-').map(|p| p.parse::<u16>());
+        match (bounds.next(), bounds.next()) {
+            (Some(Ok(port)), None) => port > 0,
+            (Some(Ok(start)), Some(Ok(end))) => start > 0 && start <= end,
+            _ => false,
+        }
+    })
+}
+
+/// ACL name for a host's firewall role
+pub fn acl_name(host: &str) -> String {
+    format!("{}-firewall", host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_firewall_rules() {
+        let rule = FirewallRule::parse("allow tcp port 443 from 0.0.0.0/0").unwrap();
+        assert_eq!(
+            rule.to_string(),
+            "ingress action=allow protocol=tcp destination_port=443 source=0.0.0.0/0"
+        );
+
+        let rule = FirewallRule::parse("deny out udp port 53,5353 to 10.0.0.0/8").unwrap();
+        assert_eq!(
+            rule.to_string(),
+            "egress action=drop protocol=udp destination_port=53,5353 destination=10.0.0.0/8"
+        );
+
+        for invalid in [
+            "",
+            "permit tcp",
+            "allow tcp port",
+            "allow tcp port 0",
+            "allow port 80",
+            "allow tcp from 10.0.0.0/33",
+            "allow sctp",
+        ] {
+            assert!(
+                FirewallRule::parse(invalid).is_err(),
+                "{} accepted",
+                invalid
+            );
+        }
+    }
+}
@@ -0,0 +1,316 @@
+use ipnet::Ipv4Net;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::incus::{incus_json, parse_instances, parse_networks, IncusInstance};
+use crate::schema::{
+    CpuSpec, Flavor, Host, Image, IncusCompose, IncusLockfile, InstanceType, MemorySpec, Subnet,
+    SubnetAssignment, SubnetConfig,
+};
+
+/// A host's NIC as detected on the running instance
+struct DetectedNic {
+    network: String,
+    mac_address: Option<String>,
+    ip_address: Option<String>,
+}
+
+/// Capture the current incus installation by querying the `incus` CLI
+pub fn import_from_incus() -> Result<(IncusCompose, IncusLockfile), Box<dyn std::error::Error>> {
+    let instances_json = incus_json(&["list", "--format", "json"])?;
+    let networks_json = incus_json(&["network", "list", "--format", "json"])?;
+    import_from_json(&instances_json, &networks_json)
+}
+
+/// Synthesize a compose file and matching lockfile from captured incus JSON
+///
+/// The compose file describes hosts, subnets, flavors and images; the lockfile
+/// pins the detected MAC and IP addresses so a later run preserves them.
+pub fn import_from_json(
+    instances_json: &str,
+    networks_json: &str,
+) -> Result<(IncusCompose, IncusLockfile), Box<dyn std::error::Error>> {
+    let instances = parse_instances(instances_json)?;
+    let networks = parse_networks(networks_json)?;
+
+    // Only managed networks with an IPv4 address can become subnets
+    let mut subnets = Vec::new();
+    for network in networks.iter().filter(|n| n.managed) {
+        let cidr = match network.config.get("ipv4.address") {
+            Some(address) if address != "none" => {
+                let net: Ipv4Net = address.parse().map_err(|e| {
+                    format!(
+                        "network '{}' has invalid ipv4.address '{}': {}",
+                        network.name, address, e
+                    )
+                })?;
+                Some(net.trunc().to_string())
+            }
+            _ => None,
+        };
+        subnets.push(Subnet::Full(Box::new(SubnetConfig {
+            name: network.name.clone(),
+            cidr,
+            mtu: network
+                .config
+                .get("bridge.mtu")
+                .and_then(|mtu| mtu.parse().ok()),
+            external_interfaces: config_list(&network.config, "bridge.external_interfaces"),
+            ipv4_routes: config_list(&network.config, "ipv4.routes"),
+            dns_mode: network
+                .config
+                .get("dns.mode")
+                .and_then(|mode| serde_yaml::from_str(mode).ok()),
+            ..Default::default()
+        })));
+    }
+
+    let mut compose = IncusCompose {
+        version: "1.0".to_string(),
+        remote: None,
+        launch: false,
+        dns_domain: None,
+        seed: None,
+        loaded_hash: None,
+        include: Vec::new(),
+        catalog_cache: None,
+        defaults: Default::default(),
+        hosts: Vec::new(),
+        groups: Vec::new(),
+        subnets,
+        flavors: BTreeMap::new(),
+        images: BTreeMap::new(),
+        storage_pools: Vec::new(),
+        roles: BTreeMap::new(),
+    };
+
+    let mut detected = HashMap::new();
+    for instance in &instances {
+        let nics: Vec<DetectedNic> = instance
+            .network_nics()
+            .filter(|(_, _, network)| compose.subnets.iter().any(|s| s.name() == *network))
+            .map(|(device_name, device, network)| DetectedNic {
+                network: network.to_string(),
+                mac_address: instance.nic_hwaddr(device_name, device),
+                ip_address: instance.nic_ipv4(device_name, device),
+            })
+            .collect();
+
+        let flavor = guess_flavor(instance);
+        let image = guess_image(instance);
+
+        compose.hosts.push(Host {
+            name: instance.name.clone(),
+            flavor: flavor.name.clone(),
+            image: image.name.clone(),
+            subnets: nics
+                .iter()
+                .map(|nic| SubnetAssignment::Name(nic.network.clone()))
+                .collect(),
+            ..Default::default()
+        });
+        compose.flavors.entry(flavor.name.clone()).or_insert(flavor);
+        compose.images.entry(image.name.clone()).or_insert(image);
+        detected.insert(instance.name.clone(), nics);
+    }
+
+    let mut lockfile = compose.generate_lockfile()?;
+
+    // Replace generated values with what is actually running
+    for host in &mut lockfile.hosts {
+        let Some(nics) = detected.get(&host.name) else {
+            continue;
+        };
+        if let Some(mac) = nics.first().and_then(|nic| nic.mac_address.clone()) {
+            host.mac_address = Some(mac);
+        }
+        for nic in nics {
+            if let Some(ref ip) = nic.ip_address {
+                host.ip_addresses.insert(nic.network.clone(), ip.clone());
+            }
+        }
+    }
+    lockfile.rebuild_used_values();
+
+    Ok((compose, lockfile))
+}
+
+/// Comma-separated list under `key` in an incus config map, empty when unset
+fn config_list(config: &HashMap<String, String>, key: &str) -> Vec<String> {
+    config
+        .get(key)
+        .map(|list| {
+            list.split(',')
+                .map(|item| item.trim().to_string())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Derive a flavor from the instance's resource limits
+fn guess_flavor(instance: &IncusInstance) -> Flavor {
+    let cores = instance
+        .expanded_config
+        .get("limits.cpu")
+        .and_then(|cpu| cpu.parse::<u32>().ok())
+        .unwrap_or(1);
+    let memory = instance
+        .expanded_config
+        .get("limits.memory")
+        .cloned()
+        .unwrap_or_else(|| "1GB".to_string());
+    let instance_type = if instance.instance_type == "virtual-machine" {
+        InstanceType::VirtualMachine
+    } else {
+        InstanceType::Container
+    };
+    let type_suffix = match instance_type {
+        InstanceType::Container => "",
+        InstanceType::VirtualMachine => "-vm",
+    };
+
+    Flavor {
+        name: format!("{}cpu-{}{}", cores, memory.to_lowercase(), type_suffix),
+        description: Some("Imported from running instance limits".to_string()),
+        cpu: CpuSpec {
+            cores: cores.into(),
+            limit: None,
+            allowance: None,
+            priority: None,
+        },
+        memory: MemorySpec {
+            limit: memory,
+            swap: None,
+            swap_priority: None,
+            enforce: None,
+        },
+        storage: None,
+        instance_type,
+    }
+}
+
+/// Derive an image reference from the instance's image metadata
+fn guess_image(instance: &IncusInstance) -> Image {
+    let config = &instance.expanded_config;
+    let os = config.get("image.os").map(|os| os.to_lowercase());
+    let release = config.get("image.release");
+
+    let name = match (&os, release) {
+        (Some(os), Some(release)) => format!("{}/{}", os, release),
+        (Some(os), None) => os.clone(),
+        _ => "unknown".to_string(),
+    };
+
+    Image {
+        name,
+        description: config.get("image.description").cloned(),
+        source: "images:".to_string(),
+        fingerprint: config.get("volatile.base_image").cloned(),
+        iso: None,
+        architecture: config
+            .get("image.architecture")
+            .cloned()
+            .unwrap_or_else(|| "x86_64".to_string()),
+        os,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INSTANCES_JSON: &str = r#"[
+  {
+    "name": "web",
+    "type": "container",
+    "status": "Running",
+    "expanded_config": {
+      "image.os": "Ubuntu",
+      "image.release": "24.04",
+      "limits.cpu": "2",
+      "limits.memory": "2GiB",
+      "volatile.eth0.hwaddr": "00:16:3e:aa:bb:01"
+    },
+    "expanded_devices": {
+      "eth0": {"type": "nic", "network": "frontend", "name": "eth0"},
+      "root": {"type": "disk", "path": "/", "pool": "default"}
+    },
+    "state": {
+      "network": {
+        "eth0": {
+          "hwaddr": "00:16:3e:aa:bb:01",
+          "addresses": [
+            {"family": "inet", "address": "10.0.1.20", "netmask": "24"},
+            {"family": "inet6", "address": "fd42::20", "netmask": "64"}
+          ]
+        }
+      }
+    }
+  },
+  {
+    "name": "db",
+    "type": "virtual-machine",
+    "status": "Stopped",
+    "expanded_config": {
+      "image.os": "Debian",
+      "image.release": "12",
+      "volatile.eth0.hwaddr": "00:16:3e:aa:bb:02"
+    },
+    "expanded_devices": {
+      "eth0": {"type": "nic", "network": "backend"}
+    },
+    "state": null
+  }
+]"#;
+
+    const NETWORKS_JSON: &str = r#"[
+  {"name": "frontend", "type": "bridge", "managed": true,
+   "config": {"ipv4.address": "10.0.1.1/24"}},
+  {"name": "backend", "type": "bridge", "managed": true,
+   "config": {"ipv4.address": "10.0.2.1/24"}},
+  {"name": "eno1", "type": "physical", "managed": false, "config": {}}
+]"#;
+
+    #[test]
+    fn test_import_from_json_fixtures() {
+        let (compose, lockfile) = import_from_json(INSTANCES_JSON, NETWORKS_JSON).unwrap();
+
+        let subnets: Vec<(&str, Option<&str>)> = compose
+            .subnets
+            .iter()
+            .map(|s| (s.name(), s.cidr()))
+            .collect();
+        assert_eq!(
+            subnets,
+            vec![
+                ("frontend", Some("10.0.1.0/24")),
+                ("backend", Some("10.0.2.0/24"))
+            ]
+        );
+
+        assert_eq!(compose.hosts.len(), 2);
+        let web = &compose.hosts[0];
+        assert_eq!(web.name, "web");
+        assert_eq!(web.flavor, "2cpu-2gib");
+        assert_eq!(web.image, "ubuntu/24.04");
+        assert_eq!(web.subnet_names(), vec!["frontend"]);
+        assert_eq!(compose.flavors["2cpu-2gib"].cpu.cores, 2);
+
+        let db = &compose.hosts[1];
+        assert_eq!(db.subnet_names(), vec!["backend"]);
+        assert!(matches!(
+            compose.flavors[&db.flavor].instance_type,
+            InstanceType::VirtualMachine
+        ));
+
+        let web_lock = &lockfile.hosts[0];
+        assert_eq!(web_lock.mac_address.as_deref(), Some("00:16:3e:aa:bb:01"));
+        assert_eq!(web_lock.ip_addresses["frontend"], "10.0.1.20");
+        let db_lock = &lockfile.hosts[1];
+        assert_eq!(db_lock.mac_address.as_deref(), Some("00:16:3e:aa:bb:02"));
+        assert!(lockfile
+            .metadata
+            .used_values
+            .mac_addresses
+            .contains(&"00:16:3e:aa:bb:01".to_string()));
+    }
+}
@@ -0,0 +1,157 @@
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::process::Command;
+
+/// Instance entry from `incus list --format json`
+#[derive(Debug, Deserialize)]
+pub struct IncusInstance {
+    pub name: String,
+
+    #[serde(rename = "type", default)]
+    pub instance_type: String,
+
+    /// Runtime status as reported by incus, e.g. "Running" or "Stopped"
+    #[serde(default)]
+    pub status: String,
+
+    #[serde(default)]
+    pub expanded_config: HashMap<String, String>,
+
+    #[serde(default)]
+    pub expanded_devices: BTreeMap<String, HashMap<String, String>>,
+
+    #[serde(default)]
+    pub state: Option<InstanceState>,
+}
+
+/// Runtime state of an instance (absent for stopped instances on some versions)
+#[derive(Debug, Default, Deserialize)]
+pub struct InstanceState {
+    #[serde(default)]
+    pub network: Option<HashMap<String, InterfaceState>>,
+}
+
+/// Per-interface runtime state, keyed by the in-guest interface name
+#[derive(Debug, Deserialize)]
+pub struct InterfaceState {
+    #[serde(default)]
+    pub addresses: Vec<InterfaceAddress>,
+
+    #[serde(default)]
+    pub hwaddr: String,
+}
+
+/// A single address on an interface
+#[derive(Debug, Deserialize)]
+pub struct InterfaceAddress {
+    pub family: String,
+    pub address: String,
+}
+
+/// Network entry from `incus network list --format json`
+#[derive(Debug, Deserialize)]
+pub struct IncusNetwork {
+    pub name: String,
+
+    #[serde(default)]
+    pub managed: bool,
+
+    #[serde(default)]
+    pub config: HashMap<String, String>,
+}
+
+impl IncusInstance {
+    /// NIC devices attached to a network, as (device name, device config, network)
+    pub fn network_nics(&self) -> impl Iterator<Item = (&str, &HashMap<String, String>, &str)> {
+        self.expanded_devices
+            .iter()
+            .filter(|(_, device)| device.get("type").map(String::as_str) == Some("nic"))
+            .filter_map(|(device_name, device)| {
+                let network = device.get("network")?;
+                Some((device_name.as_str(), device, network.as_str()))
+            })
+    }
+
+    /// Runtime state of the guest interface backing a NIC device
+    pub fn interface(
+        &self,
+        device_name: &str,
+        device: &HashMap<String, String>,
+    ) -> Option<&InterfaceState> {
+        let guest_name = device
+            .get("name")
+            .map(String::as_str)
+            .unwrap_or(device_name);
+        self.state
+            .as_ref()
+            .and_then(|state| state.network.as_ref())
+            .and_then(|interfaces| interfaces.get(guest_name))
+    }
+
+    /// IPv4 address of a NIC, preferring a statically configured one
+    ///
+    /// A static address written with its prefix (`device_address: cidr`) is returned bare.
+    pub fn nic_ipv4(&self, device_name: &str, device: &HashMap<String, String>) -> Option<String> {
+        let configured = device
+            .get("ipv4.address")
+            .map(|address| address.split('/').next().unwrap_or(address).to_string());
+        configured.or_else(|| {
+            self.interface(device_name, device).and_then(|i| {
+                i.addresses
+                    .iter()
+                    .find(|a| a.family == "inet")
+                    .map(|a| a.address.clone())
+            })
+        })
+    }
+
+    /// MAC address of a NIC from its config, volatile state or runtime state
+    pub fn nic_hwaddr(
+        &self,
+        device_name: &str,
+        device: &HashMap<String, String>,
+    ) -> Option<String> {
+        device
+            .get("hwaddr")
+            .or_else(|| {
+                self.expanded_config
+                    .get(&format!("volatile.{}.hwaddr", device_name))
+            })
+            .cloned()
+            .or_else(|| {
+                self.interface(device_name, device)
+                    .map(|i| i.hwaddr.clone())
+                    .filter(|mac| !mac.is_empty())
+            })
+    }
+}
+
+/// Parse the output of `incus list --format json`
+pub fn parse_instances(json: &str) -> Result<Vec<IncusInstance>, Box<dyn std::error::Error>> {
+    Ok(serde_json::from_str(json).map_err(|e| format!("invalid 'incus list' output: {}", e))?)
+}
+
+/// Parse the output of `incus network list --format json`
+pub fn parse_networks(json: &str) -> Result<Vec<IncusNetwork>, Box<dyn std::error::Error>> {
+    Ok(serde_json::from_str(json)
+        .map_err(|e| format!("invalid 'incus network list' output: {}", e))?)
+}
+
+/// Run an incus command and return its stdout
+pub fn incus_json(args: &[&str]) -> Result<String, Box<dyn std::error::Error>> {
+    let output = Command::new("incus")
+        .args(args)
+        .output()
+        .map_err(|e| format!("failed to run 'incus {}': {}", args.join(" "), e))?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "'incus {}' failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+
+    Ok(String::from_utf8(output.stdout)?)
+}
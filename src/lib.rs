@@ -0,0 +1,218 @@
+//! Declarative Incus topologies: load a compose file, merge it with the
+//! previous lockfile and generate the incus commands realizing it
+//!
+//! [`Composer`] runs that pipeline on in-memory values; the `incus-composer`
+//! binary wraps it with file handling and reporting.
+
+pub mod apply;
+pub mod catalog;
+pub mod exit;
+pub mod firewall;
+pub mod import;
+pub mod incus;
+pub mod merge;
+pub mod report;
+pub mod schema;
+pub mod secrets;
+pub mod status;
+#[cfg(test)]
+mod test_support;
+
+use merge::{merge_lockfiles, MergePolicy};
+use schema::{IncusCompose, IncusLockfile};
+use secrets::SecretResolver;
+
+/// Generates a lockfile and commands from a configuration, merging with a previous lockfile
+///
+/// ```no_run
+/// use incus_composer::Composer;
+///
+/// let yaml = std::fs::read_to_string("incus-compose.yaml")?;
+/// let composition = Composer::from_yaml(&yaml, "incus-compose.yaml")?.run()?;
+/// for command in &composition.commands {
+///     println!("{}", command);
+/// }
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct Composer {
+    compose: IncusCompose,
+    existing: Option<IncusLockfile>,
+    policy: MergePolicy,
+    strict: bool,
+    verbose: bool,
+}
+
+/// What a [`Composer`] run produced
+#[derive(Debug, Clone)]
+pub struct Composition {
+    pub lockfile: IncusLockfile,
+
+    /// Incus commands in execution order, with secret references unresolved
+    pub commands: Vec<String>,
+
+    /// Problems worth reporting that don't stop generation, e.g. anti-affinity conflicts
+    pub warnings: Vec<String>,
+
+    /// The previous lockfile the run merged with, if any
+    pub previous: Option<IncusLockfile>,
+}
+
+impl Composition {
+    /// What the commands will create, as printed by `--plan`
+    pub fn plan_summary(&self) -> Vec<String> {
+        report::plan_summary(&self.lockfile)
+    }
+
+    /// How `name`'s addresses and MACs were arrived at, as printed by `--explain`
+    pub fn explain_host(&self, name: &str) -> Result<Vec<String>, String> {
+        report::explain_host(&self.lockfile, self.previous.as_ref(), name)
+    }
+
+    /// The lockfile summary printed by `--output json`
+    pub fn summary_json(&self) -> serde_json::Value {
+        report::lockfile_summary_json(&self.lockfile)
+    }
+
+    /// The `--dry-run` script with secrets resolved, and its number of commands
+    pub fn render_script(
+        &self,
+        secrets: &SecretResolver,
+        plan: bool,
+        verbose: bool,
+    ) -> Result<(String, usize), Box<dyn std::error::Error>> {
+        report::render_dry_run_script(&self.lockfile, secrets, plan, verbose)
+    }
+}
+
+impl Composer {
+    /// Compose an already loaded (normalized and validated) configuration
+    pub fn new(compose: IncusCompose) -> Self {
+        Composer {
+            compose,
+            existing: None,
+            policy: MergePolicy::Preserve,
+            strict: false,
+            verbose: false,
+        }
+    }
+
+    /// Parse, normalize and validate configuration text; `source_name` labels errors
+    pub fn from_yaml(yaml: &str, source_name: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self::new(IncusCompose::load_from_reader(
+            yaml.as_bytes(),
+            source_name,
+        )?))
+    }
+
+    /// Keep stable values (ids, MACs, addresses) from a previous lockfile
+    pub fn with_lockfile(mut self, existing: IncusLockfile) -> Self {
+        self.existing = Some(existing);
+        self
+    }
+
+    /// How the previous lockfile's values are carried over (default: `Preserve`)
+    pub fn with_policy(mut self, policy: MergePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Fail on warnings instead of returning them
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Print merge progress on stdout
+    pub fn with_verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    /// The configuration being composed
+    pub fn compose(&self) -> &IncusCompose {
+        &self.compose
+    }
+
+    /// Generate the lockfile, merge it with the previous one and derive the commands
+    pub fn run(self) -> Result<Composition, Box<dyn std::error::Error>> {
+        let mut lockfile = self.compose.generate_lockfile()?;
+        lockfile.validate()?;
+
+        let mut warnings = lockfile.anti_affinity_conflicts();
+        warnings.extend(lockfile.undeclared_storage_pools());
+        if self.strict && !warnings.is_empty() {
+            return Err(warnings.join("; ").into());
+        }
+
+        if let Some(existing) = self.existing.clone() {
+            lockfile = merge_lockfiles(lockfile, existing, self.policy, self.verbose)?;
+        }
+
+        let commands = lockfile.generate_incus_commands();
+        lockfile.check_command_references(&commands)?;
+
+        Ok(Composition {
+            commands,
+            lockfile,
+            warnings,
+            previous: self.existing,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::APPEND_BASE;
+
+    #[test]
+    fn test_composer_end_to_end_in_memory() {
+        let first = Composer::from_yaml(APPEND_BASE, "<memory>")
+            .unwrap()
+            .run()
+            .unwrap();
+        assert_eq!(
+            first.lockfile.hosts[1].ip_addresses["frontend"],
+            "10.0.1.11"
+        );
+        assert!(first
+            .commands
+            .contains(&"incus network create frontend --type=bridge".to_string()));
+        assert!(first.commands.contains(&"incus start api".to_string()));
+        assert!(first.warnings.is_empty());
+
+        // Dropping `web` would move `api` to .10 without the previous lockfile
+        let without_web = APPEND_BASE.replace(
+            "  - name: web\n    flavor: small_flavor\n    image: base_image\n    subnets: [frontend]\n",
+            "",
+        );
+        let second = Composer::from_yaml(&without_web, "<memory>")
+            .unwrap()
+            .with_lockfile(first.lockfile.clone())
+            .run()
+            .unwrap();
+        assert_eq!(second.lockfile.hosts.len(), 1);
+        assert_eq!(
+            second.lockfile.hosts[0].ip_addresses["frontend"],
+            "10.0.1.11"
+        );
+        assert_eq!(second.lockfile.hosts[0].id, first.lockfile.hosts[1].id);
+        assert!(!second.commands.iter().any(|c| c.contains("web")));
+
+        // The binary's reports come from the same values
+        assert!(second
+            .plan_summary()
+            .contains(&"  instances to create: 1".to_string()));
+        assert!(second
+            .explain_host("api")
+            .unwrap()
+            .contains(&"    address: 10.0.1.11 (preserved from lockfile)".to_string()));
+        assert_eq!(second.summary_json()["counts"]["hosts"], 1);
+        let (script, count) = second
+            .render_script(&SecretResolver::default(), false, false)
+            .unwrap();
+        assert_eq!(count, second.commands.len());
+        assert!(script.contains("\nincus start api\n"));
+    }
+}
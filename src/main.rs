@@ -1,11 +1,20 @@
-use clap::Parser;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
 use std::fs;
-use std::path::Path;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::time::Duration;
 
-mod schema;
-
-use schema::{IncusCompose, IncusLockfile};
+use incus_composer::exit::ErrorKind;
+use incus_composer::merge::MergePolicy;
+use incus_composer::report::{
+    explain_host, lockfile_summary_json, plan_summary, render_dry_run_script, teardown_summary,
+    SUBNET_UTILIZATION_WARNING,
+};
+use incus_composer::schema::{self, IncusCompose, IncusLockfile};
+use incus_composer::secrets::SecretResolver;
+use incus_composer::{apply, import, status, Composer};
 
 /// A tool for managing Incus system containers and VMs using declarative YAML configuration
 #[derive(Parser)]
@@ -14,7 +23,7 @@ use schema::{IncusCompose, IncusLockfile};
 #[command(version = "0.1.0")]
 #[command(about = "A tool for managing Incus system containers and VMs using declarative YAML configuration", long_about = None)]
 struct Cli {
-    /// Path to the incus-compose.yaml configuration file
+    /// Path to the incus-compose.yaml configuration file (`-` reads stdin)
     #[arg(
         short = 'c',
         long = "config",
@@ -23,6 +32,15 @@ struct Cli {
     )]
     config: String,
 
+    /// Change to DIR first, so every relative path (config, overrides, lockfile,
+    /// secrets, outputs) resolves against it
+    #[arg(short = 'C', long = "chdir", value_name = "DIR")]
+    chdir: Option<String>,
+
+    /// Deep-merge FILE onto the configuration (repeatable, applied in order)
+    #[arg(long = "override", value_name = "FILE")]
+    overrides: Vec<String>,
+
     /// Path to the lockfile (defaults to config file with .lock extension)
     #[arg(short = 'l', long = "lockfile", value_name = "FILE")]
     lockfile: Option<String>,
@@ -31,13 +49,252 @@ struct Cli {
     #[arg(short = 'd', long = "dry-run", value_name = "FILE")]
     dry_run: Option<String>,
 
+    /// Strict merge: never move an existing host's addresses, error instead
+    #[arg(long = "append")]
+    append: bool,
+
+    /// Resolve ${secret:name} references from FILE (key=value lines)
+    #[arg(long = "secrets-file", value_name = "FILE")]
+    secrets_file: Option<String>,
+
+    /// Shell command printing a secret; {name} stands for the secret name (passed as "$1")
+    #[arg(long = "secrets-command", value_name = "CMD")]
+    secrets_command: Option<String>,
+
+    /// Print incus export commands backing up every host into DIR
+    #[arg(long = "backup", value_name = "DIR")]
+    backup: Option<String>,
+
+    /// Write the device removals reconciling changed hosts with the previous lockfile to FILE
+    #[arg(long = "prune-script", value_name = "FILE")]
+    prune_script: Option<String>,
+
+    /// Print incus commands deleting every host and network, skipping protected hosts
+    ///
+    /// With --apply, run them instead.
+    #[arg(long = "teardown")]
+    teardown: bool,
+
+    /// Include protected hosts in --teardown, lifting their delete protection
+    #[arg(long = "force-protected", requires = "teardown")]
+    force_protected: bool,
+
+    /// Generate from an in-memory lockfile without writing it to disk
+    #[arg(long = "no-lock")]
+    no_lock: bool,
+
+    /// Print the fully resolved configuration as YAML and exit
+    #[arg(long = "dump-merged")]
+    dump_merged: bool,
+
+    /// Only refresh the lockfile; exit nonzero if its contents changed
+    #[arg(
+        long = "lockfile-only",
+        conflicts_with_all = [
+            "dry_run",
+            "no_lock",
+            "ansible_inventory",
+            "instances_yaml",
+            "backup",
+            "out_dir",
+            "prune_script",
+            "teardown",
+            "apply"
+        ]
+    )]
+    lockfile_only: bool,
+
+    /// Run the generated incus commands
+    #[arg(long = "apply")]
+    apply: bool,
+
+    /// Skip the confirmation prompt before --apply, e.g. in automation
+    #[arg(short = 'y', long = "yes", requires = "apply")]
+    yes: bool,
+
+    /// Create and start up to N independent hosts at once with --apply
+    #[arg(
+        long = "parallel",
+        value_name = "N",
+        default_value_t = 1,
+        requires = "apply"
+    )]
+    parallel: usize,
+
+    /// Create up to N networks at once with --apply, each configured after its create
+    #[arg(
+        long = "max-parallel-networks",
+        value_name = "N",
+        default_value_t = 1,
+        requires = "apply"
+    )]
+    max_parallel_networks: usize,
+
+    /// Retry a command failing with a transient error up to N times with --apply
+    #[arg(
+        long = "retries",
+        value_name = "N",
+        default_value_t = 0,
+        requires = "apply"
+    )]
+    retries: u32,
+
+    /// Wait MS milliseconds before the first retry, doubling for each one after
+    #[arg(
+        long = "retry-backoff",
+        value_name = "MS",
+        default_value_t = 1000,
+        requires = "apply"
+    )]
+    retry_backoff: u64,
+
+    /// Randomize new MAC and IP allocations reproducibly from SEED (sequential without it)
+    #[arg(long = "seed", value_name = "SEED")]
+    seed: Option<u64>,
+
+    /// Target incus remote NAME, overriding the configuration's `remote`
+    #[arg(long = "remote", value_name = "NAME")]
+    remote: Option<String>,
+
+    /// Only generate, apply or tear down hosts tagged TAG (repeatable, any tag matches)
+    #[arg(long = "tag", value_name = "TAG")]
+    tags: Vec<String>,
+
+    /// Generate one `incus launch` per host instead of create, config and start
+    #[arg(long = "launch")]
+    launch: bool,
+
+    /// Warn if the existing lockfile was generated from a different configuration
+    #[arg(long = "verify-lock")]
+    verify_lock: bool,
+
+    /// Treat warnings (such as anti-affinity conflicts) as errors
+    #[arg(long = "strict")]
+    strict: bool,
+
+    /// Write an Ansible inventory (INI) of the lockfile hosts to FILE
+    #[arg(long = "ansible-inventory", value_name = "FILE")]
+    ansible_inventory: Option<String>,
+
+    /// Write the lockfile, dry-run script, DOT graph and allocation CSV into DIR
+    #[arg(long = "out-dir", value_name = "DIR")]
+    out_dir: Option<String>,
+
+    /// Write incus instance definitions (name, type, config, devices) as YAML to FILE
+    #[arg(long = "instances-yaml", value_name = "FILE")]
+    instances_yaml: Option<String>,
+
+    /// Print an execution plan summary, also embedded at the top of the --dry-run script
+    #[arg(long = "plan")]
+    plan: bool,
+
+    /// Describe how host NAME's resources, addresses and MACs were chosen
+    #[arg(long = "explain", value_name = "NAME")]
+    explain: Option<String>,
+
+    /// Verify the --dry-run script is up to date instead of rewriting it
+    #[arg(long = "check", requires = "dry_run")]
+    check: bool,
+
+    /// Format of the configuration and lockfile summaries
+    #[arg(long = "output", value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+
     /// Enable verbose output
     #[arg(short = 'v', long = "verbose")]
     verbose: bool,
+
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
+
+/// Summary output format
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+enum OutputFormat {
+    /// Human-readable summaries, printed with --verbose
+    Text,
+
+    /// A single JSON summary object on stdout
+    Json,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Capture a running incus installation as a compose file and lockfile
+    Import {
+        /// Path to write the generated compose file to
+        #[arg(value_name = "FILE")]
+        output: String,
+
+        /// Overwrite the output files if they already exist
+        #[arg(long = "force")]
+        force: bool,
+    },
+
+    /// Compare lockfile hosts against what incus is actually running
+    Status,
+
+    /// Drop lockfile used values no longer referenced by the configuration
+    Prune,
+
+    /// Print the next address the lockfile would assign on a subnet, without taking it
+    NextIp {
+        /// Subnet to look in
+        #[arg(value_name = "SUBNET")]
+        subnet: String,
+
+        /// Use the router address range instead of the host range
+        #[arg(long = "router")]
+        router: bool,
+    },
+
+    /// Rewrite the configuration file in canonical form (comments are dropped)
+    Fmt {
+        /// Only check the file is formatted; exit nonzero if it is not
+        #[arg(long = "check")]
+        check: bool,
+    },
+
+    /// Print a tab completion script for SHELL on stdout
+    Completions {
+        #[arg(value_name = "SHELL")]
+        shell: Shell,
+    },
 }
 
 fn main() {
     let cli = Cli::parse();
+    // Changed here rather than in `run`, which tests call in parallel
+    if let Some(dir) = &cli.chdir {
+        if let Err(e) = std::env::set_current_dir(dir) {
+            eprintln!("✗ Cannot change to directory '{}': {}", dir, e);
+            process::exit(ErrorKind::Other.code());
+        }
+    }
+    if let Err(kind) = run(&cli) {
+        process::exit(kind.code());
+    }
+}
+
+/// Carry out `cli`, having already reported any failure on stderr
+fn run(cli: &Cli) -> Result<(), ErrorKind> {
+    if let Some(command) = &cli.command {
+        return match command {
+            Commands::Import { output, force } => {
+                run_import(output, cli.lockfile.as_deref(), *force, cli.verbose)
+            }
+            Commands::Status => run_status(&cli.config, cli.lockfile.as_deref(), cli.verbose),
+            Commands::Prune => run_prune(cli, cli.verbose),
+            Commands::NextIp { subnet, router } => {
+                run_next_ip(&cli.config, cli.lockfile.as_deref(), subnet, *router)
+            }
+            Commands::Fmt { check } => run_fmt(&cli.config, *check, cli.verbose),
+            Commands::Completions { shell } => {
+                print!("{}", completion_script(*shell));
+                Ok(())
+            }
+        };
+    }
 
     let config_path = &cli.config;
     let verbose = cli.verbose;
@@ -45,6 +302,9 @@ fn main() {
     // Determine lockfile path
     let lockfile_path = if let Some(path) = &cli.lockfile {
         path.clone()
+    } else if config_path == "-" && !cli.dump_merged {
+        eprintln!("✗ Reading the configuration from stdin requires --lockfile");
+        return Err(ErrorKind::Usage);
     } else {
         format!("{}.lock", config_path)
     };
@@ -61,69 +321,173 @@ fn main() {
     }
 
     // Load the configuration file
-    let compose = match load_compose_file(config_path, verbose) {
+    let mut compose = match load_compose_file(config_path, &cli.overrides, verbose) {
         Ok(compose) => compose,
         Err(e) => {
             eprintln!(
                 "✗ Error loading configuration file '{}': {}",
                 config_path, e
             );
-            process::exit(1);
+            return Err(ErrorKind::of_config_error(&*e));
         }
     };
 
-    if verbose {
+    compose.pin_loaded_hash();
+    compose.seed = cli.seed;
+    compose.launch |= cli.launch;
+
+    if let Some(remote) = &cli.remote {
+        compose.remote = Some(remote.clone());
+        if let Err(e) = compose.validate() {
+            eprintln!("✗ Invalid --remote: {}", e);
+            return Err(ErrorKind::Validation);
+        }
+    }
+
+    if cli.dump_merged {
+        match dump_merged(&compose) {
+            Ok(yaml) => print!("{}", yaml),
+            Err(e) => {
+                eprintln!("✗ Error serializing configuration: {}", e);
+                return Err(ErrorKind::Other);
+            }
+        }
+        return Ok(());
+    }
+
+    if verbose && cli.output == OutputFormat::Text {
         print_compose_summary(&compose);
     }
 
-    // Load existing lockfile if it exists
-    let existing_lockfile = if Path::new(&lockfile_path).exists() {
+    if cli.verify_lock && Path::new(&lockfile_path).exists() {
         match IncusLockfile::load_from_file(&lockfile_path) {
-            Ok(lockfile) => {
-                if verbose {
-                    println!("✓ Loaded existing lockfile: {}", lockfile_path);
+            Ok(existing) => match lockfile_drift(&compose, &existing) {
+                Some(drift) if cli.strict => {
+                    eprintln!("✗ Lockfile '{}': {}", lockfile_path, drift);
+                    return Err(ErrorKind::Validation);
                 }
-                Some(lockfile)
-            }
+                Some(drift) => eprintln!("⚠ Lockfile '{}': {}", lockfile_path, drift),
+                None if verbose => println!("✓ Lockfile matches the configuration"),
+                None => {}
+            },
             Err(e) => {
-                if verbose {
-                    println!(
-                        "⚠ Could not load existing lockfile (will create new): {}",
-                        e
-                    );
-                }
-                None
+                eprintln!("✗ Error loading lockfile '{}': {}", lockfile_path, e);
+                return Err(ErrorKind::LockfileIo);
             }
         }
+    }
+
+    // The lockfile as it was before this run, to tell --explain what was preserved
+    // and --prune-script what to remove
+    let lockfile_before = if cli.explain.is_some() || cli.prune_script.is_some() {
+        IncusLockfile::load_from_file(&lockfile_path).ok()
     } else {
-        if verbose {
-            println!("ℹ No existing lockfile found, will create new one");
-        }
         None
     };
 
-    // Generate new lockfile from compose configuration
-    let mut lockfile = compose.generate_lockfile();
+    // Remember what was on disk so --lockfile-only can report a change
+    let previous_lockfile = if cli.lockfile_only {
+        fs::read_to_string(&lockfile_path).ok()
+    } else {
+        None
+    };
 
-    // If we had an existing lockfile, preserve stable values where possible
-    if let Some(existing) = existing_lockfile {
-        lockfile = merge_lockfiles(lockfile, existing, verbose);
+    let policy = if cli.append {
+        MergePolicy::Append
+    } else {
+        MergePolicy::Preserve
+    };
+    let lockfile = match update_lockfile(
+        &compose,
+        &lockfile_path,
+        policy,
+        !cli.no_lock,
+        cli.strict,
+        verbose,
+    ) {
+        Ok(lockfile) => lockfile,
+        Err(e) => {
+            eprintln!("✗ Error updating lockfile '{}': {}", lockfile_path, e);
+            return Err(ErrorKind::of_lockfile_error(&*e));
+        }
+    };
+
+    // The whole lockfile is saved; everything after covers only the tagged hosts
+    let full_lockfile = lockfile;
+    let lockfile = full_lockfile.select_tagged(&cli.tags);
+    if lockfile.hosts.is_empty() && !full_lockfile.hosts.is_empty() {
+        eprintln!("✗ No hosts are tagged {}", cli.tags.join(" or "));
+        return Err(ErrorKind::Usage);
     }
 
-    // Save the updated lockfile
-    if let Err(e) = lockfile.save_to_file(&lockfile_path) {
-        eprintln!("✗ Error saving lockfile '{}': {}", lockfile_path, e);
-        process::exit(1);
+    match cli.output {
+        OutputFormat::Json => println!("{}", lockfile_summary_json(&lockfile)),
+        OutputFormat::Text if verbose => print_lockfile_summary(&lockfile),
+        OutputFormat::Text => {}
     }
 
-    if verbose {
-        println!("✓ Updated lockfile: {}", lockfile_path);
-        print_lockfile_summary(&lockfile);
+    if cli.lockfile_only {
+        if lockfile_changed(previous_lockfile.as_deref(), &full_lockfile) {
+            eprintln!("✗ Lockfile changed: {}", lockfile_path);
+            return Err(ErrorKind::Other);
+        }
+        if verbose {
+            println!("✓ Lockfile unchanged: {}", lockfile_path);
+        }
+        return Ok(());
+    }
+
+    // Secrets are only substituted into generated commands, never the lockfile
+    let secrets = match load_secrets(cli) {
+        Ok(secrets) => secrets,
+        Err(e) => {
+            eprintln!("✗ Error loading secrets: {}", e);
+            return Err(ErrorKind::Other);
+        }
+    };
+
+    if cli.plan {
+        for line in plan_summary(&lockfile) {
+            println!("{}", line);
+        }
+    }
+
+    if let Some(name) = &cli.explain {
+        match explain_host(&lockfile, lockfile_before.as_ref(), name) {
+            Ok(lines) => {
+                for line in lines {
+                    println!("{}", line);
+                }
+            }
+            Err(e) => {
+                eprintln!("✗ Cannot explain host '{}': {}", name, e);
+                return Err(ErrorKind::Other);
+            }
+        }
     }
 
     // Handle dry-run mode
-    if let Some(dry_run_file) = &cli.dry_run {
-        match generate_dry_run(dry_run_file, &lockfile, verbose) {
+    if let (Some(dry_run_file), true) = (&cli.dry_run, cli.check) {
+        match check_dry_run(dry_run_file, &lockfile, &secrets, cli.plan, verbose) {
+            Ok(None) => {
+                if verbose {
+                    println!("✓ Dry-run script is up to date: {}", dry_run_file);
+                }
+            }
+            Ok(Some(diff)) => {
+                eprintln!("✗ Dry-run script '{}' is out of date:", dry_run_file);
+                for line in diff {
+                    eprintln!("{}", line);
+                }
+                return Err(ErrorKind::Other);
+            }
+            Err(e) => {
+                eprintln!("✗ Error checking dry-run file '{}': {}", dry_run_file, e);
+                return Err(ErrorKind::Other);
+            }
+        }
+    } else if let Some(dry_run_file) = &cli.dry_run {
+        match generate_dry_run(dry_run_file, &lockfile, &secrets, cli.plan, verbose) {
             Ok(()) => {
                 if verbose {
                     println!("✓ Dry-run commands written to: {}", dry_run_file);
@@ -131,33 +495,438 @@ fn main() {
             }
             Err(e) => {
                 eprintln!("✗ Error writing dry-run file '{}': {}", dry_run_file, e);
-                process::exit(1);
+                return Err(ErrorKind::Other);
             }
         }
-    } else {
+    } else if verbose && !cli.apply {
+        println!("ℹ Use --dry-run to generate incus commands without executing");
+    }
+
+    let retry = apply::RetryPolicy {
+        retries: cli.retries,
+        backoff: Duration::from_millis(cli.retry_backoff),
+    };
+    let teardown_commands =
+        full_lockfile.generate_tagged_teardown_commands(cli.force_protected, &cli.tags);
+
+    if cli.apply {
+        let plan = if cli.teardown {
+            teardown_summary(&teardown_commands)
+        } else {
+            plan_summary(&lockfile)
+        };
+        match confirm(
+            &plan,
+            cli.teardown,
+            cli.yes,
+            &mut io::stdin().lock(),
+            &mut io::stderr(),
+        ) {
+            Ok(true) => {}
+            Ok(false) => {
+                eprintln!("✗ Aborted, nothing was changed");
+                return Err(ErrorKind::Other);
+            }
+            Err(e) => {
+                eprintln!("✗ Error reading confirmation: {}", e);
+                return Err(ErrorKind::Other);
+            }
+        }
+    }
+
+    if cli.apply && cli.teardown {
+        if let Err(e) = apply::run_commands(&teardown_commands, &secrets, &retry) {
+            eprintln!("✗ Error tearing down: {}", e);
+            return Err(ErrorKind::Apply);
+        }
+        println!("✓ Tore down {} resources", teardown_commands.len());
+    } else if cli.apply {
+        match apply::apply(
+            &lockfile,
+            &secrets,
+            cli.parallel,
+            cli.max_parallel_networks,
+            &retry,
+            verbose,
+        ) {
+            Ok(failures) if failures.is_empty() => {
+                println!("✓ Applied {} hosts", lockfile.hosts.len());
+            }
+            Ok(failures) => {
+                eprintln!(
+                    "✗ {} of {} hosts failed to apply:",
+                    failures.len(),
+                    lockfile.hosts.len()
+                );
+                for failure in failures {
+                    eprintln!("  ✗ {}: {}", failure.host, failure.reason);
+                }
+                return Err(ErrorKind::Apply);
+            }
+            Err(e) => {
+                eprintln!("✗ Error applying configuration: {}", e);
+                return Err(ErrorKind::Apply);
+            }
+        }
+    }
+
+    if let Some(inventory_file) = &cli.ansible_inventory {
+        if let Err(e) = fs::write(inventory_file, lockfile.generate_ansible_inventory()) {
+            eprintln!(
+                "✗ Error writing Ansible inventory '{}': {}",
+                inventory_file, e
+            );
+            return Err(ErrorKind::Other);
+        }
         if verbose {
-            println!("ℹ Use --dry-run to generate incus commands without executing");
+            println!("✓ Ansible inventory written to: {}", inventory_file);
+        }
+    }
+
+    if let Some(instances_file) = &cli.instances_yaml {
+        let written = serde_yaml::to_string(&lockfile.generate_instance_definitions())
+            .map_err(|e| e.to_string())
+            .and_then(|yaml| fs::write(instances_file, yaml).map_err(|e| e.to_string()));
+        if let Err(e) = written {
+            eprintln!(
+                "✗ Error writing instance definitions '{}': {}",
+                instances_file, e
+            );
+            return Err(ErrorKind::Other);
+        }
+        if verbose {
+            println!("✓ Instance definitions written to: {}", instances_file);
+        }
+    }
+
+    if let Some(out_dir) = &cli.out_dir {
+        let source = if config_path == "-" {
+            lockfile_path.as_str()
+        } else {
+            config_path.as_str()
+        };
+        let base = Path::new(source)
+            .file_stem()
+            .map_or("incus-compose".into(), |stem| stem.to_string_lossy());
+        match write_out_dir(out_dir, &base, &lockfile, &secrets, cli.plan, verbose) {
+            Ok(paths) if verbose => {
+                for path in paths {
+                    println!("✓ Written: {}", path.display());
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("✗ Error writing to '{}': {}", out_dir, e);
+                return Err(ErrorKind::Other);
+            }
+        }
+    }
+
+    if let Some(prune_file) = &cli.prune_script {
+        let commands = lockfile_before
+            .as_ref()
+            .map(|before| lockfile.generate_prune_commands(before))
+            .unwrap_or_default();
+        if let Err(e) = write_script(prune_file, &commands) {
+            eprintln!("✗ Error writing prune script '{}': {}", prune_file, e);
+            return Err(ErrorKind::Other);
+        }
+        if verbose {
+            println!(
+                "✓ {} device removals written to: {}",
+                commands.len(),
+                prune_file
+            );
+        }
+    }
+
+    if let Some(backup_dir) = &cli.backup {
+        for command in lockfile.generate_backup_commands(backup_dir) {
+            println!("{}", command);
+        }
+    }
+
+    if cli.teardown && !cli.apply {
+        for command in &teardown_commands {
+            println!("{}", command);
         }
     }
 
     if verbose {
         println!("\n✓ Operation completed successfully");
     }
+    Ok(())
+}
+
+fn run_import(
+    output: &str,
+    lockfile: Option<&str>,
+    force: bool,
+    verbose: bool,
+) -> Result<(), ErrorKind> {
+    let lockfile_path = lockfile
+        .map(|path| path.to_string())
+        .unwrap_or_else(|| format!("{}.lock", output));
+
+    for path in [output, lockfile_path.as_str()] {
+        if !force && Path::new(path).exists() {
+            eprintln!(
+                "✗ Refusing to overwrite existing file '{}' (use --force)",
+                path
+            );
+            return Err(ErrorKind::Other);
+        }
+    }
+
+    if verbose {
+        println!("🔍 Querying incus for instances and networks");
+    }
+
+    let (compose, lockfile) = match import::import_from_incus() {
+        Ok(result) => result,
+        Err(e) => {
+            eprintln!("✗ Error importing from incus: {}", e);
+            return Err(ErrorKind::Other);
+        }
+    };
+
+    let yaml = match serde_yaml::to_string(&compose) {
+        Ok(yaml) => yaml,
+        Err(e) => {
+            eprintln!("✗ Error serializing compose file: {}", e);
+            return Err(ErrorKind::Other);
+        }
+    };
+    if let Err(e) = fs::write(output, yaml) {
+        eprintln!("✗ Error writing compose file '{}': {}", output, e);
+        return Err(ErrorKind::Other);
+    }
+    if let Err(e) = lockfile.save_to_file(&lockfile_path) {
+        eprintln!("✗ Error saving lockfile '{}': {}", lockfile_path, e);
+        return Err(ErrorKind::LockfileIo);
+    }
+
+    if verbose {
+        print_compose_summary(&compose);
+    }
+    println!(
+        "✓ Imported {} hosts and {} subnets to {} (lockfile: {})",
+        compose.hosts.len(),
+        compose.subnets.len(),
+        output,
+        lockfile_path
+    );
+    Ok(())
+}
+
+fn run_status(config: &str, lockfile: Option<&str>, verbose: bool) -> Result<(), ErrorKind> {
+    let lockfile_path = lockfile
+        .map(|path| path.to_string())
+        .unwrap_or_else(|| format!("{}.lock", config));
+
+    let lockfile = match IncusLockfile::load_from_file(&lockfile_path) {
+        Ok(lockfile) => lockfile,
+        Err(e) => {
+            eprintln!("✗ Error loading lockfile '{}': {}", lockfile_path, e);
+            return Err(ErrorKind::LockfileIo);
+        }
+    };
+
+    if verbose {
+        println!("🔍 Querying incus for instances");
+    }
+
+    let statuses = match status::status_from_incus(&lockfile) {
+        Ok(statuses) => statuses,
+        Err(e) => {
+            eprintln!("✗ Error querying incus: {}", e);
+            return Err(ErrorKind::Other);
+        }
+    };
+
+    for host in &statuses {
+        let marker = match host.state {
+            status::HostState::Missing => "✗",
+            _ if host.matches() => "✓",
+            _ => "⚠",
+        };
+        println!("{} {}: {}", marker, host.name, host.state);
+        for mismatch in &host.ip_mismatches {
+            println!(
+                "    {}: expected {}, found {}",
+                mismatch.subnet,
+                mismatch.expected,
+                mismatch.actual.as_deref().unwrap_or("no address")
+            );
+        }
+    }
+
+    let drifted = statuses.iter().filter(|host| !host.matches()).count();
+    if drifted > 0 {
+        eprintln!(
+            "✗ {} of {} hosts do not match the lockfile",
+            drifted,
+            statuses.len()
+        );
+        return Err(ErrorKind::Other);
+    }
+    if verbose {
+        println!("✓ All {} hosts match the lockfile", statuses.len());
+    }
+    Ok(())
+}
+
+/// Completion script for `shell`, covering every option and subcommand
+fn completion_script(shell: Shell) -> String {
+    let mut script = Vec::new();
+    clap_complete::generate(shell, &mut Cli::command(), "incus-composer", &mut script);
+    String::from_utf8_lossy(&script).into_owned()
+}
+
+/// Print the next assignable address on `subnet` from the lockfile, leaving it unchanged
+fn run_next_ip(
+    config: &str,
+    lockfile: Option<&str>,
+    subnet: &str,
+    router: bool,
+) -> Result<(), ErrorKind> {
+    let lockfile_path = lockfile
+        .map(|path| path.to_string())
+        .unwrap_or_else(|| format!("{}.lock", config));
+
+    let lockfile = match IncusLockfile::load_from_file(&lockfile_path) {
+        Ok(lockfile) => lockfile,
+        Err(e) => {
+            eprintln!("✗ Error loading lockfile '{}': {}", lockfile_path, e);
+            return Err(ErrorKind::LockfileIo);
+        }
+    };
+
+    match lockfile.next_unused_ip(subnet, router) {
+        Ok(ip) => {
+            println!("{}", ip);
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("✗ {}", e);
+            Err(ErrorKind::Validation)
+        }
+    }
+}
+
+/// Rewrite `config` in canonical form, or with `check` only report whether it is
+///
+/// A `-` config is read from stdin and the formatted text printed on stdout.
+fn run_fmt(config: &str, check: bool, verbose: bool) -> Result<(), ErrorKind> {
+    let content = if config == "-" {
+        let mut content = String::new();
+        io::Read::read_to_string(&mut io::stdin().lock(), &mut content).map(|_| content)
+    } else {
+        fs::read_to_string(config)
+    };
+    let content = match content {
+        Ok(content) => content,
+        Err(e) => {
+            eprintln!("✗ Error reading configuration file '{}': {}", config, e);
+            return Err(ErrorKind::of_config_error(&e));
+        }
+    };
+
+    let formatted = match schema::format_compose(&content, config) {
+        Ok(formatted) => formatted,
+        Err(e) => {
+            eprintln!("✗ Error parsing configuration file '{}': {}", config, e);
+            return Err(ErrorKind::Parse);
+        }
+    };
+
+    if check {
+        if formatted != content {
+            eprintln!("✗ Configuration file '{}' is not formatted", config);
+            return Err(ErrorKind::Other);
+        }
+        if verbose {
+            println!("✓ Configuration file '{}' is formatted", config);
+        }
+    } else if config == "-" {
+        print!("{}", formatted);
+    } else if formatted != content {
+        if let Err(e) = fs::write(config, &formatted) {
+            eprintln!("✗ Error writing configuration file '{}': {}", config, e);
+            return Err(ErrorKind::Other);
+        }
+        if verbose {
+            println!("✓ Formatted: {}", config);
+        }
+    }
+    Ok(())
+}
+
+/// Regenerate the lockfile from the configuration and reclaim stale used values
+fn run_prune(cli: &Cli, verbose: bool) -> Result<(), ErrorKind> {
+    let lockfile_path = cli
+        .lockfile
+        .clone()
+        .unwrap_or_else(|| format!("{}.lock", cli.config));
+
+    let compose = match load_compose_file(&cli.config, &cli.overrides, verbose) {
+        Ok(compose) => compose,
+        Err(e) => {
+            eprintln!("✗ Error loading configuration file '{}': {}", cli.config, e);
+            return Err(ErrorKind::of_config_error(&*e));
+        }
+    };
+
+    let mut lockfile = match update_lockfile(
+        &compose,
+        &lockfile_path,
+        MergePolicy::Preserve,
+        false,
+        cli.strict,
+        verbose,
+    ) {
+        Ok(lockfile) => lockfile,
+        Err(e) => {
+            eprintln!("✗ Error updating lockfile '{}': {}", lockfile_path, e);
+            return Err(ErrorKind::of_lockfile_error(&*e));
+        }
+    };
+
+    let reclaimed = lockfile.prune_used_values();
+    if let Err(e) = lockfile.save_to_file(&lockfile_path) {
+        eprintln!("✗ Error saving lockfile '{}': {}", lockfile_path, e);
+        return Err(ErrorKind::LockfileIo);
+    }
+    println!(
+        "✓ Reclaimed {} unused value{} from {}",
+        reclaimed,
+        if reclaimed == 1 { "" } else { "s" },
+        lockfile_path
+    );
+    Ok(())
 }
 
 fn load_compose_file(
     path: &str,
+    overrides: &[String],
     verbose: bool,
 ) -> Result<IncusCompose, Box<dyn std::error::Error>> {
-    if !Path::new(path).exists() {
-        return Err(format!("Configuration file '{}' does not exist", path).into());
+    if path != "-" && !Path::new(path).exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Configuration file '{}' does not exist", path),
+        )
+        .into());
     }
 
     if verbose {
         println!("📖 Loading configuration file: {}", path);
+        for override_path in overrides {
+            println!("📖 Layering override file: {}", override_path);
+        }
     }
 
-    let compose = IncusCompose::load_from_file(path)?;
+    let compose = IncusCompose::load_with_overrides(path.to_string(), overrides)?;
 
     if verbose {
         println!("✓ Successfully parsed configuration file");
@@ -166,6 +935,99 @@ fn load_compose_file(
     Ok(compose)
 }
 
+/// Generate a lockfile from `compose`, merged with the one at `lockfile_path`
+///
+/// The merge always runs so addresses stay stable; the result is only written
+/// back when `write` is set. Under `strict`, warnings abort before writing.
+fn update_lockfile(
+    compose: &IncusCompose,
+    lockfile_path: &str,
+    policy: MergePolicy,
+    write: bool,
+    strict: bool,
+    verbose: bool,
+) -> Result<IncusLockfile, Box<dyn std::error::Error>> {
+    // Load existing lockfile if it exists
+    let existing_lockfile = if Path::new(lockfile_path).exists() {
+        match IncusLockfile::load_with_migrations(lockfile_path) {
+            Ok((lockfile, migrations)) => {
+                if verbose {
+                    println!("✓ Loaded existing lockfile: {}", lockfile_path);
+                    for note in &migrations {
+                        println!("  ↑ Migrated: {}", note);
+                    }
+                }
+                Some(lockfile)
+            }
+            Err(e) => {
+                if verbose {
+                    println!(
+                        "⚠ Could not load existing lockfile (will create new): {}",
+                        e
+                    );
+                }
+                None
+            }
+        }
+    } else {
+        if verbose {
+            println!("ℹ No existing lockfile found, will create new one");
+        }
+        None
+    };
+
+    // Generate a new lockfile, preserving stable values from the existing one
+    let mut composer = Composer::new(compose.clone())
+        .with_policy(policy)
+        .with_strict(strict)
+        .with_verbose(verbose);
+    if let Some(existing) = existing_lockfile {
+        composer = composer.with_lockfile(existing);
+    }
+    let composition = composer.run()?;
+    for warning in &composition.warnings {
+        eprintln!("⚠ {}", warning);
+    }
+    let lockfile = composition.lockfile;
+
+    if write {
+        lockfile.save_to_file(lockfile_path)?;
+        if verbose {
+            println!("✓ Updated lockfile: {}", lockfile_path);
+        }
+    } else if verbose {
+        println!("ℹ Lockfile left untouched (--no-lock): {}", lockfile_path);
+    }
+
+    Ok(lockfile)
+}
+
+/// The configuration as the generator sees it, after normalization and group expansion
+fn dump_merged(compose: &IncusCompose) -> Result<String, Box<dyn std::error::Error>> {
+    Ok(serde_yaml::to_string(compose)?)
+}
+
+/// Whether `lockfile` differs from the `previous` lockfile text
+///
+/// The generation timestamp is ignored, so regenerating an unchanged compose
+/// file does not count as a change.
+fn lockfile_changed(previous: Option<&str>, lockfile: &IncusLockfile) -> bool {
+    let Some(mut previous) =
+        previous.and_then(|text| serde_yaml::from_str::<IncusLockfile>(text).ok())
+    else {
+        return true;
+    };
+    previous.metadata.generated_at = lockfile.metadata.generated_at.clone();
+
+    match (
+        serde_yaml::to_string(&previous),
+        serde_yaml::to_string(lockfile),
+    ) {
+        (Ok(before), Ok(after)) => before != after,
+        _ => true,
+    }
+}
+
 fn print_compose_summary(compose: &IncusCompose) {
     println!("Configuration Summary:");
     println!("  Version: {}", compose.version);
@@ -210,17 +1072,24 @@ fn print_compose_summary(compose: &IncusCompose) {
                 format!(" [{}]", flags.join(", "))
             };
 
-            let subnet_str = if host.subnets.is_empty() {
+            let subnet_names = host.subnet_names();
+            let subnet_str = if subnet_names.is_empty() {
+                String::new()
+            } else if subnet_names.len() == 1 {
+                format!(" → {}", subnet_names[0])
+            } else {
+                format!(" → {}", subnet_names.join(", "))
+            };
+
+            let tag_str = if host.tags.is_empty() {
                 String::new()
-            } else if host.subnets.len() == 1 {
-                format!(" → {}", host.subnets[0])
             } else {
-                format!(" → {}", host.subnets.join(", "))
+                format!(" #{}", host.tags.join(" #"))
             };
 
             println!(
-                "  • {} ({}{}){}",
-                host.name, host.image, flag_str, subnet_str
+                "  • {} ({}{}){}{}",
+                host.name, host.image, flag_str, subnet_str, tag_str
             );
         }
     }
@@ -275,9 +1144,13 @@ fn print_lockfile_summary(lockfile: &IncusLockfile) {
         println!("\nExpanded Host Configuration:");
         for host in &lockfile.hosts {
             let ip_list: Vec<String> = host
-                .ip_addresses
+                .subnets
                 .iter()
-                .map(|(subnet, ip)| format!("{}:{}", subnet, ip))
+                .filter_map(|s| {
+                    host.ip_addresses
+                        .get(&s.name)
+                        .map(|ip| format!("{}:{}", s.name, ip))
+                })
                 .collect();
 
             let ip_str = if ip_list.is_empty() {
@@ -286,12 +1159,19 @@ fn print_lockfile_summary(lockfile: &IncusLockfile) {
                 format!(" [{}]", ip_list.join(", "))
             };
 
+            let tag_str = if host.tags.is_empty() {
+                String::new()
+            } else {
+                format!(" #{}", host.tags.join(" #"))
+            };
+
             println!(
-                "  • {} (ID: {}) → MAC: {}{}",
+                "  • {} (ID: {}) → MAC: {}{}{}",
                 host.name,
                 host.id,
                 host.mac_address.as_ref().unwrap_or(&"none".to_string()),
-                ip_str
+                ip_str,
+                tag_str
             );
         }
     }
@@ -304,137 +1184,166 @@ fn print_lockfile_summary(lockfile: &IncusLockfile) {
                 subnet.name, subnet.id, subnet.cidr, subnet.gateway
             );
         }
+
+        println!("\nSubnet Utilization:");
+        for usage in lockfile.subnet_utilization() {
+            println!(
+                "  • {}: {}/{} used ({:.0}%)",
+                usage.name,
+                usage.used,
+                usage.capacity,
+                usage.percent()
+            );
+            if usage.percent() > SUBNET_UTILIZATION_WARNING {
+                println!(
+                    "    ⚠ Subnet '{}' is over {:.0}% full",
+                    usage.name, SUBNET_UTILIZATION_WARNING
+                );
+            }
+        }
     }
     println!();
 }
 
-fn merge_lockfiles(
-    new_lockfile: IncusLockfile,
-    existing: IncusLockfile,
-    verbose: bool,
-) -> IncusLockfile {
-    if verbose {
-        println!("🔄 Merging with existing lockfile to preserve stable values");
-    }
-
-    let mut merged = new_lockfile;
-
-    // Preserve MAC addresses and IDs for existing hosts
-    for new_host in &mut merged.hosts {
-        if let Some(existing_host) = existing.hosts.iter().find(|h| h.name == new_host.name) {
-            // Preserve stable identifiers
-            new_host.id = existing_host.id.clone();
-            new_host.mac_address = existing_host.mac_address.clone();
-
-            // Preserve IP addresses where subnets haven't changed
-            for (subnet_name, existing_ip) in &existing_host.ip_addresses {
-                if new_host.subnets.contains(subnet_name) {
-                    new_host
-                        .ip_addresses
-                        .insert(subnet_name.clone(), existing_ip.clone());
-                }
-            }
+/// Why `lockfile` doesn't match `compose`, if it was generated from something else
+fn lockfile_drift(compose: &IncusCompose, lockfile: &IncusLockfile) -> Option<String> {
+    let expected = compose.source_hash();
+    (lockfile.metadata.source_hash != expected).then(|| {
+        format!(
+            "source hash {} does not match the configuration ({}); was it edited by hand?",
+            lockfile.metadata.source_hash, expected
+        )
+    })
+}
 
-            if verbose {
-                println!("  ↻ Preserved identifiers for host: {}", new_host.name);
-            }
-        }
+/// Show `plan` on `output` and ask whether to go ahead; `yes` answers without asking
+///
+/// A `destructive` plan gets a warning banner and must be confirmed by typing
+/// `yes` in full. No answer (end of input) declines.
+fn confirm(
+    plan: &[String],
+    destructive: bool,
+    yes: bool,
+    input: &mut impl io::BufRead,
+    output: &mut impl io::Write,
+) -> io::Result<bool> {
+    if yes {
+        return Ok(true);
     }
 
-    // Preserve subnet IDs and configurations where possible
-    for new_subnet in &mut merged.subnets {
-        if let Some(existing_subnet) = existing.subnets.iter().find(|s| s.name == new_subnet.name) {
-            // Only preserve if CIDR hasn't changed
-            if new_subnet.cidr == existing_subnet.cidr {
-                new_subnet.id = existing_subnet.id.clone();
-                new_subnet.gateway = existing_subnet.gateway.clone();
-
-                if verbose {
-                    println!(
-                        "  ↻ Preserved configuration for subnet: {}",
-                        new_subnet.name
-                    );
-                }
-            }
-        }
+    if destructive {
+        writeln!(
+            output,
+            "⚠ ═══ TEARDOWN: the following will be PERMANENTLY DELETED ═══"
+        )?;
     }
+    for line in plan {
+        writeln!(output, "{}", line)?;
+    }
+    if destructive {
+        write!(output, "⚠ Type 'yes' to delete these resources: ")?;
+    } else {
+        write!(output, "Apply this plan? [y/N] ")?;
+    }
+    output.flush()?;
 
-    // Update metadata but preserve some used values tracking
-    merged.metadata.used_values.mac_addresses = existing.metadata.used_values.mac_addresses;
-    merged.metadata.used_values.host_ids = existing.metadata.used_values.host_ids;
-    merged.metadata.used_values.subnet_ids = existing.metadata.used_values.subnet_ids;
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    let answer = answer.trim().to_lowercase();
+    Ok(if destructive {
+        answer == "yes"
+    } else {
+        answer == "y" || answer == "yes"
+    })
+}
 
-    merged
+fn load_secrets(cli: &Cli) -> Result<SecretResolver, Box<dyn std::error::Error>> {
+    let resolver = match &cli.secrets_file {
+        Some(path) => SecretResolver::from_file(path)?,
+        None => SecretResolver::default(),
+    };
+    Ok(match &cli.secrets_command {
+        Some(command) => resolver.with_command(command),
+        None => resolver,
+    })
 }
 
 fn generate_dry_run(
     output_file: &str,
     lockfile: &IncusLockfile,
+    secrets: &SecretResolver,
+    plan: bool,
     verbose: bool,
 ) -> Result<(), Box<dyn std::error::Error>> {
     if verbose {
         println!("📝 Generating incus commands for dry-run");
     }
 
-    let commands = lockfile.generate_incus_commands();
+    let (script_content, command_count) = render_dry_run_script(lockfile, secrets, plan, verbose)?;
+    fs::write(output_file, script_content)?;
 
-    let mut output = Vec::new();
-    output.push("#!/bin/bash".to_string());
-    output.push("# Generated by incus-composer".to_string());
-    output.push(format!(
-        "# Generated at: {}",
-        lockfile.metadata.generated_at
-    ));
-    output.push(format!(
-        "# Generator version: {}",
-        lockfile.metadata.generator_version
-    ));
-    output.push(format!("# Source hash: {}", lockfile.metadata.source_hash));
-    output.push("".to_string());
-    output.push("set -e  # Exit on any error".to_string());
-    output.push("".to_string());
+    // Make the script executable on Unix systems
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mut perms = fs::metadata(output_file)?.permissions();
+        perms.set_mode(0o755);
+        fs::set_permissions(output_file, perms)?;
+    }
 
     if verbose {
-        output.push("echo 'Starting incus-composer deployment...'".to_string());
-        output.push("".to_string());
+        println!("  📊 Generated {} commands", command_count);
+        println!("  📄 Script saved as executable: {}", output_file);
     }
 
-    // Add section comments
-    output.push("# ============================================".to_string());
-    output.push("# Network Creation".to_string());
-    output.push("# ============================================".to_string());
-    output.push("".to_string());
+    Ok(())
+}
 
-    let mut in_network_section = true;
-    for command in &commands {
-        if command.starts_with("incus create") && in_network_section {
-            output.push("".to_string());
-            output.push("# ============================================".to_string());
-            output.push("# Instance Creation and Configuration".to_string());
-            output.push("# ============================================".to_string());
-            output.push("".to_string());
-            in_network_section = false;
-        }
+/// Write the lockfile, dry-run script, DOT graph and allocation CSV into `dir`
+///
+/// The files share `base` as their name (`<base>.lock`, `.sh`, `.dot`, `.csv`),
+/// so one run leaves a consistent set of artifacts.
+fn write_out_dir(
+    dir: &str,
+    base: &str,
+    lockfile: &IncusLockfile,
+    secrets: &SecretResolver,
+    plan: bool,
+    verbose: bool,
+) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    fs::create_dir_all(dir)?;
+    let path = |extension: &str| Path::new(dir).join(format!("{}.{}", base, extension));
 
-        if command.starts_with('#') {
-            output.push(command.clone());
-        } else {
-            if verbose {
-                output.push(format!("echo 'Executing: {}'", command));
-            }
-            output.push(command.clone());
-        }
-    }
+    let lock_path = path("lock");
+    lockfile.save_to_file(&lock_path)?;
+    let script_path = path("sh");
+    generate_dry_run(
+        &script_path.display().to_string(),
+        lockfile,
+        secrets,
+        plan,
+        verbose,
+    )?;
+    let dot_path = path("dot");
+    fs::write(&dot_path, lockfile.generate_dot_graph())?;
+    let csv_path = path("csv");
+    fs::write(&csv_path, lockfile.generate_allocation_csv())?;
 
-    output.push("".to_string());
-    if verbose {
-        output.push("echo 'Deployment completed successfully!'".to_string());
-    }
+    Ok(vec![lock_path, script_path, dot_path, csv_path])
+}
 
-    let script_content = output.join("\n");
-    fs::write(output_file, script_content)?;
+/// Write `commands` to an executable script stopping at the first failure
+fn write_script(output_file: &str, commands: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut output = vec![
+        "#!/bin/bash".to_string(),
+        "# Generated by incus-composer".to_string(),
+        "".to_string(),
+        "set -e  # Exit on any error".to_string(),
+        "".to_string(),
+    ];
+    output.extend(commands.iter().cloned());
+    fs::write(output_file, output.join("\n") + "\n")?;
 
-    // Make the script executable on Unix systems
     #[cfg(unix)]
     {
         use std::os::unix::fs::PermissionsExt;
@@ -443,10 +1352,439 @@ fn generate_dry_run(
         fs::set_permissions(output_file, perms)?;
     }
 
-    if verbose {
-        println!("  📊 Generated {} commands", commands.len());
-        println!("  📄 Script saved as executable: {}", output_file);
+    Ok(())
+}
+
+/// Compare the dry-run script that would be generated against an existing file
+///
+/// Returns a line diff when they differ. The `# Generated at:` header is ignored
+/// since it changes on every run.
+fn check_dry_run(
+    script_file: &str,
+    lockfile: &IncusLockfile,
+    secrets: &SecretResolver,
+    plan: bool,
+    verbose: bool,
+) -> Result<Option<Vec<String>>, Box<dyn std::error::Error>> {
+    let existing = fs::read_to_string(script_file)?;
+    let (expected, _) = render_dry_run_script(lockfile, secrets, plan, verbose)?;
+
+    let comparable = |script: &str| -> Vec<String> {
+        script
+            .lines()
+            .filter(|line| !line.starts_with("# Generated at:"))
+            .map(|line| line.to_string())
+            .collect()
+    };
+    let existing_lines = comparable(&existing);
+    let expected_lines = comparable(&expected);
+
+    if existing_lines == expected_lines {
+        Ok(None)
+    } else {
+        Ok(Some(line_diff(&existing_lines, &expected_lines)))
     }
+}
 
-    Ok(())
+/// Minimal line diff (longest common subsequence) marking removals with `-` and additions with `+`
+fn line_diff(old: &[String], new: &[String]) -> Vec<String> {
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            diff.push(format!("-{}", old[i]));
+            i += 1;
+        } else {
+            diff.push(format!("+{}", new[j]));
+            j += 1;
+        }
+    }
+    diff.extend(old[i..].iter().map(|line| format!("-{}", line)));
+    diff.extend(new[j..].iter().map(|line| format!("+{}", line)));
+    diff
+}
+
+// Shared with the library's tests; `crate::schema` resolves through the import above
+#[cfg(test)]
+mod test_support;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{lockfile_from_yaml, APPEND_BASE};
+
+    #[test]
+    fn test_yes_skips_confirmation_prompt() {
+        let plan = vec!["Plan:".to_string()];
+
+        // Nothing to read and nothing written: --yes never prompts
+        let mut output = Vec::new();
+        assert!(confirm(&plan, false, true, &mut &b""[..], &mut output).unwrap());
+        assert!(confirm(&plan, true, true, &mut &b""[..], &mut output).unwrap());
+        assert!(output.is_empty());
+
+        let mut output = Vec::new();
+        assert!(confirm(&plan, false, false, &mut &b"y\n"[..], &mut output).unwrap());
+        assert!(String::from_utf8(output).unwrap().contains("[y/N]"));
+        assert!(!confirm(&plan, false, false, &mut &b"\n"[..], &mut Vec::new()).unwrap());
+        assert!(!confirm(&plan, false, false, &mut &b""[..], &mut Vec::new()).unwrap());
+
+        // Teardown wants the whole word
+        let mut output = Vec::new();
+        assert!(!confirm(&plan, true, false, &mut &b"y\n"[..], &mut output).unwrap());
+        assert!(String::from_utf8(output).unwrap().contains("TEARDOWN"));
+        assert!(confirm(&plan, true, false, &mut &b"yes\n"[..], &mut Vec::new()).unwrap());
+    }
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("incus-composer-{}-{}", process::id(), name))
+            .display()
+            .to_string()
+    }
+
+    #[test]
+    fn test_lockfile_only_reports_changes() {
+        let lockfile = lockfile_from_yaml(APPEND_BASE);
+        let mut previous = lockfile.clone();
+        previous.metadata.generated_at = "earlier".to_string();
+        let previous = serde_yaml::to_string(&previous).unwrap();
+
+        // Only the timestamp differs: exit zero
+        assert!(!lockfile_changed(Some(&previous), &lockfile));
+
+        // A new host or a missing lockfile: exit nonzero
+        let grown = lockfile_from_yaml(&APPEND_BASE.replace(
+            "subnets:\n  - name: frontend",
+            "  - name: db\n    flavor: small_flavor\n    image: base_image\nsubnets:\n  - name: frontend",
+        ));
+        assert_eq!(grown.hosts.len(), 3);
+        assert!(lockfile_changed(Some(&previous), &grown));
+        assert!(lockfile_changed(None, &lockfile));
+    }
+
+    #[test]
+    fn test_dump_merged_shows_normalized_config() {
+        let yaml = "hosts:\n  - name: web\n    flavor: small_flavor\n    image: base_image\n    subnet: frontend\nsubnets: [frontend]\n";
+        let compose = IncusCompose::load_from_reader(yaml.as_bytes(), "<test>").unwrap();
+
+        let dump = dump_merged(&compose).unwrap();
+        assert!(dump.contains("  subnets:\n  - frontend\n"), "{}", dump);
+        assert!(!dump.contains("subnet:"));
+
+        // The dump is itself a valid configuration
+        let reloaded = IncusCompose::load_from_reader(dump.as_bytes(), "<dump>").unwrap();
+        assert_eq!(reloaded.hosts[0].subnet_names(), vec!["frontend"]);
+    }
+
+    #[test]
+    fn test_bash_completions() {
+        let script = completion_script(Shell::Bash);
+        assert!(script.contains("--config"));
+        assert!(script.contains("next-ip"));
+        for shell in [Shell::Zsh, Shell::Fish, Shell::PowerShell] {
+            assert!(!completion_script(shell).is_empty());
+        }
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        let run_with = |config: &str| {
+            let lockfile = temp_path("exit-codes.lock");
+            let result = run(&Cli::parse_from([
+                "incus-composer",
+                "--config",
+                config,
+                "--lockfile",
+                &lockfile,
+                "--no-lock",
+            ]));
+            result.map_err(ErrorKind::code)
+        };
+
+        let missing = temp_path("missing.yaml");
+        assert_eq!(run_with(&missing), Err(3));
+
+        let config = temp_path("exit-codes.yaml");
+        fs::write(&config, "hosts: [not a host\n").unwrap();
+        assert_eq!(run_with(&config), Err(4));
+
+        fs::write(
+            &config,
+            "hosts:\n  - name: web\n    image: base_image\nsubnets: []\n",
+        )
+        .unwrap();
+        assert_eq!(run_with(&config), Err(5));
+
+        fs::write(
+            &config,
+            "hosts:\n  - name: web\n    flavor: small_flavor\n    image: base_image\nsubnets: []\n",
+        )
+        .unwrap();
+        assert_eq!(run_with(&config), Ok(()));
+        fs::remove_file(&config).unwrap();
+    }
+
+    #[test]
+    fn test_tag_selects_only_tagged_hosts() {
+        let config = temp_path("tags.yaml");
+        let script = temp_path("tags.sh");
+        fs::write(
+            &config,
+            r#"
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend]
+    tags: [prod]
+  - name: staging-web
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend]
+    tags: [staging]
+  - name: db
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend]
+    tags: [prod, data]
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+"#,
+        )
+        .unwrap();
+
+        let run_with = |tag: &str| {
+            run(&Cli::parse_from([
+                "incus-composer",
+                "--config",
+                &config,
+                "--no-lock",
+                "--tag",
+                tag,
+                "--dry-run",
+                &script,
+            ]))
+        };
+        let result = run_with("prod");
+        let generated = fs::read_to_string(&script).unwrap();
+        let unmatched = run_with("qa");
+        fs::remove_file(&config).unwrap();
+        fs::remove_file(&script).unwrap();
+
+        assert_eq!(result, Ok(()));
+        assert!(generated.contains("incus start web"));
+        assert!(generated.contains("incus start db"));
+        assert!(!generated.contains("staging-web"));
+        // Addresses still come from the whole configuration
+        assert!(generated.contains("ipv4.address=10.0.1.12"));
+        assert_eq!(unmatched, Err(ErrorKind::Usage));
+    }
+
+    #[test]
+    fn test_lockfile_drift() {
+        let compose: IncusCompose = serde_yaml::from_str(APPEND_BASE).unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+        assert_eq!(lockfile_drift(&compose, &lockfile), None);
+
+        let changed: IncusCompose =
+            serde_yaml::from_str(&APPEND_BASE.replace("10.0.1.0/24", "10.0.9.0/24")).unwrap();
+        let drift = lockfile_drift(&changed, &lockfile).unwrap();
+        assert!(drift.contains(&lockfile.metadata.source_hash), "{}", drift);
+    }
+
+    #[test]
+    fn test_cli_overrides_do_not_count_as_drift() {
+        let on_disk: IncusCompose = serde_yaml::from_str(APPEND_BASE).unwrap();
+
+        // What main does: pin the hash, then apply the overrides
+        let mut compose = on_disk.clone();
+        compose.pin_loaded_hash();
+        compose.remote = Some("prod".to_string());
+        compose.launch = true;
+        let lockfile = compose.generate_lockfile().unwrap();
+
+        // A later run without the overrides sees the same file
+        let mut reloaded = on_disk;
+        reloaded.pin_loaded_hash();
+        assert_eq!(lockfile_drift(&reloaded, &lockfile), None);
+    }
+
+    #[test]
+    fn test_no_lock_leaves_lockfile_untouched() {
+        let lockfile_path = temp_path("no-lock.lock");
+        let existing = lockfile_from_yaml(APPEND_BASE);
+        existing.save_to_file(&lockfile_path).unwrap();
+        let before = fs::read_to_string(&lockfile_path).unwrap();
+
+        // Without `web`, a fresh generation would move `api` to .10
+        let compose: IncusCompose = serde_yaml::from_str(
+            r#"
+hosts:
+  - name: api
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend]
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+"#,
+        )
+        .unwrap();
+        let lockfile = update_lockfile(
+            &compose,
+            &lockfile_path,
+            MergePolicy::Preserve,
+            false,
+            false,
+            false,
+        )
+        .unwrap();
+        let after = fs::read_to_string(&lockfile_path).unwrap();
+        fs::remove_file(&lockfile_path).unwrap();
+
+        assert_eq!(before, after);
+        // The merge still ran in memory, so `api` keeps its address
+        assert_eq!(lockfile.hosts[0].ip_addresses["frontend"], "10.0.1.11");
+    }
+
+    #[test]
+    fn test_check_detects_stale_dry_run() {
+        let original = lockfile_from_yaml(
+            r#"
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend]
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+"#,
+        );
+        let script = temp_path("check.sh");
+        let secrets = SecretResolver::default();
+        generate_dry_run(&script, &original, &secrets, false, false).unwrap();
+
+        // Regenerating the same topology is up to date
+        assert!(check_dry_run(&script, &original, &secrets, false, false)
+            .unwrap()
+            .is_none());
+
+        let mut changed = original.clone();
+        changed.hosts[0].name = "api".to_string();
+        let diff = check_dry_run(&script, &changed, &secrets, false, false)
+            .unwrap()
+            .unwrap();
+        let before = fs::read_to_string(&script).unwrap();
+        fs::remove_file(&script).unwrap();
+
+        assert!(diff.contains(&"-incus start web".to_string()));
+        assert!(diff.contains(&"+incus start api".to_string()));
+        // The checked file is never rewritten
+        assert!(before.contains("incus start web"));
+    }
+
+    #[test]
+    fn test_out_dir_writes_all_artifacts() {
+        let lockfile = lockfile_from_yaml(APPEND_BASE);
+        let dir = temp_path("out-dir");
+        let paths = write_out_dir(
+            &dir,
+            "incus-compose",
+            &lockfile,
+            &SecretResolver::default(),
+            false,
+            false,
+        )
+        .unwrap();
+
+        let names: Vec<String> = paths
+            .iter()
+            .map(|p| p.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "incus-compose.lock",
+                "incus-compose.sh",
+                "incus-compose.dot",
+                "incus-compose.csv"
+            ]
+        );
+        assert!(paths.iter().all(|p| p.is_file()));
+
+        let reloaded = IncusLockfile::load_from_file(&paths[0]).unwrap();
+        assert_eq!(reloaded.hosts.len(), 2);
+        assert!(fs::read_to_string(&paths[1])
+            .unwrap()
+            .contains("incus start api"));
+        assert!(fs::read_to_string(&paths[2])
+            .unwrap()
+            .contains("\"web\" -> \"frontend\" [label=\"eth0 10.0.1.10\"];"));
+        assert!(fs::read_to_string(&paths[3])
+            .unwrap()
+            .contains("\napi,frontend,eth0,10.0.1.11,"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_secrets_expand_in_commands_but_not_lockfile() {
+        let lockfile = lockfile_from_yaml(
+            r#"
+hosts:
+  - name: db
+    flavor: small_flavor
+    image: base_image
+    environment:
+      DB_PASSWORD: "${secret:db_password}"
+    roles:
+      - name: postgresql
+        values: ["password=${secret:db_password}"]
+subnets: []
+"#,
+        );
+        let secrets_path = temp_path("secrets.env");
+        fs::write(&secrets_path, "db_password=hunter2\n").unwrap();
+        let secrets = SecretResolver::from_file(&secrets_path).unwrap();
+        fs::remove_file(&secrets_path).unwrap();
+
+        let (script, _) = render_dry_run_script(&lockfile, &secrets, false, true).unwrap();
+        assert!(script
+            .lines()
+            .any(|line| line == "incus config set db environment.DB_PASSWORD='hunter2'"));
+        // Comments and echoed commands keep the reference
+        for line in script.lines().filter(|line| line.contains("hunter2")) {
+            assert!(
+                !line.starts_with('#') && !line.starts_with("echo "),
+                "{}",
+                line
+            );
+        }
+        assert!(script.contains("# Apply role 'postgresql'"));
+
+        let lock_yaml = serde_yaml::to_string(&lockfile).unwrap();
+        assert!(lock_yaml.contains("password=${secret:db_password}"));
+        assert!(!lock_yaml.contains("hunter2"));
+
+        // Without a resolver the reference is an error rather than leaking through
+        assert!(
+            render_dry_run_script(&lockfile, &SecretResolver::default(), false, false).is_err()
+        );
+    }
 }
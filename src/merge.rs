@@ -0,0 +1,461 @@
+use std::collections::BTreeMap;
+
+use crate::schema::{ip_pool_key, IncusLockfile};
+
+/// How values from an existing lockfile are carried into a regenerated one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MergePolicy {
+    /// Keep ids, MACs and addresses where the host's subnets still match (default)
+    Preserve,
+
+    /// Existing host addresses are never moved: changing the CIDR of a subnet
+    /// an existing host is addressed on is an error, and new hosts are allocated
+    /// around the addresses and MACs already held
+    Append,
+}
+
+pub fn merge_lockfiles(
+    new_lockfile: IncusLockfile,
+    existing: IncusLockfile,
+    policy: MergePolicy,
+    verbose: bool,
+) -> Result<IncusLockfile, Box<dyn std::error::Error>> {
+    if verbose {
+        println!("🔄 Merging with existing lockfile to preserve stable values");
+    }
+
+    let mut merged = new_lockfile;
+
+    if policy == MergePolicy::Append {
+        check_append_safe(&merged, &existing)?;
+    }
+
+    // Preserve MAC addresses and IDs for existing hosts
+    for new_host in &mut merged.hosts {
+        if let Some(existing_host) = existing.hosts.iter().find(|h| h.name == new_host.name) {
+            // Preserve stable identifiers
+            new_host.id = existing_host.id.clone();
+            new_host.mac_address = existing_host.mac_address.clone();
+
+            // Preserve IP addresses where subnets haven't changed, unless now pinned
+            for (subnet_name, existing_ip) in &existing_host.ip_addresses {
+                let attachment = new_host.subnets.iter().find(|s| &s.name == subnet_name);
+                if attachment.is_some_and(|s| s.ip4addr.is_none()) {
+                    new_host
+                        .ip_addresses
+                        .insert(subnet_name.clone(), existing_ip.clone());
+                }
+            }
+
+            // Keep repeated-subnet NICs, matched in order within each subnet
+            let mut seen: BTreeMap<String, usize> = BTreeMap::new();
+            for nic in &mut new_host.additional_nics {
+                let ordinal = seen.entry(nic.subnet.clone()).or_default();
+                if let Some(existing_nic) = existing_host
+                    .additional_nics
+                    .iter()
+                    .filter(|n| n.subnet == nic.subnet)
+                    .nth(*ordinal)
+                {
+                    *nic = existing_nic.clone();
+                }
+                *ordinal += 1;
+            }
+
+            if verbose {
+                println!("  ↻ Preserved identifiers for host: {}", new_host.name);
+            }
+        }
+    }
+
+    // Preserve subnet IDs and configurations where possible
+    for new_subnet in &mut merged.subnets {
+        if let Some(existing_subnet) = existing.subnets.iter().find(|s| s.name == new_subnet.name) {
+            // Only preserve if CIDR hasn't changed
+            if new_subnet.cidr == existing_subnet.cidr {
+                new_subnet.id = existing_subnet.id.clone();
+                new_subnet.gateway = existing_subnet.gateway.clone();
+
+                if verbose {
+                    println!(
+                        "  ↻ Preserved configuration for subnet: {}",
+                        new_subnet.name
+                    );
+                }
+            }
+        }
+    }
+
+    // Update metadata but preserve some used values tracking
+    merged.metadata.used_values.mac_addresses = existing.metadata.used_values.mac_addresses.clone();
+    merged.metadata.used_values.host_ids = existing.metadata.used_values.host_ids.clone();
+    merged.metadata.used_values.subnet_ids = existing.metadata.used_values.subnet_ids.clone();
+
+    reclaim_removed_subnets(&mut merged, &existing, verbose);
+    renumber_clashing_ids(&mut merged, &existing, verbose);
+
+    if policy == MergePolicy::Append {
+        allocate_new_hosts_around_existing(&mut merged, &existing, verbose)?;
+    }
+
+    // Gateway hosts may have kept or been given different addresses above
+    merged.resolve_gateway_hosts()?;
+    merged.validate()?;
+
+    // A run that changes nothing keeps the old timestamp, so rewriting the lockfile is a no-op
+    let generated_at = std::mem::replace(
+        &mut merged.metadata.generated_at,
+        existing.metadata.generated_at.clone(),
+    );
+    if serde_yaml::to_string(&merged)? != serde_yaml::to_string(&existing)? {
+        merged.metadata.generated_at = generated_at;
+    } else if verbose {
+        println!("  ↻ Nothing changed, keeping generated_at");
+    }
+
+    Ok(merged)
+}
+
+/// Free the id, CIDR and address pool of subnets no longer in the compose file
+fn reclaim_removed_subnets(merged: &mut IncusLockfile, existing: &IncusLockfile, verbose: bool) {
+    for removed in existing
+        .subnets
+        .iter()
+        .filter(|old| !merged.subnets.iter().any(|s| s.name == old.name))
+    {
+        let used_values = &mut merged.metadata.used_values;
+        used_values.subnet_ids.retain(|id| id != &removed.id);
+
+        // Another subnet may have taken over the same block
+        if !merged.subnets.iter().any(|s| s.cidr == removed.cidr) {
+            used_values.ip_addresses.remove(&ip_pool_key(&removed.cidr));
+        }
+
+        if verbose {
+            println!("  ♻ Reclaimed subnet: {} ({})", removed.name, removed.cidr);
+        }
+    }
+
+    // Subnets new to this lockfile may reuse a reclaimed id
+    for subnet in &merged.subnets {
+        let subnet_ids = &mut merged.metadata.used_values.subnet_ids;
+        if !subnet_ids.contains(&subnet.id) {
+            subnet_ids.push(subnet.id.clone());
+        }
+    }
+}
+
+/// Give new hosts and subnets whose generated id a preserved one already holds a free id
+///
+/// Index ids shift when the lists are reordered, so a new entry can be handed the
+/// id an existing entry kept above. Name-derived ids (`id_scheme: name`) don't clash.
+fn renumber_clashing_ids(merged: &mut IncusLockfile, existing: &IncusLockfile, verbose: bool) {
+    let host_ids: Vec<String> = merged.hosts.iter().map(|h| h.id.clone()).collect();
+    for (index, host) in merged.hosts.iter_mut().enumerate() {
+        let is_new = !existing.hosts.iter().any(|h| h.name == host.name);
+        let clashes = host_ids
+            .iter()
+            .enumerate()
+            .any(|(other, id)| other != index && id == &host.id);
+        if is_new && clashes {
+            let used = &mut merged.metadata.used_values.host_ids;
+            host.id = free_index_id("host", &host_ids, used);
+            used.push(host.id.clone());
+            if verbose {
+                println!("  ↻ Renumbered new host {} to {}", host.name, host.id);
+            }
+        }
+    }
+
+    let subnet_ids: Vec<String> = merged.subnets.iter().map(|s| s.id.clone()).collect();
+    for (index, subnet) in merged.subnets.iter_mut().enumerate() {
+        let preserved = existing
+            .subnets
+            .iter()
+            .any(|s| s.name == subnet.name && s.id == subnet.id);
+        let clashes = subnet_ids
+            .iter()
+            .enumerate()
+            .any(|(other, id)| other != index && id == &subnet.id);
+        if !preserved && clashes {
+            let used = &mut merged.metadata.used_values.subnet_ids;
+            subnet.id = free_index_id("subnet", &subnet_ids, used);
+            used.push(subnet.id.clone());
+            if verbose {
+                println!("  ↻ Renumbered new subnet {} to {}", subnet.name, subnet.id);
+            }
+        }
+    }
+}
+
+/// Lowest `<kind>_NNN` id neither held nor recorded as used
+fn free_index_id(kind: &str, held: &[String], used: &[String]) -> String {
+    (1..)
+        .map(|n| format!("{}_{:03}", kind, n))
+        .find(|id| !held.contains(id) && !used.contains(id))
+        .unwrap()
+}
+
+/// Under `--append`, refuse any change that would re-address an existing host
+fn check_append_safe(
+    new_lockfile: &IncusLockfile,
+    existing: &IncusLockfile,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for existing_host in &existing.hosts {
+        let Some(new_host) = new_lockfile
+            .hosts
+            .iter()
+            .find(|h| h.name == existing_host.name)
+        else {
+            continue;
+        };
+
+        for (subnet_name, ip) in &existing_host.ip_addresses {
+            if !new_host.subnets.iter().any(|s| &s.name == subnet_name) {
+                continue;
+            }
+            let old_cidr = existing
+                .subnets
+                .iter()
+                .find(|s| &s.name == subnet_name)
+                .map(|s| s.cidr.as_str());
+            let new_cidr = new_lockfile
+                .subnets
+                .iter()
+                .find(|s| &s.name == subnet_name)
+                .map(|s| s.cidr.as_str());
+            if old_cidr != new_cidr {
+                return Err(format!(
+                    "--append: subnet '{}' changed from {} to {}, which would re-address host '{}' ({})",
+                    subnet_name,
+                    old_cidr.unwrap_or("none"),
+                    new_cidr.unwrap_or("none"),
+                    existing_host.name,
+                    ip
+                )
+                .into());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Give hosts that are new to the lockfile addresses and MACs nobody else holds
+fn allocate_new_hosts_around_existing(
+    merged: &mut IncusLockfile,
+    existing: &IncusLockfile,
+    verbose: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for idx in 0..merged.hosts.len() {
+        if existing
+            .hosts
+            .iter()
+            .any(|h| h.name == merged.hosts[idx].name)
+        {
+            continue;
+        }
+
+        let mac_taken = merged.hosts[idx].mac_address.as_ref().is_some_and(|mac| {
+            merged
+                .hosts
+                .iter()
+                .enumerate()
+                .any(|(other, h)| other != idx && h.mac_address.as_ref() == Some(mac))
+        });
+        if mac_taken {
+            merged.hosts[idx].mac_address = None;
+            let mac = merged.next_free_mac().ok_or_else(|| {
+                format!(
+                    "no free MAC addresses left for host '{}'",
+                    merged.hosts[idx].name
+                )
+            })?;
+            merged.metadata.used_values.mac_addresses.push(mac.clone());
+            merged.hosts[idx].mac_address = Some(mac);
+        }
+
+        let subnet_names: Vec<String> = merged.hosts[idx]
+            .subnets
+            .iter()
+            .map(|s| s.name.clone())
+            .collect();
+        for subnet_name in subnet_names {
+            let Some(ip) = merged.hosts[idx].ip_addresses.get(&subnet_name).cloned() else {
+                continue;
+            };
+            let ip_taken =
+                merged.hosts.iter().enumerate().any(|(other, h)| {
+                    other != idx && h.ip_addresses.get(&subnet_name) == Some(&ip)
+                });
+            if !ip_taken {
+                continue;
+            }
+
+            merged.hosts[idx].ip_addresses.remove(&subnet_name);
+            let is_router = merged.hosts[idx].is_router;
+            let fresh = merged
+                .next_free_ip(&subnet_name, is_router)
+                .ok_or_else(|| {
+                    format!(
+                        "--append: no free address left on subnet '{}' for new host '{}'",
+                        subnet_name, merged.hosts[idx].name
+                    )
+                })?;
+            if verbose {
+                println!(
+                    "  + Allocated {} on {} for new host {} ({} is held)",
+                    fresh, subnet_name, merged.hosts[idx].name, ip
+                );
+            }
+            merged.mark_ip_used(&subnet_name, &fresh);
+            merged.hosts[idx]
+                .ip_addresses
+                .insert(subnet_name.clone(), fresh);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{lockfile_from_yaml, APPEND_BASE};
+
+    #[test]
+    fn test_append_rejects_cidr_change() {
+        let existing = lockfile_from_yaml(APPEND_BASE);
+        let changed = lockfile_from_yaml(&APPEND_BASE.replace("10.0.1.0/24", "10.0.9.0/24"));
+
+        let err = merge_lockfiles(
+            changed.clone(),
+            existing.clone(),
+            MergePolicy::Append,
+            false,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("frontend"), "unexpected error: {}", err);
+        assert!(err.contains("10.0.9.0/24"));
+
+        // The default policy still merges
+        assert!(merge_lockfiles(changed, existing, MergePolicy::Preserve, false).is_ok());
+    }
+
+    #[test]
+    fn test_append_allocates_new_hosts_around_existing() {
+        let existing = lockfile_from_yaml(APPEND_BASE);
+
+        // Dropping `web` moves `api` to the front, so a fresh generation hands
+        // api's old address and MAC to the new `db`
+        let regenerated = lockfile_from_yaml(
+            r#"
+hosts:
+  - name: api
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend]
+  - name: db
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend]
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+"#,
+        );
+        assert_eq!(
+            regenerated.hosts[1].ip_addresses["frontend"],
+            existing.hosts[1].ip_addresses["frontend"]
+        );
+        let merged =
+            merge_lockfiles(regenerated, existing.clone(), MergePolicy::Append, false).unwrap();
+
+        let api = merged.hosts.iter().find(|h| h.name == "api").unwrap();
+        let db = merged.hosts.iter().find(|h| h.name == "db").unwrap();
+        assert_eq!(
+            api.ip_addresses["frontend"],
+            existing.hosts[1].ip_addresses["frontend"]
+        );
+        assert_eq!(api.mac_address, existing.hosts[1].mac_address);
+        assert_ne!(db.ip_addresses["frontend"], api.ip_addresses["frontend"]);
+        assert_ne!(db.mac_address, api.mac_address);
+    }
+
+    #[test]
+    fn test_new_host_does_not_take_a_preserved_id() {
+        let existing = lockfile_from_yaml(APPEND_BASE);
+        let prepended = APPEND_BASE.replace(
+            "hosts:\n",
+            "hosts:\n  - name: db\n    flavor: small_flavor\n    image: base_image\n    subnets: [frontend]\n",
+        );
+        let merged = merge_lockfiles(
+            lockfile_from_yaml(&prepended),
+            existing.clone(),
+            MergePolicy::Preserve,
+            false,
+        )
+        .unwrap();
+
+        let ids: Vec<&str> = merged.hosts.iter().map(|h| h.id.as_str()).collect();
+        assert_eq!(ids[1..], [&existing.hosts[0].id, &existing.hosts[1].id]);
+        assert_eq!(ids[0], "host_003");
+    }
+
+    #[test]
+    fn test_no_op_merge_keeps_generated_at() {
+        let mut existing = lockfile_from_yaml(APPEND_BASE);
+        existing.metadata.generated_at = "2024-01-15T10:30:00Z".to_string();
+
+        let merged = merge_lockfiles(
+            lockfile_from_yaml(APPEND_BASE),
+            existing.clone(),
+            MergePolicy::Preserve,
+            false,
+        )
+        .unwrap();
+        assert_eq!(merged.metadata.generated_at, "2024-01-15T10:30:00Z");
+        assert_eq!(merged.metadata.source_hash, existing.metadata.source_hash);
+
+        let changed = lockfile_from_yaml(&APPEND_BASE.replace("10.0.1.0/24", "10.0.9.0/24"));
+        let merged = merge_lockfiles(changed, existing, MergePolicy::Preserve, false).unwrap();
+        assert_ne!(merged.metadata.generated_at, "2024-01-15T10:30:00Z");
+    }
+
+    #[test]
+    fn test_removed_subnet_is_reclaimed() {
+        let existing = lockfile_from_yaml(&format!("{}  - legacy\n", APPEND_BASE));
+        let legacy = existing.subnets[1].clone();
+        assert_eq!(legacy.cidr, "192.168.11.0/24");
+
+        // Removing it drops its tracking entirely
+        let merged = merge_lockfiles(
+            lockfile_from_yaml(APPEND_BASE),
+            existing.clone(),
+            MergePolicy::Preserve,
+            false,
+        )
+        .unwrap();
+        let used_values = &merged.metadata.used_values;
+        assert!(!used_values.subnet_ids.contains(&legacy.id));
+        assert!(!used_values
+            .ip_addresses
+            .contains_key(&ip_pool_key(&legacy.cidr)));
+
+        // A subnet taking its place is auto-assigned the freed block
+        let merged = merge_lockfiles(
+            lockfile_from_yaml(&format!("{}  - reports\n", APPEND_BASE)),
+            existing,
+            MergePolicy::Preserve,
+            false,
+        )
+        .unwrap();
+        assert_eq!(merged.subnets[1].name, "reports");
+        assert_eq!(merged.subnets[1].cidr, legacy.cidr);
+        assert_eq!(
+            merged.metadata.used_values.subnet_ids,
+            vec!["subnet_001", "subnet_002"]
+        );
+    }
+}
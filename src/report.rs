@@ -0,0 +1,427 @@
+//! Human- and machine-readable views of a lockfile: plan and teardown
+//! summaries, per-host explanations, the JSON summary and the dry-run script
+
+use crate::schema::IncusLockfile;
+use crate::secrets::SecretResolver;
+
+/// Utilization percentage above which the summary warns about a subnet
+pub const SUBNET_UTILIZATION_WARNING: f64 = 90.0;
+
+/// What the generated commands will create, followed by any warnings
+pub fn plan_summary(lockfile: &IncusLockfile) -> Vec<String> {
+    let networks = lockfile.subnets.iter().filter(|s| s.is_managed()).count();
+    let addresses: usize = lockfile.hosts.iter().map(|h| h.ip_addresses.len()).sum();
+
+    let mut lines = vec![
+        "Plan:".to_string(),
+        format!("  networks to create: {}", networks),
+        format!("  instances to create: {}", lockfile.hosts.len()),
+        format!("  addresses allocated: {}", addresses),
+    ];
+    for conflict in lockfile.anti_affinity_conflicts() {
+        lines.push(format!("  warning: {}", conflict));
+    }
+    for usage in lockfile.subnet_utilization() {
+        if usage.percent() > SUBNET_UTILIZATION_WARNING {
+            lines.push(format!(
+                "  warning: subnet '{}' is over {:.0}% full",
+                usage.name, SUBNET_UTILIZATION_WARNING
+            ));
+        }
+    }
+    lines
+}
+
+/// What running the teardown commands deletes, in the style of `plan_summary`
+pub fn teardown_summary(commands: &[String]) -> Vec<String> {
+    let count = |prefix: &str| commands.iter().filter(|c| c.starts_with(prefix)).count();
+    vec![
+        "Plan:".to_string(),
+        format!("  instances to delete: {}", count("incus delete ")),
+        format!("  networks to delete: {}", count("incus network delete ")),
+        format!(
+            "  firewall ACLs to delete: {}",
+            count("incus network acl delete ")
+        ),
+        format!(
+            "  DNS zones to delete: {}",
+            count("incus network zone delete ")
+        ),
+        format!(
+            "  storage pools to delete: {}",
+            count("incus storage delete ")
+        ),
+    ]
+}
+
+/// How `name`'s resources, addresses and MACs were arrived at
+///
+/// Each address is attributed to a pin (`ip4addr`), the lockfile as it was
+/// before this run (`before`), or fresh allocation.
+pub fn explain_host(
+    lockfile: &IncusLockfile,
+    before: Option<&IncusLockfile>,
+    name: &str,
+) -> Result<Vec<String>, String> {
+    let host = lockfile
+        .hosts
+        .iter()
+        .find(|h| h.name == name)
+        .ok_or_else(|| "no such host in the lockfile".to_string())?;
+    let previous = before.and_then(|b| b.hosts.iter().find(|h| h.name == name));
+    let previous_nics = previous.map(|p| p.nics()).unwrap_or_default();
+
+    let resources = &host.resources;
+    let mut summary = format!(
+        "{}, {} cores, {} memory",
+        host.instance_type.as_str(),
+        resources.cpu.cores,
+        resources.memory.limit
+    );
+    if let Some(ref storage) = resources.storage {
+        summary.push_str(&format!(", {} storage", storage.size));
+    }
+    let mut lines = vec![
+        format!("Host '{}' ({}):", host.name, host.id),
+        format!("  flavor: {} ({})", host.flavor, summary),
+    ];
+
+    for nic in host.nics() {
+        let previous_nic = previous_nics.iter().find(|p| p.device == nic.device);
+        let Some(subnet) = lockfile
+            .subnets
+            .iter()
+            .find(|s| s.name == nic.attachment.name)
+        else {
+            continue;
+        };
+        lines.push(format!(
+            "  {} on subnet '{}' ({}, gateway {}):",
+            nic.device, subnet.name, subnet.cidr, subnet.gateway
+        ));
+
+        if let Some(ip) = nic.ip_address {
+            let source = if nic.attachment.ip4addr.is_some() {
+                "pinned by ip4addr"
+            } else if previous_nic.and_then(|p| p.ip_address) == Some(ip) {
+                "preserved from lockfile"
+            } else {
+                "allocated"
+            };
+            let (start, skipped) = lockfile.allocation_trace(subnet, host.is_router, ip);
+            if let Some(start) = start {
+                lines.push(format!(
+                    "    allocation starts at {}{}",
+                    start,
+                    if host.is_router {
+                        " (router range)"
+                    } else {
+                        ""
+                    }
+                ));
+            }
+            if !skipped.is_empty() {
+                lines.push(format!("    reserved, skipped: {}", skipped.join(", ")));
+            }
+            lines.push(format!("    address: {} ({})", ip, source));
+        }
+
+        if let Some(mac) = nic.mac_address {
+            let source = if previous_nic.and_then(|p| p.mac_address) == Some(mac) {
+                "preserved from lockfile"
+            } else {
+                "generated"
+            };
+            lines.push(format!("    mac: {} ({})", mac, source));
+        }
+    }
+
+    Ok(lines)
+}
+
+/// Machine-readable lockfile summary: counts, host addresses and subnet usage
+pub fn lockfile_summary_json(lockfile: &IncusLockfile) -> serde_json::Value {
+    let hosts: Vec<serde_json::Value> = lockfile
+        .hosts
+        .iter()
+        .map(|host| {
+            serde_json::json!({
+                "name": host.name,
+                "id": host.id,
+                "mac_address": host.mac_address,
+                "ip_addresses": host.ip_addresses,
+                "tags": host.tags,
+            })
+        })
+        .collect();
+
+    let utilization = lockfile.subnet_utilization();
+    let subnets: Vec<serde_json::Value> = lockfile
+        .subnets
+        .iter()
+        .map(|subnet| {
+            let usage = utilization.iter().find(|u| u.name == subnet.name);
+            serde_json::json!({
+                "name": subnet.name,
+                "id": subnet.id,
+                "cidr": subnet.cidr,
+                "gateway": subnet.gateway,
+                "used": usage.map(|u| u.used),
+                "capacity": usage.map(|u| u.capacity),
+            })
+        })
+        .collect();
+
+    serde_json::json!({
+        "generated_at": lockfile.metadata.generated_at,
+        "generator_version": lockfile.metadata.generator_version,
+        "source_hash": lockfile.metadata.source_hash,
+        "counts": {
+            "hosts": lockfile.hosts.len(),
+            "subnets": lockfile.subnets.len(),
+            "flavors": lockfile.flavors.len(),
+            "images": lockfile.images.len(),
+        },
+        "hosts": hosts,
+        "subnets": subnets,
+    })
+}
+
+/// Build the dry-run script in memory, returning it with the number of commands
+///
+/// With `plan`, the plan summary heads the script as comments.
+pub fn render_dry_run_script(
+    lockfile: &IncusLockfile,
+    secrets: &SecretResolver,
+    plan: bool,
+    verbose: bool,
+) -> Result<(String, usize), Box<dyn std::error::Error>> {
+    let mut network_commands = lockfile.generate_storage_commands();
+    network_commands.extend(lockfile.generate_network_commands());
+    let host_sections: Vec<(&str, Vec<String>)> = lockfile
+        .host_layers()
+        .into_iter()
+        .flatten()
+        .map(|host| (host.name.as_str(), lockfile.generate_host_commands(host)))
+        .collect();
+    let command_count = network_commands.len()
+        + host_sections
+            .iter()
+            .map(|(_, commands)| commands.len())
+            .sum::<usize>();
+
+    let mut output = Vec::new();
+    output.push("#!/bin/bash".to_string());
+    output.push("# Generated by incus-composer".to_string());
+    output.push(format!(
+        "# Generated at: {}",
+        lockfile.metadata.generated_at
+    ));
+    output.push(format!(
+        "# Generator version: {}",
+        lockfile.metadata.generator_version
+    ));
+    output.push(format!("# Source hash: {}", lockfile.metadata.source_hash));
+    if plan {
+        output.push("#".to_string());
+        for line in plan_summary(lockfile) {
+            output.push(format!("# {}", line));
+        }
+    }
+    output.push("".to_string());
+    output.push("set -e  # Exit on any error".to_string());
+    output.push("".to_string());
+
+    if verbose {
+        output.push("echo 'Starting incus-composer deployment...'".to_string());
+        output.push("".to_string());
+    }
+
+    // Add section comments
+    output.push("# ============================================".to_string());
+    output.push("# Network Creation".to_string());
+    output.push("# ============================================".to_string());
+    output.push("".to_string());
+
+    let push_command =
+        |output: &mut Vec<String>, command: &str| -> Result<(), Box<dyn std::error::Error>> {
+            // Comments keep their references: they are never run, only read
+            if command.starts_with('#') {
+                output.push(command.to_string());
+                return Ok(());
+            }
+            // Echo the unresolved form so secrets don't end up in logs
+            if verbose {
+                output.push(format!("echo 'Executing: {}'", command));
+            }
+            output.push(secrets.expand(command)?);
+            Ok(())
+        };
+
+    for command in &network_commands {
+        push_command(&mut output, command)?;
+    }
+
+    if !host_sections.is_empty() {
+        output.push("".to_string());
+        output.push("# ============================================".to_string());
+        output.push("# Instance Creation and Configuration".to_string());
+        output.push("# ============================================".to_string());
+    }
+    // One section per host, in creation order
+    for (name, commands) in &host_sections {
+        output.push("".to_string());
+        output.push(format!("# --- host: {} ---", name));
+        for command in commands {
+            push_command(&mut output, command)?;
+        }
+    }
+
+    output.push("".to_string());
+    if verbose {
+        output.push("echo 'Deployment completed successfully!'".to_string());
+    }
+
+    Ok((output.join("\n"), command_count))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merge::{merge_lockfiles, MergePolicy};
+    use crate::test_support::{lockfile_from_yaml, APPEND_BASE};
+
+    #[test]
+    fn test_lockfile_summary_json() {
+        let lockfile = lockfile_from_yaml(APPEND_BASE);
+        let text = lockfile_summary_json(&lockfile).to_string();
+
+        let summary: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(summary["counts"]["hosts"], 2);
+        assert_eq!(summary["hosts"][0]["name"], "web");
+        assert_eq!(summary["hosts"][0]["ip_addresses"]["frontend"], "10.0.1.10");
+        assert_eq!(summary["subnets"][0]["cidr"], "10.0.1.0/24");
+    }
+
+    #[test]
+    fn test_plan_summary_counts() {
+        let lockfile = lockfile_from_yaml(
+            r#"
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend, backend]
+  - name: db
+    flavor: small_flavor
+    image: base_image
+    subnets: [backend]
+  - name: nas
+    flavor: small_flavor
+    image: base_image
+    subnets: [storage]
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+  - name: backend
+    cidr: 10.0.2.0/24
+  - name: storage
+    cidr: 10.0.3.0/24
+    network_type: macvlan
+    parent: eno1
+"#,
+        );
+
+        let plan = plan_summary(&lockfile);
+        assert!(plan.contains(&"  networks to create: 2".to_string()));
+        assert!(plan.contains(&"  instances to create: 3".to_string()));
+        assert!(plan.contains(&"  addresses allocated: 4".to_string()));
+        assert!(!plan.iter().any(|line| line.contains("warning")));
+
+        let (script, _) =
+            render_dry_run_script(&lockfile, &SecretResolver::default(), true, false).unwrap();
+        assert!(script.contains("\n# Plan:\n#   networks to create: 2\n"));
+    }
+
+    #[test]
+    fn test_explain_preserved_host() {
+        let yaml = r#"
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend]
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+    excluded_ips: [10.0.1.10]
+"#;
+        let existing = lockfile_from_yaml(yaml);
+        let grown = lockfile_from_yaml(&yaml.replace(
+            "subnets:\n  - name: frontend\n    cidr",
+            "  - name: api\n    flavor: small_flavor\n    image: base_image\n    subnets: [frontend]\nsubnets:\n  - name: frontend\n    cidr",
+        ));
+        let merged =
+            merge_lockfiles(grown, existing.clone(), MergePolicy::Preserve, false).unwrap();
+
+        let web = explain_host(&merged, Some(&existing), "web").unwrap();
+        assert!(web
+            .contains(&"  eth0 on subnet 'frontend' (10.0.1.0/24, gateway 10.0.1.1):".to_string()));
+        assert!(web.contains(&"    allocation starts at 10.0.1.10".to_string()));
+        assert!(web.contains(&"    reserved, skipped: 10.0.1.10".to_string()));
+        assert!(web.contains(&"    address: 10.0.1.11 (preserved from lockfile)".to_string()));
+        assert!(web.iter().any(
+            |line| line.starts_with("    mac: ") && line.ends_with("(preserved from lockfile)")
+        ));
+
+        let api = explain_host(&merged, Some(&existing), "api").unwrap();
+        assert!(api
+            .iter()
+            .any(|line| line.starts_with("    address: ") && line.ends_with("(allocated)")));
+
+        assert!(explain_host(&merged, None, "missing").is_err());
+    }
+
+    #[test]
+    fn test_dry_run_groups_commands_per_host() {
+        let lockfile = lockfile_from_yaml(APPEND_BASE);
+        let (script, count) =
+            render_dry_run_script(&lockfile, &SecretResolver::default(), false, false).unwrap();
+        assert_eq!(count, lockfile.generate_incus_commands().len());
+
+        let lines: Vec<&str> = script.lines().collect();
+        let instances = lines
+            .iter()
+            .position(|l| *l == "# Instance Creation and Configuration")
+            .unwrap();
+        assert!(lines[..instances]
+            .iter()
+            .any(|l| l.starts_with("incus network create frontend")));
+
+        for host in ["web", "api"] {
+            let banner = format!("# --- host: {} ---", host);
+            let start = lines.iter().position(|l| *l == banner).unwrap();
+            assert!(start > instances);
+            let section: Vec<&str> = lines[start + 1..]
+                .iter()
+                .take_while(|l| !l.is_empty())
+                .copied()
+                .collect();
+            assert!(section[0].starts_with("incus create "), "{}", section[0]);
+            assert_eq!(
+                section.last(),
+                Some(&format!("incus start {}", host).as_str())
+            );
+            // Nothing of this host outside its section
+            let outside = lines
+                .iter()
+                .enumerate()
+                .filter(|(i, l)| {
+                    (*i < start || *i > start + section.len())
+                        && l.split_whitespace().any(|word| word == host)
+                })
+                .count();
+            assert_eq!(outside, 0, "{}", script);
+        }
+    }
+}
@@ -1,6 +1,13 @@
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use crate::apply::dependency_layers;
+use crate::catalog::{self, Catalog};
+use crate::firewall::{acl_name, FirewallRule, FIREWALL_ROLE};
+use ipnet::Ipv4Net;
+use serde::{Deserialize, Deserializer, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs;
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr};
 use std::path::Path;
 
 /// Root structure for incus-compose.yaml
@@ -10,6 +17,35 @@ pub struct IncusCompose {
     #[serde(default = "default_version")]
     pub version: String,
 
+    /// Incus remote that generated commands target (e.g. `prod` for `prod:web`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remote: Option<String>,
+
+    /// Create, configure and start each host with a single `incus launch`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub launch: bool,
+
+    /// DNS zone holding an A record for every addressed host
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dns_domain: Option<String>,
+
+    /// Seed for randomized MAC and IP allocation (`--seed`); sequential when unset
+    #[serde(skip)]
+    pub seed: Option<u64>,
+
+    /// Hash of the configuration as loaded, pinned before command-line overrides
+    /// (`--remote`, `--launch`) so they don't count as a change to the file
+    #[serde(skip)]
+    pub loaded_hash: Option<String>,
+
+    /// Files or http(s) URLs of flavor/image catalogs (merged into `flavors` and `images` on load)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+
+    /// Directory keeping the last fetched copy of each included URL
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub catalog_cache: Option<String>,
+
     /// Default configuration for optional elements
     #[serde(default)]
     pub defaults: Defaults,
@@ -17,25 +53,51 @@ pub struct IncusCompose {
     /// Collection of hosts to manage
     pub hosts: Vec<Host>,
 
+    /// Host groups sharing common fields (expanded into `hosts` on load)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<HostGroup>,
+
     /// Network subnets configuration
     pub subnets: Vec<Subnet>,
 
     /// Global flavors configuration (optional, can be defined externally)
     #[serde(default)]
-    pub flavors: HashMap<String, Flavor>,
+    pub flavors: BTreeMap<String, Flavor>,
 
     /// Global images configuration (optional, can be defined externally)
     #[serde(default)]
-    pub images: HashMap<String, Image>,
+    pub images: BTreeMap<String, Image>,
+
+    /// Storage pools to create before any instance
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub storage_pools: Vec<StoragePool>,
+
+    /// What each role assigned to hosts stands for in incus
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub roles: BTreeMap<String, RoleTemplate>,
 }
 
 /// Expanded lockfile structure with all optional fields made explicit
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct IncusLockfile {
     /// Version of the incus-compose schema
+    #[serde(default = "default_version")]
     pub version: String,
 
+    /// Incus remote that generated commands target
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remote: Option<String>,
+
+    /// Create, configure and start each host with a single `incus launch`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub launch: bool,
+
+    /// DNS zone holding an A record for every addressed host
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dns_domain: Option<String>,
+
     /// Default configuration used during generation
+    #[serde(default)]
     pub defaults: Defaults,
 
     /// Collection of hosts with all optional fields populated
@@ -45,12 +107,27 @@ pub struct IncusLockfile {
     pub subnets: Vec<ExpandedSubnet>,
 
     /// Resolved flavor definitions
-    pub flavors: HashMap<String, Flavor>,
+    #[serde(default)]
+    pub flavors: BTreeMap<String, Flavor>,
 
     /// Resolved image definitions
-    pub images: HashMap<String, Image>,
+    #[serde(default)]
+    pub images: BTreeMap<String, Image>,
+
+    /// Storage pools to create, one entry per name
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub storage_pools: Vec<StoragePool>,
+
+    /// Role templates that hosts' roles expand against
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub roles: BTreeMap<String, RoleTemplate>,
+
+    /// Every host when `hosts` is a `select_tagged` subset, empty otherwise
+    #[serde(skip)]
+    pub all_hosts: Vec<ExpandedHost>,
 
     /// Generated metadata
+    #[serde(default)]
     pub metadata: LockfileMetadata,
 }
 
@@ -72,6 +149,72 @@ pub struct Defaults {
     /// CIDR ranges for automatic subnet assignment
     #[serde(default)]
     pub cidr4_ranges: Vec<CidrRange>,
+
+    /// Blocks (e.g. VPN routes) that auto-assigned subnets must not overlap
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub excluded_cidrs: Vec<String>,
+
+    /// Subnet every host joins without listing it, unless it sets `management: false`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub management_subnet: Option<String>,
+
+    /// Leading three octets of generated MAC addresses (e.g. an organization's OUI)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac_prefix: Option<String>,
+
+    /// Gateway placement for subnets that don't set their own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gateway_position: Option<GatewayPosition>,
+
+    /// Incus cluster members that anti-affine hosts are spread across
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cluster_members: Vec<String>,
+
+    /// Flavor for hosts that get none directly or from a group
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_flavor: Option<String>,
+
+    /// Image for hosts that get none directly or from a group
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_image: Option<String>,
+
+    /// How host and subnet ids are derived (index when unset)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id_scheme: Option<IdScheme>,
+}
+
+/// How generated host and subnet ids are derived
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IdScheme {
+    /// `host_001`, `subnet_002`, ... by position, so reordering the lists changes them
+    #[default]
+    Index,
+    /// `host_1a2b3c4d`, ... from a hash of the name, so they survive reordering
+    Name,
+}
+
+impl IdScheme {
+    /// Id of the `index`th (from 0) host or subnet, `kind` being `host` or `subnet`
+    pub fn id(self, kind: &str, index: usize, name: &str) -> String {
+        match self {
+            IdScheme::Index => format!("{}_{:03}", kind, index + 1),
+            IdScheme::Name => format!("{}_{}", kind, &sha256_hex(name.as_bytes())[..8]),
+        }
+    }
+}
+
+/// MAC prefix used when `defaults.mac_prefix` is unset (locally administered)
+const DEFAULT_MAC_PREFIX: &str = "02:00:00";
+
+impl Defaults {
+    /// Leading octets for generated MAC addresses, normalized to lowercase
+    pub fn mac_prefix(&self) -> String {
+        self.mac_prefix
+            .as_deref()
+            .unwrap_or(DEFAULT_MAC_PREFIX)
+            .to_lowercase()
+    }
 }
 
 /// IP address range specification
@@ -84,6 +227,9 @@ pub struct IpRange {
     pub end: String,
 }
 
+/// Prefix length of auto-assigned subnet CIDRs that don't set `prefix`
+const AUTO_CIDR_PREFIX: u8 = 24;
+
 /// CIDR range specification for subnet assignment
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CidrRange {
@@ -94,16 +240,41 @@ pub struct CidrRange {
     pub end: String,
 }
 
+impl CidrRange {
+    /// First and last address covered: from `start`'s address to the end of `end`'s block
+    pub fn bounds(&self) -> Result<(u32, u32), String> {
+        let parse = |cidr: &str| {
+            cidr.parse::<Ipv4Net>().map_err(|_| {
+                format!(
+                    "defaults.cidr4_ranges: '{}' is not an IPv4 CIDR block",
+                    cidr
+                )
+            })
+        };
+        let start = u32::from(parse(&self.start)?.addr());
+        let end = u32::from(parse(&self.end)?.broadcast());
+        if start > end {
+            return Err(format!(
+                "defaults.cidr4_ranges: range {} - {} ends before it starts",
+                self.start, self.end
+            ));
+        }
+        Ok((start, end))
+    }
+}
+
 /// Host definition in incus-compose file
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Host {
     /// Name of the host
     pub name: String,
 
-    /// Flavor reference (defines resource allocation)
+    /// Flavor reference (defines resource allocation; may come from a group)
+    #[serde(default)]
     pub flavor: String,
 
-    /// Image reference
+    /// Image reference (may come from a group)
+    #[serde(default)]
     pub image: String,
 
     /// Whether this host should have a floating IP
@@ -118,13 +289,105 @@ pub struct Host {
     #[serde(default)]
     pub is_router: bool,
 
+    /// Whether this host runs as a privileged container
+    #[serde(default)]
+    pub privileged: bool,
+
+    /// Instance type overriding the flavor's (e.g. a VM from a container flavor)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub instance_type: Option<InstanceType>,
+
+    /// Additional incus profiles to attach, in order
+    #[serde(default)]
+    pub profiles: Vec<String>,
+
+    /// Hosts that must be created and started before this one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub depends_on: Vec<String>,
+
+    /// Whether this host is included in backup exports (default: true)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backup: Option<bool>,
+
+    /// Whether this host joins `defaults.management_subnet` (default: true)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub management: Option<bool>,
+
+    /// Start the instance when the incus daemon starts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub autostart: Option<bool>,
+
+    /// Autostart order (higher starts first)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub autostart_priority: Option<u32>,
+
+    /// Seconds to wait after starting this instance before starting the next
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub autostart_delay: Option<u32>,
+
+    /// Restart the instance when it exits unexpectedly (`false` leaves it stopped)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub autorestart: Option<bool>,
+
+    /// Seconds to wait for a clean shutdown when the incus host shuts down
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host_shutdown_timeout: Option<u32>,
+
+    /// Refuse deletion, and keep the host out of teardown unless forced
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub protected: bool,
+
+    /// Create the instance `--ephemeral`, so incus deletes it when it stops
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub ephemeral: bool,
+
+    /// Mount the root disk read-only
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub root_readonly: bool,
+
+    /// Ports forwarded from the floating IP to this host
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub forwards: Vec<Forward>,
+
+    /// Root disk size override (e.g. `50GB`), independent of the flavor's storage
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disk_size: Option<String>,
+
+    /// Cluster member to create the instance on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+
+    /// Placement group whose hosts are spread across distinct cluster members
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub anti_affinity: Option<String>,
+
+    /// Host group whose members get consecutive addresses (set by `contiguous` groups)
+    #[serde(skip)]
+    pub contiguous_group: Option<String>,
+
+    /// Free-form description, stored as `user.description`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+
+    /// Searchable metadata, stored as `user.<key>` config
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+
+    /// Environment variables for the guest, stored as `environment.<KEY>` config
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub environment: BTreeMap<String, String>,
+
+    /// Tags selecting this host with `--tag`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+
     /// Roles assigned to this host
     #[serde(default)]
     pub roles: Vec<Role>,
 
     /// Subnet assignments (can be single or multiple)
     #[serde(default)]
-    pub subnets: Vec<String>,
+    pub subnets: Vec<SubnetAssignment>,
 
     /// Backward compatibility: single subnet assignment
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -141,10 +404,11 @@ impl Host {
         // If subnets is empty, populate it from subnet or subnet_list
         if self.subnets.is_empty() {
             if let Some(ref subnet) = self.subnet {
-                self.subnets.push(subnet.clone());
+                self.subnets.push(SubnetAssignment::Name(subnet.clone()));
             }
             if let Some(ref subnet_list) = self.subnet_list {
-                self.subnets.extend(subnet_list.iter().cloned());
+                self.subnets
+                    .extend(subnet_list.iter().cloned().map(SubnetAssignment::Name));
             }
         }
 
@@ -152,6 +416,152 @@ impl Host {
         self.subnet = None;
         self.subnet_list = None;
     }
+
+    /// Names of the subnets this host is assigned to, in declared order
+    pub fn subnet_names(&self) -> Vec<&str> {
+        self.subnets.iter().map(|s| s.name()).collect()
+    }
+}
+
+/// A set of hosts sharing common fields
+///
+/// Each member name becomes a host; fields set explicitly on a host of the same
+/// name in `hosts` win over the group's.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HostGroup {
+    /// Name of the group
+    pub name: String,
+
+    /// Names of the member hosts
+    pub hosts: Vec<String>,
+
+    /// Flavor for members that don't set one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flavor: Option<String>,
+
+    /// Image for members that don't set one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+
+    /// Subnets for members that don't list any
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub subnets: Vec<SubnetAssignment>,
+
+    /// Roles for members that don't list any
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roles: Vec<Role>,
+
+    /// Profiles for members that don't list any
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub profiles: Vec<String>,
+
+    /// Labels added to members unless they set the same key
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+
+    /// Give members consecutive addresses on every subnet they all share
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub contiguous: bool,
+}
+
+impl HostGroup {
+    /// Fill in the fields `host` leaves unset from this group
+    fn apply_to(&self, host: &mut Host) {
+        if host.flavor.is_empty() {
+            if let Some(ref flavor) = self.flavor {
+                host.flavor = flavor.clone();
+            }
+        }
+        if host.image.is_empty() {
+            if let Some(ref image) = self.image {
+                host.image = image.clone();
+            }
+        }
+        if host.subnets.is_empty() {
+            host.subnets = self.subnets.clone();
+        }
+        if host.roles.is_empty() {
+            host.roles = self.roles.clone();
+        }
+        if host.profiles.is_empty() {
+            host.profiles = self.profiles.clone();
+        }
+        for (key, value) in &self.labels {
+            host.labels
+                .entry(key.clone())
+                .or_insert_with(|| value.clone());
+        }
+        if self.contiguous {
+            host.contiguous_group = Some(self.name.clone());
+        }
+    }
+}
+
+/// Subnet assignment on a host
+/// Can be either a string (shorthand) or full object
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SubnetAssignment {
+    /// Shorthand string format (just the subnet name)
+    Name(String),
+    /// Full assignment configuration
+    Full(SubnetAttachment),
+}
+
+/// Full subnet assignment configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubnetAttachment {
+    /// Name of the subnet
+    pub name: String,
+
+    /// VLAN id to tag this host's NIC with (1-4094)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vlan: Option<u16>,
+
+    /// Interface name inside the guest (e.g. `wan`), instead of the device name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nic_name: Option<String>,
+
+    /// Static IPv4 address for this NIC instead of one from the allocator
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip4addr: Option<String>,
+
+    /// Make this NIC `eth0`, carrying the host's MAC, wherever it is listed
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub primary: bool,
+}
+
+impl SubnetAssignment {
+    /// Get the subnet name regardless of format
+    pub fn name(&self) -> &str {
+        match self {
+            SubnetAssignment::Name(name) => name,
+            SubnetAssignment::Full(config) => &config.name,
+        }
+    }
+
+    /// Convert to full configuration format
+    pub fn to_full_config(&self) -> SubnetAttachment {
+        match self {
+            SubnetAssignment::Name(name) => SubnetAttachment {
+                name: name.clone(),
+                vlan: None,
+                nic_name: None,
+                ip4addr: None,
+                primary: false,
+            },
+            SubnetAssignment::Full(config) => config.clone(),
+        }
+    }
+}
+
+/// Accept both shorthand and full subnet assignments when reading a lockfile
+fn deserialize_attachments<'de, D>(deserializer: D) -> Result<Vec<SubnetAttachment>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let assignments = Vec::<SubnetAssignment>::deserialize(deserializer)?;
+    Ok(assignments.iter().map(|a| a.to_full_config()).collect())
 }
 
 /// Expanded host definition in lockfile with all fields explicit
@@ -175,11 +585,92 @@ pub struct ExpandedHost {
     /// Whether this host acts as a router (always explicit)
     pub is_router: bool,
 
+    /// Whether this host runs as a privileged container (always explicit)
+    #[serde(default)]
+    pub privileged: bool,
+
+    /// Additional incus profiles, de-duplicated in declared order
+    #[serde(default)]
+    pub profiles: Vec<String>,
+
+    /// Hosts that must be created and started before this one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub depends_on: Vec<String>,
+
+    /// Whether this host is included in backup exports (always explicit)
+    #[serde(default = "default_backup")]
+    pub backup: bool,
+
+    /// Start the instance when the incus daemon starts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub autostart: Option<bool>,
+
+    /// Autostart order (higher starts first)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub autostart_priority: Option<u32>,
+
+    /// Seconds to wait after starting this instance before starting the next
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub autostart_delay: Option<u32>,
+
+    /// Restart the instance when it exits unexpectedly (`false` leaves it stopped)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub autorestart: Option<bool>,
+
+    /// Seconds to wait for a clean shutdown when the incus host shuts down
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host_shutdown_timeout: Option<u32>,
+
+    /// Refuse deletion, and keep the host out of teardown unless forced
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub protected: bool,
+
+    /// Create the instance `--ephemeral`, so incus deletes it when it stops
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub ephemeral: bool,
+
+    /// Mount the root disk read-only
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub root_readonly: bool,
+
+    /// Ports forwarded from the floating IP to this host
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub forwards: Vec<Forward>,
+
+    /// Root disk size override in canonical form
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disk_size: Option<String>,
+
+    /// Cluster member the instance is created on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+
+    /// Placement group whose hosts are spread across distinct cluster members
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub anti_affinity: Option<String>,
+
+    /// Free-form description, stored as `user.description`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+
+    /// Searchable metadata, stored as `user.<key>` config
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+
+    /// Environment variables for the guest, stored as `environment.<KEY>` config
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub environment: BTreeMap<String, String>,
+
+    /// Tags selecting this host with `--tag`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+
     /// Roles assigned to this host (always present, may be empty)
     pub roles: Vec<RoleConfig>,
 
     /// Subnet assignments (always present, may be empty)
-    pub subnets: Vec<String>,
+    #[serde(deserialize_with = "deserialize_attachments")]
+    pub subnets: Vec<SubnetAttachment>,
 
     /// Generated unique identifier
     pub id: String,
@@ -189,7 +680,11 @@ pub struct ExpandedHost {
     pub mac_address: Option<String>,
 
     /// Assigned IP addresses per subnet
-    pub ip_addresses: HashMap<String, String>,
+    pub ip_addresses: BTreeMap<String, String>,
+
+    /// NICs beyond the first on subnets listed more than once, in declared order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub additional_nics: Vec<AdditionalNic>,
 
     /// Instance type (derived from flavor and configuration)
     pub instance_type: InstanceType,
@@ -198,6 +693,140 @@ pub struct ExpandedHost {
     pub resources: Resources,
 }
 
+/// A second (or later) NIC on a subnet, with its own address and MAC
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AdditionalNic {
+    /// Subnet the NIC attaches to
+    pub subnet: String,
+
+    /// Generated MAC address
+    pub mac_address: String,
+
+    /// Assigned IP address on the subnet
+    pub ip_address: String,
+}
+
+/// A port on the floating IP forwarded to the host (`incus network forward port`)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Forward {
+    /// Port listened on at the floating IP
+    pub listen_port: u16,
+
+    /// Port on the host, when it differs from `listen_port`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_port: Option<u16>,
+
+    /// Transport protocol (default: tcp)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<ForwardProtocol>,
+}
+
+/// Transport protocol of a port forward
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ForwardProtocol {
+    #[default]
+    Tcp,
+    Udp,
+}
+
+impl ForwardProtocol {
+    /// Value used by incus for this protocol
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ForwardProtocol::Tcp => "tcp",
+            ForwardProtocol::Udp => "udp",
+        }
+    }
+}
+
+/// One NIC of an expanded host, in device order
+#[derive(Debug, Clone)]
+pub struct HostNic<'a> {
+    /// Incus device name (`eth0`, `eth1`, ...)
+    pub device: String,
+    pub attachment: &'a SubnetAttachment,
+    pub ip_address: Option<&'a String>,
+    pub mac_address: Option<&'a String>,
+}
+
+impl ExpandedHost {
+    /// Whether the host carries any of `tags`; every host matches no tags
+    pub fn has_any_tag(&self, tags: &[String]) -> bool {
+        tags.is_empty() || self.tags.iter().any(|tag| tags.contains(tag))
+    }
+
+    /// The attachment marked `primary`, or the first one
+    pub fn primary_subnet(&self) -> Option<&SubnetAttachment> {
+        self.subnets
+            .iter()
+            .find(|s| s.primary)
+            .or_else(|| self.subnets.first())
+    }
+
+    /// Every NIC in device order, pairing repeated subnets with `additional_nics`
+    ///
+    /// The first NIC on a subnet takes the address from `ip_addresses`. The
+    /// primary NIC is `eth0` and the only one carrying the host's `mac_address`;
+    /// the rest follow in list order.
+    pub fn nics(&self) -> Vec<HostNic<'_>> {
+        let primary = self.subnets.iter().position(|s| s.primary).unwrap_or(0);
+        let mut seen: BTreeMap<&str, usize> = BTreeMap::new();
+        let mut nics: Vec<HostNic<'_>> = self
+            .subnets
+            .iter()
+            .enumerate()
+            .map(|(i, attachment)| {
+                let repeat = seen.entry(attachment.name.as_str()).or_default();
+                let (ip_address, mac_address) = if *repeat == 0 {
+                    (
+                        self.ip_addresses.get(&attachment.name),
+                        self.mac_address.as_ref().filter(|_| i == primary),
+                    )
+                } else {
+                    let nic = self
+                        .additional_nics
+                        .iter()
+                        .filter(|nic| nic.subnet == attachment.name)
+                        .nth(*repeat - 1);
+                    (
+                        nic.map(|nic| &nic.ip_address),
+                        nic.map(|nic| &nic.mac_address),
+                    )
+                };
+                *repeat += 1;
+                HostNic {
+                    device: String::new(),
+                    attachment,
+                    ip_address,
+                    mac_address,
+                }
+            })
+            .collect();
+
+        if primary < nics.len() {
+            let nic = nics.remove(primary);
+            nics.insert(0, nic);
+        }
+        for (i, nic) in nics.iter_mut().enumerate() {
+            nic.device = format!("eth{}", i);
+        }
+        nics
+    }
+}
+
+fn default_backup() -> bool {
+    true
+}
+
+fn default_host_start() -> u32 {
+    10
+}
+
+fn default_ipv6_dhcp() -> bool {
+    true
+}
+
 /// Role definition
 /// Can be either a string (shorthand) or full object
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -220,6 +849,104 @@ pub struct RoleConfig {
     pub values: Vec<String>,
 }
 
+impl RoleConfig {
+    /// Bare values without a `=`, e.g. `prometheus` in `[prometheus, port=9090]`
+    pub fn flags(&self) -> BTreeSet<&str> {
+        self.values
+            .iter()
+            .filter(|value| !value.contains('='))
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// `key=value` values as a map; a repeated key keeps its last value
+    pub fn params(&self) -> BTreeMap<&str, &str> {
+        self.values
+            .iter()
+            .filter_map(|value| value.split_once('='))
+            .map(|(key, value)| (key.trim(), value.trim()))
+            .collect()
+    }
+}
+
+/// Incus settings and commands a role stands for, declared under `roles`
+///
+/// Templates may use `{{host}}` (the instance), `{{value}}` (the role's flags,
+/// comma-separated) and `{{KEY}}` (the role's `KEY=...` parameter).
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RoleTemplate {
+    /// Instance config keys set on every host with the role
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub config: BTreeMap<String, String>,
+
+    /// Commands run once the config is set
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub commands: Vec<String>,
+}
+
+impl RoleTemplate {
+    /// The config and commands for one host's use of the role, placeholders filled in
+    pub fn render(
+        &self,
+        role: &RoleConfig,
+        instance: &str,
+    ) -> Result<(BTreeMap<String, String>, Vec<String>), String> {
+        let config = self
+            .config
+            .iter()
+            .map(|(key, value)| {
+                Ok((
+                    interpolate(key, role, instance)?,
+                    interpolate(value, role, instance)?,
+                ))
+            })
+            .collect::<Result<_, String>>()?;
+        let commands = self
+            .commands
+            .iter()
+            .map(|command| interpolate(command, role, instance))
+            .collect::<Result<_, String>>()?;
+        Ok((config, commands))
+    }
+}
+
+/// Fill `{{...}}` placeholders in a role template from a host's use of the role
+fn interpolate(template: &str, role: &RoleConfig, instance: &str) -> Result<String, String> {
+    let params = role.params();
+    let mut result = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after
+            .find("}}")
+            .ok_or_else(|| format!("unterminated placeholder in '{}'", template))?;
+        let value = match after[..end].trim() {
+            "host" => instance.to_string(),
+            "value" => {
+                let flags: Vec<&str> = role
+                    .values
+                    .iter()
+                    .filter(|value| !value.contains('='))
+                    .map(String::as_str)
+                    .collect();
+                if flags.is_empty() {
+                    return Err("'{{value}}' needs at least one value".to_string());
+                }
+                flags.join(",")
+            }
+            key => params
+                .get(key)
+                .ok_or_else(|| format!("'{{{{{}}}}}' needs a '{}=...' value", key, key))?
+                .to_string(),
+        };
+        result.push_str(&value);
+        rest = &after[end + 2..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
 impl Role {
     /// Get the role name regardless of format
     pub fn name(&self) -> &str {
@@ -238,13 +965,10 @@ impl Role {
     }
 
     /// Convert to full configuration format
-    pub fn to_full_config(self) -> RoleConfig {
-        match self {
-            Role::Name(name) => RoleConfig {
-                name,
-                values: vec![],
-            },
-            Role::Full(config) => config,
+    pub fn to_full_config(&self) -> RoleConfig {
+        RoleConfig {
+            name: self.name().to_string(),
+            values: self.values().to_vec(),
         }
     }
 }
@@ -257,23 +981,161 @@ pub enum Subnet {
     /// Shorthand string format (just the subnet name)
     Name(String),
     /// Full subnet configuration
-    Full(SubnetConfig),
+    Full(Box<SubnetConfig>),
 }
 
-/// Full subnet configuration
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct SubnetConfig {
-    /// Name of the subnet
-    pub name: String,
+/// Where in a subnet the gateway address sits
+/// Can be `first`, `last`, or a numeric offset from the network address
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum GatewayPosition {
+    /// First or last usable address
+    Edge(GatewayEdge),
+    /// Offset from the network address (1 is the first usable address)
+    Offset(u32),
+}
 
-    /// CIDR notation for the subnet (optional, may be auto-assigned)
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub cidr: Option<String>,
+/// Named gateway positions
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GatewayEdge {
+    First,
+    Last,
 }
 
-impl Subnet {
-    /// Get the subnet name regardless of format
-    pub fn name(&self) -> &str {
+impl Default for GatewayPosition {
+    fn default() -> Self {
+        GatewayPosition::Edge(GatewayEdge::First)
+    }
+}
+
+impl GatewayPosition {
+    /// Resolve to an address within `net`, or `None` if the offset falls outside it
+    pub fn resolve(&self, net: &Ipv4Net) -> Option<Ipv4Addr> {
+        let network = u32::from(net.network());
+        let broadcast = u32::from(net.broadcast());
+        // /31 and /32 have no network or broadcast address to skip
+        let (first, last) = if net.prefix_len() >= 31 {
+            (network, broadcast)
+        } else {
+            (network + 1, broadcast - 1)
+        };
+
+        let address = match self {
+            GatewayPosition::Edge(GatewayEdge::First) => first,
+            GatewayPosition::Edge(GatewayEdge::Last) => last,
+            GatewayPosition::Offset(offset) => network.checked_add(*offset)?,
+        };
+        (first..=last).contains(&address).then(|| address.into())
+    }
+}
+
+/// Full subnet configuration
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SubnetConfig {
+    /// Name of the subnet
+    pub name: String,
+
+    /// CIDR notation for the subnet (optional, may be auto-assigned)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cidr: Option<String>,
+
+    /// Prefix length of the auto-assigned CIDR when `cidr` is unset (default 24)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<u8>,
+
+    /// MTU for the subnet's bridge (left to incus when unset)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mtu: Option<u32>,
+
+    /// Individual addresses the allocator must never hand out
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub excluded_ips: Vec<String>,
+
+    /// Address ranges the allocator must never hand out
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reserved_ranges: Vec<IpRange>,
+
+    /// Gateway placement, overriding `defaults.gateway_position`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gateway_position: Option<GatewayPosition>,
+
+    /// Last octet of the first address handed to regular hosts (default 10)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host_start: Option<u32>,
+
+    /// IPv6 address configuration toggles (left to incus when unset)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ipv6: Option<Ipv6Config>,
+
+    /// Network type (default bridge); macvlan, sriov and physical attach to `parent`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network_type: Option<NetworkType>,
+
+    /// Host interface that unmanaged NICs (macvlan, sriov, physical) attach to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
+
+    /// Physical host interfaces enslaved to a bridge subnet (`bridge.external_interfaces`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub external_interfaces: Vec<String>,
+
+    /// How NIC `ipv4.address` settings are written (bare address when unset)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_address: Option<AddressFormat>,
+
+    /// Extra IPv4 routes the network advertises and routes to its instances (`ipv4.routes`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ipv4_routes: Vec<String>,
+
+    /// Host whose address on this subnet is the gateway (e.g. a router container)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gateway_host: Option<String>,
+
+    /// Whether the subnet reaches an external network, so hosts on it can take floating IPs
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub uplink: bool,
+
+    /// Resolvers advertised to instances on the subnet (`dns.nameservers`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dns_servers: Vec<String>,
+
+    /// Search domains advertised to instances on the subnet (`dns.search`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dns_search: Vec<String>,
+
+    /// Whether the bridge's DNS server runs (`dns.mode`), independent of DHCP
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dns_mode: Option<DnsMode>,
+}
+
+/// IPv6 address configuration for a subnet's bridge
+///
+/// Defaults to stateless: router advertisements for SLAAC plus DHCPv6 for
+/// other configuration, with no addresses handed out over DHCPv6.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Ipv6Config {
+    /// Run a DHCPv6 server on the bridge (`ipv6.dhcp`)
+    #[serde(default = "default_ipv6_dhcp")]
+    pub dhcp: bool,
+
+    /// Hand out addresses over DHCPv6 instead of SLAAC (`ipv6.dhcp.stateful`)
+    #[serde(default)]
+    pub stateful: bool,
+}
+
+impl Default for Ipv6Config {
+    fn default() -> Self {
+        Ipv6Config {
+            dhcp: default_ipv6_dhcp(),
+            stateful: false,
+        }
+    }
+}
+
+impl Subnet {
+    /// Get the subnet name regardless of format
+    pub fn name(&self) -> &str {
         match self {
             Subnet::Name(name) => name,
             Subnet::Full(config) => &config.name,
@@ -289,10 +1151,13 @@ impl Subnet {
     }
 
     /// Convert to full configuration format
-    pub fn to_full_config(self) -> SubnetConfig {
+    pub fn to_full_config(&self) -> SubnetConfig {
         match self {
-            Subnet::Name(name) => SubnetConfig { name, cidr: None },
-            Subnet::Full(config) => config,
+            Subnet::Name(name) => SubnetConfig {
+                name: name.clone(),
+                ..Default::default()
+            },
+            Subnet::Full(config) => (**config).clone(),
         }
     }
 }
@@ -318,7 +1183,172 @@ pub struct ExpandedSubnet {
 
     /// Network configuration
     #[serde(default)]
-    pub config: HashMap<String, String>,
+    pub config: BTreeMap<String, String>,
+
+    /// Bridge MTU
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mtu: Option<u32>,
+
+    /// Individual addresses excluded from allocation
+    #[serde(default)]
+    pub excluded_ips: Vec<String>,
+
+    /// Address ranges excluded from allocation
+    #[serde(default)]
+    pub reserved_ranges: Vec<IpRange>,
+
+    /// Last octet of the first address handed to regular hosts
+    #[serde(default = "default_host_start")]
+    pub host_start: u32,
+
+    /// IPv6 address configuration toggles
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ipv6: Option<Ipv6Config>,
+
+    /// Host interface for unmanaged network types
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
+
+    /// Physical interfaces enslaved to the bridge
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub external_interfaces: Vec<String>,
+
+    /// How NIC `ipv4.address` settings are written
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_address: Option<AddressFormat>,
+
+    /// Extra IPv4 routes advertised by the network
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ipv4_routes: Vec<String>,
+
+    /// Host providing the gateway; the bridge itself then has no address
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gateway_host: Option<String>,
+
+    /// Whether the subnet reaches an external network
+    #[serde(default)]
+    pub uplink: bool,
+
+    /// Advertised DNS resolvers
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dns_servers: Vec<String>,
+
+    /// Advertised DNS search domains
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dns_search: Vec<String>,
+
+    /// The bridge's DNS mode, left to incus when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dns_mode: Option<DnsMode>,
+}
+
+impl ExpandedSubnet {
+    /// Whether incus manages this subnet as a network (bridge or OVN)
+    pub fn is_managed(&self) -> bool {
+        self.network_type.is_managed()
+    }
+
+    /// NIC device arguments connecting an instance to this subnet
+    fn nic_source(&self) -> String {
+        match self.network_type {
+            NetworkType::Bridge | NetworkType::Ovn => format!("network={}", self.name),
+            NetworkType::Macvlan | NetworkType::Sriov | NetworkType::Physical => format!(
+                "nictype={} parent={}",
+                self.network_type.as_str(),
+                self.parent.as_deref().unwrap_or_default()
+            ),
+        }
+    }
+
+    /// Gateway address with the subnet's prefix length, e.g. `10.0.1.1/24`
+    pub fn gateway_cidr(&self) -> String {
+        match self.cidr.split_once('/') {
+            Some((_, prefix)) => format!("{}/{}", self.gateway, prefix),
+            None => self.gateway.clone(),
+        }
+    }
+
+    /// A host address as NIC `ipv4.address` settings on this subnet take it
+    pub fn device_ipv4(&self, ip: &str) -> String {
+        match (
+            self.device_address.unwrap_or_default(),
+            self.cidr.split_once('/'),
+        ) {
+            (AddressFormat::Cidr, Some((_, prefix))) => format!("{}/{}", ip, prefix),
+            _ => ip.to_string(),
+        }
+    }
+
+    /// Number of usable host addresses in the subnet's CIDR
+    ///
+    /// Network and broadcast addresses are excluded except on /31 and /32.
+    pub fn capacity(&self) -> Option<u64> {
+        let net: Ipv4Net = self.cidr.parse().ok()?;
+        let total = 1u64 << (32 - net.prefix_len());
+        Some(if net.prefix_len() >= 31 {
+            total
+        } else {
+            total - 2
+        })
+    }
+}
+
+/// How a NIC's static address is written in its `ipv4.address` setting
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AddressFormat {
+    /// The bare address, e.g. `10.0.1.10`, as incus expects
+    #[default]
+    Bare,
+    /// With the subnet's prefix length, e.g. `10.0.1.10/24`, for guest templating
+    Cidr,
+}
+
+/// What the DNS server of a bridge subnet answers for (`dns.mode`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DnsMode {
+    /// No DNS server on the bridge
+    None,
+    /// Records for instances with a static name, incus' default
+    #[default]
+    Managed,
+    /// Also records for names instances register through DHCP
+    Dynamic,
+}
+
+impl DnsMode {
+    /// Value used by incus for this mode
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DnsMode::None => "none",
+            DnsMode::Managed => "managed",
+            DnsMode::Dynamic => "dynamic",
+        }
+    }
+}
+
+/// Address usage of a single subnet
+#[derive(Debug, Clone)]
+pub struct SubnetUtilization {
+    /// Name of the subnet
+    pub name: String,
+
+    /// Addresses taken (assigned or reserved)
+    pub used: usize,
+
+    /// Usable addresses in the CIDR
+    pub capacity: u64,
+}
+
+impl SubnetUtilization {
+    /// Percentage of the usable addresses that are taken
+    pub fn percent(&self) -> f64 {
+        if self.capacity == 0 {
+            return 100.0;
+        }
+        self.used as f64 * 100.0 / self.capacity as f64
+    }
 }
 
 fn default_network_type() -> NetworkType {
@@ -354,6 +1384,77 @@ fn default_instance_type() -> InstanceType {
     InstanceType::Container
 }
 
+impl Flavor {
+    /// Copy of this flavor with memory and storage sizes in canonical form
+    ///
+    /// Sizes that fail to parse are kept as written; `validate` rejects them.
+    pub fn normalized(&self) -> Flavor {
+        let mut flavor = self.clone();
+        if let Ok(limit) = normalize_memory_limit(&flavor.memory.limit) {
+            flavor.memory.limit = limit;
+        }
+        if let Some(ref mut storage) = flavor.storage {
+            if let Ok(size) = normalize_size(&storage.size) {
+                storage.size = size;
+            }
+        }
+        flavor
+    }
+}
+
+/// Size units accepted in memory and storage sizes, in canonical spelling
+const SIZE_UNITS: &[&str] = &[
+    "KB", "MB", "GB", "TB", "PB", "KiB", "MiB", "GiB", "TiB", "PiB",
+];
+
+/// Normalize a size such as `2gb` or `512 MiB` to `<number><unit>` (`2GB`, `512MiB`)
+fn normalize_size(size: &str) -> Result<String, String> {
+    let trimmed = size.trim();
+    let split = trimmed
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(trimmed.len());
+    let (number, unit) = trimmed.split_at(split);
+    let unit = unit.trim();
+
+    let value: u64 = number
+        .parse()
+        .map_err(|_| format!("size '{}' must start with a whole number", size))?;
+    if unit.is_empty() {
+        return Err(format!(
+            "size '{}' is missing a unit (one of {})",
+            size,
+            SIZE_UNITS.join(", ")
+        ));
+    }
+    let canonical = SIZE_UNITS
+        .iter()
+        .find(|u| u.eq_ignore_ascii_case(unit))
+        .ok_or_else(|| {
+            format!(
+                "size '{}' has unknown unit '{}' (expected one of {})",
+                size,
+                unit,
+                SIZE_UNITS.join(", ")
+            )
+        })?;
+
+    Ok(format!("{}{}", value, canonical))
+}
+
+/// Normalize a memory limit, which may also be a percentage of host memory
+fn normalize_memory_limit(limit: &str) -> Result<String, String> {
+    match limit.trim().strip_suffix('%') {
+        Some(percent) if percent.parse::<u32>().is_ok_and(|p| (1..=100).contains(&p)) => {
+            Ok(limit.trim().to_string())
+        }
+        Some(_) => Err(format!(
+            "memory limit '{}' is not a valid percentage",
+            limit
+        )),
+        None => normalize_size(limit),
+    }
+}
+
 /// Image definition
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Image {
@@ -372,6 +1473,10 @@ pub struct Image {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub fingerprint: Option<String>,
 
+    /// ISO file attached as a bootable disk, e.g. an installer for an `empty` VM
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iso: Option<String>,
+
     /// Architecture
     #[serde(default = "default_architecture")]
     pub architecture: String,
@@ -381,6 +1486,18 @@ pub struct Image {
     pub os: Option<String>,
 }
 
+/// Image `source` creating instances with no root filesystem (`incus create --empty`)
+pub const EMPTY_IMAGE_SOURCE: &str = "empty";
+
+/// Device name of the ISO disk attached for an image's `iso`
+const ISO_DEVICE: &str = "iso";
+
+/// Boot priority of the ISO disk, above the root disk's default of 0
+const ISO_BOOT_PRIORITY: u32 = 10;
+
+/// Image servers every incus installation knows, besides the configured `remote`
+const KNOWN_IMAGE_REMOTES: [&str; 4] = ["images", "ubuntu", "ubuntu-daily", "local"];
+
 fn default_image_source() -> String {
     "images:".to_string()
 }
@@ -397,8 +1514,18 @@ pub enum InstanceType {
     VirtualMachine,
 }
 
+impl InstanceType {
+    /// Name used by incus for this type
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            InstanceType::Container => "container",
+            InstanceType::VirtualMachine => "virtual-machine",
+        }
+    }
+}
+
 /// Network type enumeration
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum NetworkType {
     Bridge,
@@ -408,11 +1535,39 @@ pub enum NetworkType {
     Physical,
 }
 
+impl NetworkType {
+    /// Name used by incus for this type
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NetworkType::Bridge => "bridge",
+            NetworkType::Macvlan => "macvlan",
+            NetworkType::Sriov => "sriov",
+            NetworkType::Ovn => "ovn",
+            NetworkType::Physical => "physical",
+        }
+    }
+
+    /// Whether incus creates a managed network for this type
+    pub fn is_managed(&self) -> bool {
+        matches!(self, NetworkType::Bridge | NetworkType::Ovn)
+    }
+
+    /// Whether NICs of this type accept a static `ipv4.address`
+    pub fn supports_static_ipv4(&self) -> bool {
+        self.is_managed()
+    }
+
+    /// Whether NICs of this type accept a `vlan` tag (OVN does its own segmentation)
+    pub fn supports_vlan(&self) -> bool {
+        !matches!(self, NetworkType::Ovn)
+    }
+}
+
 /// CPU specification
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CpuSpec {
-    /// Number of CPU cores
-    pub cores: u32,
+    /// Number of CPU cores, or the set of CPUs to pin to (e.g. `0-3`)
+    pub cores: CpuCores,
 
     /// CPU limit (percentage)
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -427,19 +1582,118 @@ pub struct CpuSpec {
     pub priority: Option<u32>,
 }
 
+/// `limits.cpu` value: a core count, or a pin set of CPU ids and ranges
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum CpuCores {
+    /// Any N cores, e.g. `4`
+    Count(u32),
+    /// Specific CPUs, e.g. `0-3` or `0,2,4`
+    Pinned(String),
+}
+
+impl CpuCores {
+    /// Check a pin set is comma-separated CPU ids or `first-last` ranges
+    pub fn validate(&self) -> Result<(), String> {
+        let CpuCores::Pinned(pins) = self else {
+            return Ok(());
+        };
+        let valid = pins.split(',').all(|item| {
+            let (first, last) = item.split_once('-').unwrap_or((item, item));
+            match (first.parse::<u32>(), last.parse::<u32>()) {
+                (Ok(first), Ok(last)) => first <= last,
+                _ => false,
+            }
+        });
+        if !valid {
+            return Err(format!(
+                "cpu cores '{}' must be a count or CPU ids and ranges like 0-3 or 0,2,4",
+                pins
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl From<u32> for CpuCores {
+    fn from(count: u32) -> Self {
+        CpuCores::Count(count)
+    }
+}
+
+impl PartialEq<u32> for CpuCores {
+    fn eq(&self, other: &u32) -> bool {
+        *self == CpuCores::Count(*other)
+    }
+}
+
+impl std::fmt::Display for CpuCores {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CpuCores::Count(count) => write!(f, "{}", count),
+            CpuCores::Pinned(pins) => f.write_str(pins),
+        }
+    }
+}
+
 /// Memory specification
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MemorySpec {
     /// Memory limit (e.g., "2GB", "512MB")
     pub limit: String,
 
-    /// Memory swap limit
-    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Whether the instance may swap (`limits.memory.swap`)
+    #[serde(
+        default,
+        deserialize_with = "deserialize_swap",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub swap: Option<String>,
 
-    /// Memory swap priority
+    /// Swap priority from 0 to 10, higher swaps later (`limits.memory.swap.priority`)
     #[serde(skip_serializing_if = "Option::is_none")]
     pub swap_priority: Option<u32>,
+
+    /// Whether the memory limit is hard or soft (`limits.memory.enforce`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enforce: Option<MemoryEnforce>,
+}
+
+/// How incus enforces a memory limit
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MemoryEnforce {
+    Hard,
+    Soft,
+}
+
+impl MemoryEnforce {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MemoryEnforce::Hard => "hard",
+            MemoryEnforce::Soft => "soft",
+        }
+    }
+}
+
+/// Accept `swap: false` as well as the string form
+fn deserialize_swap<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Swap {
+        Flag(bool),
+        Value(String),
+    }
+
+    Ok(
+        Option::<Swap>::deserialize(deserializer)?.map(|swap| match swap {
+            Swap::Flag(flag) => flag.to_string(),
+            Swap::Value(value) => value,
+        }),
+    )
 }
 
 /// Storage specification
@@ -457,6 +1711,30 @@ pub struct StorageSpec {
     pub storage_type: Option<String>,
 }
 
+/// Pool that incus always has after `incus admin init`, so never needs declaring
+const DEFAULT_STORAGE_POOL: &str = "default";
+
+/// Network the NIC of a host without subnets attaches to
+const DEFAULT_NETWORK: &str = "bridge";
+
+/// A storage pool declared under `storage_pools`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StoragePool {
+    /// Name of the pool
+    pub name: String,
+
+    /// Storage driver, e.g. `dir`, `btrfs`, `zfs` or `lvm`
+    pub driver: String,
+
+    /// Existing device, dataset or directory to build the pool on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+
+    /// Size of a loop-backed pool (e.g. `30GB`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<String>,
+}
+
 /// Resolved resource limits (combination of CPU, memory, storage)
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Resources {
@@ -471,16 +1749,22 @@ pub struct Resources {
     pub storage: Option<StorageSpec>,
 }
 
+/// Generator version recorded in newly written lockfiles
+pub const GENERATOR_VERSION: &str = "0.1.0";
+
 /// Lockfile metadata
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct LockfileMetadata {
     /// Generation timestamp
+    #[serde(default)]
     pub generated_at: String,
 
-    /// Generator version
+    /// Generator version (empty in lockfiles predating the field)
+    #[serde(default)]
     pub generator_version: String,
 
     /// Source compose file hash
+    #[serde(default)]
     pub source_hash: String,
 
     /// Used value tracker for uniqueness
@@ -493,7 +1777,7 @@ pub struct LockfileMetadata {
 pub struct UsedValues {
     /// Used IP addresses
     #[serde(default)]
-    pub ip_addresses: HashMap<String, Vec<String>>,
+    pub ip_addresses: BTreeMap<String, Vec<String>>,
 
     /// Used MAC addresses
     #[serde(default)]
@@ -508,570 +1792,6798 @@ pub struct UsedValues {
     pub subnet_ids: Vec<String>,
 }
 
-impl IncusCompose {
-    /// Load an incus-compose.yaml file from disk
-    pub fn load_from_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn std::error::Error>> {
-        let content = fs::read_to_string(path)?;
-        let mut compose: IncusCompose = serde_yaml::from_str(&content)?;
+/// An instance in the YAML shape `incus create <image> <name> < file` accepts
+#[derive(Debug, Clone, Serialize)]
+pub struct InstanceDefinition {
+    pub name: String,
 
-        // Normalize legacy subnet fields
-        for host in &mut compose.hosts {
-            host.normalize();
-        }
+    #[serde(rename = "type")]
+    pub instance_type: InstanceType,
 
-        Ok(compose)
-    }
+    /// Image the instance is created from
+    pub image: String,
 
-    /// Generate a lockfile from this compose configuration
-    pub fn generate_lockfile(&self) -> IncusLockfile {
-        let mut used_values = UsedValues::default();
-        let mut expanded_hosts = Vec::new();
-        let mut expanded_subnets = Vec::new();
+    /// Deleted by incus when it stops
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub ephemeral: bool,
 
-        // Generate expanded subnets first (needed for IP allocation)
-        for (idx, subnet) in self.subnets.iter().enumerate() {
-            let subnet_id = format!("subnet_{:03}", idx + 1);
-            let subnet_name = subnet.name();
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub profiles: Vec<String>,
 
-            // Use explicit CIDR or auto-assign
-            let cidr = subnet
-                .cidr()
-                .map(|c| c.to_string())
-                .unwrap_or_else(|| self.auto_assign_cidr(&mut used_values));
+    /// Instance config keys, e.g. `limits.cpu`
+    pub config: BTreeMap<String, String>,
 
-            // Calculate gateway (typically .1)
-            let gateway = self.calculate_gateway(&cidr);
+    /// Devices by name (`eth0`, `root`, ...), each a map of device keys
+    pub devices: BTreeMap<String, BTreeMap<String, String>>,
+}
 
-            expanded_subnets.push(ExpandedSubnet {
-                name: subnet_name.to_string(),
-                cidr: cidr.clone(),
-                id: subnet_id.clone(),
-                gateway,
-                network_type: default_network_type(),
-                config: HashMap::new(),
-            });
+/// A configuration that parsed but breaks a constraint checked by `validate`
+#[derive(Debug)]
+pub struct ValidationError(pub String);
 
-            used_values.subnet_ids.push(subnet_id);
-        }
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
 
-        // Generate expanded hosts
-        for (idx, host) in self.hosts.iter().enumerate() {
-            let host_id = format!("host_{:03}", idx + 1);
-            let mac_address = self.generate_mac_address(&mut used_values);
+impl std::error::Error for ValidationError {}
+
+/// Format a YAML parse error as `file:line:col: message` so editors can jump to it
+fn located_yaml_error(source_name: &str, err: serde_yaml::Error) -> Box<dyn std::error::Error> {
+    let message = message_without_location(&err);
+    match err.location() {
+        Some(location) => format!(
+            "{}:{}:{}: {}",
+            source_name,
+            location.line(),
+            location.column(),
+            message
+        )
+        .into(),
+        None => format!("{}: {}", source_name, message).into(),
+    }
+}
 
-            // Assign IP addresses for each subnet
-            let mut ip_addresses = HashMap::new();
-            for subnet_name in &host.subnets {
-                if let Some(expanded_subnet) =
-                    expanded_subnets.iter().find(|s| &s.name == subnet_name)
-                {
-                    let ip = self.assign_ip_address(
-                        &expanded_subnet.cidr,
-                        host.is_router,
-                        &mut used_values,
-                    );
-                    ip_addresses.insert(subnet_name.clone(), ip);
-                }
-            }
+/// An error's message without the " at line X column Y" serde_yaml adds to it
+fn message_without_location(err: &serde_yaml::Error) -> String {
+    let message = err.to_string();
+    match err.location() {
+        Some(location) => {
+            let suffix = format!(" at line {} column {}", location.line(), location.column());
+            message.replacen(&suffix, "", 1)
+        }
+        None => message,
+    }
+}
 
-            // Resolve instance type and resources from flavor (simplified for now)
-            let instance_type = default_instance_type();
-            let resources = Resources {
-                cpu: CpuSpec {
-                    cores: 2, // Default values - should be looked up from flavor
-                    limit: Some("100%".to_string()),
-                    allowance: None,
-                    priority: None,
-                },
-                memory: MemorySpec {
-                    limit: "2GB".to_string(), // Default - should be from flavor
-                    swap: None,
-                    swap_priority: None,
-                },
-                storage: None,
-            };
+/// Whether `value` holds a `<<` merge key at any depth
+fn has_merge_keys(value: &serde_yaml::Value) -> bool {
+    match value {
+        serde_yaml::Value::Mapping(mapping) => mapping
+            .iter()
+            .any(|(key, value)| key.as_str() == Some("<<") || has_merge_keys(value)),
+        serde_yaml::Value::Sequence(sequence) => sequence.iter().any(has_merge_keys),
+        serde_yaml::Value::Tagged(tagged) => has_merge_keys(&tagged.value),
+        _ => false,
+    }
+}
 
-            expanded_hosts.push(ExpandedHost {
-                name: host.name.clone(),
-                flavor: host.flavor.clone(),
-                image: host.image.clone(),
-                floating_ip: host.floating_ip,
-                master: host.master,
-                is_router: host.is_router,
-                roles: host
-                    .roles
-                    .iter()
-                    .map(|r| r.clone().to_full_config())
-                    .collect(),
-                subnets: host.subnets.clone(),
-                id: host_id.clone(),
-                mac_address: Some(mac_address),
-                ip_addresses,
-                instance_type,
-                resources,
-            });
+/// Directory relative paths in the configuration file at `path` are read from
+fn config_dir(path: &Path) -> &Path {
+    path.parent().unwrap_or(Path::new(""))
+}
 
-            used_values.host_ids.push(host_id);
-        }
+/// Parse YAML text into a value with `<<` merge keys expanded
+///
+/// serde_yaml keeps `<<` as an ordinary key, so a host built from an anchor
+/// with `<<: *base` would silently lose every field it inherits.
+fn parse_merged_yaml(
+    content: &str,
+    source_name: &str,
+) -> Result<serde_yaml::Value, Box<dyn std::error::Error>> {
+    let mut value: serde_yaml::Value =
+        serde_yaml::from_str(content).map_err(|e| located_yaml_error(source_name, e))?;
+    value
+        .apply_merge()
+        .map_err(|e| located_yaml_error(source_name, e))?;
+    Ok(value)
+}
 
-        IncusLockfile {
-            version: self.version.clone(),
-            hosts: expanded_hosts,
-            subnets: expanded_subnets,
-            flavors: self.flavors.clone(),
-            images: self.images.clone(),
-            defaults: self.defaults.clone(),
-            metadata: LockfileMetadata {
-                generated_at: simple_timestamp(),
-                generator_version: "0.1.0".to_string(),
-                source_hash: self.calculate_hash(),
-                used_values,
-            },
+/// Deserialize YAML text, expanding `<<` merge keys first
+///
+/// Text without merge keys is deserialized directly so errors keep their line
+/// and column. With merge keys, an error the file as written also has keeps its
+/// location; one in merged-in keys is reported with its key path instead.
+fn from_merged_yaml<T: serde::de::DeserializeOwned>(
+    content: &str,
+    source_name: &str,
+) -> Result<T, Box<dyn std::error::Error>> {
+    let mut value: serde_yaml::Value =
+        serde_yaml::from_str(content).map_err(|e| located_yaml_error(source_name, e))?;
+    if !has_merge_keys(&value) {
+        return serde_yaml::from_str(content).map_err(|e| located_yaml_error(source_name, e));
+    }
+    value
+        .apply_merge()
+        .map_err(|e| located_yaml_error(source_name, e))?;
+    let merged_err = match serde_yaml::from_value(value.clone()) {
+        Ok(parsed) => return Ok(parsed),
+        Err(e) => e,
+    };
+
+    // Deserializing a value loses positions; re-reading it as text names the key path
+    let merged_text = serde_yaml::to_string(&value)?;
+    let Err(err) = serde_yaml::from_str::<T>(&merged_text) else {
+        return Err(format!("{}: {}", source_name, merged_err).into());
+    };
+    let message = message_without_location(&err);
+    match serde_yaml::from_str::<T>(content) {
+        Err(original) if message_without_location(&original) == message => {
+            Err(located_yaml_error(source_name, original))
         }
+        _ => Err(format!("{}: {} (in keys merged with `<<`)", source_name, message).into()),
     }
+}
 
-    /// Auto-assign a CIDR block from configured ranges
-    fn auto_assign_cidr(&self, used_values: &mut UsedValues) -> String {
-        // Simplified implementation - should use actual CIDR range logic
-        let base_cidr = "192.168.{}.0/24";
-        let subnet_num = used_values.subnet_ids.len() + 10; // Start from 192.168.10.0/24
-        base_cidr.replace("{}", &subnet_num.to_string())
+/// Rewrite compose file text in canonical form, as `incus-composer fmt` does
+///
+/// Legacy `subnet`/`subnet_list` fields become `subnets`, full entries carrying
+/// nothing but a name collapse to the shorthand, keys follow schema order and
+/// keys left at their default are dropped. Includes and groups stay as written;
+/// comments and anchors do not survive (merge keys are expanded).
+pub fn format_compose(
+    content: &str,
+    source_name: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let mut compose: IncusCompose = from_merged_yaml(content, source_name)?;
+    for host in &mut compose.hosts {
+        host.normalize();
+        collapse_subnet_assignments(&mut host.subnets);
+        collapse_roles(&mut host.roles);
     }
-
-    /// Calculate gateway IP for a CIDR block
-    fn calculate_gateway(&self, cidr: &str) -> String {
-        // Simplified - typically .1 of the network
-        if let Some(network_part) = cidr.split('/').next() {
-            let parts: Vec<&str> = network_part.split('.').collect();
-            if parts.len() == 4 {
-                return format!("{}.{}.{}.1", parts[0], parts[1], parts[2]);
+    for group in &mut compose.groups {
+        collapse_subnet_assignments(&mut group.subnets);
+        collapse_roles(&mut group.roles);
+    }
+    for subnet in &mut compose.subnets {
+        if let Subnet::Full(ref config) = subnet {
+            let shorthand = Subnet::Name(config.name.clone());
+            if same_yaml(&shorthand.to_full_config(), config.as_ref()) {
+                *subnet = shorthand;
             }
         }
-        "192.168.1.1".to_string() // Fallback
     }
 
-    /// Generate a unique MAC address
-    fn generate_mac_address(&self, used_values: &mut UsedValues) -> String {
-        let mut counter = used_values.mac_addresses.len() + 1;
-        loop {
-            let mac = format!(
-                "02:00:00:00:{:02x}:{:02x}",
-                (counter >> 8) & 0xff,
-                counter & 0xff
-            );
-            if !used_values.mac_addresses.contains(&mac) {
-                used_values.mac_addresses.push(mac.clone());
-                return mac;
-            }
-            counter += 1;
+    let full = serde_yaml::to_value(&compose)?;
+    let mut canonical = full.clone();
+    for path in empty_value_paths(&full, &mut Vec::new()) {
+        // Only drop a key if reading the file back gives the same configuration
+        let mut trial = canonical.clone();
+        remove_yaml_path(&mut trial, &path);
+        let reread = serde_yaml::from_value::<IncusCompose>(trial.clone())
+            .ok()
+            .and_then(|c| serde_yaml::to_value(&c).ok());
+        if reread.as_ref() == Some(&full) {
+            canonical = trial;
         }
     }
+    Ok(serde_yaml::to_string(&canonical)?)
+}
 
-    /// Assign IP address within a subnet
-    fn assign_ip_address(
-        &self,
-        cidr: &str,
-        is_router: bool,
-        used_values: &mut UsedValues,
-    ) -> String {
-        // Simplified implementation
-        let network_base = if let Some(network_part) = cidr.split('/').next() {
-            let parts: Vec<&str> = network_part.split('.').collect();
-            if parts.len() == 4 {
-                format!("{}.{}.{}", parts[0], parts[1], parts[2])
-            } else {
-                "192.168.1".to_string()
-            }
+/// Whether two values serialize identically
+fn same_yaml<T: Serialize>(a: &T, b: &T) -> bool {
+    matches!(
+        (serde_yaml::to_value(a), serde_yaml::to_value(b)),
+        (Ok(a), Ok(b)) if a == b
+    )
+}
+
+fn collapse_subnet_assignments(assignments: &mut [SubnetAssignment]) {
+    for assignment in assignments {
+        let shorthand = SubnetAssignment::Name(assignment.name().to_string());
+        if same_yaml(&shorthand.to_full_config(), &assignment.to_full_config()) {
+            *assignment = shorthand;
+        }
+    }
+}
+
+fn collapse_roles(roles: &mut [Role]) {
+    for role in roles {
+        if role.values().is_empty() {
+            *role = Role::Name(role.name().to_string());
+        }
+    }
+}
+
+/// Key paths under `value` holding `false`, null or an empty list or mapping
+fn empty_value_paths(
+    value: &serde_yaml::Value,
+    prefix: &mut Vec<serde_yaml::Value>,
+) -> Vec<Vec<serde_yaml::Value>> {
+    use serde_yaml::Value;
+    let mut paths = Vec::new();
+    let children: Vec<(Value, &Value)> = match value {
+        Value::Mapping(map) => map.iter().map(|(k, v)| (k.clone(), v)).collect(),
+        Value::Sequence(items) => items
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (Value::from(i as u64), v))
+            .collect(),
+        _ => return paths,
+    };
+    for (key, child) in children {
+        prefix.push(key);
+        let empty = match child {
+            Value::Null | Value::Bool(false) => true,
+            Value::Sequence(items) => items.is_empty(),
+            Value::Mapping(map) => map.is_empty(),
+            _ => false,
+        };
+        if empty && value.is_mapping() {
+            paths.push(prefix.clone());
         } else {
-            "192.168.1".to_string()
+            paths.extend(empty_value_paths(child, prefix));
+        }
+        prefix.pop();
+    }
+    paths
+}
+
+/// Remove the mapping entry at `path`, where numbers index into lists
+fn remove_yaml_path(value: &mut serde_yaml::Value, path: &[serde_yaml::Value]) {
+    let Some((last, parents)) = path.split_last() else {
+        return;
+    };
+    let mut current = value;
+    for key in parents {
+        let next = match current {
+            serde_yaml::Value::Sequence(items) => {
+                key.as_u64().and_then(|i| items.get_mut(i as usize))
+            }
+            other => other.get_mut(key),
         };
+        match next {
+            Some(next) => current = next,
+            None => return,
+        }
+    }
+    if let serde_yaml::Value::Mapping(map) = current {
+        map.shift_remove(last);
+    }
+}
 
-        let subnet_name = format!("subnet_{}", network_base.replace(".", "_"));
-        let used_ips = used_values
-            .ip_addresses
-            .entry(subnet_name.clone())
-            .or_insert_with(Vec::new);
+impl IncusCompose {
+    /// Load an incus-compose.yaml file from disk
+    ///
+    /// A path of `-` reads the configuration from stdin.
+    pub fn load_from_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = path.as_ref();
+        if path == Path::new("-") {
+            return Self::load_from_reader(std::io::stdin().lock(), "<stdin>");
+        }
+        let content = fs::read_to_string(path)?;
+        Self::load_from_str(&content, &path.display().to_string(), config_dir(path))
+    }
 
-        // Start from .10 for regular hosts, .2 for routers (after gateway .1)
-        let start_ip = if is_router { 2 } else { 10 };
+    /// Load and validate configuration read to end from `reader`
+    pub fn load_from_reader<R: Read>(
+        mut reader: R,
+        source_name: &str,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut content = String::new();
+        reader.read_to_string(&mut content)?;
+        Self::load_from_str(&content, source_name, Path::new(""))
+    }
 
-        for i in start_ip..255 {
-            let ip = format!("{}.{}", network_base, i);
-            if !used_ips.contains(&ip) {
-                used_ips.push(ip.clone());
-                return ip;
-            }
+    /// Load `path`, deep-merge each override file onto it in order, then validate
+    ///
+    /// Mappings merge key by key and scalars are replaced. Lists whose entries
+    /// are named (hosts, subnets, groups, roles, subnet attachments) merge entry by
+    /// entry on `name`, appending new names; other lists are replaced outright.
+    pub fn load_with_overrides<P: AsRef<Path>>(
+        path: P,
+        overrides: &[P],
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        if overrides.is_empty() {
+            return Self::load_from_file(path);
+        }
+
+        let path = path.as_ref();
+        let content = if path == Path::new("-") {
+            let mut content = String::new();
+            std::io::stdin().lock().read_to_string(&mut content)?;
+            content
+        } else {
+            fs::read_to_string(path)?
+        };
+        let mut merged = parse_merged_yaml(&content, &path.display().to_string())?;
+
+        for override_path in overrides {
+            let override_path = override_path.as_ref();
+            let content = fs::read_to_string(override_path)
+                .map_err(|e| format!("{}: {}", override_path.display(), e))?;
+            let overlay = parse_merged_yaml(&content, &override_path.display().to_string())?;
+            merge_yaml(&mut merged, overlay);
         }
 
-        format!("{}.100", network_base) // Fallback
+        let compose: IncusCompose =
+            serde_yaml::from_value(merged).map_err(|e| format!("merged configuration: {}", e))?;
+        compose.finish_loading(config_dir(path))
     }
 
-    /// Calculate hash of the compose file for change detection
-    fn calculate_hash(&self) -> String {
-        // Simplified implementation - should use proper hashing
-        format!(
-            "sha256:abc123def456_{}",
-            self.hosts.len() + self.subnets.len()
-        )
+    /// Parse, normalize and validate configuration text; `source_name` labels errors
+    ///
+    /// Relative includes are read from `base_dir`.
+    fn load_from_str(
+        content: &str,
+        source_name: &str,
+        base_dir: &Path,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let compose: IncusCompose = from_merged_yaml(content, source_name)?;
+        compose.finish_loading(base_dir)
     }
-}
 
-impl IncusLockfile {
-    /// Save lockfile to disk
-    pub fn save_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn std::error::Error>> {
-        let yaml = serde_yaml::to_string(self)?;
-        fs::write(path, yaml)?;
+    /// Normalize, expand groups and validate freshly parsed configuration
+    fn finish_loading(mut self, base_dir: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        self.resolve_includes(base_dir)?;
+
+        // Normalize legacy subnet fields
+        for host in &mut self.hosts {
+            host.normalize();
+        }
+        self.expand_groups();
+        self.apply_default_flavor_and_image();
+
+        self.validate()
+            .map_err(|e| ValidationError(e.to_string()))?;
+
+        Ok(self)
+    }
+
+    /// Merge the flavors, images and roles of every `include` entry, in order
+    ///
+    /// Definitions in the compose file itself win over included ones, and
+    /// earlier includes win over later ones. The `include` list itself is kept,
+    /// so the dumped configuration and its hash still name the catalogs used.
+    /// Relative include and cache paths are taken from `base_dir`, the directory
+    /// of the configuration file.
+    pub fn resolve_includes(&mut self, base_dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let cache_dir = self.catalog_cache.as_ref().map(|dir| base_dir.join(dir));
+        for source in &self.include {
+            let located = if catalog::is_url(source) {
+                source.clone()
+            } else {
+                base_dir.join(source).display().to_string()
+            };
+            let content = catalog::read_source(&located, cache_dir.as_deref())?;
+            let included: Catalog = from_merged_yaml(&content, source)?;
+            for (name, flavor) in included.flavors {
+                self.flavors.entry(name).or_insert(flavor);
+            }
+            for (name, image) in included.images {
+                self.images.entry(name).or_insert(image);
+            }
+            for (name, role) in included.roles {
+                self.roles.entry(name).or_insert(role);
+            }
+        }
         Ok(())
     }
 
-    /// Load lockfile from disk
-    pub fn load_from_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn std::error::Error>> {
-        let content = fs::read_to_string(path)?;
-        let lockfile: IncusLockfile = serde_yaml::from_str(&content)?;
-        Ok(lockfile)
+    /// A host's instance type: its own override, else its flavor's, else a container
+    pub fn host_instance_type(&self, host: &Host) -> InstanceType {
+        host.instance_type.clone().unwrap_or_else(|| {
+            self.flavors
+                .get(&host.flavor)
+                .map(|f| f.instance_type.clone())
+                .unwrap_or_else(default_instance_type)
+        })
     }
 
-    /// Generate incus commands for dry-run
-    pub fn generate_incus_commands(&self) -> Vec<String> {
-        let mut commands = Vec::new();
+    /// Fill in `defaults.default_flavor`/`default_image` on hosts still lacking them
+    pub fn apply_default_flavor_and_image(&mut self) {
+        for host in &mut self.hosts {
+            if host.flavor.is_empty() {
+                if let Some(ref flavor) = self.defaults.default_flavor {
+                    host.flavor = flavor.clone();
+                }
+            }
+            if host.image.is_empty() {
+                if let Some(ref image) = self.defaults.default_image {
+                    host.image = image.clone();
+                }
+            }
+        }
+    }
 
-        // Create networks first
-        for subnet in &self.subnets {
-            commands.push(format!(
-                "incus network create {} --type=bridge",
-                subnet.name
-            ));
-            commands.push(format!(
-                "incus network set {} ipv4.address={}",
-                subnet.name, subnet.gateway
-            ));
-            commands.push(format!("incus network set {} ipv4.dhcp=false", subnet.name));
+    /// Expand `groups` into `hosts`, appending members not already listed
+    pub fn expand_groups(&mut self) {
+        for group in std::mem::take(&mut self.groups) {
+            for member in &group.hosts {
+                let idx = match self.hosts.iter().position(|h| &h.name == member) {
+                    Some(idx) => idx,
+                    None => {
+                        self.hosts.push(Host {
+                            name: member.clone(),
+                            ..Default::default()
+                        });
+                        self.hosts.len() - 1
+                    }
+                };
+                group.apply_to(&mut self.hosts[idx]);
+            }
         }
+    }
 
-        // Create instances
-        for host in &self.hosts {
-            let instance_type = match host.instance_type {
-                InstanceType::Container => "container",
-                InstanceType::VirtualMachine => "virtual-machine",
-            };
+    /// Check constraints that deserialization alone cannot enforce
+    pub fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(ref remote) = self.remote {
+            if remote.is_empty() || remote.contains(':') {
+                return Err(
+                    format!("remote '{}' must be a non-empty name without ':'", remote).into(),
+                );
+            }
+        }
 
-            commands.push(format!(
-                "incus create {} {} --type={}",
-                host.image, host.name, instance_type
-            ));
+        if let Some(ref domain) = self.dns_domain {
+            if !is_domain_name(domain) {
+                return Err(format!("dns_domain '{}' is not a valid domain name", domain).into());
+            }
+        }
 
-            // Set resource limits
-            commands.push(format!(
-                "incus config set {} limits.cpu={}",
-                host.name, host.resources.cpu.cores
-            ));
-            commands.push(format!(
-                "incus config set {} limits.memory={}",
-                host.name, host.resources.memory.limit
-            ));
+        for cidr in &self.defaults.excluded_cidrs {
+            if cidr.parse::<Ipv4Net>().is_err() {
+                return Err(format!(
+                    "defaults: excluded cidr '{}' is not a valid IPv4 CIDR",
+                    cidr
+                )
+                .into());
+            }
+        }
 
-            // Set MAC address
-            if let Some(ref mac) = host.mac_address {
-                commands.push(format!(
-                    "incus config device add {} eth0 nic network={} hwaddr={}",
-                    host.name,
-                    host.subnets.get(0).unwrap_or(&"bridge".to_string()),
-                    mac
-                ));
+        if let Some(ref management) = self.defaults.management_subnet {
+            if !self.subnets.iter().any(|s| s.name() == management) {
+                return Err(format!(
+                    "defaults: management_subnet '{}' is not defined in subnets",
+                    management
+                )
+                .into());
+            }
+        }
+
+        if let Some(ref prefix) = self.defaults.mac_prefix {
+            let octets: Vec<&str> = prefix.split(':').collect();
+            let valid = octets.len() == 3
+                && octets
+                    .iter()
+                    .all(|o| o.len() == 2 && o.chars().all(|c| c.is_ascii_hexdigit()));
+            if !valid {
+                return Err(format!(
+                    "defaults: mac_prefix '{}' must be three hex octets, e.g. 02:00:00",
+                    prefix
+                )
+                .into());
             }
+        }
 
-            // Assign to networks and set IP addresses
-            for (i, subnet_name) in host.subnets.iter().enumerate() {
-                let device_name = if i == 0 {
-                    "eth0".to_string()
-                } else {
-                    format!("eth{}", i)
+        for subnet in self.subnets.iter().map(|s| s.to_full_config()) {
+            check_network_name(&subnet.name)
+                .map_err(|e| format!("subnet '{}': {}", subnet.name, e))?;
+            let position = subnet.gateway_position.or(self.defaults.gateway_position);
+            if let (Some(position), Some(cidr)) = (position, &subnet.cidr) {
+                if let Ok(net) = cidr.parse::<Ipv4Net>() {
+                    if position.resolve(&net).is_none() {
+                        return Err(format!(
+                            "subnet '{}': gateway_position {:?} is outside {}",
+                            subnet.name, position, cidr
+                        )
+                        .into());
+                    }
+                }
+            }
+            if let Some(ref gateway_host) = subnet.gateway_host {
+                let attached = self.hosts.iter().any(|h| {
+                    &h.name == gateway_host && h.subnet_names().contains(&subnet.name.as_str())
+                });
+                if !attached {
+                    return Err(format!(
+                        "subnet '{}': gateway_host '{}' is not attached to it",
+                        subnet.name, gateway_host
+                    )
+                    .into());
+                }
+            }
+            let network_type = subnet.network_type.unwrap_or_else(default_network_type);
+            if !network_type.is_managed() && subnet.parent.is_none() {
+                return Err(format!(
+                    "subnet '{}': network_type {} needs a parent interface",
+                    subnet.name,
+                    network_type.as_str()
+                )
+                .into());
+            }
+            if subnet.dns_mode.is_some() && network_type != NetworkType::Bridge {
+                return Err(format!(
+                    "subnet '{}': dns_mode needs network_type bridge, not {}",
+                    subnet.name,
+                    network_type.as_str()
+                )
+                .into());
+            }
+            if !subnet.external_interfaces.is_empty() && network_type != NetworkType::Bridge {
+                return Err(format!(
+                    "subnet '{}': external_interfaces needs network_type bridge, not {}",
+                    subnet.name,
+                    network_type.as_str()
+                )
+                .into());
+            }
+            for interface in &subnet.external_interfaces {
+                if interface.is_empty()
+                    || interface
+                        .chars()
+                        .any(|c| c == ',' || c == '/' || c.is_whitespace())
+                {
+                    return Err(format!(
+                        "subnet '{}': invalid external interface '{}'",
+                        subnet.name, interface
+                    )
+                    .into());
+                }
+            }
+            for route in &subnet.ipv4_routes {
+                if route.parse::<Ipv4Net>().is_err() {
+                    return Err(format!(
+                        "subnet '{}': ipv4_routes entry '{}' is not an IPv4 CIDR",
+                        subnet.name, route
+                    )
+                    .into());
+                }
+            }
+            if let Some(prefix) = subnet.prefix {
+                if subnet.cidr.is_some() {
+                    return Err(format!(
+                        "subnet '{}': prefix only applies to an auto-assigned cidr, but cidr is set",
+                        subnet.name
+                    )
+                    .into());
+                }
+                if !(8..=30).contains(&prefix) {
+                    return Err(format!(
+                        "subnet '{}': prefix /{} is out of range (/8-/30)",
+                        subnet.name, prefix
+                    )
+                    .into());
+                }
+            }
+            if let Some(host_start) = subnet.host_start {
+                if !(1..=254).contains(&host_start) {
+                    return Err(format!(
+                        "subnet '{}': host_start {} is out of range (1-254)",
+                        subnet.name, host_start
+                    )
+                    .into());
+                }
+            }
+            for server in &subnet.dns_servers {
+                if server.parse::<IpAddr>().is_err() {
+                    return Err(format!(
+                        "subnet '{}': dns server '{}' is not a valid IP address",
+                        subnet.name, server
+                    )
+                    .into());
+                }
+            }
+            for domain in &subnet.dns_search {
+                if !is_domain_name(domain) {
+                    return Err(format!(
+                        "subnet '{}': dns search domain '{}' is not a valid domain name",
+                        subnet.name, domain
+                    )
+                    .into());
+                }
+            }
+            for ip in &subnet.excluded_ips {
+                if ip.parse::<Ipv4Addr>().is_err() {
+                    return Err(format!(
+                        "subnet '{}': excluded ip '{}' is not a valid IPv4 address",
+                        subnet.name, ip
+                    )
+                    .into());
+                }
+            }
+            for range in &subnet.reserved_ranges {
+                match (
+                    range.start.parse::<Ipv4Addr>(),
+                    range.end.parse::<Ipv4Addr>(),
+                ) {
+                    (Ok(start), Ok(end)) if start <= end => {}
+                    _ => {
+                        return Err(format!(
+                            "subnet '{}': reserved range {}-{} is not a valid IPv4 range",
+                            subnet.name, range.start, range.end
+                        )
+                        .into())
+                    }
+                }
+            }
+        }
+
+        for (name, flavor) in &self.flavors {
+            flavor
+                .cpu
+                .cores
+                .validate()
+                .map_err(|e| format!("flavor '{}': {}", name, e))?;
+            normalize_memory_limit(&flavor.memory.limit)
+                .map_err(|e| format!("flavor '{}': {}", name, e))?;
+            if let Some(priority) = flavor.memory.swap_priority.filter(|p| *p > 10) {
+                return Err(format!(
+                    "flavor '{}': swap_priority {} is out of range (0-10)",
+                    name, priority
+                )
+                .into());
+            }
+            if let Some(ref storage) = flavor.storage {
+                normalize_size(&storage.size)
+                    .map_err(|e| format!("flavor '{}': storage {}", name, e))?;
+            }
+        }
+
+        for pool in &self.storage_pools {
+            if pool.driver.is_empty() {
+                return Err(format!("storage pool '{}' needs a driver", pool.name).into());
+            }
+            if let Some(ref size) = pool.size {
+                normalize_size(size)
+                    .map_err(|e| format!("storage pool '{}': size {}", pool.name, e))?;
+            }
+            let conflicting = self
+                .storage_pools
+                .iter()
+                .any(|other| other.name == pool.name && other != pool);
+            if conflicting {
+                return Err(format!(
+                    "storage pool '{}' is declared more than once with different settings",
+                    pool.name
+                )
+                .into());
+            }
+        }
+
+        for (name, image) in &self.images {
+            check_image_source(image, self.remote.as_deref())
+                .map_err(|e| format!("image '{}': {}", name, e))?;
+        }
+
+        for host in &self.hosts {
+            check_instance_name(&host.name).map_err(|e| format!("host '{}': {}", host.name, e))?;
+            if host.flavor.is_empty() || host.image.is_empty() {
+                return Err(format!(
+                    "host '{}' needs both a flavor and an image (set them directly, via a group or in defaults)",
+                    host.name
+                )
+                .into());
+            }
+
+            if let Some(ref size) = host.disk_size {
+                normalize_size(size)
+                    .map_err(|e| format!("host '{}': disk_size {}", host.name, e))?;
+            }
+
+            let is_vm = matches!(self.host_instance_type(host), InstanceType::VirtualMachine);
+            if host.privileged && is_vm {
+                let source = match host.instance_type {
+                    Some(_) => "its instance_type".to_string(),
+                    None => format!("flavor '{}'", host.flavor),
                 };
+                return Err(format!(
+                    "host '{}': privileged is only supported for containers, but {} is a virtual-machine",
+                    host.name, source
+                )
+                .into());
+            }
 
-                if i > 0 {
-                    // eth0 already added above
-                    commands.push(format!(
-                        "incus config device add {} {} nic network={}",
-                        host.name, device_name, subnet_name
-                    ));
+            let iso = self.images.get(&host.image).and_then(|i| i.iso.as_ref());
+            if iso.is_some() && !is_vm {
+                return Err(format!(
+                    "host '{}': image '{}' attaches an ISO, which needs a virtual-machine",
+                    host.name, host.image
+                )
+                .into());
+            }
+
+            if host.floating_ip {
+                let subnet_names = host.subnet_names();
+                let has_uplink = self.subnets.iter().any(|subnet| {
+                    subnet_names.contains(&subnet.name()) && subnet.to_full_config().uplink
+                });
+                if !has_uplink {
+                    return Err(format!(
+                        "host '{}': floating_ip needs a subnet with `uplink: true`, but it is on [{}]",
+                        host.name,
+                        subnet_names.join(", ")
+                    )
+                    .into());
+                }
+            } else if !host.forwards.is_empty() {
+                return Err(format!(
+                    "host '{}': forwards listen on the floating IP, so they need `floating_ip: true`",
+                    host.name
+                )
+                .into());
+            }
+            for forward in &host.forwards {
+                if forward.listen_port == 0 || forward.target_port == Some(0) {
+                    return Err(format!(
+                        "host '{}': forward ports must be between 1 and 65535",
+                        host.name
+                    )
+                    .into());
+                }
+            }
+
+            for key in host.environment.keys() {
+                let valid = key
+                    .chars()
+                    .next()
+                    .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+                    && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+                if !valid {
+                    return Err(format!(
+                        "host '{}': environment variable '{}' is not a valid name",
+                        host.name, key
+                    )
+                    .into());
                 }
+            }
 
-                if let Some(ip) = host.ip_addresses.get(subnet_name) {
-                    commands.push(format!(
-                        "incus config device set {} {} ipv4.address={}",
-                        host.name, device_name, ip
-                    ));
+            for dependency in &host.depends_on {
+                if !self.hosts.iter().any(|h| &h.name == dependency) {
+                    return Err(format!(
+                        "host '{}': depends_on references unknown host '{}'",
+                        host.name, dependency
+                    )
+                    .into());
                 }
             }
 
-            // Configure roles (simplified - would need actual role implementation)
             for role in &host.roles {
-                commands.push(format!(
-                    "# Apply role '{}' to {} with values: {:?}",
-                    role.name, host.name, role.values
-                ));
+                if let Some(template) = self.roles.get(role.name()) {
+                    template
+                        .render(&role.to_full_config(), &host.name)
+                        .map_err(|e| {
+                            format!("host '{}': role '{}': {}", host.name, role.name(), e)
+                        })?;
+                }
             }
 
-            // Start the instance
-            commands.push(format!("incus start {}", host.name));
+            for role in host.roles.iter().filter(|r| r.name() == FIREWALL_ROLE) {
+                for rule in role.values() {
+                    FirewallRule::parse(rule)
+                        .map_err(|e| format!("host '{}': firewall {}", host.name, e))?;
+                }
+            }
+
+            // Legacy fields are checked too, in case normalize() hasn't run yet
+            let referenced = host
+                .subnet_names()
+                .into_iter()
+                .chain(host.subnet.as_deref())
+                .chain(host.subnet_list.iter().flatten().map(String::as_str));
+            for subnet_name in referenced {
+                if !self.subnets.iter().any(|s| s.name() == subnet_name) {
+                    return Err(format!(
+                        "host '{}': subnet '{}' is not defined in subnets",
+                        host.name, subnet_name
+                    )
+                    .into());
+                }
+            }
+
+            let primaries: Vec<&SubnetAttachment> = host
+                .subnets
+                .iter()
+                .filter_map(|s| match s {
+                    SubnetAssignment::Full(attachment) if attachment.primary => Some(attachment),
+                    _ => None,
+                })
+                .collect();
+            if primaries.len() > 1 {
+                return Err(format!(
+                    "host '{}': only one subnet can be primary, found {}",
+                    host.name,
+                    primaries.len()
+                )
+                .into());
+            }
+            if let Some(primary) = primaries.first() {
+                // A repeated entry has its own MAC in additional_nics
+                let first = host.subnets.iter().find(|s| s.name() == primary.name);
+                if !matches!(first, Some(SubnetAssignment::Full(a)) if a.primary) {
+                    return Err(format!(
+                        "host '{}': primary must be the first entry for subnet '{}'",
+                        host.name, primary.name
+                    )
+                    .into());
+                }
+            }
+
+            let mut nic_names = BTreeSet::new();
+            for attachment in host.subnets.iter().map(|s| s.to_full_config()) {
+                if let Some(ref name) = attachment.nic_name {
+                    // Linux interface names are at most 15 bytes
+                    let valid = !name.is_empty()
+                        && name.len() <= 15
+                        && name
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+                    if !valid {
+                        return Err(format!(
+                            "host '{}': nic_name '{}' is not a valid interface name",
+                            host.name, name
+                        )
+                        .into());
+                    }
+                    if !nic_names.insert(name.clone()) {
+                        return Err(format!(
+                            "host '{}': nic_name '{}' is used more than once",
+                            host.name, name
+                        )
+                        .into());
+                    }
+                }
+                let network_type = self
+                    .subnets
+                    .iter()
+                    .find(|s| s.name() == attachment.name)
+                    .and_then(|s| s.to_full_config().network_type)
+                    .unwrap_or_else(default_network_type);
+                if attachment.vlan.is_some() && !network_type.supports_vlan() {
+                    return Err(format!(
+                        "host '{}': vlan is not supported on {} subnet '{}'",
+                        host.name,
+                        network_type.as_str(),
+                        attachment.name
+                    )
+                    .into());
+                }
+                if let Some(vlan) = attachment.vlan {
+                    if !(1..=4094).contains(&vlan) {
+                        return Err(format!(
+                            "host '{}': vlan {} on subnet '{}' is out of range (1-4094)",
+                            host.name, vlan, attachment.name
+                        )
+                        .into());
+                    }
+                }
+                if let Some(ref pinned) = attachment.ip4addr {
+                    let Ok(ip) = pinned.parse::<Ipv4Addr>() else {
+                        return Err(format!(
+                            "host '{}': ip4addr '{}' on subnet '{}' is not a valid IPv4 address",
+                            host.name, pinned, attachment.name
+                        )
+                        .into());
+                    };
+                    let cidr = self
+                        .subnets
+                        .iter()
+                        .find(|s| s.name() == attachment.name)
+                        .and_then(|s| s.to_full_config().cidr);
+                    if let Some(net) = cidr.and_then(|c| c.parse::<Ipv4Net>().ok()) {
+                        if !net.contains(&ip) {
+                            return Err(format!(
+                                "host '{}': ip4addr {} is not inside subnet '{}' ({})",
+                                host.name, ip, attachment.name, net
+                            )
+                            .into());
+                        }
+                    }
+                }
+            }
         }
 
-        commands
+        // Catch the same address pinned twice before the allocator sees it
+        let mut pinned: BTreeMap<(&str, &str), &str> = BTreeMap::new();
+        for host in &self.hosts {
+            for assignment in &host.subnets {
+                let SubnetAssignment::Full(attachment) = assignment else {
+                    continue;
+                };
+                let Some(ref ip) = attachment.ip4addr else {
+                    continue;
+                };
+                if let Some(other) =
+                    pinned.insert((attachment.name.as_str(), ip.as_str()), host.name.as_str())
+                {
+                    return Err(format!(
+                        "subnet '{}': {} is pinned by both host '{}' and host '{}'",
+                        attachment.name, ip, other, host.name
+                    )
+                    .into());
+                }
+            }
+        }
+
+        let dependencies: Vec<(&str, &[String])> = self
+            .hosts
+            .iter()
+            .map(|h| (h.name.as_str(), h.depends_on.as_slice()))
+            .collect();
+        dependency_layers(&dependencies)?;
+
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Generate a lockfile from this compose configuration
+    ///
+    /// Fails when a subnet runs out of addresses or the MAC space is exhausted.
+    pub fn generate_lockfile(&self) -> Result<IncusLockfile, Box<dyn std::error::Error>> {
+        // Generate from the hosts as attached, but keep the hash of the file as written
+        if let Some(attached) = self.with_management_subnet() {
+            let mut lockfile = attached.generate_lockfile()?;
+            lockfile.metadata.source_hash = self.source_hash();
+            return Ok(lockfile);
+        }
+
+        let mut used_values = UsedValues::default();
+        let mut rng = self.seed.map(SeededRng);
+        let id_scheme = self.defaults.id_scheme.unwrap_or_default();
+        let mut expanded_hosts = Vec::new();
+        let mut expanded_subnets = Vec::new();
+
+        // Generate expanded subnets first (needed for IP allocation)
+        for (idx, subnet) in self.subnets.iter().enumerate() {
+            let subnet_id = id_scheme.id("subnet", idx, subnet.name());
+            let subnet_config = subnet.to_full_config();
+
+            // Use explicit CIDR or auto-assign
+            let cidr = subnet_config.cidr.clone().map_or_else(
+                || {
+                    self.auto_assign_cidr(
+                        &subnet_config.name,
+                        subnet_config.prefix.unwrap_or(AUTO_CIDR_PREFIX),
+                        &used_values,
+                        &expanded_subnets,
+                    )
+                },
+                Ok,
+            )?;
+
+            let position = subnet_config
+                .gateway_position
+                .or(self.defaults.gateway_position)
+                .unwrap_or_default();
+            let gateway = self.calculate_gateway(&cidr, position);
+
+            expanded_subnets.push(ExpandedSubnet {
+                name: subnet_config.name.clone(),
+                cidr: cidr.clone(),
+                id: subnet_id.clone(),
+                gateway,
+                network_type: subnet_config
+                    .network_type
+                    .unwrap_or_else(default_network_type),
+                config: BTreeMap::new(),
+                mtu: subnet_config.mtu,
+                host_start: subnet_config.host_start.unwrap_or_else(default_host_start),
+                ipv6: subnet_config.ipv6,
+                excluded_ips: subnet_config.excluded_ips.clone(),
+                reserved_ranges: subnet_config.reserved_ranges.clone(),
+                parent: subnet_config.parent.clone(),
+                external_interfaces: subnet_config.external_interfaces.clone(),
+                device_address: subnet_config.device_address,
+                ipv4_routes: subnet_config.ipv4_routes.clone(),
+                gateway_host: subnet_config.gateway_host.clone(),
+                uplink: subnet_config.uplink,
+                dns_servers: subnet_config.dns_servers.clone(),
+                dns_search: subnet_config.dns_search.clone(),
+                dns_mode: subnet_config.dns_mode,
+            });
+
+            // Seed excluded addresses so the allocator skips them
+            let used_ips = used_values
+                .ip_addresses
+                .entry(ip_pool_key(&cidr))
+                .or_default();
+            for ip in
+                reserved_addresses(&subnet_config.excluded_ips, &subnet_config.reserved_ranges)
+            {
+                if !used_ips.contains(&ip) {
+                    used_ips.push(ip);
+                }
+            }
+
+            used_values.subnet_ids.push(subnet_id);
+        }
+
+        // Seed pinned addresses so no other host is allocated one of them. Pins are
+        // checked here, once auto-assigned CIDRs are known.
+        for (host, attachment) in self.hosts.iter().flat_map(|h| {
+            h.subnets
+                .iter()
+                .map(move |s| (h, SubnetAssignment::to_full_config(s)))
+        }) {
+            let (Some(ip), Some(subnet)) = (
+                attachment.ip4addr,
+                expanded_subnets.iter().find(|s| s.name == attachment.name),
+            ) else {
+                continue;
+            };
+            check_pinned_ip(&host.name, subnet, &ip)?;
+            let used_ips = used_values
+                .ip_addresses
+                .entry(ip_pool_key(&subnet.cidr))
+                .or_default();
+            if !used_ips.contains(&ip) {
+                used_ips.push(ip);
+            }
+        }
+
+        // Contiguous groups take their blocks before single hosts fragment the range
+        let mut contiguous_ips =
+            self.allocate_contiguous_blocks(&expanded_subnets, &mut used_values)?;
+
+        // Generate expanded hosts
+        for (idx, host) in self.hosts.iter().enumerate() {
+            let host_id = id_scheme.id("host", idx, &host.name);
+            let mac_address = self
+                .generate_mac_address(&mut used_values, rng.as_mut())
+                .ok_or_else(|| {
+                    format!(
+                        "no free MAC addresses left under prefix {} for host '{}'",
+                        self.defaults.mac_prefix(),
+                        host.name
+                    )
+                })?;
+
+            // Assign IP addresses for each subnet; repeats get a NIC of their own
+            let mut ip_addresses = BTreeMap::new();
+            let mut additional_nics = Vec::new();
+            for attachment in host.subnets.iter().map(SubnetAssignment::to_full_config) {
+                let subnet_name = attachment.name.as_str();
+                if let Some(expanded_subnet) =
+                    expanded_subnets.iter().find(|s| s.name == subnet_name)
+                {
+                    let block_ip =
+                        contiguous_ips.remove(&(host.name.clone(), subnet_name.to_string()));
+                    let ip = match (attachment.ip4addr, block_ip) {
+                        (Some(ref pinned), _) => pinned.clone(),
+                        (None, Some(ip)) => ip,
+                        (None, None) => {
+                            self.assign_ip_address(
+                                expanded_subnet,
+                                host.is_router,
+                                &mut used_values,
+                                rng.as_mut(),
+                            )
+                            .ok_or_else(|| self.exhaustion_error(expanded_subnet, host.is_router))?
+                        }
+                    };
+                    if !ip_addresses.contains_key(subnet_name) {
+                        ip_addresses.insert(subnet_name.to_string(), ip);
+                        continue;
+                    }
+                    let mac_address = self
+                        .generate_mac_address(&mut used_values, rng.as_mut())
+                        .ok_or_else(|| {
+                            format!(
+                                "no free MAC addresses left under prefix {} for host '{}'",
+                                self.defaults.mac_prefix(),
+                                host.name
+                            )
+                        })?;
+                    additional_nics.push(AdditionalNic {
+                        subnet: subnet_name.to_string(),
+                        mac_address,
+                        ip_address: ip,
+                    });
+                }
+            }
+
+            // Resolve instance type and resources from flavor, falling back to defaults
+            // when the flavor is defined externally
+            let flavor = self.flavors.get(&host.flavor);
+            let instance_type = self.host_instance_type(host);
+            let resources = match flavor.map(Flavor::normalized) {
+                Some(flavor) => Resources {
+                    cpu: flavor.cpu,
+                    memory: flavor.memory,
+                    storage: flavor.storage,
+                },
+                None => Resources {
+                    cpu: CpuSpec {
+                        cores: CpuCores::Count(2),
+                        limit: Some("100%".to_string()),
+                        allowance: None,
+                        priority: None,
+                    },
+                    memory: MemorySpec {
+                        limit: "2GB".to_string(),
+                        swap: None,
+                        swap_priority: None,
+                        enforce: None,
+                    },
+                    storage: None,
+                },
+            };
+
+            expanded_hosts.push(ExpandedHost {
+                name: host.name.clone(),
+                flavor: host.flavor.clone(),
+                image: host.image.clone(),
+                floating_ip: host.floating_ip,
+                master: host.master,
+                is_router: host.is_router,
+                privileged: host.privileged,
+                depends_on: host.depends_on.clone(),
+                profiles: host
+                    .profiles
+                    .iter()
+                    .fold(Vec::new(), |mut profiles, profile| {
+                        if !profiles.contains(profile) {
+                            profiles.push(profile.clone());
+                        }
+                        profiles
+                    }),
+                backup: host.backup.unwrap_or_else(default_backup),
+                autostart: host.autostart,
+                autostart_priority: host.autostart_priority,
+                autostart_delay: host.autostart_delay,
+                autorestart: host.autorestart,
+                host_shutdown_timeout: host.host_shutdown_timeout,
+                protected: host.protected,
+                ephemeral: host.ephemeral,
+                root_readonly: host.root_readonly,
+                forwards: host.forwards.clone(),
+                disk_size: host
+                    .disk_size
+                    .as_deref()
+                    .map(|size| normalize_size(size).unwrap_or_else(|_| size.to_string())),
+                target: host.target.clone(),
+                anti_affinity: host.anti_affinity.clone(),
+                description: host.description.clone(),
+                labels: host.labels.clone(),
+                environment: host.environment.clone(),
+                tags: host.tags.clone(),
+                roles: host.roles.iter().map(|r| r.to_full_config()).collect(),
+                subnets: host.subnets.iter().map(|s| s.to_full_config()).collect(),
+                id: host_id.clone(),
+                mac_address: Some(mac_address),
+                ip_addresses,
+                additional_nics,
+                instance_type,
+                resources,
+            });
+
+            used_values.host_ids.push(host_id);
+        }
+
+        self.assign_anti_affinity_targets(&mut expanded_hosts);
+
+        let mut lockfile = IncusLockfile {
+            version: self.version.clone(),
+            remote: self.remote.clone(),
+            launch: self.launch,
+            dns_domain: self.dns_domain.clone(),
+            hosts: expanded_hosts,
+            subnets: expanded_subnets,
+            flavors: self
+                .flavors
+                .iter()
+                .map(|(name, flavor)| (name.clone(), flavor.normalized()))
+                .collect(),
+            images: self.images.clone(),
+            roles: self.roles.clone(),
+            all_hosts: Vec::new(),
+            storage_pools: self.storage_pools.iter().fold(
+                Vec::new(),
+                |mut pools: Vec<StoragePool>, pool| {
+                    if !pools.iter().any(|p| p.name == pool.name) {
+                        let mut pool = pool.clone();
+                        pool.size = pool.size.map(|size| normalize_size(&size).unwrap_or(size));
+                        pools.push(pool);
+                    }
+                    pools
+                },
+            ),
+            defaults: self.defaults.clone(),
+            metadata: LockfileMetadata {
+                generated_at: simple_timestamp(),
+                generator_version: GENERATOR_VERSION.to_string(),
+                source_hash: self.source_hash(),
+                used_values,
+            },
+        };
+        lockfile.resolve_gateway_hosts()?;
+        Ok(lockfile)
+    }
+
+    /// A copy with `defaults.management_subnet` appended to every host that joins it
+    ///
+    /// `None` when no host is left to attach: no management subnet is set, or
+    /// every host already lists it or sets `management: false`.
+    fn with_management_subnet(&self) -> Option<IncusCompose> {
+        let management = self.defaults.management_subnet.as_deref()?;
+        let joins = |host: &Host| {
+            host.management.unwrap_or(true) && !host.subnet_names().contains(&management)
+        };
+        if !self.hosts.iter().any(joins) {
+            return None;
+        }
+
+        let mut attached = self.clone();
+        for host in attached.hosts.iter_mut().filter(|host| joins(host)) {
+            host.subnets
+                .push(SubnetAssignment::Name(management.to_string()));
+        }
+        Some(attached)
+    }
+
+    /// Auto-assign a `/prefix` block for the subnet `name`
+    ///
+    /// With `defaults.cidr4_ranges`, the first free block inside a range is
+    /// taken, after checking that a block of that size fits in one at all.
+    /// Without ranges, a /24 for the n-th subnet starts looking at
+    /// 192.168.(n + 10).0/24 and moves up, and other sizes are carved from
+    /// 192.168.0.0/16. Blocks overlapping `defaults.excluded_cidrs`, an explicit
+    /// subnet CIDR or a block already assigned to an earlier subnet are skipped.
+    fn auto_assign_cidr(
+        &self,
+        name: &str,
+        prefix: u8,
+        used_values: &UsedValues,
+        assigned: &[ExpandedSubnet],
+    ) -> Result<String, String> {
+        let taken: Vec<Ipv4Net> = self
+            .defaults
+            .excluded_cidrs
+            .iter()
+            .cloned()
+            .chain(self.subnets.iter().filter_map(|s| s.to_full_config().cidr))
+            .chain(assigned.iter().map(|s| s.cidr.clone()))
+            .filter_map(|cidr| cidr.parse().ok())
+            .collect();
+        let free = |block: &Ipv4Net| {
+            !taken
+                .iter()
+                .any(|net| net.contains(block) || block.contains(net))
+        };
+
+        let ranges = if self.defaults.cidr4_ranges.is_empty() {
+            if prefix == AUTO_CIDR_PREFIX {
+                return (used_values.subnet_ids.len() + 10..=255)
+                    .filter_map(|octet| {
+                        Ipv4Net::new(Ipv4Addr::new(192, 168, octet as u8, 0), prefix).ok()
+                    })
+                    .find(free)
+                    .map(|block| block.to_string())
+                    .ok_or_else(|| {
+                        format!(
+                            "subnet '{}': no free /24 left in 192.168.0.0/16 to auto-assign (check defaults.excluded_cidrs)",
+                            name
+                        )
+                    });
+            }
+            vec![(
+                u32::from(Ipv4Addr::new(192, 168, 0, 0)),
+                u32::from(Ipv4Addr::new(192, 168, 255, 255)),
+            )]
+        } else {
+            self.defaults
+                .cidr4_ranges
+                .iter()
+                .map(|range| range.bounds())
+                .collect::<Result<Vec<_>, _>>()?
+        };
+        let describe = || {
+            ranges
+                .iter()
+                .map(|(start, end)| {
+                    format!("{} - {}", Ipv4Addr::from(*start), Ipv4Addr::from(*end))
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+
+        // Candidate blocks of each range, aligned to the block size
+        let size = 1u64 << (32 - prefix);
+        let blocks = |(start, end): (u32, u32)| {
+            let first = (u64::from(start)).div_ceil(size) * size;
+            (0..)
+                .map(move |i| first + i * size)
+                .take_while(move |base| base + size - 1 <= u64::from(end))
+        };
+        if !ranges.iter().any(|range| blocks(*range).next().is_some()) {
+            return Err(format!(
+                "subnet '{}': a /{} block does not fit in the CIDR ranges ({})",
+                name,
+                prefix,
+                describe()
+            ));
+        }
+
+        ranges
+            .iter()
+            .flat_map(|range| blocks(*range))
+            .filter_map(|base| Ipv4Net::new(Ipv4Addr::from(base as u32), prefix).ok())
+            .find(free)
+            .map(|block| block.to_string())
+            .ok_or_else(|| {
+                format!(
+                    "subnet '{}': no free /{} left in the CIDR ranges ({}) to auto-assign (check defaults.excluded_cidrs)",
+                    name,
+                    prefix,
+                    describe()
+                )
+            })
+    }
+
+    /// Spread each anti-affinity group round-robin across the cluster members
+    ///
+    /// Hosts with an explicit `target` keep it; the rest take the next member not
+    /// already used by their group, wrapping around once every member is taken.
+    fn assign_anti_affinity_targets(&self, hosts: &mut [ExpandedHost]) {
+        let members = &self.defaults.cluster_members;
+        if members.is_empty() {
+            return;
+        }
+
+        let mut taken: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for host in hosts.iter() {
+            if let (Some(group), Some(target)) = (&host.anti_affinity, &host.target) {
+                taken.entry(group.clone()).or_default().push(target.clone());
+            }
+        }
+
+        for host in hosts.iter_mut() {
+            let Some(ref group) = host.anti_affinity else {
+                continue;
+            };
+            if host.target.is_some() {
+                continue;
+            }
+            let used = taken.entry(group.clone()).or_default();
+            let member = members
+                .iter()
+                .find(|m| !used.contains(m))
+                .unwrap_or(&members[used.len() % members.len()])
+                .clone();
+            used.push(member.clone());
+            host.target = Some(member);
+        }
+    }
+
+    /// Calculate gateway IP for a CIDR block
+    fn calculate_gateway(&self, cidr: &str, position: GatewayPosition) -> String {
+        if let Some(gateway) = cidr
+            .parse::<Ipv4Net>()
+            .ok()
+            .and_then(|net| position.resolve(&net))
+        {
+            return gateway.to_string();
+        }
+
+        // Unparseable CIDR or out-of-range offset: .1 of the network
+        if let Some(network_part) = cidr.split('/').next() {
+            let parts: Vec<&str> = network_part.split('.').collect();
+            if parts.len() == 4 {
+                return format!("{}.{}.{}.1", parts[0], parts[1], parts[2]);
+            }
+        }
+        "192.168.1.1".to_string() // Fallback
+    }
+
+    /// Generate a unique MAC address, or `None` once the prefix is exhausted
+    fn generate_mac_address(
+        &self,
+        used_values: &mut UsedValues,
+        rng: Option<&mut SeededRng>,
+    ) -> Option<String> {
+        let prefix = self.defaults.mac_prefix();
+        let mac = match rng {
+            Some(rng) => random_unused_mac(&prefix, &used_values.mac_addresses, rng),
+            None => next_unused_mac(&prefix, &used_values.mac_addresses),
+        }?;
+        used_values.mac_addresses.push(mac.clone());
+        Some(mac)
+    }
+
+    /// Assign IP address within a subnet, or `None` once its pool is exhausted
+    fn assign_ip_address(
+        &self,
+        subnet: &ExpandedSubnet,
+        is_router: bool,
+        used_values: &mut UsedValues,
+        rng: Option<&mut SeededRng>,
+    ) -> Option<String> {
+        let used_ips = used_values
+            .ip_addresses
+            .entry(ip_pool_key(&subnet.cidr))
+            .or_default();
+
+        let mut free = candidate_addresses(subnet, &self.defaults, is_router)
+            .into_iter()
+            .filter(|ip| ip != &subnet.gateway && !used_ips.contains(ip));
+        let ip = match rng {
+            Some(rng) => {
+                let free: Vec<String> = free.collect();
+                if free.is_empty() {
+                    return None;
+                }
+                free[rng.below(free.len())].clone()
+            }
+            None => free.next()?,
+        };
+        used_ips.push(ip.clone());
+        Some(ip)
+    }
+
+    /// Reserve a run of consecutive addresses for each contiguous group
+    ///
+    /// Covers every subnet all members are on, except where a member pins its
+    /// address, and fails if a subnet has no long enough free run. Returns the
+    /// address for each `(host, subnet)`.
+    fn allocate_contiguous_blocks(
+        &self,
+        subnets: &[ExpandedSubnet],
+        used_values: &mut UsedValues,
+    ) -> Result<BTreeMap<(String, String), String>, String> {
+        let mut groups: BTreeMap<&str, Vec<&Host>> = BTreeMap::new();
+        for host in &self.hosts {
+            if let Some(ref group) = host.contiguous_group {
+                groups.entry(group).or_default().push(host);
+            }
+        }
+
+        let mut allocated = BTreeMap::new();
+        for (group, members) in groups {
+            let is_router = members[0].is_router;
+            if members.iter().any(|m| m.is_router != is_router) {
+                return Err(format!(
+                    "group '{}': contiguous groups cannot mix routers and other hosts",
+                    group
+                ));
+            }
+
+            for subnet_name in members[0].subnet_names() {
+                let shared = members.iter().all(|member| {
+                    member
+                        .subnets
+                        .iter()
+                        .any(|s| s.name() == subnet_name && s.to_full_config().ip4addr.is_none())
+                });
+                let Some(subnet) = subnets.iter().find(|s| s.name == subnet_name) else {
+                    continue;
+                };
+                if !shared {
+                    continue;
+                }
+
+                let used_ips = used_values
+                    .ip_addresses
+                    .entry(ip_pool_key(&subnet.cidr))
+                    .or_default();
+                let mut free: Vec<u32> = candidate_addresses(subnet, &self.defaults, is_router)
+                    .into_iter()
+                    .filter(|ip| ip != &subnet.gateway && !used_ips.contains(ip))
+                    .filter_map(|ip| ip.parse::<Ipv4Addr>().ok().map(u32::from))
+                    .collect();
+                free.sort_unstable();
+                free.dedup();
+                let start = free
+                    .windows(members.len())
+                    .find(|run| run[run.len() - 1] - run[0] == run.len() as u32 - 1)
+                    .map(|run| run[0])
+                    .ok_or_else(|| {
+                        format!(
+                            "group '{}': subnet '{}' ({}) has no {} consecutive free addresses",
+                            group,
+                            subnet.name,
+                            subnet.cidr,
+                            members.len()
+                        )
+                    })?;
+
+                for (offset, member) in members.iter().enumerate() {
+                    let ip = Ipv4Addr::from(start + offset as u32).to_string();
+                    used_ips.push(ip.clone());
+                    allocated.insert((member.name.clone(), subnet.name.clone()), ip);
+                }
+            }
+        }
+        Ok(allocated)
+    }
+
+    /// Describe a subnet that has run out of host or router addresses
+    fn exhaustion_error(&self, subnet: &ExpandedSubnet, is_router: bool) -> String {
+        let requested = self
+            .hosts
+            .iter()
+            .filter(|h| {
+                h.is_router == is_router && h.subnet_names().contains(&subnet.name.as_str())
+            })
+            .count();
+        let reserved = reserved_addresses(&subnet.excluded_ips, &subnet.reserved_ranges);
+        let available = candidate_addresses(subnet, &self.defaults, is_router)
+            .iter()
+            .filter(|ip| *ip != &subnet.gateway && !reserved.contains(ip))
+            .count();
+        let kind = if is_router { "router" } else { "host" };
+        format!(
+            "subnet '{}' ({}) is out of {} addresses: {} {}s requested, {} available",
+            subnet.name, subnet.cidr, kind, requested, kind, available
+        )
+    }
+
+    /// SHA-256 of the normalized configuration, as recorded in `metadata.source_hash`
+    pub fn calculate_hash(&self) -> String {
+        let canonical = serde_yaml::to_string(self).unwrap_or_default();
+        format!("sha256:{}", sha256_hex(canonical.as_bytes()))
+    }
+
+    /// Hash recorded as the lockfile's source: `loaded_hash` if pinned, else the current one
+    pub fn source_hash(&self) -> String {
+        self.loaded_hash
+            .clone()
+            .unwrap_or_else(|| self.calculate_hash())
+    }
+
+    /// Pin the current hash as `loaded_hash`, before applying command-line overrides
+    pub fn pin_loaded_hash(&mut self) {
+        self.loaded_hash = Some(self.calculate_hash());
+    }
+}
+
+/// Hex SHA-256 digest of `data`
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// First MAC address with the given three-octet prefix not present in `used`
+fn next_unused_mac(prefix: &str, used: &[String]) -> Option<String> {
+    for counter in (used.len() + 1)..=0xffffff {
+        let mac = format!(
+            "{}:{:02x}:{:02x}:{:02x}",
+            prefix,
+            (counter >> 16) & 0xff,
+            (counter >> 8) & 0xff,
+            counter & 0xff
+        );
+        if !used.contains(&mac) {
+            return Some(mac);
+        }
+    }
+    None
+}
+
+/// Whether dotted version `version` predates `current` (empty counts as oldest)
+fn version_older(version: &str, current: &str) -> bool {
+    let parse = |v: &str| -> Vec<u32> { v.split('.').map(|p| p.parse().unwrap_or(0)).collect() };
+    version.is_empty() || parse(version) < parse(current)
+}
+
+/// Deep-merge `overlay` onto `base` (see `IncusCompose::load_with_overrides`)
+fn merge_yaml(base: &mut serde_yaml::Value, overlay: serde_yaml::Value) {
+    use serde_yaml::Value;
+
+    match (base, overlay) {
+        (Value::Mapping(base), Value::Mapping(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_yaml(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (Value::Sequence(base), Value::Sequence(overlay))
+            if overlay.iter().all(|item| yaml_entry_name(item).is_some()) =>
+        {
+            for item in overlay {
+                let name = yaml_entry_name(&item).map(str::to_string);
+                match base
+                    .iter_mut()
+                    .find(|existing| yaml_entry_name(existing).map(str::to_string) == name)
+                {
+                    // A bare name only asserts the entry exists
+                    Some(_) if item.is_string() => {}
+                    Some(existing) => {
+                        if let Value::String(shorthand) = existing {
+                            let mut full = serde_yaml::Mapping::new();
+                            full.insert("name".into(), Value::String(shorthand.clone()));
+                            *existing = Value::Mapping(full);
+                        }
+                        merge_yaml(existing, item);
+                    }
+                    None => base.push(item),
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// Name of a list entry written as a bare string or a mapping with a `name` key
+fn yaml_entry_name(item: &serde_yaml::Value) -> Option<&str> {
+    match item {
+        serde_yaml::Value::String(name) => Some(name),
+        serde_yaml::Value::Mapping(map) => map.get("name").and_then(|name| name.as_str()),
+        _ => None,
+    }
+}
+
+/// DNS label for a host name (underscores aren't valid in host names)
+fn dns_record_name(host: &str) -> String {
+    host.to_lowercase().replace('_', "-")
+}
+
+/// Deterministic SplitMix64 generator behind `--seed` allocation
+#[derive(Debug, Clone)]
+struct SeededRng(u64);
+
+impl SeededRng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform-enough index below `n` (which must be non-zero)
+    fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+/// Pick a random unused MAC under `prefix`, scanning sequentially if unlucky
+fn random_unused_mac(prefix: &str, used: &[String], rng: &mut SeededRng) -> Option<String> {
+    for _ in 0..64 {
+        let suffix = 1 + rng.below(0xffffff) as u32;
+        let mac = format!(
+            "{}:{:02x}:{:02x}:{:02x}",
+            prefix,
+            (suffix >> 16) & 0xff,
+            (suffix >> 8) & 0xff,
+            suffix & 0xff
+        );
+        if !used.contains(&mac) {
+            return Some(mac);
+        }
+    }
+    next_unused_mac(prefix, used)
+}
+
+/// Single-quote `value` for the shell unless it is made only of safe characters
+pub(crate) fn shell_quote(value: &str) -> String {
+    let safe = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.,:/@%+=".contains(c));
+    if safe {
+        value.to_string()
+    } else {
+        format!("'{}'", value.replace('\'', "'\\''"))
+    }
+}
+
+/// Ansible group name for a role, with characters Ansible rejects replaced by `_`
+fn ansible_group_name(role: &str) -> String {
+    role.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// First three octets of a CIDR's network address (e.g. "10.0.1" for 10.0.1.0/24)
+fn network_base(cidr: &str) -> String {
+    if let Some(network_part) = cidr.split('/').next() {
+        let parts: Vec<&str> = network_part.split('.').collect();
+        if parts.len() == 4 {
+            return format!("{}.{}.{}", parts[0], parts[1], parts[2]);
+        }
+    }
+    "192.168.1".to_string()
+}
+
+/// ` name=<nic_name>` for a device-add command, if the attachment names its interface
+fn guest_name_arg(attachment: Option<&SubnetAttachment>) -> String {
+    attachment
+        .and_then(|a| a.nic_name.as_ref())
+        .map(|name| format!(" name={}", name))
+        .unwrap_or_default()
+}
+
+/// A host's eth0, which `incus launch` attaches with `--network` and `-d eth0,...`
+struct PrimaryNic {
+    /// Managed network to attach to, `None` for macvlan, SR-IOV and physical NICs
+    network: Option<String>,
+    /// NIC source arguments for `incus config device add`
+    source: String,
+    /// Settings given when adding the device
+    add: Vec<String>,
+    /// Settings set on the device once added
+    set: Vec<String>,
+}
+
+impl PrimaryNic {
+    /// Commands adding and configuring the device on an existing instance
+    fn commands(&self, instance: &str) -> Vec<String> {
+        let mut commands = vec![format!(
+            "incus config device add {} eth0 nic {} {}",
+            instance,
+            self.source,
+            self.add.join(" ")
+        )];
+        commands.extend(
+            self.set
+                .iter()
+                .map(|setting| format!("incus config device set {} eth0 {}", instance, setting)),
+        );
+        commands
+    }
+}
+
+/// Longest instance name incus accepts, that of a hostname label
+const MAX_INSTANCE_NAME_LEN: usize = 63;
+
+/// Longest network name incus accepts, that of a Linux interface
+const MAX_NETWORK_NAME_LEN: usize = 15;
+
+/// Check `name` is a valid incus instance name: a hostname label
+///
+/// The error suggests a sanitized name to use instead.
+fn check_instance_name(name: &str) -> Result<(), String> {
+    let valid = (1..=MAX_INSTANCE_NAME_LEN).contains(&name.len())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        && name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && !name.ends_with('-');
+    if valid {
+        return Ok(());
+    }
+
+    let mut suggestion: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+    if !suggestion.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        suggestion.insert_str(0, "host-");
+    }
+    suggestion.truncate(MAX_INSTANCE_NAME_LEN);
+    let suggestion = suggestion.trim_end_matches('-');
+    Err(format!(
+        "incus instance names must be 1-{} letters, digits and '-', starting with a letter and not ending with '-'; try '{}'",
+        MAX_INSTANCE_NAME_LEN, suggestion
+    ))
+}
+
+/// Check `name` is a valid incus network name: a short Linux interface name
+///
+/// The error suggests a sanitized name to use instead.
+fn check_network_name(name: &str) -> Result<(), String> {
+    let allowed = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.');
+    let valid = (1..=MAX_NETWORK_NAME_LEN).contains(&name.len())
+        && name.chars().all(allowed)
+        && !name.starts_with(['-', '.']);
+    if valid {
+        return Ok(());
+    }
+
+    let mut suggestion: String = name
+        .chars()
+        .map(|c| if allowed(c) { c } else { '-' })
+        .collect::<String>()
+        .trim_start_matches(['-', '.'])
+        .to_string();
+    suggestion.truncate(MAX_NETWORK_NAME_LEN);
+    if suggestion.is_empty() {
+        suggestion.push_str("net");
+    }
+    Err(format!(
+        "incus network names must be 1-{} letters, digits, '-', '_' and '.', not starting with '-' or '.'; try '{}'",
+        MAX_NETWORK_NAME_LEN, suggestion
+    ))
+}
+
+/// Whether `name` is a dot-separated list of alphanumeric/hyphen labels
+fn is_domain_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// Check an image's `source` names a known remote or a local path, with an alias to fetch
+///
+/// A remote source needs the alias either after the colon (`images:debian/12`)
+/// or in `fingerprint`; a local path (`/`, `./` or `../`) or `empty` is complete on its own.
+fn check_image_source(image: &Image, remote: Option<&str>) -> Result<(), String> {
+    if let Some(ref fingerprint) = image.fingerprint {
+        if fingerprint.is_empty() || fingerprint.chars().any(char::is_whitespace) {
+            return Err(format!("fingerprint '{}' is malformed", fingerprint));
+        }
+    }
+
+    if let Some(ref iso) = image.iso {
+        if iso.is_empty() || iso.chars().any(char::is_whitespace) {
+            return Err(format!("iso '{}' is not a valid path", iso));
+        }
+    }
+
+    let source = image.source.as_str();
+    if source == EMPTY_IMAGE_SOURCE
+        || source.starts_with('/')
+        || source.starts_with("./")
+        || source.starts_with("../")
+    {
+        return Ok(());
+    }
+
+    let Some((server, alias)) = source.split_once(':') else {
+        return Err(format!(
+            "source '{}' is neither a remote (e.g. \"images:\") nor a local path",
+            source
+        ));
+    };
+    if !KNOWN_IMAGE_REMOTES.contains(&server) && Some(server) != remote {
+        return Err(format!(
+            "source '{}' uses unknown remote '{}:' (expected one of {})",
+            source,
+            server,
+            KNOWN_IMAGE_REMOTES
+                .iter()
+                .copied()
+                .chain(remote)
+                .map(|r| format!("{}:", r))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+    }
+    if alias.is_empty() && image.fingerprint.is_none() {
+        return Err(format!(
+            "source '{}' needs a fingerprint or alias naming the image",
+            source
+        ));
+    }
+    Ok(())
+}
+
+/// Check an `ip4addr` pin lies in its subnet and is not the gateway or reserved
+fn check_pinned_ip(host: &str, subnet: &ExpandedSubnet, ip: &str) -> Result<(), String> {
+    let inside = match (ip.parse::<Ipv4Addr>(), subnet.cidr.parse::<Ipv4Net>()) {
+        (Ok(addr), Ok(net)) => net.contains(&addr),
+        _ => true,
+    };
+    let problem = if !inside {
+        format!("is not inside subnet '{}' ({})", subnet.name, subnet.cidr)
+    } else if ip == subnet.gateway {
+        format!("is the gateway of subnet '{}'", subnet.name)
+    } else if reserved_addresses(&subnet.excluded_ips, &subnet.reserved_ranges)
+        .iter()
+        .any(|reserved| reserved == ip)
+    {
+        format!("is excluded or reserved on subnet '{}'", subnet.name)
+    } else {
+        return Ok(());
+    };
+    Err(format!("host '{}': ip4addr {} {}", host, ip, problem))
+}
+
+/// All excluded addresses of a subnet, with reserved ranges expanded
+fn reserved_addresses(excluded_ips: &[String], reserved_ranges: &[IpRange]) -> Vec<String> {
+    let mut addresses = excluded_ips.to_vec();
+    for range in reserved_ranges {
+        let (Ok(start), Ok(end)) = (
+            range.start.parse::<Ipv4Addr>(),
+            range.end.parse::<Ipv4Addr>(),
+        ) else {
+            continue;
+        };
+        addresses
+            .extend((u32::from(start)..=u32::from(end)).map(|ip| Ipv4Addr::from(ip).to_string()));
+    }
+    addresses
+}
+
+/// Addresses to try, in order, for a new host or router on `subnet`
+///
+/// The configured `router_ip4_ranges` / `host_ip4_ranges` that intersect the subnet
+/// are used, clipped to its usable addresses. When none applies, routers start
+/// at .2 and hosts at the subnet's `host_start`.
+fn candidate_addresses(
+    subnet: &ExpandedSubnet,
+    defaults: &Defaults,
+    is_router: bool,
+) -> Vec<String> {
+    let ranges = if is_router {
+        &defaults.router_ip4_ranges
+    } else {
+        &defaults.host_ip4_ranges
+    };
+
+    if let Ok(net) = subnet.cidr.parse::<Ipv4Net>() {
+        let (first, last) = usable_bounds(&net);
+
+        let mut addresses = Vec::new();
+        for range in ranges {
+            let (Ok(start), Ok(end)) = (
+                range.start.parse::<Ipv4Addr>(),
+                range.end.parse::<Ipv4Addr>(),
+            ) else {
+                continue;
+            };
+            let start = u32::from(start).max(first);
+            let end = u32::from(end).min(last);
+            addresses.extend((start..=end).map(|ip| Ipv4Addr::from(ip).to_string()));
+        }
+        if !addresses.is_empty() {
+            return addresses;
+        }
+    }
+
+    let start_ip = if is_router { 2 } else { subnet.host_start };
+    if let Ok(net) = subnet.cidr.parse::<Ipv4Net>() {
+        let (first, last) = usable_bounds(&net);
+        let preferred = u32::from(net.network()).saturating_add(start_ip);
+        let start = if preferred <= last {
+            preferred.max(first)
+        } else {
+            first
+        };
+        return (start..=last)
+            .map(|ip| Ipv4Addr::from(ip).to_string())
+            .collect();
+    }
+
+    let network_base = network_base(&subnet.cidr);
+    (start_ip..255)
+        .map(|i| format!("{}.{}", network_base, i))
+        .collect()
+}
+
+/// First and last assignable addresses of a network (all of it for /31 and /32)
+fn usable_bounds(net: &Ipv4Net) -> (u32, u32) {
+    if net.prefix_len() >= 31 {
+        (u32::from(net.network()), u32::from(net.broadcast()))
+    } else {
+        (u32::from(net.network()) + 1, u32::from(net.broadcast()) - 1)
+    }
+}
+
+/// Key under which a subnet's addresses are tracked in `UsedValues::ip_addresses`
+pub fn ip_pool_key(cidr: &str) -> String {
+    format!("subnet_{}", network_base(cidr).replace(".", "_"))
+}
+
+impl IncusLockfile {
+    /// Anti-affinity groups that have more than one host on the same cluster member
+    pub fn anti_affinity_conflicts(&self) -> Vec<String> {
+        let mut placements: BTreeMap<(&str, &str), Vec<&str>> = BTreeMap::new();
+        for host in &self.hosts {
+            if let (Some(group), Some(target)) = (&host.anti_affinity, &host.target) {
+                placements
+                    .entry((group.as_str(), target.as_str()))
+                    .or_default()
+                    .push(host.name.as_str());
+            }
+        }
+
+        placements
+            .into_iter()
+            .filter(|(_, hosts)| hosts.len() > 1)
+            .map(|((group, target), hosts)| {
+                format!(
+                    "anti-affinity group '{}' places {} on the same cluster member '{}'",
+                    group,
+                    hosts.join(", "),
+                    target
+                )
+            })
+            .collect()
+    }
+
+    /// Check constraints that need resolved values, such as auto-assigned CIDRs
+    pub fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut networks = Vec::new();
+        for subnet in &self.subnets {
+            let net: Ipv4Net = subnet.cidr.parse().map_err(|e| {
+                format!(
+                    "subnet '{}': cidr '{}' is invalid: {}",
+                    subnet.name, subnet.cidr, e
+                )
+            })?;
+            let gateway_inside = subnet
+                .gateway
+                .parse::<Ipv4Addr>()
+                .is_ok_and(|gateway| net.contains(&gateway));
+            if !gateway_inside {
+                return Err(format!(
+                    "subnet '{}': gateway {} is not inside its cidr {}",
+                    subnet.name, subnet.gateway, subnet.cidr
+                )
+                .into());
+            }
+            networks.push((subnet, net));
+        }
+
+        for (i, (a, a_net)) in networks.iter().enumerate() {
+            for (b, b_net) in &networks[i + 1..] {
+                if a_net.contains(b_net) || b_net.contains(a_net) {
+                    return Err(format!(
+                        "subnets '{}' ({}) and '{}' ({}) overlap",
+                        a.name, a.cidr, b.name, b.cidr
+                    )
+                    .into());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Rebuild the used value tracker from the hosts and subnets actually present
+    pub fn rebuild_used_values(&mut self) {
+        let mut used_values = UsedValues::default();
+
+        for subnet in &self.subnets {
+            used_values.subnet_ids.push(subnet.id.clone());
+            let used_ips = used_values
+                .ip_addresses
+                .entry(ip_pool_key(&subnet.cidr))
+                .or_default();
+            for ip in reserved_addresses(&subnet.excluded_ips, &subnet.reserved_ranges) {
+                if !used_ips.contains(&ip) {
+                    used_ips.push(ip);
+                }
+            }
+        }
+
+        for host in &self.hosts {
+            used_values.host_ids.push(host.id.clone());
+            if let Some(ref mac) = host.mac_address {
+                used_values.mac_addresses.push(mac.clone());
+            }
+            for nic in &host.additional_nics {
+                used_values.mac_addresses.push(nic.mac_address.clone());
+            }
+            for nic in host.nics() {
+                let (Some(ip), Some(subnet)) = (
+                    nic.ip_address,
+                    self.subnets.iter().find(|s| s.name == nic.attachment.name),
+                ) else {
+                    continue;
+                };
+                used_values
+                    .ip_addresses
+                    .entry(ip_pool_key(&subnet.cidr))
+                    .or_default()
+                    .push(ip.clone());
+            }
+        }
+
+        self.metadata.used_values = used_values;
+    }
+
+    /// Lowest address on a subnet that no host holds and that isn't reserved
+    ///
+    /// Candidates come from the same place as allocation: the configured router or
+    /// host ranges, else .2 for routers and the subnet's `host_start` for hosts.
+    pub fn next_free_ip(&self, subnet_name: &str, is_router: bool) -> Option<String> {
+        let subnet = self.subnets.iter().find(|s| s.name == subnet_name)?;
+        let reserved = reserved_addresses(&subnet.excluded_ips, &subnet.reserved_ranges);
+        let held: Vec<&String> = self
+            .hosts
+            .iter()
+            .filter_map(|host| host.ip_addresses.get(subnet_name))
+            .collect();
+
+        candidate_addresses(subnet, &self.defaults, is_router)
+            .into_iter()
+            .find(|ip| ip != &subnet.gateway && !held.contains(&ip) && !reserved.contains(ip))
+    }
+
+    /// Next address `generate_lockfile` would hand out on a subnet, without taking it
+    ///
+    /// Unlike `next_free_ip`, addresses recorded in `used_values` stay taken even
+    /// when no host holds them any more.
+    pub fn next_unused_ip(&self, subnet_name: &str, is_router: bool) -> Result<String, String> {
+        let subnet = self
+            .subnets
+            .iter()
+            .find(|s| s.name == subnet_name)
+            .ok_or_else(|| format!("unknown subnet '{}'", subnet_name))?;
+        let reserved = reserved_addresses(&subnet.excluded_ips, &subnet.reserved_ranges);
+        let used = self
+            .metadata
+            .used_values
+            .ip_addresses
+            .get(&ip_pool_key(&subnet.cidr));
+        let held: Vec<&String> = self
+            .hosts
+            .iter()
+            .filter_map(|host| host.ip_addresses.get(subnet_name))
+            .collect();
+
+        candidate_addresses(subnet, &self.defaults, is_router)
+            .into_iter()
+            .find(|ip| {
+                ip != &subnet.gateway
+                    && !reserved.contains(ip)
+                    && !held.contains(&ip)
+                    && !used.is_some_and(|used| used.contains(ip))
+            })
+            .ok_or_else(|| {
+                format!(
+                    "subnet '{}' ({}) has no free {} address",
+                    subnet.name,
+                    subnet.cidr,
+                    if is_router { "router" } else { "host" }
+                )
+            })
+    }
+
+    /// Record an address on a subnet in the used value tracker
+    pub fn mark_ip_used(&mut self, subnet_name: &str, ip: &str) {
+        let Some(subnet) = self.subnets.iter().find(|s| s.name == subnet_name) else {
+            return;
+        };
+        let used_ips = self
+            .metadata
+            .used_values
+            .ip_addresses
+            .entry(ip_pool_key(&subnet.cidr))
+            .or_default();
+        if !used_ips.iter().any(|used| used == ip) {
+            used_ips.push(ip.to_string());
+        }
+    }
+
+    /// First generated MAC address not held by any host or recorded as used
+    pub fn next_free_mac(&self) -> Option<String> {
+        let mut used = self.metadata.used_values.mac_addresses.clone();
+        used.extend(self.hosts.iter().filter_map(|h| h.mac_address.clone()));
+        next_unused_mac(&self.defaults.mac_prefix(), &used)
+    }
+
+    /// Where allocation on `subnet` starts and the reserved addresses it skips before `ip`
+    ///
+    /// Returns the first candidate address and the gateway, excluded and reserved
+    /// addresses that precede `ip` among the candidates.
+    pub fn allocation_trace(
+        &self,
+        subnet: &ExpandedSubnet,
+        is_router: bool,
+        ip: &str,
+    ) -> (Option<String>, Vec<String>) {
+        let candidates = candidate_addresses(subnet, &self.defaults, is_router);
+        let mut reserved = reserved_addresses(&subnet.excluded_ips, &subnet.reserved_ranges);
+        reserved.push(subnet.gateway.clone());
+        let skipped = candidates
+            .iter()
+            .take_while(|candidate| candidate.as_str() != ip)
+            .filter(|candidate| reserved.contains(candidate))
+            .cloned()
+            .collect();
+        (candidates.first().cloned(), skipped)
+    }
+
+    /// Per-subnet address utilization based on the used value tracker
+    pub fn subnet_utilization(&self) -> Vec<SubnetUtilization> {
+        self.subnets
+            .iter()
+            .filter_map(|subnet| {
+                let capacity = subnet.capacity()?;
+                let used = self
+                    .metadata
+                    .used_values
+                    .ip_addresses
+                    .get(&ip_pool_key(&subnet.cidr))
+                    .map_or(0, |ips| ips.len());
+                Some(SubnetUtilization {
+                    name: subnet.name.clone(),
+                    used,
+                    capacity,
+                })
+            })
+            .collect()
+    }
+
+    /// Save lockfile to disk
+    pub fn save_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn std::error::Error>> {
+        let yaml = serde_yaml::to_string(self)?;
+        fs::write(path, yaml)?;
+        Ok(())
+    }
+
+    /// Load lockfile from disk, upgrading it if an older generator wrote it
+    pub fn load_from_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self::load_with_migrations(path)?.0)
+    }
+
+    /// Load lockfile from disk, also returning a note for each migration applied
+    pub fn load_with_migrations<P: AsRef<Path>>(
+        path: P,
+    ) -> Result<(Self, Vec<String>), Box<dyn std::error::Error>> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)?;
+        let mut lockfile: IncusLockfile = serde_yaml::from_str(&content)
+            .map_err(|e| located_yaml_error(&path.display().to_string(), e))?;
+        let notes = lockfile.migrate();
+        Ok((lockfile, notes))
+    }
+
+    /// Upgrade a lockfile written by an older generator to the current format
+    ///
+    /// Fields added since then default on load; this fills in what can be
+    /// derived from the rest of the lockfile. Returns a note per change made.
+    pub fn migrate(&mut self) -> Vec<String> {
+        let mut notes = Vec::new();
+
+        if version_older(&self.metadata.generator_version, GENERATOR_VERSION) {
+            notes.push(format!(
+                "upgraded lockfile from generator {} to {}",
+                if self.metadata.generator_version.is_empty() {
+                    "unknown"
+                } else {
+                    &self.metadata.generator_version
+                },
+                GENERATOR_VERSION
+            ));
+            self.metadata.generator_version = GENERATOR_VERSION.to_string();
+        }
+
+        // Older lockfiles may lack used value tracking; add whatever the hosts and
+        // subnets hold, keeping values recorded for hosts that are gone
+        let recorded_before = std::mem::take(&mut self.metadata.used_values);
+        self.rebuild_used_values();
+        let present = std::mem::replace(&mut self.metadata.used_values, recorded_before);
+        let used = &mut self.metadata.used_values;
+        let mut recorded = 0;
+        let mut record = |values: &mut Vec<String>, value: String| {
+            if !values.contains(&value) {
+                values.push(value);
+                recorded += 1;
+            }
+        };
+        for id in present.subnet_ids {
+            record(&mut used.subnet_ids, id);
+        }
+        for id in present.host_ids {
+            record(&mut used.host_ids, id);
+        }
+        for mac in present.mac_addresses {
+            record(&mut used.mac_addresses, mac);
+        }
+        for (pool, addresses) in present.ip_addresses {
+            let pool = used.ip_addresses.entry(pool).or_default();
+            for ip in addresses {
+                record(pool, ip);
+            }
+        }
+        if recorded > 0 {
+            notes.push(format!(
+                "recorded {} used values missing from metadata",
+                recorded
+            ));
+        }
+
+        let mut normalized = 0;
+        for flavor in self.flavors.values_mut() {
+            let canonical = flavor.normalized();
+            let storage_size = |f: &Flavor| f.storage.as_ref().map(|s| s.size.clone());
+            if canonical.memory.limit != flavor.memory.limit
+                || storage_size(&canonical) != storage_size(flavor)
+            {
+                *flavor = canonical;
+                normalized += 1;
+            }
+        }
+        if normalized > 0 {
+            notes.push(format!("normalized sizes in {} flavors", normalized));
+        }
+
+        notes
+    }
+
+    /// Drop used values no current host or subnet references, returning how many
+    ///
+    /// Excluded and reserved addresses of current subnets are kept, as the
+    /// allocator must go on skipping them.
+    pub fn prune_used_values(&mut self) -> usize {
+        let used = &mut self.metadata.used_values;
+        let before = used.host_ids.len()
+            + used.subnet_ids.len()
+            + used.mac_addresses.len()
+            + used.ip_addresses.values().map(Vec::len).sum::<usize>();
+
+        used.host_ids
+            .retain(|id| self.hosts.iter().any(|h| &h.id == id));
+        used.subnet_ids
+            .retain(|id| self.subnets.iter().any(|s| &s.id == id));
+        used.mac_addresses.retain(|mac| {
+            self.hosts.iter().any(|h| {
+                h.mac_address.as_ref() == Some(mac)
+                    || h.additional_nics.iter().any(|nic| &nic.mac_address == mac)
+            })
+        });
+
+        let mut referenced: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        for subnet in &self.subnets {
+            referenced
+                .entry(ip_pool_key(&subnet.cidr))
+                .or_default()
+                .extend(reserved_addresses(
+                    &subnet.excluded_ips,
+                    &subnet.reserved_ranges,
+                ));
+        }
+        for host in &self.hosts {
+            let addresses = host.ip_addresses.iter().chain(
+                host.additional_nics
+                    .iter()
+                    .map(|nic| (&nic.subnet, &nic.ip_address)),
+            );
+            for (subnet_name, ip) in addresses {
+                if let Some(subnet) = self.subnets.iter().find(|s| &s.name == subnet_name) {
+                    referenced
+                        .entry(ip_pool_key(&subnet.cidr))
+                        .or_default()
+                        .insert(ip.clone());
+                }
+            }
+        }
+        for (pool, ips) in used.ip_addresses.iter_mut() {
+            ips.retain(|ip| referenced.get(pool).is_some_and(|r| r.contains(ip)));
+        }
+        used.ip_addresses.retain(|_, ips| !ips.is_empty());
+
+        let after = used.host_ids.len()
+            + used.subnet_ids.len()
+            + used.mac_addresses.len()
+            + used.ip_addresses.values().map(Vec::len).sum::<usize>();
+        before - after
+    }
+
+    /// Point each subnet with a `gateway_host` at that host's address on it
+    pub fn resolve_gateway_hosts(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        for subnet in &mut self.subnets {
+            let Some(ref gateway_host) = subnet.gateway_host else {
+                continue;
+            };
+            let address = self
+                .hosts
+                .iter()
+                .find(|h| &h.name == gateway_host)
+                .and_then(|h| h.ip_addresses.get(&subnet.name))
+                .ok_or_else(|| {
+                    format!(
+                        "subnet '{}': gateway_host '{}' is not attached to it",
+                        subnet.name, gateway_host
+                    )
+                })?;
+            subnet.gateway = address.clone();
+        }
+        Ok(())
+    }
+
+    /// NIC device arguments for attaching to the subnet named `subnet_name`
+    fn nic_source(&self, subnet_name: &str) -> String {
+        self.subnets
+            .iter()
+            .find(|s| s.name == subnet_name)
+            .map(|s| s.nic_source())
+            .unwrap_or_else(|| format!("network={}", subnet_name))
+    }
+
+    /// Qualify an instance, network or ACL name with the configured remote
+    pub fn on_remote(&self, name: &str) -> String {
+        match self.remote {
+            Some(ref remote) => format!("{}:{}", remote, name),
+            None => name.to_string(),
+        }
+    }
+
+    /// Declarative equivalents of the host commands, one per instance
+    ///
+    /// Carries the same limits, boot, metadata and NIC settings as
+    /// `generate_host_commands`, without the remote prefix.
+    pub fn generate_instance_definitions(&self) -> Vec<InstanceDefinition> {
+        self.hosts
+            .iter()
+            .map(|host| {
+                let mut config = BTreeMap::new();
+                config.insert(
+                    "limits.cpu".to_string(),
+                    host.resources.cpu.cores.to_string(),
+                );
+                config.insert(
+                    "limits.memory".to_string(),
+                    host.resources.memory.limit.clone(),
+                );
+                let memory = &host.resources.memory;
+                if let Some(ref swap) = memory.swap {
+                    config.insert("limits.memory.swap".to_string(), swap.clone());
+                }
+                if let Some(priority) = memory.swap_priority {
+                    config.insert(
+                        "limits.memory.swap.priority".to_string(),
+                        priority.to_string(),
+                    );
+                }
+                if let Some(enforce) = memory.enforce {
+                    config.insert(
+                        "limits.memory.enforce".to_string(),
+                        enforce.as_str().to_string(),
+                    );
+                }
+                if host.privileged {
+                    config.insert("security.privileged".to_string(), "true".to_string());
+                }
+                if let Some(autostart) = host.autostart {
+                    config.insert("boot.autostart".to_string(), autostart.to_string());
+                }
+                if let Some(priority) = host.autostart_priority {
+                    config.insert("boot.autostart.priority".to_string(), priority.to_string());
+                }
+                if let Some(delay) = host.autostart_delay {
+                    config.insert("boot.autostart.delay".to_string(), delay.to_string());
+                }
+                if let Some(autorestart) = host.autorestart {
+                    config.insert("boot.autorestart".to_string(), autorestart.to_string());
+                }
+                if let Some(timeout) = host.host_shutdown_timeout {
+                    config.insert(
+                        "boot.host_shutdown_timeout".to_string(),
+                        timeout.to_string(),
+                    );
+                }
+                if host.protected {
+                    config.insert("security.protection.delete".to_string(), "true".to_string());
+                }
+                if let Some(ref description) = host.description {
+                    config.insert("user.description".to_string(), description.clone());
+                }
+                for (key, value) in &host.labels {
+                    config.insert(format!("user.{}", key), value.clone());
+                }
+                for (key, value) in &host.environment {
+                    config.insert(format!("environment.{}", key), value.clone());
+                }
+                for role in &host.roles {
+                    if let Some(Ok((role_config, _))) = self
+                        .roles
+                        .get(&role.name)
+                        .map(|template| template.render(role, &host.name))
+                    {
+                        config.extend(role_config);
+                    }
+                }
+
+                let mut devices = BTreeMap::new();
+                for host_nic in host.nics() {
+                    let attachment = host_nic.attachment;
+                    let mut nic: BTreeMap<String, String> = self
+                        .nic_source(&attachment.name)
+                        .split_whitespace()
+                        .filter_map(|arg| arg.split_once('='))
+                        .map(|(key, value)| (key.to_string(), value.to_string()))
+                        .collect();
+                    nic.insert("type".to_string(), "nic".to_string());
+
+                    let subnet = self.subnets.iter().find(|s| s.name == attachment.name);
+                    let network_type = subnet.map_or_else(default_network_type, |s| s.network_type);
+                    if let Some(mac) = host_nic.mac_address {
+                        nic.insert("hwaddr".to_string(), mac.clone());
+                    }
+                    if let Some(ref name) = attachment.nic_name {
+                        nic.insert("name".to_string(), name.clone());
+                    }
+                    if let Some(ip) = host_nic
+                        .ip_address
+                        .filter(|_| network_type.supports_static_ipv4())
+                    {
+                        let address = subnet.map_or_else(|| ip.clone(), |s| s.device_ipv4(ip));
+                        nic.insert("ipv4.address".to_string(), address);
+                    }
+                    if let Some(vlan) = attachment.vlan.filter(|_| network_type.supports_vlan()) {
+                        nic.insert("vlan".to_string(), vlan.to_string());
+                    }
+                    devices.insert(host_nic.device, nic);
+                }
+                if host.disk_size.is_some() || host.root_readonly {
+                    let mut root = BTreeMap::from([
+                        ("type".to_string(), "disk".to_string()),
+                        ("path".to_string(), "/".to_string()),
+                    ]);
+                    if let Some(ref size) = host.disk_size {
+                        root.insert("size".to_string(), size.clone());
+                    }
+                    if host.root_readonly {
+                        root.insert("readonly".to_string(), "true".to_string());
+                    }
+                    devices.insert("root".to_string(), root);
+                }
+                if let Some(iso) = self.images.get(&host.image).and_then(|i| i.iso.as_ref()) {
+                    devices.insert(
+                        ISO_DEVICE.to_string(),
+                        BTreeMap::from([
+                            ("type".to_string(), "disk".to_string()),
+                            ("source".to_string(), iso.clone()),
+                            ("boot.priority".to_string(), ISO_BOOT_PRIORITY.to_string()),
+                        ]),
+                    );
+                }
+
+                InstanceDefinition {
+                    name: host.name.clone(),
+                    instance_type: host.instance_type.clone(),
+                    image: host.image.clone(),
+                    ephemeral: host.ephemeral,
+                    profiles: host.profiles.clone(),
+                    config,
+                    devices,
+                }
+            })
+            .collect()
+    }
+
+    /// Generate the device removals that reconcile hosts kept from `previous`
+    ///
+    /// A NIC is removed when its device is gone or now attaches to another
+    /// subnet, so the creation commands can add it afresh. Hosts that are new
+    /// or dropped are left to creation and `--teardown`.
+    pub fn generate_prune_commands(&self, previous: &IncusLockfile) -> Vec<String> {
+        let mut commands = Vec::new();
+        for host in &self.hosts {
+            let Some(old) = previous.hosts.iter().find(|h| h.name == host.name) else {
+                continue;
+            };
+            let desired: BTreeMap<String, &str> = host
+                .nics()
+                .into_iter()
+                .map(|nic| (nic.device, nic.attachment.name.as_str()))
+                .collect();
+            for nic in old.nics() {
+                if desired.get(&nic.device) != Some(&nic.attachment.name.as_str()) {
+                    commands.push(format!(
+                        "incus config device remove {} {}",
+                        self.on_remote(&host.name),
+                        nic.device
+                    ));
+                }
+            }
+        }
+        commands
+    }
+
+    /// Generate export commands backing up each host into `dir`
+    pub fn generate_backup_commands(&self, dir: &str) -> Vec<String> {
+        let dir = dir.trim_end_matches('/');
+        self.hosts
+            .iter()
+            .filter(|host| host.backup)
+            .map(|host| {
+                format!(
+                    "incus export {} {}/{}.tar.gz",
+                    self.on_remote(&host.name),
+                    dir,
+                    host.name
+                )
+            })
+            .collect()
+    }
+
+    /// Commands deleting every host, then the ACLs, networks and storage pools they used
+    ///
+    /// Hosts go in reverse creation order. A `protected` host is left alone, along
+    /// with the networks and ACL it still uses, unless `force_protected` is set; its
+    /// delete protection is then lifted first. Storage pools and the DNS zone are
+    /// only removed once no host is left.
+    pub fn generate_teardown_commands(&self, force_protected: bool) -> Vec<String> {
+        self.generate_tagged_teardown_commands(force_protected, &[])
+    }
+
+    /// Teardown commands for the hosts carrying any of `tags`
+    ///
+    /// Other hosts are kept like protected ones, and so are the networks and
+    /// ACLs they use.
+    pub fn generate_tagged_teardown_commands(
+        &self,
+        force_protected: bool,
+        tags: &[String],
+    ) -> Vec<String> {
+        let mut commands = Vec::new();
+        let mut kept = Vec::new();
+        for host in self.host_layers().into_iter().flatten().rev() {
+            let instance = self.on_remote(&host.name);
+            if !host.has_any_tag(tags) {
+                kept.push(host);
+                continue;
+            }
+            if host.protected {
+                if !force_protected {
+                    kept.push(host);
+                    continue;
+                }
+                commands.push(format!(
+                    "incus config unset {} security.protection.delete",
+                    instance
+                ));
+            }
+            if let Some((uplink, listen)) = self
+                .floating_address(host)
+                .filter(|_| !host.forwards.is_empty())
+            {
+                commands.push(format!(
+                    "incus network forward delete {} {}",
+                    self.on_remote(uplink),
+                    listen
+                ));
+            }
+            if host.ephemeral {
+                commands.push(format!(
+                    "# {} is ephemeral: stopping it already deletes it",
+                    instance
+                ));
+            }
+            commands.push(format!("incus delete {} --force", instance));
+        }
+
+        let in_use = |subnet: &str| {
+            kept.iter()
+                .any(|host| host.subnets.iter().any(|s| s.name == subnet))
+        };
+        for subnet in self.subnets.iter().rev().filter(|s| s.is_managed()) {
+            if !in_use(&subnet.name) {
+                commands.push(format!(
+                    "incus network delete {}",
+                    self.on_remote(&subnet.name)
+                ));
+            }
+        }
+        for host in &self.hosts {
+            let has_acl = host.roles.iter().any(|role| role.name == FIREWALL_ROLE);
+            let acl_in_use = host.subnets.iter().any(|s| in_use(&s.name));
+            if has_acl && !acl_in_use {
+                commands.push(format!(
+                    "incus network acl delete {}",
+                    self.on_remote(&acl_name(&host.name))
+                ));
+            }
+        }
+
+        if kept.is_empty() {
+            if let Some(ref domain) = self.dns_domain {
+                commands.push(format!(
+                    "incus network zone delete {}",
+                    self.on_remote(domain)
+                ));
+            }
+            for pool in self.storage_pools.iter().rev() {
+                commands.push(format!(
+                    "incus storage delete {}",
+                    self.on_remote(&pool.name)
+                ));
+            }
+        }
+        commands
+    }
+
+    /// Generate a Graphviz DOT graph of hosts, their subnets and dependencies
+    ///
+    /// Subnets are boxes, NIC edges are labelled with the device and address,
+    /// and dashed edges point from a host to what it waits for.
+    pub fn generate_dot_graph(&self) -> String {
+        let mut lines = vec!["digraph incus {".to_string()];
+        for subnet in &self.subnets {
+            lines.push(format!(
+                "  \"{}\" [shape=box, label=\"{}\\n{}\"];",
+                subnet.name, subnet.name, subnet.cidr
+            ));
+        }
+        for host in &self.hosts {
+            lines.push(format!("  \"{}\";", host.name));
+            for nic in host.nics() {
+                let label = match nic.ip_address {
+                    Some(ip) => format!("{} {}", nic.device, ip),
+                    None => nic.device.clone(),
+                };
+                lines.push(format!(
+                    "  \"{}\" -> \"{}\" [label=\"{}\"];",
+                    host.name, nic.attachment.name, label
+                ));
+            }
+        }
+        for (host, dependencies) in self.host_dependencies() {
+            for dependency in dependencies {
+                lines.push(format!(
+                    "  \"{}\" -> \"{}\" [style=dashed];",
+                    host, dependency
+                ));
+            }
+        }
+        lines.push("}".to_string());
+        lines.join("\n") + "\n"
+    }
+
+    /// Generate a CSV of every NIC's allocated address and MAC
+    pub fn generate_allocation_csv(&self) -> String {
+        let mut lines = vec!["host,subnet,device,ip_address,mac_address".to_string()];
+        for host in &self.hosts {
+            for nic in host.nics() {
+                lines.push(format!(
+                    "{},{},{},{},{}",
+                    host.name,
+                    nic.attachment.name,
+                    nic.device,
+                    nic.ip_address.map_or("", |ip| ip.as_str()),
+                    nic.mac_address.map_or("", |mac| mac.as_str())
+                ));
+            }
+        }
+        lines.join("\n") + "\n"
+    }
+
+    /// Generate an INI Ansible inventory grouping hosts by role
+    ///
+    /// Each host's `ansible_host` is its address on its primary subnet. Master and
+    /// router hosts are also collected into `masters` and `routers` groups whose
+    /// group vars carry the flags.
+    pub fn generate_ansible_inventory(&self) -> String {
+        let mut groups: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        let mut ungrouped = Vec::new();
+        let mut masters = Vec::new();
+        let mut routers = Vec::new();
+
+        for host in &self.hosts {
+            let mut entry = match host
+                .primary_subnet()
+                .and_then(|subnet| host.ip_addresses.get(&subnet.name))
+            {
+                Some(ip) => format!("{} ansible_host={}", host.name, ip),
+                None => host.name.clone(),
+            };
+            // Role `key=value` parameters become host vars named `<role>_<key>`
+            for role in &host.roles {
+                for (key, value) in role.params() {
+                    entry.push_str(&format!(
+                        " {}={}",
+                        ansible_group_name(&format!("{}_{}", role.name, key)),
+                        shell_quote(value)
+                    ));
+                }
+            }
+
+            if host.roles.is_empty() {
+                ungrouped.push(entry.clone());
+            }
+            for role in &host.roles {
+                let group = ansible_group_name(&role.name);
+                let members = groups.entry(group).or_default();
+                if !members.contains(&entry) {
+                    members.push(entry.clone());
+                }
+            }
+            if host.master {
+                masters.push(entry.clone());
+            }
+            if host.is_router {
+                routers.push(entry);
+            }
+        }
+
+        let mut sections = Vec::new();
+        if !ungrouped.is_empty() {
+            sections.push(format!("[ungrouped]\n{}\n", ungrouped.join("\n")));
+        }
+        for (group, members) in &groups {
+            sections.push(format!("[{}]\n{}\n", group, members.join("\n")));
+        }
+        if !masters.is_empty() {
+            sections.push(format!("[masters]\n{}\n", masters.join("\n")));
+            sections.push("[masters:vars]\nincus_master=true\n".to_string());
+        }
+        if !routers.is_empty() {
+            sections.push(format!("[routers]\n{}\n", routers.join("\n")));
+            sections.push("[routers:vars]\nincus_router=true\n".to_string());
+        }
+
+        sections.join("\n")
+    }
+
+    /// A copy keeping only the hosts carrying any of `tags` (all hosts for no tags)
+    ///
+    /// Subnets, flavors and images are kept whole, and the left-out hosts are
+    /// kept in `all_hosts` so firewall ACLs and DNS records still cover them;
+    /// network commands are therefore unchanged. Dependencies on hosts left
+    /// out are dropped, as those are expected to exist already.
+    pub fn select_tagged(&self, tags: &[String]) -> IncusLockfile {
+        let mut selected = self.clone();
+        selected.all_hosts = self.network_hosts().to_vec();
+        selected.hosts.retain(|host| host.has_any_tag(tags));
+        let names: Vec<String> = selected.hosts.iter().map(|h| h.name.clone()).collect();
+        for host in &mut selected.hosts {
+            host.depends_on
+                .retain(|dependency| names.contains(dependency));
+        }
+        selected
+    }
+
+    /// Hosts that shared network config (firewall ACLs, DNS records) is built from
+    ///
+    /// All of them, even when `hosts` was narrowed by `select_tagged`, so a
+    /// partial run doesn't drop what the other hosts put on a network.
+    fn network_hosts(&self) -> &[ExpandedHost] {
+        if self.all_hosts.is_empty() {
+            &self.hosts
+        } else {
+            &self.all_hosts
+        }
+    }
+
+    /// Check generated commands against this lockfile for dangling references
+    ///
+    /// Every `network=` device argument must name a subnet (or the default
+    /// network, for a host without subnets), and every `incus create` must use
+    /// the image of the host it creates (`--empty` only for a declared empty
+    /// image). A failure means command generation is broken, not the
+    /// configuration.
+    pub fn check_command_references(
+        &self,
+        commands: &[String],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for command in commands {
+            let args: Vec<&str> = command.split_whitespace().collect();
+
+            // `incus create IMAGE INSTANCE ...` or `incus create INSTANCE --empty ...`,
+            // likewise for `incus launch`
+            let (instance, image) = match args.as_slice() {
+                ["incus", "config", "device", "add", instance, ..] => (*instance, None),
+                ["incus", "create" | "launch", instance, "--empty", ..] => (*instance, Some(None)),
+                ["incus", "create" | "launch", image, instance, ..] => {
+                    (*instance, Some(Some(*image)))
+                }
+                _ => continue,
+            };
+            let host = self
+                .hosts
+                .iter()
+                .find(|h| self.on_remote(&h.name) == instance);
+
+            let networks = args
+                .iter()
+                .filter_map(|arg| arg.strip_prefix("network="))
+                .chain(
+                    args.windows(2)
+                        .filter(|w| w[0] == "--network")
+                        .map(|w| w[1]),
+                );
+            for network in networks {
+                let default =
+                    network == DEFAULT_NETWORK && host.is_some_and(|h| h.subnets.is_empty());
+                if !default && !self.subnets.iter().any(|s| s.name == network) {
+                    return Err(format!(
+                        "generated command references network '{}', which is not a subnet: {}",
+                        network, command
+                    )
+                    .into());
+                }
+            }
+
+            let Some(image) = image else {
+                continue;
+            };
+            let known = host.is_some_and(|host| match image {
+                Some(image) => host.image == image,
+                None => self
+                    .images
+                    .get(&host.image)
+                    .is_some_and(|i| i.source == EMPTY_IMAGE_SOURCE),
+            });
+            if !known {
+                return Err(format!(
+                    "generated command creates an instance from an unknown image: {}",
+                    command
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Generate incus commands for dry-run
+    pub fn generate_incus_commands(&self) -> Vec<String> {
+        let mut commands = self.generate_storage_commands();
+        commands.extend(self.generate_network_commands());
+        for host in self.host_layers().into_iter().flatten() {
+            commands.extend(self.generate_host_commands(host));
+        }
+        commands
+    }
+
+    /// What each host must wait for: its `depends_on` plus the routers on its subnets
+    ///
+    /// Router edges are left out entirely if they would form a cycle with the
+    /// declared dependencies.
+    pub fn host_dependencies(&self) -> BTreeMap<&str, Vec<String>> {
+        let declared: BTreeMap<&str, Vec<String>> = self
+            .hosts
+            .iter()
+            .map(|host| (host.name.as_str(), host.depends_on.clone()))
+            .collect();
+
+        let mut with_routers = declared.clone();
+        for host in self.hosts.iter().filter(|h| !h.is_router) {
+            let routers = self.hosts.iter().filter(|router| {
+                router.is_router
+                    && router
+                        .subnets
+                        .iter()
+                        .any(|r| host.subnets.iter().any(|s| s.name == r.name))
+            });
+            let deps = with_routers.entry(host.name.as_str()).or_default();
+            for router in routers {
+                if !deps.contains(&router.name) {
+                    deps.push(router.name.clone());
+                }
+            }
+        }
+
+        if dependency_layers(&self.dependency_pairs(&with_routers)).is_ok() {
+            with_routers
+        } else {
+            declared
+        }
+    }
+
+    /// Hosts grouped so each depends only on hosts in earlier groups
+    ///
+    /// Declaration order is kept within a group. A cycle (which validation
+    /// rejects) leaves every host in a single group.
+    pub fn host_layers(&self) -> Vec<Vec<&ExpandedHost>> {
+        let dependencies = self.host_dependencies();
+        match dependency_layers(&self.dependency_pairs(&dependencies)) {
+            Ok(layers) => layers
+                .into_iter()
+                .map(|layer| {
+                    layer
+                        .into_iter()
+                        .filter_map(|name| self.hosts.iter().find(|h| h.name == name))
+                        .collect()
+                })
+                .collect(),
+            Err(_) => vec![self.hosts.iter().collect()],
+        }
+    }
+
+    /// `(name, dependencies)` pairs in declaration order for `dependency_layers`
+    fn dependency_pairs<'a>(
+        &'a self,
+        dependencies: &'a BTreeMap<&str, Vec<String>>,
+    ) -> Vec<(&'a str, &'a [String])> {
+        self.hosts
+            .iter()
+            .map(|host| {
+                let deps = dependencies
+                    .get(host.name.as_str())
+                    .map(Vec::as_slice)
+                    .unwrap_or_default();
+                (host.name.as_str(), deps)
+            })
+            .collect()
+    }
+
+    /// Commands creating the declared storage pools, which must precede every host
+    pub fn generate_storage_commands(&self) -> Vec<String> {
+        self.storage_pools
+            .iter()
+            .map(|pool| {
+                let mut command = format!(
+                    "incus storage create {} {}",
+                    self.on_remote(&pool.name),
+                    pool.driver
+                );
+                if let Some(ref source) = pool.source {
+                    command.push_str(&format!(" source={}", source));
+                }
+                if let Some(ref size) = pool.size {
+                    command.push_str(&format!(" size={}", size));
+                }
+                command
+            })
+            .collect()
+    }
+
+    /// Storage pools referenced by a flavor but missing from `storage_pools`
+    ///
+    /// The `default` pool is assumed to exist and never reported.
+    pub fn undeclared_storage_pools(&self) -> Vec<String> {
+        self.flavors
+            .iter()
+            .filter_map(|(name, flavor)| {
+                let pool = flavor.storage.as_ref()?.pool.as_deref()?;
+                let declared = pool == DEFAULT_STORAGE_POOL
+                    || self.storage_pools.iter().any(|p| p.name == pool);
+                (!declared).then(|| {
+                    format!(
+                        "flavor '{}' uses storage pool '{}', which is not declared in storage_pools",
+                        name, pool
+                    )
+                })
+            })
+            .collect()
+    }
+
+    /// Commands creating networks and firewall ACLs, which must precede every host
+    pub fn generate_network_commands(&self) -> Vec<String> {
+        // Create networks first; macvlan, sriov and physical NICs attach to a host parent
+        let mut commands: Vec<String> = self
+            .subnets
+            .iter()
+            .filter(|s| s.is_managed())
+            .flat_map(|subnet| self.generate_subnet_commands(subnet))
+            .collect();
+        commands.extend(self.generate_acl_and_zone_commands());
+        commands
+    }
+
+    /// Commands creating and configuring one managed subnet's network, `create` first
+    ///
+    /// They only touch that network, so different subnets' commands may run concurrently.
+    pub fn generate_subnet_commands(&self, subnet: &ExpandedSubnet) -> Vec<String> {
+        let mut commands = Vec::new();
+        commands.push(format!(
+            "incus network create {} --type={}",
+            self.on_remote(&subnet.name),
+            subnet.network_type.as_str()
+        ));
+        // A gateway host routes the subnet, so the bridge stays layer 2 only
+        let bridge_address = match subnet.gateway_host {
+            Some(_) => "none".to_string(),
+            None => subnet.gateway_cidr(),
+        };
+        commands.push(format!(
+            "incus network set {} ipv4.address={}",
+            self.on_remote(&subnet.name),
+            bridge_address
+        ));
+        commands.push(format!(
+            "incus network set {} ipv4.dhcp=false",
+            self.on_remote(&subnet.name)
+        ));
+        if let Some(mtu) = subnet.mtu {
+            commands.push(format!(
+                "incus network set {} bridge.mtu={}",
+                self.on_remote(&subnet.name),
+                mtu
+            ));
+        }
+        if !subnet.external_interfaces.is_empty() {
+            commands.push(format!(
+                "incus network set {} bridge.external_interfaces={}",
+                self.on_remote(&subnet.name),
+                subnet.external_interfaces.join(",")
+            ));
+        }
+        if !subnet.ipv4_routes.is_empty() {
+            commands.push(format!(
+                "incus network set {} ipv4.routes={}",
+                self.on_remote(&subnet.name),
+                subnet.ipv4_routes.join(",")
+            ));
+        }
+        if !subnet.dns_servers.is_empty() {
+            commands.push(format!(
+                "incus network set {} dns.nameservers={}",
+                self.on_remote(&subnet.name),
+                subnet.dns_servers.join(",")
+            ));
+        }
+        if !subnet.dns_search.is_empty() {
+            commands.push(format!(
+                "incus network set {} dns.search={}",
+                self.on_remote(&subnet.name),
+                subnet.dns_search.join(",")
+            ));
+        }
+        if let Some(mode) = subnet.dns_mode {
+            commands.push(format!(
+                "incus network set {} dns.mode={}",
+                self.on_remote(&subnet.name),
+                mode.as_str()
+            ));
+        }
+        if let Some(ipv6) = subnet.ipv6 {
+            commands.push(format!(
+                "incus network set {} ipv6.dhcp={}",
+                self.on_remote(&subnet.name),
+                ipv6.dhcp
+            ));
+            commands.push(format!(
+                "incus network set {} ipv6.dhcp.stateful={}",
+                self.on_remote(&subnet.name),
+                ipv6.stateful
+            ));
+        }
+        commands
+    }
+
+    /// Firewall ACL and DNS zone commands, which need every network to exist
+    pub fn generate_acl_and_zone_commands(&self) -> Vec<String> {
+        let mut commands = Vec::new();
+
+        // Firewall ACLs, attached to every subnet of the host carrying the role
+        let mut subnet_acls: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+        for host in self.network_hosts() {
+            if !host.roles.iter().any(|role| role.name == FIREWALL_ROLE) {
+                continue;
+            }
+
+            let acl = acl_name(&host.name);
+            commands.push(format!("incus network acl create {}", self.on_remote(&acl)));
+            let rules = host
+                .roles
+                .iter()
+                .filter(|role| role.name == FIREWALL_ROLE)
+                .flat_map(|role| &role.values)
+                .filter_map(|rule| FirewallRule::parse(rule).ok());
+            for rule in rules {
+                commands.push(format!(
+                    "incus network acl rule add {} {}",
+                    self.on_remote(&acl),
+                    rule
+                ));
+            }
+            for subnet in &host.subnets {
+                subnet_acls
+                    .entry(subnet.name.as_str())
+                    .or_default()
+                    .push(acl.clone());
+            }
+        }
+        for subnet in self.subnets.iter().filter(|s| s.is_managed()) {
+            if let Some(acls) = subnet_acls.get(subnet.name.as_str()) {
+                commands.push(format!(
+                    "incus network set {} security.acls={}",
+                    self.on_remote(&subnet.name),
+                    acls.join(",")
+                ));
+            }
+        }
+
+        // DNS records for each host's address on its first subnet
+        if let Some(ref domain) = self.dns_domain {
+            let zone = self.on_remote(domain);
+            commands.push(format!("incus network zone create {}", zone));
+            for host in self.network_hosts() {
+                let Some(ip) = host
+                    .subnets
+                    .first()
+                    .and_then(|subnet| host.ip_addresses.get(&subnet.name))
+                else {
+                    continue;
+                };
+                let record = dns_record_name(&host.name);
+                commands.push(format!(
+                    "incus network zone record create {} {}",
+                    zone, record
+                ));
+                commands.push(format!(
+                    "incus network zone record entry add {} {} A {}",
+                    zone, record, ip
+                ));
+            }
+        }
+
+        commands
+    }
+
+    /// Uplink subnet and address a host's floating IP listens on
+    ///
+    /// The floating IP is the host's allocated address on its first `uplink` subnet.
+    pub fn floating_address<'a>(&self, host: &'a ExpandedHost) -> Option<(&'a str, &'a str)> {
+        if !host.floating_ip {
+            return None;
+        }
+        host.subnets.iter().find_map(|attachment| {
+            let uplink = self
+                .subnets
+                .iter()
+                .any(|s| s.name == attachment.name && s.uplink);
+            let ip = host.ip_addresses.get(&attachment.name)?;
+            uplink.then_some((attachment.name.as_str(), ip.as_str()))
+        })
+    }
+
+    /// Network forward commands exposing a host's `forwards` on its floating IP
+    ///
+    /// Traffic goes to the host's address on its primary subnet.
+    pub fn generate_forward_commands(&self, host: &ExpandedHost) -> Vec<String> {
+        let mut commands = Vec::new();
+        if host.forwards.is_empty() {
+            return commands;
+        }
+        let Some((uplink, listen)) = self.floating_address(host) else {
+            return commands;
+        };
+        let target = host
+            .primary_subnet()
+            .and_then(|s| host.ip_addresses.get(&s.name))
+            .map_or(listen, |ip| ip.as_str());
+
+        let network = self.on_remote(uplink);
+        commands.push(format!(
+            "incus network forward create {} {}",
+            network, listen
+        ));
+        for forward in &host.forwards {
+            let target_port = forward
+                .target_port
+                .map(|port| format!(" {}", port))
+                .unwrap_or_default();
+            commands.push(format!(
+                "incus network forward port add {} {} {} {} {}{}",
+                network,
+                listen,
+                forward.protocol.unwrap_or_default().as_str(),
+                forward.listen_port,
+                target,
+                target_port
+            ));
+        }
+        commands
+    }
+
+    /// Commands creating, configuring and starting a single host
+    pub fn generate_host_commands(&self, host: &ExpandedHost) -> Vec<String> {
+        let instance = self.on_remote(&host.name);
+        let instance_type = host.instance_type.as_str();
+
+        let profile_args: String = host
+            .profiles
+            .iter()
+            .map(|profile| format!(" --profile {}", profile))
+            .collect();
+        let target_arg = host
+            .target
+            .as_ref()
+            .map(|target| format!(" --target={}", target))
+            .unwrap_or_default();
+        let ephemeral_arg = if host.ephemeral { " --ephemeral" } else { "" };
+        let image = self.images.get(&host.image);
+        let create_args = if image.is_some_and(|i| i.source == EMPTY_IMAGE_SOURCE) {
+            let vm_arg = match host.instance_type {
+                InstanceType::VirtualMachine => " --vm",
+                InstanceType::Container => "",
+            };
+            format!(
+                "{} --empty{}{}{}{}",
+                instance, vm_arg, ephemeral_arg, profile_args, target_arg
+            )
+        } else {
+            format!(
+                "{} {} --type={}{}{}{}",
+                host.image, instance, instance_type, ephemeral_arg, profile_args, target_arg
+            )
+        };
+
+        // Instance config as `key=value`, values ready for the shell
+        let mut config = vec![
+            format!("limits.cpu={}", host.resources.cpu.cores),
+            format!("limits.memory={}", host.resources.memory.limit),
+        ];
+        if let Some(ref swap) = host.resources.memory.swap {
+            config.push(format!("limits.memory.swap={}", swap));
+        }
+        if let Some(priority) = host.resources.memory.swap_priority {
+            config.push(format!("limits.memory.swap.priority={}", priority));
+        }
+        if let Some(enforce) = host.resources.memory.enforce {
+            config.push(format!("limits.memory.enforce={}", enforce.as_str()));
+        }
+        if host.privileged {
+            config.push("security.privileged=true".to_string());
+        }
+
+        // Boot behaviour
+        if let Some(autostart) = host.autostart {
+            config.push(format!("boot.autostart={}", autostart));
+        }
+        if let Some(priority) = host.autostart_priority {
+            config.push(format!("boot.autostart.priority={}", priority));
+        }
+        if let Some(delay) = host.autostart_delay {
+            config.push(format!("boot.autostart.delay={}", delay));
+        }
+        if let Some(autorestart) = host.autorestart {
+            config.push(format!("boot.autorestart={}", autorestart));
+        }
+        if let Some(timeout) = host.host_shutdown_timeout {
+            config.push(format!("boot.host_shutdown_timeout={}", timeout));
+        }
+        if host.protected {
+            config.push("security.protection.delete=true".to_string());
+        }
+
+        // Metadata
+        if let Some(ref description) = host.description {
+            config.push(format!("user.description={}", shell_quote(description)));
+        }
+        for (key, value) in &host.labels {
+            config.push(format!("user.{}={}", key, shell_quote(value)));
+        }
+        for (key, value) in &host.environment {
+            config.push(format!("environment.{}={}", key, shell_quote(value)));
+        }
+
+        let mut root_overrides = Vec::new();
+        if let Some(ref size) = host.disk_size {
+            root_overrides.push(format!("size={}", size));
+        }
+        if host.root_readonly {
+            root_overrides.push("readonly=true".to_string());
+        }
+
+        // eth0 is added on the primary subnet with the assigned MAC address
+        let mut eth0 = host.mac_address.as_ref().map(|mac| {
+            let primary = host.primary_subnet();
+            let network = primary.map_or(DEFAULT_NETWORK, |s| s.name.as_str());
+            let managed = self
+                .subnets
+                .iter()
+                .find(|s| s.name == network)
+                .is_none_or(|s| s.is_managed());
+            let mut add = vec![format!("hwaddr={}", mac)];
+            add.extend(
+                primary
+                    .and_then(|s| s.nic_name.as_ref())
+                    .map(|name| format!("name={}", name)),
+            );
+            PrimaryNic {
+                network: managed.then(|| network.to_string()),
+                source: primary
+                    .map(|s| self.nic_source(&s.name))
+                    .unwrap_or_else(|| format!("network={}", DEFAULT_NETWORK)),
+                add,
+                set: Vec::new(),
+            }
+        });
+
+        // Assign to networks and set IP addresses
+        let mut rest = Vec::new();
+        for (i, nic) in host.nics().into_iter().enumerate() {
+            let subnet_name = &nic.attachment.name;
+            let device_name = &nic.device;
+
+            if i > 0 {
+                // eth0 already added above; repeated subnets bring their own MAC
+                let hwaddr = nic
+                    .mac_address
+                    .map(|mac| format!(" hwaddr={}", mac))
+                    .unwrap_or_default();
+                rest.push(format!(
+                    "incus config device add {} {} nic {}{}{}",
+                    instance,
+                    device_name,
+                    self.nic_source(subnet_name),
+                    hwaddr,
+                    guest_name_arg(Some(nic.attachment))
+                ));
+            }
+
+            // Only set what the NIC type supports; the guest configures the rest
+            let subnet = self.subnets.iter().find(|s| &s.name == subnet_name);
+            let network_type = subnet.map_or_else(default_network_type, |s| s.network_type);
+            let mut settings = Vec::new();
+            let ip = nic
+                .ip_address
+                .filter(|_| network_type.supports_static_ipv4());
+            if let Some(ip) = ip {
+                settings.push(format!(
+                    "ipv4.address={}",
+                    subnet.map_or_else(|| ip.clone(), |s| s.device_ipv4(ip))
+                ));
+            }
+            if let Some(vlan) = nic.attachment.vlan.filter(|_| network_type.supports_vlan()) {
+                settings.push(format!("vlan={}", vlan));
+            }
+
+            match eth0.as_mut().filter(|_| i == 0) {
+                Some(eth0) => eth0.set.extend(settings),
+                None => rest.extend(settings.iter().map(|setting| {
+                    format!(
+                        "incus config device set {} {} {}",
+                        instance, device_name, setting
+                    )
+                })),
+            }
+        }
+
+        rest.extend(self.generate_forward_commands(host));
+
+        // Roles declared under `roles` expand to their config and commands
+        for role in &host.roles {
+            if let Some(template) = self.roles.get(&role.name) {
+                match template.render(role, &instance) {
+                    Ok((role_config, role_commands)) => {
+                        config.extend(
+                            role_config
+                                .into_iter()
+                                .map(|(key, value)| format!("{}={}", key, shell_quote(&value))),
+                        );
+                        rest.extend(role_commands);
+                    }
+                    Err(e) => rest.push(format!("# role '{}': {}", role.name, e)),
+                }
+                continue;
+            }
+
+            let params: Vec<String> = role
+                .params()
+                .iter()
+                .map(|(key, value)| format!("{}={}", key, value))
+                .collect();
+            rest.push(format!(
+                "# Apply role '{}' to {} with flags: {:?} params: {:?}",
+                role.name,
+                host.name,
+                role.flags(),
+                params
+            ));
+        }
+
+        // A launch would boot before the ISO is attached, so those hosts keep create and start
+        let iso = image.and_then(|i| i.iso.as_ref());
+        if self.launch && iso.is_none() {
+            let mut launch = format!("incus launch {}", create_args);
+            for setting in &config {
+                launch.push_str(&format!(" -c {}", setting));
+            }
+            for setting in &root_overrides {
+                launch.push_str(&format!(" -d root,{}", setting));
+            }
+            match eth0 {
+                Some(PrimaryNic {
+                    network: Some(network),
+                    add,
+                    set,
+                    ..
+                }) => {
+                    launch.push_str(&format!(" --network {}", network));
+                    for setting in add.iter().chain(&set) {
+                        launch.push_str(&format!(" -d eth0,{}", setting));
+                    }
+                }
+                // `--network` only takes managed networks
+                Some(eth0) => {
+                    rest.splice(0..0, eth0.commands(&instance));
+                }
+                None => {}
+            }
+
+            let mut commands = vec![launch];
+            commands.extend(rest);
+            return commands;
+        }
+
+        let mut commands = vec![format!("incus create {}", create_args)];
+        commands.extend(
+            config
+                .iter()
+                .map(|setting| format!("incus config set {} {}", instance, setting)),
+        );
+        if !root_overrides.is_empty() {
+            commands.push(format!(
+                "incus config device override {} root {}",
+                instance,
+                root_overrides.join(" ")
+            ));
+        }
+        if let Some(iso) = iso {
+            commands.push(format!(
+                "incus config device add {} {} disk source={} boot.priority={}",
+                instance, ISO_DEVICE, iso, ISO_BOOT_PRIORITY
+            ));
+        }
+        if let Some(eth0) = eth0 {
+            commands.extend(eth0.commands(&instance));
+        }
+        commands.extend(rest);
+
+        // Start the instance
+        commands.push(format!("incus start {}", instance));
+        commands
+    }
+}
+
+// Add chrono dependency for timestamp generation
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Simple timestamp implementation since we don't want to add chrono dependency yet
+fn simple_timestamp() -> String {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(duration) => {
+            let secs = duration.as_secs();
+            format!(
+                "2024-01-01T{:02}:{:02}:{:02}Z",
+                (secs / 3600) % 24,
+                (secs / 60) % 60,
+                secs % 60
+            )
+        }
+        Err(_) => "2024-01-01T00:00:00Z".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serialize_basic_compose() {
+        let hosts = vec![Host {
+            name: "web-server".to_string(),
+            flavor: "small_flavor".to_string(),
+            image: "base_image".to_string(),
+            floating_ip: false,
+            master: false,
+            is_router: false,
+            roles: vec![Role::Full(RoleConfig {
+                name: "web".to_string(),
+                values: vec![],
+            })],
+            subnets: vec![SubnetAssignment::Name("frontend".to_string())],
+            subnet: None,
+            subnet_list: None,
+            ..Default::default()
+        }];
+
+        let subnets = vec![Subnet::Full(Box::new(SubnetConfig {
+            name: "frontend".to_string(),
+            cidr: Some("10.0.1.0/24".to_string()),
+            ..Default::default()
+        }))];
+
+        let compose = IncusCompose {
+            version: "1.0".to_string(),
+            remote: None,
+            launch: false,
+            dns_domain: None,
+            seed: None,
+            loaded_hash: None,
+            include: Vec::new(),
+            catalog_cache: None,
+            hosts,
+            groups: Vec::new(),
+            subnets,
+            flavors: BTreeMap::new(),
+            images: BTreeMap::new(),
+            storage_pools: Vec::new(),
+            roles: BTreeMap::new(),
+            defaults: Defaults::default(),
+        };
+
+        let yaml = serde_yaml::to_string(&compose).unwrap();
+        assert!(yaml.contains("version"));
+        assert!(yaml.contains("web-server"));
+        assert!(yaml.contains("frontend"));
+    }
+
+    #[test]
+    fn test_deserialize_hospital_example() {
+        let yaml = r#"
+hosts:
+  - name: web_server
+    flavor: small_flavor
+    image: base_image
+    roles:
+      - web
+      - name: monitoring
+        values: ["prometheus"]
+    subnet: frontend
+
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+  - backend
+"#;
+
+        let mut compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+
+        // Normalize subnet fields
+        for host in &mut compose.hosts {
+            host.normalize();
+        }
+
+        assert_eq!(compose.version, "1.0"); // default value
+        assert_eq!(compose.hosts.len(), 1);
+        assert_eq!(compose.subnets.len(), 2);
+
+        let host = &compose.hosts[0];
+        assert_eq!(host.name, "web_server");
+        assert_eq!(host.roles.len(), 2);
+        assert_eq!(host.roles[0].name(), "web");
+        assert_eq!(host.roles[0].values(), &[] as &[String]);
+        assert_eq!(host.roles[1].name(), "monitoring");
+        assert_eq!(host.roles[1].values(), &["prometheus".to_string()]);
+        assert_eq!(host.subnet_names(), vec!["frontend"]);
+
+        // Test subnet formats
+        assert_eq!(compose.subnets[0].name(), "frontend");
+        assert_eq!(compose.subnets[0].cidr(), Some("10.0.1.0/24"));
+        assert_eq!(compose.subnets[1].name(), "backend");
+        assert_eq!(compose.subnets[1].cidr(), None);
+    }
+
+    #[test]
+    fn test_merge_key_host_base_is_normalized() {
+        let yaml = r#"
+x-host-base: &host-base
+  flavor: small_flavor
+  image: base_image
+  subnet: frontend
+
+hosts:
+  - name: web
+    <<: *host-base
+  - name: api
+    <<: *host-base
+    subnet: backend
+
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+  - name: backend
+    cidr: 10.0.2.0/24
+"#;
+
+        let compose = IncusCompose::load_from_reader(yaml.as_bytes(), "test.yaml").unwrap();
+        assert_eq!(compose.hosts[0].flavor, "small_flavor");
+        assert_eq!(compose.hosts[0].subnet_names(), vec!["frontend"]);
+        assert_eq!(compose.hosts[1].image, "base_image");
+        assert_eq!(compose.hosts[1].subnet_names(), vec!["backend"]);
+        assert!(compose.hosts.iter().all(|h| h.subnet.is_none()));
+
+        let lockfile = compose.generate_lockfile().unwrap();
+        assert_eq!(lockfile.hosts[1].ip_addresses["backend"], "10.0.2.10");
+    }
+
+    #[test]
+    fn test_merge_key_errors_keep_locations() {
+        // `<<` in a string is not a merge key
+        let plain = "hosts:\n  - name: web\n    flavor: small_flavor\n    image: base_image\n    description: \"a << b\"\n    floating_ip: maybe\nsubnets: []\n";
+        let err = IncusCompose::load_from_reader(plain.as_bytes(), "test.yaml")
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with("test.yaml:6:18: "), "{}", err);
+
+        let merged = "x-base: &base\n  flavor: small_flavor\n  image: base_image\nhosts:\n  - name: web\n    <<: *base\n  - name: api\n    <<: *base\n    floating_ip: maybe\nsubnets: []\n";
+        let err = IncusCompose::load_from_reader(merged.as_bytes(), "test.yaml")
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.starts_with("test.yaml:9:18: hosts[1].floating_ip: invalid type"),
+            "{}",
+            err
+        );
+
+        // Only the merged document has the bad value, so its key path has to do
+        let inherited = merged
+            .replace(
+                "  image: base_image\n",
+                "  image: base_image\n  floating_ip: maybe\n",
+            )
+            .replace("    floating_ip: maybe\n", "");
+        let err = IncusCompose::load_from_reader(inherited.as_bytes(), "test.yaml")
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.starts_with("test.yaml: hosts[0].floating_ip: invalid type"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_format_compose_is_idempotent() {
+        let messy = r#"
+subnets:
+  - name: frontend
+  - name: backend
+    cidr: 10.0.2.0/24
+hosts:
+  # comments are dropped
+  - name: web
+    image: base_image
+    flavor: small_flavor
+    floating_ip: false
+    subnet: frontend
+    roles:
+      - name: web
+  - name: db
+    flavor: small_flavor
+    image: base_image
+    subnets:
+      - name: backend
+        ip4addr: 10.0.2.50
+"#;
+
+        let formatted = format_compose(messy, "test.yaml").unwrap();
+        assert!(formatted.contains("- name: web\n  flavor: small_flavor\n  image: base_image\n  roles:\n  - web\n  subnets:\n  - frontend\n"), "{}", formatted);
+        assert!(formatted.contains("subnets:\n- frontend\n- name: backend\n"));
+        assert!(formatted.contains("    ip4addr: 10.0.2.50\n"));
+        assert!(!formatted.contains("floating_ip"));
+        assert!(!formatted.contains("comments"));
+
+        assert_eq!(format_compose(&formatted, "test.yaml").unwrap(), formatted);
+
+        // Formatting never changes what the file means
+        let before = IncusCompose::load_from_reader(messy.as_bytes(), "test.yaml").unwrap();
+        let after = IncusCompose::load_from_reader(formatted.as_bytes(), "test.yaml").unwrap();
+        assert_eq!(
+            before
+                .generate_lockfile()
+                .unwrap()
+                .generate_incus_commands(),
+            after.generate_lockfile().unwrap().generate_incus_commands()
+        );
+    }
+
+    #[test]
+    fn test_legacy_subnet_field_must_be_defined() {
+        let yaml = r#"
+hosts:
+  - name: db
+    flavor: small_flavor
+    image: base_image
+    subnet: backend
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+"#;
+
+        let err = IncusCompose::load_from_reader(yaml.as_bytes(), "test.yaml")
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("host 'db': subnet 'backend' is not defined"),
+            "{}",
+            err
+        );
+
+        // Also caught before normalization moves the field into `subnets`
+        let compose: IncusCompose = serde_yaml::from_str(
+            &yaml.replace("subnet: backend", "subnet_list: [frontend, backend]"),
+        )
+        .unwrap();
+        assert!(compose.validate().is_err());
+    }
+
+    #[test]
+    fn test_router_with_multiple_subnets() {
+        let yaml = r#"
+hosts:
+  - name: core_router
+    flavor: medium_flavor
+    image: router_image
+    is_router: true
+    roles:
+      - router
+    subnet_list:
+      - frontend
+      - backend
+      - dmz
+
+subnets:
+  - frontend
+  - backend
+  - dmz
+"#;
+
+        let mut compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+
+        // Normalize subnet fields
+        for host in &mut compose.hosts {
+            host.normalize();
+        }
+
+        let host = &compose.hosts[0];
+        assert!(host.is_router);
+        assert_eq!(host.subnets.len(), 3);
+        assert_eq!(host.subnet_names(), vec!["frontend", "backend", "dmz"]);
+
+        // Test shorthand subnet format
+        assert_eq!(compose.subnets[0].name(), "frontend");
+        assert_eq!(compose.subnets[1].name(), "backend");
+        assert_eq!(compose.subnets[2].name(), "dmz");
+        for subnet in &compose.subnets {
+            assert_eq!(subnet.cidr(), None);
+        }
+    }
+
+    #[test]
+    fn test_defaults_configuration() {
+        let yaml = r#"
+defaults:
+  host_ip4_ranges:
+    - start: 192.168.10.100
+      end: 192.168.10.200
+  router_ip4_ranges:
+    - start: 192.168.1.100
+      end: 192.168.1.200
+  cidr4_ranges:
+    - start: 192.168.20.0/16
+      end: 192.168.80.0/16
+
+hosts:
+  - name: test_host
+    flavor: small_flavor
+    image: base_image
+
+subnets:
+  - test_subnet
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+
+        assert_eq!(compose.defaults.host_ip4_ranges.len(), 1);
+        assert_eq!(compose.defaults.host_ip4_ranges[0].start, "192.168.10.100");
+        assert_eq!(compose.defaults.host_ip4_ranges[0].end, "192.168.10.200");
+
+        assert_eq!(compose.defaults.router_ip4_ranges.len(), 1);
+        assert_eq!(compose.defaults.router_ip4_ranges[0].start, "192.168.1.100");
+        assert_eq!(compose.defaults.router_ip4_ranges[0].end, "192.168.1.200");
+
+        assert_eq!(compose.defaults.cidr4_ranges.len(), 1);
+        assert_eq!(compose.defaults.cidr4_ranges[0].start, "192.168.20.0/16");
+        assert_eq!(compose.defaults.cidr4_ranges[0].end, "192.168.80.0/16");
+    }
+
+    #[test]
+    fn test_custom_mac_prefix() {
+        let yaml = r#"
+defaults:
+  mac_prefix: "00:16:3E"
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+  - name: db
+    flavor: small_flavor
+    image: base_image
+subnets: []
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        compose.validate().unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+        assert_eq!(
+            lockfile.hosts[0].mac_address.as_deref(),
+            Some("00:16:3e:00:00:01")
+        );
+        assert_eq!(
+            lockfile.hosts[1].mac_address.as_deref(),
+            Some("00:16:3e:00:00:02")
+        );
+        assert!(lockfile.next_free_mac().unwrap().starts_with("00:16:3e:"));
+
+        let invalid = yaml.replace("00:16:3E", "00:16:3e:01");
+        let compose: IncusCompose = serde_yaml::from_str(&invalid).unwrap();
+        assert!(compose.validate().is_err());
+    }
+
+    #[test]
+    fn test_gateway_position() {
+        let yaml = r#"
+defaults:
+  gateway_position: last
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    subnets: [first_net, last_net, offset_net]
+subnets:
+  - name: first_net
+    cidr: 10.0.1.0/24
+    gateway_position: first
+  - name: last_net
+    cidr: 10.0.2.0/24
+  - name: offset_net
+    cidr: 10.0.3.0/24
+    gateway_position: 10
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        compose.validate().unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+
+        let gateways: Vec<&str> = lockfile
+            .subnets
+            .iter()
+            .map(|s| s.gateway.as_str())
+            .collect();
+        assert_eq!(gateways, vec!["10.0.1.1", "10.0.2.254", "10.0.3.10"]);
+
+        // Hosts start at .10, so the allocator must step over the offset gateway
+        assert_eq!(lockfile.hosts[0].ip_addresses["offset_net"], "10.0.3.11");
+
+        let outside = yaml.replace("gateway_position: 10", "gateway_position: 300");
+        let compose: IncusCompose = serde_yaml::from_str(&outside).unwrap();
+        assert!(compose.validate().is_err());
+    }
+
+    #[test]
+    fn test_overlapping_subnets_rejected() {
+        let yaml = r#"
+hosts: []
+subnets:
+  - name: campus
+    cidr: 10.0.0.0/16
+  - name: lab
+    cidr: 10.0.1.0/24
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        let err = compose
+            .generate_lockfile()
+            .unwrap()
+            .validate()
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("campus") && err.contains("lab"), "{}", err);
+
+        let disjoint = yaml.replace("10.0.0.0/16", "10.1.0.0/16");
+        let compose: IncusCompose = serde_yaml::from_str(&disjoint).unwrap();
+        assert!(compose.generate_lockfile().unwrap().validate().is_ok());
+    }
+
+    #[test]
+    fn test_anti_affinity_spreads_replicas() {
+        let yaml = r#"
+defaults:
+  cluster_members: [node1, node2, node3]
+hosts:
+  - {name: web1, flavor: small_flavor, image: base_image, anti_affinity: web}
+  - {name: web2, flavor: small_flavor, image: base_image, anti_affinity: web}
+  - {name: web3, flavor: small_flavor, image: base_image, anti_affinity: web}
+  - {name: db, flavor: small_flavor, image: base_image}
+subnets: []
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+        let targets: Vec<Option<&str>> =
+            lockfile.hosts.iter().map(|h| h.target.as_deref()).collect();
+        assert_eq!(
+            targets,
+            vec![Some("node1"), Some("node2"), Some("node3"), None]
+        );
+        assert!(lockfile.anti_affinity_conflicts().is_empty());
+        assert!(lockfile
+            .generate_incus_commands()
+            .contains(&"incus create base_image web3 --type=container --target=node3".to_string()));
+
+        // A fourth replica has to share a member
+        let crowded = yaml.replace("name: db,", "name: web4, anti_affinity: web,");
+        let compose: IncusCompose = serde_yaml::from_str(&crowded).unwrap();
+        let conflicts = compose
+            .generate_lockfile()
+            .unwrap()
+            .anti_affinity_conflicts();
+        assert_eq!(conflicts.len(), 1);
+        assert!(conflicts[0].contains("web1, web4"), "{}", conflicts[0]);
+    }
+
+    #[test]
+    fn test_size_units() {
+        assert_eq!(normalize_size("2gb").unwrap(), "2GB");
+        assert_eq!(normalize_size("512 MiB").unwrap(), "512MiB");
+        assert_eq!(normalize_size("20GIB").unwrap(), "20GiB");
+        assert_eq!(normalize_size("1TB").unwrap(), "1TB");
+        assert_eq!(normalize_memory_limit("50%").unwrap(), "50%");
+
+        for invalid in ["2GG", "512", "GB", "-1GB", "1.5GB", ""] {
+            assert!(normalize_size(invalid).is_err(), "{} accepted", invalid);
+        }
+        assert!(normalize_memory_limit("150%").is_err());
+
+        let yaml = r#"
+flavors:
+  small:
+    name: small
+    cpu: {cores: 1}
+    memory: {limit: 1gib}
+    storage: {size: 10gb}
+hosts:
+  - {name: web, flavor: small, image: base_image}
+subnets: []
+"#;
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        compose.validate().unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+        assert_eq!(lockfile.hosts[0].resources.memory.limit, "1GiB");
+        assert_eq!(
+            lockfile.flavors["small"].storage.as_ref().unwrap().size,
+            "10GB"
+        );
+
+        let typo = yaml.replace("1gib", "2GG");
+        let compose: IncusCompose = serde_yaml::from_str(&typo).unwrap();
+        let err = compose.validate().unwrap_err().to_string();
+        assert!(
+            err.contains("flavor 'small'") && err.contains("2GG"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_host_group_expansion() {
+        let path = write_temp_file(
+            "groups.yaml",
+            r#"
+hosts:
+  - name: worker1
+    image: custom_image
+groups:
+  - name: workers
+    hosts: [worker1, worker2]
+    flavor: medium_flavor
+    image: base_image
+    subnets: [cluster]
+    roles: [k8s_worker]
+subnets:
+  - name: cluster
+    cidr: 10.0.4.0/24
+"#,
+        );
+        let compose = IncusCompose::load_from_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert!(compose.groups.is_empty());
+        let names: Vec<&str> = compose.hosts.iter().map(|h| h.name.as_str()).collect();
+        assert_eq!(names, vec!["worker1", "worker2"]);
+        for host in &compose.hosts {
+            assert_eq!(host.flavor, "medium_flavor");
+            assert_eq!(host.subnet_names(), vec!["cluster"]);
+            assert_eq!(host.roles[0].name(), "k8s_worker");
+        }
+
+        // Explicit fields win over the group's
+        assert_eq!(compose.hosts[0].image, "custom_image");
+        assert_eq!(compose.hosts[1].image, "base_image");
+    }
+
+    #[test]
+    fn test_contiguous_group_gets_consecutive_addresses() {
+        let yaml = r#"
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend]
+groups:
+  - name: workers
+    hosts: [worker1, worker2, worker3]
+    flavor: small_flavor
+    image: base_image
+    subnets: [cluster]
+    contiguous: true
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+  - name: cluster
+    cidr: 10.0.4.0/24
+"#;
+
+        let compose = IncusCompose::load_from_reader(yaml.as_bytes(), "test.yaml").unwrap();
+        // The grouping is bookkeeping for the allocator, not configuration
+        assert!(!serde_yaml::to_string(&compose)
+            .unwrap()
+            .contains("contiguous_group"));
+        let lockfile = compose.generate_lockfile().unwrap();
+        let cluster: Vec<&str> = lockfile.hosts[1..]
+            .iter()
+            .map(|h| h.ip_addresses["cluster"].as_str())
+            .collect();
+        assert_eq!(cluster, vec!["10.0.4.10", "10.0.4.11", "10.0.4.12"]);
+
+        // A pinned address in the way moves the whole block past it
+        let pinned = yaml.replace(
+            "    subnets: [frontend]\n",
+            "    subnets:\n      - name: cluster\n        ip4addr: 10.0.4.11\n",
+        );
+        let lockfile = IncusCompose::load_from_reader(pinned.as_bytes(), "test.yaml")
+            .unwrap()
+            .generate_lockfile()
+            .unwrap();
+        let cluster: Vec<&str> = lockfile.hosts[1..]
+            .iter()
+            .map(|h| h.ip_addresses["cluster"].as_str())
+            .collect();
+        assert_eq!(cluster, vec!["10.0.4.12", "10.0.4.13", "10.0.4.14"]);
+
+        let crowded = yaml.replace("10.0.4.0/24", "10.0.4.0/30");
+        let err = IncusCompose::load_from_reader(crowded.as_bytes(), "test.yaml")
+            .unwrap()
+            .generate_lockfile()
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("no 3 consecutive free addresses"), "{}", err);
+    }
+
+    #[test]
+    fn test_lockfile_generation() {
+        let yaml = r#"
+hosts:
+  - name: test_host
+    flavor: small_flavor
+    image: base_image
+    subnets: [test_subnet]
+
+subnets:
+  - name: test_subnet
+    cidr: 192.168.100.0/24
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+
+        assert_eq!(lockfile.hosts.len(), 1);
+        assert_eq!(lockfile.subnets.len(), 1);
+
+        let host = &lockfile.hosts[0];
+        assert_eq!(host.name, "test_host");
+        assert_eq!(host.id, "host_001");
+        assert!(host.mac_address.is_some());
+        assert_eq!(host.ip_addresses.len(), 1);
+
+        let subnet = &lockfile.subnets[0];
+        assert_eq!(subnet.name, "test_subnet");
+        assert_eq!(subnet.cidr, "192.168.100.0/24");
+        assert_eq!(subnet.gateway, "192.168.100.1");
+    }
+
+    #[test]
+    fn test_incus_commands_generation() {
+        let yaml = r#"
+hosts:
+  - name: web_server
+    flavor: medium_flavor
+    image: base_image
+    subnets: [frontend]
+
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+        let commands = lockfile.generate_incus_commands();
+
+        assert!(!commands.is_empty());
+
+        // Check that network creation commands are present
+        assert!(commands
+            .iter()
+            .any(|cmd| cmd.contains("incus network create frontend")));
+
+        // Check that instance creation commands are present
+        assert!(commands
+            .iter()
+            .any(|cmd| cmd.contains("incus create base_image web_server")));
+
+        // Check that start command is present
+        assert!(commands
+            .iter()
+            .any(|cmd| cmd.contains("incus start web_server")));
+    }
+
+    /// Write `content` to a unique file in the temp directory for load tests
+    fn write_temp_file(name: &str, content: &str) -> std::path::PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("incus-composer-{}-{}", std::process::id(), name));
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_malformed_yaml_reports_line_and_column() {
+        let path = write_temp_file(
+            "malformed.yaml",
+            r#"hosts:
+  - name: web
+    flavor: small_flavor
+    image: [unterminated
+subnets: []
+"#,
+        );
+
+        let err = IncusCompose::load_from_file(&path).unwrap_err().to_string();
+        fs::remove_file(&path).unwrap();
+
+        let prefix = format!("{}:", path.display());
+        assert!(err.starts_with(&prefix), "unexpected error: {}", err);
+        let location: Vec<&str> = err[prefix.len()..].splitn(3, ':').collect();
+        assert!(location[0].parse::<usize>().unwrap() >= 4);
+        assert!(location[1].parse::<usize>().is_ok());
+        assert!(!err.contains(" at line 5 column"), "{}", err);
+    }
+
+    #[test]
+    fn test_load_from_stdin_reader() {
+        let yaml = b"hosts:\n  - name: web\n    flavor: small_flavor\n    image: base_image\n    subnet: frontend\nsubnets: [frontend]\n";
+
+        let compose = IncusCompose::load_from_reader(&yaml[..], "<stdin>").unwrap();
+        assert_eq!(compose.hosts[0].name, "web");
+        assert_eq!(compose.hosts[0].subnet_names(), vec!["frontend"]);
+
+        let err = IncusCompose::load_from_reader(&b"hosts: 42\n"[..], "<stdin>")
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with("<stdin>:"), "unexpected error: {}", err);
+    }
+
+    #[test]
+    fn test_invalid_lockfile_reports_line() {
+        let path = write_temp_file("invalid.lock", "version: '1.0'\nhosts: 42\n");
+
+        let err = IncusLockfile::load_from_file(&path)
+            .unwrap_err()
+            .to_string();
+        fs::remove_file(&path).unwrap();
+
+        assert!(err.starts_with(&format!("{}:", path.display())));
+        assert!(err.contains(":2:"), "unexpected error: {}", err);
+    }
+
+    #[test]
+    fn test_vlan_tagged_subnet_assignment() {
+        let yaml = r#"
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    subnets:
+      - name: frontend
+        vlan: 42
+      - backend
+
+subnets:
+  - frontend
+  - backend
+"#;
+
+        let mut compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        for host in &mut compose.hosts {
+            host.normalize();
+        }
+        compose.validate().unwrap();
+
+        let commands = compose
+            .generate_lockfile()
+            .unwrap()
+            .generate_incus_commands();
+        assert!(commands.contains(&"incus config device set web eth0 vlan=42".to_string()));
+        assert!(!commands.iter().any(|cmd| cmd.contains("eth1 vlan=")));
+    }
+
+    #[test]
+    fn test_nic_name_sets_guest_interface() {
+        let yaml = r#"
+hosts:
+  - name: edge
+    flavor: small_flavor
+    image: base_image
+    is_router: true
+    subnets:
+      - name: external
+        nic_name: wan
+      - name: frontend
+        nic_name: lan
+subnets:
+  - name: external
+    cidr: 10.0.0.0/24
+  - name: frontend
+    cidr: 10.0.1.0/24
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        compose.validate().unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+        let mac = lockfile.hosts[0].mac_address.clone().unwrap();
+        let commands = lockfile.generate_incus_commands();
+        assert!(commands.contains(&format!(
+            "incus config device add edge eth0 nic network=external hwaddr={} name=wan",
+            mac
+        )));
+        assert!(commands.contains(
+            &"incus config device add edge eth1 nic network=frontend name=lan".to_string()
+        ));
+
+        let duplicate: IncusCompose =
+            serde_yaml::from_str(&yaml.replace("nic_name: lan", "nic_name: wan")).unwrap();
+        assert!(duplicate.validate().is_err());
+    }
+
+    #[test]
+    fn test_primary_subnet_carries_mac() {
+        let yaml = r#"
+hosts:
+  - name: edge
+    flavor: small_flavor
+    image: base_image
+    subnets:
+      - frontend
+      - name: backend
+        primary: true
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+  - name: backend
+    cidr: 10.0.2.0/24
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        compose.validate().unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+        let mac = lockfile.hosts[0].mac_address.clone().unwrap();
+        let commands = lockfile.generate_incus_commands();
+        assert!(commands.contains(&format!(
+            "incus config device add edge eth0 nic network=backend hwaddr={}",
+            mac
+        )));
+        assert!(commands
+            .contains(&"incus config device add edge eth1 nic network=frontend".to_string()));
+        assert!(lockfile
+            .generate_ansible_inventory()
+            .contains("edge ansible_host=10.0.2.10"));
+
+        let two_primaries: IncusCompose = serde_yaml::from_str(&yaml.replace(
+            "      - frontend\n",
+            "      - name: frontend\n        primary: true\n",
+        ))
+        .unwrap();
+        assert!(two_primaries.validate().is_err());
+    }
+
+    #[test]
+    fn test_prune_removes_nic_of_dropped_subnet() {
+        let yaml = r#"
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend, backend]
+  - name: api
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend]
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+  - name: backend
+    cidr: 10.0.2.0/24
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        let previous = compose.generate_lockfile().unwrap();
+        assert!(previous.generate_prune_commands(&previous).is_empty());
+
+        let compose: IncusCompose =
+            serde_yaml::from_str(&yaml.replace("[frontend, backend]", "[frontend]")).unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+        assert_eq!(
+            lockfile.generate_prune_commands(&previous),
+            vec!["incus config device remove web eth1".to_string()]
+        );
+
+        // Swapping subnets moves eth0 and eth1 to other networks
+        let compose: IncusCompose =
+            serde_yaml::from_str(&yaml.replace("[frontend, backend]", "[backend, frontend]"))
+                .unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+        assert_eq!(lockfile.generate_prune_commands(&previous).len(), 2);
+    }
+
+    #[test]
+    fn test_duplicate_pinned_ip_rejected() {
+        let yaml = r#"
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    subnets:
+      - name: frontend
+        ip4addr: 10.0.1.50
+  - name: api
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend]
+  - name: cache
+    flavor: small_flavor
+    image: base_image
+    subnets:
+      - name: frontend
+        ip4addr: 10.0.1.10
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        compose.validate().unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+        let addresses: Vec<&str> = lockfile
+            .hosts
+            .iter()
+            .map(|h| h.ip_addresses["frontend"].as_str())
+            .collect();
+        // api skips 10.0.1.10 because cache has it pinned
+        assert_eq!(addresses, vec!["10.0.1.50", "10.0.1.11", "10.0.1.10"]);
+
+        let duplicate: IncusCompose =
+            serde_yaml::from_str(&yaml.replace("10.0.1.10", "10.0.1.50")).unwrap();
+        let err = duplicate.validate().unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "subnet 'frontend': 10.0.1.50 is pinned by both host 'web' and host 'cache'"
+        );
+
+        let outside: IncusCompose =
+            serde_yaml::from_str(&yaml.replace("10.0.1.50", "10.0.2.50")).unwrap();
+        assert!(outside.validate().is_err());
+    }
+
+    #[test]
+    fn test_pinned_ip_must_be_usable() {
+        let yaml = r#"
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    subnets:
+      - name: frontend
+        ip4addr: 10.0.1.50
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+    excluded_ips: [10.0.1.20]
+    reserved_ranges:
+      - start: 10.0.1.30
+        end: 10.0.1.39
+"#;
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        assert!(compose.generate_lockfile().is_ok());
+
+        let pinned_at = |ip: &str| -> String {
+            let compose: IncusCompose =
+                serde_yaml::from_str(&yaml.replace("ip4addr: 10.0.1.50", ip)).unwrap();
+            compose.generate_lockfile().unwrap_err().to_string()
+        };
+        assert_eq!(
+            pinned_at("ip4addr: 10.0.1.1"),
+            "host 'web': ip4addr 10.0.1.1 is the gateway of subnet 'frontend'"
+        );
+        for ip in ["10.0.1.20", "10.0.1.35"] {
+            let err = pinned_at(&format!("ip4addr: {}", ip));
+            assert!(err.contains("is excluded or reserved"), "{}", err);
+        }
+
+        // Without a cidr the pin is checked against the one assigned
+        let auto = yaml.replace("    cidr: 10.0.1.0/24\n", "");
+        let compose: IncusCompose = serde_yaml::from_str(&auto).unwrap();
+        compose.validate().unwrap();
+        let err = compose.generate_lockfile().unwrap_err().to_string();
+        assert!(err.contains("is not inside subnet 'frontend'"), "{}", err);
+    }
+
+    #[test]
+    fn test_cpu_pin_set() {
+        let yaml = r#"
+hosts:
+  - name: db
+    flavor: pinned
+    image: base_image
+    subnets: [frontend]
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+flavors:
+  pinned:
+    name: pinned
+    cpu: {cores: "0-3"}
+    memory: {limit: 4GB}
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        compose.validate().unwrap();
+        let commands = compose
+            .generate_lockfile()
+            .unwrap()
+            .generate_incus_commands();
+        assert!(commands.contains(&"incus config set db limits.cpu=0-3".to_string()));
+
+        let listed: IncusCompose =
+            serde_yaml::from_str(&yaml.replace("\"0-3\"", "\"0,2,4\"")).unwrap();
+        listed.validate().unwrap();
+        let counted: IncusCompose = serde_yaml::from_str(&yaml.replace("\"0-3\"", "2")).unwrap();
+        assert_eq!(counted.flavors["pinned"].cpu.cores, 2);
+
+        for invalid in ["\"3-0\"", "\"0-\"", "\"a,b\"", "\"0,,1\""] {
+            let compose: IncusCompose =
+                serde_yaml::from_str(&yaml.replace("\"0-3\"", invalid)).unwrap();
+            assert!(compose.validate().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_storage_pool_created_once() {
+        let yaml = r#"
+storage_pools:
+  - name: fast
+    driver: zfs
+    size: 30gb
+  - name: fast
+    driver: zfs
+    size: 30gb
+flavors:
+  small:
+    name: small
+    cpu: {cores: 1}
+    memory: {limit: 1GB}
+    storage: {size: 10GB, pool: fast}
+  large:
+    name: large
+    cpu: {cores: 4}
+    memory: {limit: 8GB}
+    storage: {size: 50GB, pool: fast}
+hosts:
+  - name: web
+    flavor: small
+    image: base_image
+    subnets: [frontend]
+  - name: db
+    flavor: large
+    image: base_image
+    subnets: [frontend]
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        compose.validate().unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+        let commands = lockfile.generate_incus_commands();
+        let creates: Vec<&String> = commands
+            .iter()
+            .filter(|c| c.starts_with("incus storage create"))
+            .collect();
+        assert_eq!(creates, vec!["incus storage create fast zfs size=30GB"]);
+        assert_eq!(commands[0], "incus storage create fast zfs size=30GB");
+        assert!(lockfile.undeclared_storage_pools().is_empty());
+
+        let undeclared: IncusCompose =
+            serde_yaml::from_str(&yaml.replace("pool: fast}", "pool: slow}")).unwrap();
+        let lockfile = undeclared.generate_lockfile().unwrap();
+        assert_eq!(lockfile.undeclared_storage_pools().len(), 2);
+
+        let conflicting: IncusCompose =
+            serde_yaml::from_str(&yaml.replacen("driver: zfs", "driver: btrfs", 1)).unwrap();
+        assert!(conflicting.validate().is_err());
+    }
+
+    #[test]
+    fn test_vlan_out_of_range_rejected() {
+        for vlan in [0, 4095] {
+            let yaml = format!(
+                r#"
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    subnets:
+      - name: frontend
+        vlan: {}
+
+subnets:
+  - frontend
+"#,
+                vlan
+            );
+
+            let compose: IncusCompose = serde_yaml::from_str(&yaml).unwrap();
+            let err = compose.validate().unwrap_err().to_string();
+            assert!(err.contains("out of range"), "unexpected error: {}", err);
+        }
+    }
+
+    #[test]
+    fn test_subnet_mtu() {
+        let yaml = r#"
+hosts: []
+
+subnets:
+  - name: overlay
+    cidr: 10.0.5.0/24
+    mtu: 1400
+  - name: plain
+    cidr: 10.0.6.0/24
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+        assert_eq!(lockfile.subnets[0].mtu, Some(1400));
+        assert_eq!(lockfile.subnets[1].mtu, None);
+
+        let commands = lockfile.generate_incus_commands();
+        assert!(commands.contains(&"incus network set overlay bridge.mtu=1400".to_string()));
+        assert!(!commands.iter().any(|cmd| cmd.contains("plain bridge.mtu")));
+    }
+
+    #[test]
+    fn test_bridge_external_interfaces() {
+        let yaml = r#"
+hosts: []
+
+subnets:
+  - name: lan
+    cidr: 10.0.5.0/24
+    external_interfaces: [enp3s0]
+  - name: plain
+    cidr: 10.0.6.0/24
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        compose.validate().unwrap();
+        let commands = compose
+            .generate_lockfile()
+            .unwrap()
+            .generate_incus_commands();
+        assert!(commands
+            .contains(&"incus network set lan bridge.external_interfaces=enp3s0".to_string()));
+        assert!(!commands
+            .iter()
+            .any(|cmd| cmd.contains("plain bridge.external_interfaces")));
+
+        let ovn = yaml.replace(
+            "    external_interfaces: [enp3s0]",
+            "    network_type: ovn\n    external_interfaces: [enp3s0]",
+        );
+        let compose: IncusCompose = serde_yaml::from_str(&ovn).unwrap();
+        let err = compose.validate().unwrap_err().to_string();
+        assert!(err.contains("needs network_type bridge"), "{}", err);
+    }
+
+    #[test]
+    fn test_image_source_validation() {
+        let yaml = r#"
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: debian
+    subnets: [frontend]
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+images:
+  debian:
+    name: debian
+    source: "images:"
+    fingerprint: debian/12
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        compose.validate().unwrap();
+
+        let inline = yaml.replace(
+            "\"images:\"\n    fingerprint: debian/12",
+            "images:debian/12",
+        );
+        let compose: IncusCompose = serde_yaml::from_str(&inline).unwrap();
+        compose.validate().unwrap();
+
+        let typo = yaml.replace("\"images:\"", "\"imags:\"");
+        let compose: IncusCompose = serde_yaml::from_str(&typo).unwrap();
+        let err = compose.validate().unwrap_err().to_string();
+        assert!(err.contains("unknown remote 'imags:'"), "{}", err);
+
+        let no_alias = yaml.replace("\n    fingerprint: debian/12", "");
+        let compose: IncusCompose = serde_yaml::from_str(&no_alias).unwrap();
+        let err = compose.validate().unwrap_err().to_string();
+        assert!(err.contains("needs a fingerprint or alias"), "{}", err);
+    }
+
+    #[test]
+    fn test_role_catalog_expands_templates() {
+        let yaml = r#"
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend]
+    roles:
+      - name: monitoring
+        values: [prometheus, port=9100]
+      - backup
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+roles:
+  monitoring:
+    config:
+      user.monitoring.port: "{{port}}"
+      user.monitoring.agents: "{{value}}"
+    commands:
+      - "incus exec {{host}} -- systemctl enable --now {{value}}-node-exporter"
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        compose.validate().unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+        let commands = lockfile.generate_host_commands(&lockfile.hosts[0]);
+        for expected in [
+            "incus config set web user.monitoring.agents=prometheus",
+            "incus config set web user.monitoring.port=9100",
+            "incus exec web -- systemctl enable --now prometheus-node-exporter",
+        ] {
+            assert!(commands.contains(&expected.to_string()), "{}", expected);
+        }
+        assert!(!commands
+            .iter()
+            .any(|c| c.contains("Apply role 'monitoring'")));
+        // Roles without a template are still only noted
+        assert!(commands
+            .iter()
+            .any(|c| c.starts_with("# Apply role 'backup'")));
+
+        let missing = yaml.replace(", port=9100", "");
+        let compose: IncusCompose = serde_yaml::from_str(&missing).unwrap();
+        let err = compose.validate().unwrap_err().to_string();
+        assert!(
+            err.contains("role 'monitoring'") && err.contains("port"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_name_id_scheme_survives_reordering() {
+        let yaml = r#"
+defaults:
+  id_scheme: name
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend]
+  - name: db
+    flavor: small_flavor
+    image: base_image
+    subnets: [backend]
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+  - name: backend
+    cidr: 10.0.2.0/24
+"#;
+        let reordered = yaml
+            .replace(
+                "  - name: frontend\n    cidr: 10.0.1.0/24\n  - name: backend\n    cidr: 10.0.2.0/24\n",
+                "  - name: backend\n    cidr: 10.0.2.0/24\n  - name: frontend\n    cidr: 10.0.1.0/24\n",
+            )
+            .replace("  - name: web", "  - name: first")
+            .replace("  - name: db", "  - name: web")
+            .replace("  - name: first", "  - name: db");
+
+        let ids = |yaml: &str| -> BTreeMap<String, String> {
+            let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+            let lockfile = compose.generate_lockfile().unwrap();
+            lockfile
+                .subnets
+                .iter()
+                .map(|s| (s.name.clone(), s.id.clone()))
+                .chain(
+                    lockfile
+                        .hosts
+                        .iter()
+                        .map(|h| (h.name.clone(), h.id.clone())),
+                )
+                .collect()
+        };
+        let before = ids(yaml);
+        assert_ne!(yaml, reordered);
+        assert_eq!(before, ids(&reordered));
+        assert!(before["frontend"].starts_with("subnet_"));
+        assert_ne!(before["frontend"], before["backend"]);
+        assert_ne!(before["web"], before["db"]);
+
+        // The index scheme renumbers on reordering
+        let by_index = |yaml: &str| ids(&yaml.replace("  id_scheme: name\n", "  {}\n"));
+        assert_eq!(by_index(yaml)["frontend"], "subnet_001");
+        assert_eq!(by_index(&reordered)["frontend"], "subnet_002");
+    }
+
+    #[test]
+    fn test_device_address_format() {
+        let yaml = r#"
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend, routed]
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+  - name: routed
+    cidr: 10.0.8.0/22
+    device_address: cidr
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+        let commands = lockfile.generate_incus_commands();
+        assert!(commands
+            .contains(&"incus config device set web eth0 ipv4.address=10.0.1.10".to_string()));
+        assert!(commands
+            .contains(&"incus config device set web eth1 ipv4.address=10.0.8.10/22".to_string()));
+
+        let definitions = lockfile.generate_instance_definitions();
+        let devices = &definitions[0].devices;
+        assert_eq!(devices["eth0"]["ipv4.address"], "10.0.1.10");
+        assert_eq!(devices["eth1"]["ipv4.address"], "10.0.8.10/22");
+    }
+
+    #[test]
+    fn test_incus_name_rules() {
+        let yaml = r#"
+hosts:
+  - name: web_server
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend]
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        let err = compose.validate().unwrap_err().to_string();
+        assert!(err.starts_with("host 'web_server': "), "{}", err);
+        assert!(err.ends_with("try 'web-server'"), "{}", err);
+
+        let valid = yaml.replace("web_server", "web-server");
+        let compose: IncusCompose = serde_yaml::from_str(&valid).unwrap();
+        compose.validate().unwrap();
+
+        let long_subnet = valid.replace("frontend", "frontend-network");
+        let compose: IncusCompose = serde_yaml::from_str(&long_subnet).unwrap();
+        let err = compose.validate().unwrap_err().to_string();
+        assert!(err.ends_with("try 'frontend-networ'"), "{}", err);
+
+        assert_eq!(
+            check_instance_name("01_db")
+                .unwrap_err()
+                .rsplit("try ")
+                .next(),
+            Some("'host-01-db'")
+        );
+    }
+
+    #[test]
+    fn test_empty_vm_boots_from_iso() {
+        let yaml = r#"
+hosts:
+  - name: installer
+    flavor: small_flavor
+    image: blank
+    instance_type: virtual-machine
+    subnets: [frontend]
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+images:
+  blank:
+    name: blank
+    source: empty
+    iso: /srv/iso/debian-12-netinst.iso
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        compose.validate().unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+        let commands = lockfile.generate_host_commands(&lockfile.hosts[0]);
+        assert_eq!(commands[0], "incus create installer --empty --vm");
+        assert!(commands.contains(
+            &"incus config device add installer iso disk source=/srv/iso/debian-12-netinst.iso boot.priority=10"
+                .to_string()
+        ));
+
+        let container = yaml.replace("    instance_type: virtual-machine\n", "");
+        let compose: IncusCompose = serde_yaml::from_str(&container).unwrap();
+        let err = compose.validate().unwrap_err().to_string();
+        assert!(err.contains("needs a virtual-machine"), "{}", err);
+    }
+
+    #[test]
+    fn test_launch_mode_attaches_iso_before_starting() {
+        let yaml = r#"
+launch: true
+hosts:
+  - name: installer
+    flavor: small_flavor
+    image: blank
+    instance_type: virtual-machine
+    subnets: [frontend]
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+images:
+  blank:
+    name: blank
+    source: empty
+    iso: /srv/iso/debian-12-netinst.iso
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        compose.validate().unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+        let commands = lockfile.generate_host_commands(&lockfile.hosts[0]);
+        assert!(!commands.iter().any(|c| c.starts_with("incus launch")));
+        assert_eq!(commands[0], "incus create installer --empty --vm");
+        let iso = commands
+            .iter()
+            .position(|c| c.starts_with("incus config device add installer iso disk"))
+            .unwrap();
+        let start = commands
+            .iter()
+            .position(|c| c == "incus start installer")
+            .unwrap();
+        assert!(iso < start);
+    }
+
+    #[test]
+    fn test_subnet_ipv4_routes() {
+        let yaml = r#"
+hosts: []
+
+subnets:
+  - name: routed
+    cidr: 10.0.5.0/24
+    ipv4_routes: [198.51.100.0/24, 203.0.113.16/28]
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        compose.validate().unwrap();
+        let commands = compose
+            .generate_lockfile()
+            .unwrap()
+            .generate_incus_commands();
+        assert!(commands.contains(
+            &"incus network set routed ipv4.routes=198.51.100.0/24,203.0.113.16/28".to_string()
+        ));
+
+        let invalid = yaml.replace("203.0.113.16/28", "203.0.113.16");
+        let compose: IncusCompose = serde_yaml::from_str(&invalid).unwrap();
+        let err = compose.validate().unwrap_err().to_string();
+        assert!(
+            err.contains("'203.0.113.16' is not an IPv4 CIDR"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_subnet_ipv6_toggles() {
+        let yaml = r#"
+hosts: []
+
+subnets:
+  - name: stateful
+    cidr: 10.0.5.0/24
+    ipv6:
+      stateful: true
+  - name: stateless
+    cidr: 10.0.6.0/24
+    ipv6: {}
+  - name: plain
+    cidr: 10.0.7.0/24
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        let commands = compose
+            .generate_lockfile()
+            .unwrap()
+            .generate_incus_commands();
+        for expected in [
+            "incus network set stateful ipv6.dhcp=true",
+            "incus network set stateful ipv6.dhcp.stateful=true",
+            "incus network set stateless ipv6.dhcp=true",
+            "incus network set stateless ipv6.dhcp.stateful=false",
+        ] {
+            assert!(commands.contains(&expected.to_string()), "{}", expected);
+        }
+        assert!(!commands.iter().any(|cmd| cmd.contains("plain ipv6")));
+    }
+
+    #[test]
+    fn test_subnet_host_start() {
+        let yaml = r#"
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    subnets: [apps, tools]
+  - name: gw
+    flavor: small_flavor
+    image: base_image
+    is_router: true
+    subnets: [apps]
+subnets:
+  - name: apps
+    cidr: 10.0.1.0/24
+    host_start: 20
+  - name: tools
+    cidr: 10.0.2.0/24
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        compose.validate().unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+        assert_eq!(lockfile.hosts[0].ip_addresses["apps"], "10.0.1.20");
+        assert_eq!(lockfile.hosts[0].ip_addresses["tools"], "10.0.2.10");
+        assert_eq!(lockfile.hosts[1].ip_addresses["apps"], "10.0.1.2");
+        assert_eq!(
+            lockfile.next_free_ip("apps", false).as_deref(),
+            Some("10.0.1.21")
+        );
+
+        let invalid = yaml.replace("host_start: 20", "host_start: 300");
+        let compose: IncusCompose = serde_yaml::from_str(&invalid).unwrap();
+        assert!(compose.validate().is_err());
+    }
+
+    #[test]
+    fn test_next_unused_ip_after_five_hosts() {
+        let mut yaml = String::from("hosts:\n");
+        for name in ["a", "b", "c", "d", "e"] {
+            yaml.push_str(&format!(
+                "  - name: {}\n    flavor: small_flavor\n    image: base_image\n    subnets: [frontend]\n",
+                name
+            ));
+        }
+        yaml.push_str("subnets:\n  - name: frontend\n    cidr: 10.0.1.0/24\n");
+
+        let compose: IncusCompose = serde_yaml::from_str(&yaml).unwrap();
+        let mut lockfile = compose.generate_lockfile().unwrap();
+        assert_eq!(
+            lockfile.next_unused_ip("frontend", false).unwrap(),
+            "10.0.1.15"
+        );
+        assert_eq!(
+            lockfile.next_unused_ip("frontend", true).unwrap(),
+            "10.0.1.2"
+        );
+        assert!(lockfile.next_unused_ip("backend", false).is_err());
+
+        // An address recorded as used stays taken after its host is gone
+        lockfile.hosts.pop();
+        assert_eq!(
+            lockfile.next_free_ip("frontend", false).as_deref(),
+            Some("10.0.1.14")
+        );
+        assert_eq!(
+            lockfile.next_unused_ip("frontend", false).unwrap(),
+            "10.0.1.15"
+        );
+    }
+
+    #[test]
+    fn test_subnet_exhaustion_is_an_error() {
+        let yaml = r#"
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    subnets: [tiny]
+  - name: api
+    flavor: small_flavor
+    image: base_image
+    subnets: [tiny]
+subnets:
+  - name: tiny
+    cidr: 10.0.9.0/30
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        let err = compose.generate_lockfile().unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "subnet 'tiny' (10.0.9.0/30) is out of host addresses: 2 hosts requested, 1 available"
+        );
+
+        let single = yaml.replace(
+            "  - name: api\n    flavor: small_flavor\n    image: base_image\n    subnets: [tiny]\n",
+            "",
+        );
+        let compose: IncusCompose = serde_yaml::from_str(&single).unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+        assert_eq!(lockfile.hosts[0].ip_addresses["tiny"], "10.0.9.2");
+    }
+
+    #[test]
+    fn test_host_ip_ranges_constrain_assignment() {
+        let yaml = r#"
+defaults:
+  host_ip4_ranges:
+    - start: 10.0.1.100
+      end: 10.0.1.200
+  router_ip4_ranges:
+    - start: 10.0.1.250
+      end: 10.0.1.254
+hosts:
+  - {name: web, flavor: small_flavor, image: base_image, subnets: [inside, outside]}
+  - {name: api, flavor: small_flavor, image: base_image, subnets: [inside]}
+  - {name: gw, flavor: small_flavor, image: base_image, is_router: true, subnets: [inside]}
+subnets:
+  - name: inside
+    cidr: 10.0.1.0/24
+  - name: outside
+    cidr: 10.0.2.0/24
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+        assert_eq!(lockfile.hosts[0].ip_addresses["inside"], "10.0.1.100");
+        assert_eq!(lockfile.hosts[1].ip_addresses["inside"], "10.0.1.101");
+        assert_eq!(lockfile.hosts[2].ip_addresses["inside"], "10.0.1.250");
+
+        // The ranges don't intersect `outside`, so the usual offsets apply
+        assert_eq!(lockfile.hosts[0].ip_addresses["outside"], "10.0.2.10");
+        assert_eq!(
+            lockfile.next_free_ip("inside", false).as_deref(),
+            Some("10.0.1.102")
+        );
+    }
+
+    #[test]
+    fn test_excluded_ips_skipped_by_allocator() {
+        let yaml = r#"
+hosts:
+  - name: first
+    flavor: small_flavor
+    image: base_image
+    subnets: [lab]
+  - name: second
+    flavor: small_flavor
+    image: base_image
+    subnets: [lab]
+
+subnets:
+  - name: lab
+    cidr: 10.0.9.0/24
+    excluded_ips: [10.0.9.10]
+    reserved_ranges:
+      - start: 10.0.9.12
+        end: 10.0.9.14
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        compose.validate().unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+
+        assert_eq!(lockfile.hosts[0].ip_addresses["lab"], "10.0.9.11");
+        assert_eq!(lockfile.hosts[1].ip_addresses["lab"], "10.0.9.15");
+    }
+
+    #[test]
+    fn test_invalid_excluded_ip_rejected() {
+        let yaml = r#"
+hosts: []
+subnets:
+  - name: lab
+    excluded_ips: [10.0.9.300]
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        assert!(compose.validate().is_err());
+    }
+
+    #[test]
+    fn test_privileged_container() {
+        let yaml = r#"
+hosts:
+  - name: builder
+    flavor: small_flavor
+    image: base_image
+    privileged: true
+  - name: web
+    flavor: small_flavor
+    image: base_image
+
+subnets: []
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        compose.validate().unwrap();
+        let commands = compose
+            .generate_lockfile()
+            .unwrap()
+            .generate_incus_commands();
+
+        assert!(commands.contains(&"incus config set builder security.privileged=true".to_string()));
+        assert!(!commands
+            .iter()
+            .any(|cmd| cmd.contains("web security.privileged")));
+    }
+
+    #[test]
+    fn test_privileged_virtual_machine_rejected() {
+        let yaml = r#"
+hosts:
+  - name: vm
+    flavor: vm_flavor
+    image: base_image
+    privileged: true
+
+subnets: []
+
+flavors:
+  vm_flavor:
+    name: vm_flavor
+    cpu:
+      cores: 2
+    memory:
+      limit: 4GB
+    instance_type: virtual-machine
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        let err = compose.validate().unwrap_err().to_string();
+        assert!(err.contains("privileged"), "unexpected error: {}", err);
+    }
+
+    #[test]
+    fn test_command_generation_is_deterministic() {
+        let yaml = r#"
+hosts:
+  - name: router
+    flavor: small_flavor
+    image: base_image
+    is_router: true
+    roles:
+      - router
+      - name: monitoring
+        values: ["prometheus", "port=9090"]
+    subnets: [dmz, backend, frontend]
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend, backend]
+
+subnets:
+  - frontend
+  - backend
+  - dmz
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        let first = compose
+            .generate_lockfile()
+            .unwrap()
+            .generate_incus_commands();
+        let second = compose
+            .generate_lockfile()
+            .unwrap()
+            .generate_incus_commands();
+        assert_eq!(first, second);
+
+        // Devices follow the declared subnet order, not map order
+        let router_devices: Vec<&String> = first
+            .iter()
+            .filter(|cmd| cmd.starts_with("incus config device set router"))
+            .collect();
+        assert!(router_devices[0].contains("eth0 ipv4.address=192.168.12."));
+        assert!(router_devices[1].contains("eth1 ipv4.address=192.168.11."));
+        assert!(router_devices[2].contains("eth2 ipv4.address=192.168.10."));
+    }
+
+    #[test]
+    fn test_host_profiles_on_create() {
+        let yaml = r#"
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    profiles: [default, shared-mounts, default]
+  - name: plain
+    flavor: small_flavor
+    image: base_image
+
+subnets: []
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+        assert_eq!(lockfile.hosts[0].profiles, vec!["default", "shared-mounts"]);
+
+        let commands = lockfile.generate_incus_commands();
+        assert!(commands.contains(
+            &"incus create base_image web --type=container --profile default --profile shared-mounts"
+                .to_string()
+        ));
+        assert!(commands.contains(&"incus create base_image plain --type=container".to_string()));
+    }
+
+    #[test]
+    fn test_migrate_old_lockfile() {
+        let old = r#"
+version: '1.0'
+hosts:
+- name: web
+  flavor: small_flavor
+  image: base_image
+  floating_ip: false
+  master: false
+  is_router: false
+  roles: []
+  subnets:
+  - frontend
+  id: host_001
+  mac_address: 02:00:00:00:00:01
+  ip_addresses:
+    frontend: 10.0.1.10
+  instance_type: container
+  resources:
+    cpu:
+      cores: 1
+      limit: 100%
+    memory:
+      limit: 1gb
+subnets:
+- name: frontend
+  cidr: 10.0.1.0/24
+  id: subnet_001
+  gateway: 10.0.1.1
+flavors:
+  small_flavor:
+    name: small_flavor
+    cpu:
+      cores: 1
+    memory:
+      limit: 1gb
+"#;
+        let path =
+            std::env::temp_dir().join(format!("incus-composer-{}-old.lock", std::process::id()));
+        fs::write(&path, old).unwrap();
+        let (lockfile, notes) = IncusLockfile::load_with_migrations(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(notes.len(), 3, "{:?}", notes);
+        assert_eq!(lockfile.metadata.generator_version, GENERATOR_VERSION);
+        assert_eq!(lockfile.flavors["small_flavor"].memory.limit, "1GB");
+        assert_eq!(
+            lockfile.metadata.used_values.ip_addresses[&ip_pool_key("10.0.1.0/24")],
+            vec!["10.0.1.10"]
+        );
+        assert_eq!(lockfile.metadata.used_values.host_ids, vec!["host_001"]);
+
+        let host = &lockfile.hosts[0];
+        assert!(host.backup);
+        assert!(!host.privileged);
+        assert!(host.profiles.is_empty() && host.labels.is_empty());
+        assert_eq!(host.subnets[0].name, "frontend");
+        assert_eq!(lockfile.subnets[0].host_start, 10);
+        assert!(lockfile.remote.is_none());
+
+        let mut current = lockfile.clone();
+        assert!(current.migrate().is_empty());
+    }
+
+    #[test]
+    fn test_sriov_subnet_attaches_to_parent() {
+        let yaml = r#"
+hosts:
+  - name: nfv
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend, fast]
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+  - name: fast
+    cidr: 10.0.5.0/24
+    network_type: sriov
+    parent: enp3s0f0
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        compose.validate().unwrap();
+        let commands = compose
+            .generate_lockfile()
+            .unwrap()
+            .generate_incus_commands();
+        assert!(commands.contains(
+            &"incus config device add nfv eth1 nic nictype=sriov parent=enp3s0f0".to_string()
+        ));
+        assert!(commands.contains(&"incus network create frontend --type=bridge".to_string()));
+        assert!(!commands.iter().any(|c| c.contains("network create fast")));
+        assert!(!commands.iter().any(|c| c.contains("eth1 ipv4.address")));
+
+        let orphan = yaml.replace("    parent: enp3s0f0\n", "");
+        let compose: IncusCompose = serde_yaml::from_str(&orphan).unwrap();
+        assert!(compose.validate().is_err());
+    }
+
+    #[test]
+    fn test_gateway_host_resolves_to_router_address() {
+        let yaml = r#"
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend]
+  - name: edge
+    flavor: small_flavor
+    image: base_image
+    is_router: true
+    subnets: [frontend]
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+    gateway_host: edge
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        compose.validate().unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+        assert_eq!(lockfile.subnets[0].gateway, "10.0.1.2");
+        assert_eq!(lockfile.hosts[1].ip_addresses["frontend"], "10.0.1.2");
+        assert!(lockfile
+            .generate_incus_commands()
+            .contains(&"incus network set frontend ipv4.address=none".to_string()));
+
+        let detached = yaml.replace("gateway_host: edge", "gateway_host: db");
+        let compose: IncusCompose = serde_yaml::from_str(&detached).unwrap();
+        assert!(compose.validate().is_err());
+        assert!(compose.generate_lockfile().is_err());
+    }
+
+    #[test]
+    fn test_floating_ip_requires_uplink_subnet() {
+        let yaml = r#"
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    floating_ip: true
+    subnets: [frontend, wan]
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+  - name: wan
+    cidr: 203.0.113.0/24
+    uplink: true
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        compose.validate().unwrap();
+        assert!(compose.generate_lockfile().unwrap().subnets[1].uplink);
+
+        let internal_only = yaml.replace("subnets: [frontend, wan]", "subnets: [frontend]");
+        let compose: IncusCompose = serde_yaml::from_str(&internal_only).unwrap();
+        let err = compose.validate().unwrap_err().to_string();
+        assert!(err.contains("host 'web': floating_ip"), "{}", err);
+    }
+
+    #[test]
+    fn test_forward_generates_forward_port_command() {
+        let yaml = r#"
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    floating_ip: true
+    subnets: [frontend, wan]
+    forwards:
+      - listen_port: 443
+        target_port: 8443
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+  - name: wan
+    cidr: 203.0.113.0/24
+    uplink: true
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        compose.validate().unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+        let commands = lockfile.generate_host_commands(&lockfile.hosts[0]);
+        assert!(commands.contains(&"incus network forward create wan 203.0.113.10".to_string()));
+        assert!(commands.contains(
+            &"incus network forward port add wan 203.0.113.10 tcp 443 10.0.1.10 8443".to_string()
+        ));
+        assert!(lockfile
+            .generate_teardown_commands(false)
+            .contains(&"incus network forward delete wan 203.0.113.10".to_string()));
+
+        let grounded = yaml.replace("floating_ip: true", "floating_ip: false");
+        let compose: IncusCompose = serde_yaml::from_str(&grounded).unwrap();
+        let err = compose.validate().unwrap_err().to_string();
+        assert!(err.contains("host 'web': forwards"), "{}", err);
+    }
+
+    #[test]
+    fn test_command_reference_check_catches_dangling_network_and_image() {
+        let yaml = r#"
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend]
+  - name: standalone
+    flavor: small_flavor
+    image: base_image
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+        let commands = lockfile.generate_incus_commands();
+        lockfile.check_command_references(&commands).unwrap();
+
+        let mut dangling = lockfile.clone();
+        dangling.subnets.clear();
+        let err = dangling
+            .check_command_references(&dangling.generate_incus_commands())
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("network 'frontend'"), "{}", err);
+
+        let mut swapped = lockfile.clone();
+        swapped.hosts[0].image = "other_image".to_string();
+        let err = swapped
+            .check_command_references(&commands)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("incus create base_image web"), "{}", err);
+    }
+
+    #[test]
+    fn test_launch_mode_collapses_create_config_and_start() {
+        let yaml = r#"
+launch: true
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    disk_size: 20GB
+    subnets: [frontend, backend]
+    labels:
+      tier: front
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+  - name: backend
+    cidr: 10.0.2.0/24
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+        let commands = lockfile.generate_host_commands(&lockfile.hosts[0]);
+        assert_eq!(
+            commands[0],
+            "incus launch base_image web --type=container -c limits.cpu=2 -c limits.memory=2GB \
+             -c user.tier=front -d root,size=20GB --network frontend -d eth0,hwaddr=02:00:00:00:00:01 \
+             -d eth0,ipv4.address=10.0.1.10"
+        );
+        assert_eq!(
+            &commands[1..],
+            [
+                "incus config device add web eth1 nic network=backend",
+                "incus config device set web eth1 ipv4.address=10.0.2.10",
+            ]
+        );
+        assert!(!commands.iter().any(|c| c.starts_with("incus start")));
+        lockfile
+            .check_command_references(&lockfile.generate_incus_commands())
+            .unwrap();
+
+        // `--network` only takes managed networks, so a macvlan eth0 is added after launch
+        let macvlan = yaml.replace(
+            "    cidr: 10.0.1.0/24\n",
+            "    cidr: 10.0.1.0/24\n    network_type: macvlan\n    parent: enp5s0\n",
+        );
+        let compose: IncusCompose = serde_yaml::from_str(&macvlan).unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+        let commands = lockfile.generate_host_commands(&lockfile.hosts[0]);
+        assert_eq!(
+            commands[0],
+            "incus launch base_image web --type=container -c limits.cpu=2 -c limits.memory=2GB \
+             -c user.tier=front -d root,size=20GB"
+        );
+        assert_eq!(
+            commands[1],
+            "incus config device add web eth0 nic nictype=macvlan parent=enp5s0 hwaddr=02:00:00:00:00:01"
+        );
+    }
+
+    #[test]
+    fn test_role_flags_and_params() {
+        let role: RoleConfig = serde_yaml::from_str(
+            "name: monitoring\nvalues: [prometheus, port=9090, 'retention = 15d', tls]",
+        )
+        .unwrap();
+
+        assert_eq!(
+            role.flags().into_iter().collect::<Vec<_>>(),
+            vec!["prometheus", "tls"]
+        );
+        assert_eq!(role.params().get("retention"), Some(&"15d"));
+        assert_eq!(role.params().get("port"), Some(&"9090"));
+        assert_eq!(role.params().len(), 2);
+    }
+
+    #[test]
+    fn test_dns_zone_records() {
+        let yaml = r#"
+dns_domain: lab.example.com
+hosts:
+  - name: Web-01
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend]
+  - name: db
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend]
+  - name: scratch
+    flavor: small_flavor
+    image: base_image
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        compose.validate().unwrap();
+        let commands = compose
+            .generate_lockfile()
+            .unwrap()
+            .generate_incus_commands();
+        let dns: Vec<&String> = commands
+            .iter()
+            .filter(|c| c.starts_with("incus network zone"))
+            .collect();
+        assert_eq!(
+            dns,
+            vec![
+                "incus network zone create lab.example.com",
+                "incus network zone record create lab.example.com web-01",
+                "incus network zone record entry add lab.example.com web-01 A 10.0.1.10",
+                "incus network zone record create lab.example.com db",
+                "incus network zone record entry add lab.example.com db A 10.0.1.11",
+            ]
+        );
+
+        let without = yaml.replace("dns_domain: lab.example.com\n", "");
+        let compose: IncusCompose = serde_yaml::from_str(&without).unwrap();
+        let commands = compose
+            .generate_lockfile()
+            .unwrap()
+            .generate_incus_commands();
+        assert!(!commands.iter().any(|c| c.contains("zone")));
+
+        let invalid = yaml.replace("lab.example.com", "lab example");
+        let compose: IncusCompose = serde_yaml::from_str(&invalid).unwrap();
+        assert!(compose.validate().is_err());
+    }
+
+    #[test]
+    fn test_seeded_allocation_is_reproducible() {
+        let yaml = r#"
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend]
+  - name: api
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend]
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+"#;
+        let generate = |seed: Option<u64>| {
+            let mut compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+            compose.seed = seed;
+            let lockfile = compose.generate_lockfile().unwrap();
+            lockfile
+                .hosts
+                .iter()
+                .map(|h| {
+                    (
+                        h.mac_address.clone().unwrap(),
+                        h.ip_addresses["frontend"].clone(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let first = generate(Some(42));
+        assert_eq!(first, generate(Some(42)));
+        assert_ne!(first, generate(Some(7)));
+        assert_ne!(first[0].0, first[1].0);
+        assert_ne!(first[0].1, first[1].1);
+
+        // Without a seed allocation stays sequential
+        let sequential = generate(None);
+        assert_eq!(sequential[0].0, "02:00:00:00:00:01");
+        assert_eq!(sequential[1].1, "10.0.1.11");
+    }
+
+    #[test]
+    fn test_ovn_nic_skips_bridge_only_settings() {
+        let yaml = r#"
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    subnets:
+      - name: overlay
+        vlan: 20
+subnets:
+  - name: overlay
+    cidr: 10.0.7.0/24
+    network_type: ovn
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        let err = compose.validate().unwrap_err().to_string();
+        assert!(err.contains("vlan is not supported on ovn"), "{}", err);
+
+        let commands = compose
+            .generate_lockfile()
+            .unwrap()
+            .generate_incus_commands();
+        assert!(commands.contains(&"incus network create overlay --type=ovn".to_string()));
+        assert!(commands
+            .contains(&"incus config device set web eth0 ipv4.address=10.0.7.10".to_string()));
+        assert!(!commands.iter().any(|c| c.contains("vlan=")));
+    }
+
+    #[test]
+    fn test_override_file_merges_by_name() {
+        let base = r#"
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    labels: {team: platform}
+    subnets: [frontend]
+  - name: db
+    flavor: small_flavor
+    image: base_image
+subnets:
+  - frontend
+  - name: backend
+    cidr: 10.0.2.0/24
+"#;
+        let overlay = r#"
+hosts:
+  - name: web
+    flavor: large_flavor
+    labels: {env: prod}
+  - name: cache
+    flavor: small_flavor
+    image: base_image
+subnets:
+  - name: frontend
+    cidr: 10.0.9.0/24
+"#;
+        let dir = std::env::temp_dir();
+        let base_path = dir.join(format!("incus-composer-{}-base.yaml", std::process::id()));
+        let overlay_path = dir.join(format!("incus-composer-{}-prod.yaml", std::process::id()));
+        fs::write(&base_path, base).unwrap();
+        fs::write(&overlay_path, overlay).unwrap();
+        let compose = IncusCompose::load_with_overrides(&base_path, &[&overlay_path]);
+        fs::remove_file(&base_path).unwrap();
+        fs::remove_file(&overlay_path).unwrap();
+        let compose = compose.unwrap();
+
+        let web = &compose.hosts[0];
+        assert_eq!(web.flavor, "large_flavor");
+        assert_eq!(web.image, "base_image");
+        assert_eq!(web.subnet_names(), vec!["frontend"]);
+        assert_eq!(web.labels["team"], "platform");
+        assert_eq!(web.labels["env"], "prod");
+
+        let names: Vec<&str> = compose.hosts.iter().map(|h| h.name.as_str()).collect();
+        assert_eq!(names, vec!["web", "db", "cache"]);
+        assert_eq!(compose.subnets[0].cidr(), Some("10.0.9.0/24"));
+        assert_eq!(compose.subnets[1].cidr(), Some("10.0.2.0/24"));
+    }
+
+    #[test]
+    fn test_routers_start_before_their_clients() {
+        let yaml = r#"
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend]
+  - name: worker
+    flavor: small_flavor
+    image: base_image
+    subnets: [backend]
+    depends_on: [web]
+  - name: edge
+    flavor: small_flavor
+    image: base_image
+    is_router: true
+    subnets: [frontend]
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+  - name: backend
+    cidr: 10.0.2.0/24
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        compose.validate().unwrap();
+        let commands = compose
+            .generate_lockfile()
+            .unwrap()
+            .generate_incus_commands();
+        let starts: Vec<&str> = commands
+            .iter()
+            .filter_map(|c| c.strip_prefix("incus start "))
+            .collect();
+        assert_eq!(starts, vec!["edge", "web", "worker"]);
+    }
+
+    #[test]
+    fn test_subnet_dns_servers_and_search() {
+        let yaml = r#"
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend]
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+    dns_servers: [10.0.0.53, 1.1.1.1]
+    dns_search: [lab.example.com]
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        compose.validate().unwrap();
+        let commands = compose
+            .generate_lockfile()
+            .unwrap()
+            .generate_incus_commands();
+        assert!(commands
+            .contains(&"incus network set frontend dns.nameservers=10.0.0.53,1.1.1.1".to_string()));
+        assert!(
+            commands.contains(&"incus network set frontend dns.search=lab.example.com".to_string())
+        );
+
+        let invalid: IncusCompose =
+            serde_yaml::from_str(&yaml.replace("1.1.1.1", "one.one.one.one")).unwrap();
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn test_subnet_dns_mode() {
+        let yaml = r#"
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend]
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+    dns_mode: none
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        compose.validate().unwrap();
+        let commands = compose
+            .generate_lockfile()
+            .unwrap()
+            .generate_incus_commands();
+        assert!(commands.contains(&"incus network set frontend dns.mode=none".to_string()));
+        // DHCP stays off either way
+        assert!(commands.contains(&"incus network set frontend ipv4.dhcp=false".to_string()));
+
+        let ovn: IncusCompose = serde_yaml::from_str(
+            &yaml.replace("dns_mode: none", "dns_mode: none\n    network_type: ovn"),
+        )
+        .unwrap();
+        assert!(ovn.validate().is_err());
+        assert!(serde_yaml::from_str::<IncusCompose>(&yaml.replace("none", "off")).is_err());
+    }
+
+    #[test]
+    fn test_prune_drops_stale_used_values() {
+        let yaml = r#"
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend]
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+    excluded_ips: [10.0.1.50]
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        let mut lockfile = compose.generate_lockfile().unwrap();
+        let host_mac = lockfile.hosts[0].mac_address.clone().unwrap();
+        let used = &mut lockfile.metadata.used_values;
+        used.mac_addresses.push("00:16:3e:aa:bb:cc".to_string());
+        used.host_ids.push("host-removed".to_string());
+        used.ip_addresses
+            .get_mut("subnet_10_0_1")
+            .unwrap()
+            .push("10.0.1.99".to_string());
+
+        assert_eq!(lockfile.prune_used_values(), 3);
+        let used = &lockfile.metadata.used_values;
+        assert_eq!(used.mac_addresses, vec![host_mac]);
+        assert_eq!(used.host_ids, vec![lockfile.hosts[0].id.clone()]);
+        let pool = &used.ip_addresses["subnet_10_0_1"];
+        assert!(pool.contains(&"10.0.1.50".to_string()));
+        assert!(!pool.contains(&"10.0.1.99".to_string()));
+        assert_eq!(lockfile.prune_used_values(), 0);
+    }
+
+    #[test]
+    fn test_host_instance_type_overrides_flavor() {
+        let yaml = r#"
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend]
+  - name: sandbox
+    flavor: small_flavor
+    image: base_image
+    instance_type: virtual-machine
+    subnets: [frontend]
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+flavors:
+  small_flavor:
+    name: small_flavor
+    cpu:
+      cores: 1
+    memory:
+      limit: 1GB
+    instance_type: container
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        compose.validate().unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+        assert!(matches!(
+            lockfile.hosts[0].instance_type,
+            InstanceType::Container
+        ));
+        assert!(matches!(
+            lockfile.hosts[1].instance_type,
+            InstanceType::VirtualMachine
+        ));
+        assert!(lockfile
+            .generate_incus_commands()
+            .iter()
+            .any(|c| c == "incus create base_image sandbox --type=virtual-machine"));
+
+        let privileged: IncusCompose = serde_yaml::from_str(&yaml.replace(
+            "instance_type: virtual-machine",
+            "instance_type: virtual-machine\n    privileged: true",
+        ))
+        .unwrap();
+        let err = privileged.validate().unwrap_err().to_string();
+        assert!(err.contains("instance_type"), "unexpected error: {}", err);
+    }
+
+    #[test]
+    fn test_instance_definitions_export() {
+        let yaml = r#"
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend]
+    labels:
+      team: platform
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+        let exported = serde_yaml::to_string(&lockfile.generate_instance_definitions()).unwrap();
+        let parsed: serde_yaml::Value = serde_yaml::from_str(&exported).unwrap();
+
+        let web = &parsed[0];
+        assert_eq!(web["name"], "web");
+        assert_eq!(web["type"], "container");
+        assert_eq!(web["config"]["user.team"], "platform");
+        let eth0 = &web["devices"]["eth0"];
+        assert_eq!(eth0["type"], "nic");
+        assert_eq!(eth0["network"], "frontend");
+        assert_eq!(eth0["ipv4.address"], "10.0.1.10");
+        assert_eq!(
+            eth0["hwaddr"].as_str(),
+            lockfile.hosts[0].mac_address.as_deref()
+        );
+    }
+
+    #[test]
+    fn test_bridge_address_keeps_prefix() {
+        let yaml = r#"
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend, backend]
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+  - name: backend
+    cidr: 10.0.8.0/22
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        let commands = compose
+            .generate_lockfile()
+            .unwrap()
+            .generate_incus_commands();
+        assert!(
+            commands.contains(&"incus network set frontend ipv4.address=10.0.1.1/24".to_string())
+        );
+        assert!(
+            commands.contains(&"incus network set backend ipv4.address=10.0.8.1/22".to_string())
+        );
+    }
+
+    #[test]
+    fn test_default_flavor_and_image() {
+        let yaml = r#"
+defaults:
+  default_flavor: small_flavor
+  default_image: base_image
+hosts:
+  - name: web
+    subnets: [frontend]
+  - name: db
+    flavor: large_flavor
+    subnets: [frontend]
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+"#;
+
+        let compose = IncusCompose::load_from_reader(yaml.as_bytes(), "test.yaml").unwrap();
+        assert_eq!(compose.hosts[0].flavor, "small_flavor");
+        assert_eq!(compose.hosts[0].image, "base_image");
+        assert_eq!(compose.hosts[1].flavor, "large_flavor");
+        assert_eq!(compose.hosts[1].image, "base_image");
+
+        let without = yaml.replace("  default_image: base_image\n", "");
+        let err = IncusCompose::load_from_reader(without.as_bytes(), "test.yaml")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("needs both a flavor and an image"), "{}", err);
+    }
+
+    #[test]
+    fn test_gateway_outside_cidr_rejected() {
+        let yaml = r#"
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend]
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        let mut lockfile = compose.generate_lockfile().unwrap();
+        lockfile.validate().unwrap();
+
+        lockfile.subnets[0].gateway = "10.0.2.1".to_string();
+        let err = lockfile.validate().unwrap_err().to_string();
+        assert!(
+            err.contains("10.0.2.1") && err.contains("10.0.1.0/24"),
+            "unexpected error: {}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_memory_swap_and_enforce() {
+        let yaml = r#"
+hosts:
+  - name: db
+    flavor: db_flavor
+    image: base_image
+    subnets: [frontend]
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+flavors:
+  db_flavor:
+    name: db_flavor
+    cpu:
+      cores: 2
+    memory:
+      limit: 4GB
+      swap: false
+      swap_priority: 2
+      enforce: hard
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        compose.validate().unwrap();
+        let commands = compose
+            .generate_lockfile()
+            .unwrap()
+            .generate_incus_commands();
+        for expected in [
+            "incus config set db limits.memory.swap=false",
+            "incus config set db limits.memory.swap.priority=2",
+            "incus config set db limits.memory.enforce=hard",
+        ] {
+            assert!(
+                commands.contains(&expected.to_string()),
+                "missing {}",
+                expected
+            );
+        }
+
+        let invalid: IncusCompose =
+            serde_yaml::from_str(&yaml.replace("swap_priority: 2", "swap_priority: 11")).unwrap();
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn test_repeated_subnet_gets_second_nic() {
+        let yaml = r#"
+hosts:
+  - name: lb
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend, frontend]
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        compose.validate().unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+        let host = &lockfile.hosts[0];
+        assert_eq!(host.ip_addresses["frontend"], "10.0.1.10");
+        assert_eq!(host.additional_nics.len(), 1);
+        let second = &host.additional_nics[0];
+        assert_eq!(second.ip_address, "10.0.1.11");
+        assert_ne!(host.mac_address.as_ref(), Some(&second.mac_address));
+
+        let commands = lockfile.generate_incus_commands();
+        assert!(commands.contains(&format!(
+            "incus config device add lb eth0 nic network=frontend hwaddr={}",
+            host.mac_address.as_ref().unwrap()
+        )));
+        assert!(commands.contains(&format!(
+            "incus config device add lb eth1 nic network=frontend hwaddr={}",
+            second.mac_address
+        )));
+        assert!(commands
+            .contains(&"incus config device set lb eth0 ipv4.address=10.0.1.10".to_string()));
+        assert!(commands
+            .contains(&"incus config device set lb eth1 ipv4.address=10.0.1.11".to_string()));
+
+        let used = &lockfile.metadata.used_values;
+        assert!(used.mac_addresses.contains(&second.mac_address));
+        assert!(used.ip_addresses["subnet_10_0_1"].contains(&"10.0.1.11".to_string()));
+    }
+
+    #[test]
+    fn test_disk_size_overrides_root_device() {
+        let yaml = r#"
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    disk_size: 50gb
+    subnets: [frontend]
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        compose.validate().unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+        assert_eq!(lockfile.hosts[0].disk_size.as_deref(), Some("50GB"));
+        assert!(lockfile
+            .generate_incus_commands()
+            .contains(&"incus config device override web root size=50GB".to_string()));
+
+        let invalid = yaml.replace("50gb", "lots");
+        let compose: IncusCompose = serde_yaml::from_str(&invalid).unwrap();
+        assert!(compose.validate().is_err());
+    }
+
+    #[test]
+    fn test_ephemeral_host_with_readonly_root() {
+        let yaml = r#"
+hosts:
+  - name: worker
+    flavor: small_flavor
+    image: base_image
+    ephemeral: true
+    root_readonly: true
+    disk_size: 10GB
+    subnets: [frontend]
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend]
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        compose.validate().unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+        let commands = lockfile.generate_incus_commands();
+        assert!(commands
+            .contains(&"incus create base_image worker --type=container --ephemeral".to_string()));
+        assert!(commands.contains(&"incus create base_image web --type=container".to_string()));
+        assert!(commands.contains(
+            &"incus config device override worker root size=10GB readonly=true".to_string()
+        ));
+
+        let definitions = lockfile.generate_instance_definitions();
+        assert!(definitions[0].ephemeral);
+        assert_eq!(definitions[0].devices["root"]["readonly"], "true");
+        assert!(!definitions[1].ephemeral);
+
+        let teardown = lockfile.generate_teardown_commands(false);
+        let delete = teardown
+            .iter()
+            .position(|c| c == "incus delete worker --force")
+            .unwrap();
+        assert!(teardown[delete - 1].starts_with("# worker is ephemeral"));
+    }
+
+    #[test]
+    fn test_remote_prefixes_commands() {
+        let yaml = r#"
+remote: prod
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend]
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        compose.validate().unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+        let commands = lockfile.generate_incus_commands();
+        assert!(commands.contains(&"incus network create prod:frontend --type=bridge".to_string()));
+        assert!(commands.contains(&"incus create base_image prod:web --type=container".to_string()));
+        assert!(commands
+            .contains(&"incus config device set prod:web eth0 ipv4.address=10.0.1.10".to_string()));
+        assert!(commands.contains(&"incus start prod:web".to_string()));
+        assert_eq!(
+            lockfile.generate_backup_commands("/srv"),
+            vec!["incus export prod:web /srv/web.tar.gz"]
+        );
+
+        let invalid = yaml.replace("remote: prod", "remote: 'prod:web'");
+        let compose: IncusCompose = serde_yaml::from_str(&invalid).unwrap();
+        assert!(compose.validate().is_err());
+    }
+
+    #[test]
+    fn test_teardown_skips_protected_hosts() {
+        let yaml = r#"
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend]
+  - name: db
+    flavor: small_flavor
+    image: base_image
+    subnets: [backend]
+    protected: true
+    autorestart: false
+    host_shutdown_timeout: 120
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+  - name: backend
+    cidr: 10.0.2.0/24
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+        let commands = lockfile.generate_incus_commands();
+        for expected in [
+            "incus config set db boot.autorestart=false",
+            "incus config set db boot.host_shutdown_timeout=120",
+            "incus config set db security.protection.delete=true",
+        ] {
+            assert!(commands.contains(&expected.to_string()), "{}", expected);
+        }
 
-    #[test]
-    fn test_serialize_basic_compose() {
-        let hosts = vec![Host {
-            name: "web-server".to_string(),
-            flavor: "small_flavor".to_string(),
-            image: "base_image".to_string(),
-            floating_ip: false,
-            master: false,
-            is_router: false,
-            roles: vec![Role::Full(RoleConfig {
-                name: "web".to_string(),
-                values: vec![],
-            })],
-            subnets: vec!["frontend".to_string()],
-            subnet: None,
-            subnet_list: None,
-        }];
+        assert_eq!(
+            lockfile.generate_teardown_commands(false),
+            vec!["incus delete web --force", "incus network delete frontend",]
+        );
+        assert_eq!(
+            lockfile.generate_teardown_commands(true),
+            vec![
+                "incus config unset db security.protection.delete",
+                "incus delete db --force",
+                "incus delete web --force",
+                "incus network delete backend",
+                "incus network delete frontend",
+            ]
+        );
+    }
 
-        let subnets = vec![Subnet::Full(SubnetConfig {
-            name: "frontend".to_string(),
-            cidr: Some("10.0.1.0/24".to_string()),
-        })];
+    #[test]
+    fn test_backup_commands_skip_opted_out_hosts() {
+        let yaml = r#"
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+  - name: scratch
+    flavor: small_flavor
+    image: base_image
+    backup: false
+  - name: db
+    flavor: small_flavor
+    image: base_image
 
-        let compose = IncusCompose {
-            version: "1.0".to_string(),
-            hosts,
-            subnets,
-            flavors: HashMap::new(),
-            images: HashMap::new(),
-            defaults: Defaults::default(),
-        };
+subnets: []
+"#;
 
-        let yaml = serde_yaml::to_string(&compose).unwrap();
-        assert!(yaml.contains("version"));
-        assert!(yaml.contains("web-server"));
-        assert!(yaml.contains("frontend"));
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        let commands = compose
+            .generate_lockfile()
+            .unwrap()
+            .generate_backup_commands("/srv/backups/");
+
+        assert_eq!(
+            commands,
+            vec![
+                "incus export web /srv/backups/web.tar.gz",
+                "incus export db /srv/backups/db.tar.gz",
+            ]
+        );
     }
 
     #[test]
-    fn test_deserialize_hospital_example() {
+    fn test_ansible_inventory() {
         let yaml = r#"
 hosts:
-  - name: web_server
+  - name: web1
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend]
+    roles: [web, node-exporter]
+  - name: mon
     flavor: small_flavor
     image: base_image
+    subnets: [frontend]
     roles:
-      - web
       - name: monitoring
-        values: ["prometheus"]
-    subnet: frontend
-
+        values: [prometheus, port=9090]
+  - name: gw
+    flavor: small_flavor
+    image: base_image
+    is_router: true
+    subnets: [frontend]
 subnets:
   - name: frontend
     cidr: 10.0.1.0/24
-  - backend
 "#;
 
-        let mut compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
-
-        // Normalize subnet fields
-        for host in &mut compose.hosts {
-            host.normalize();
-        }
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        let inventory = compose
+            .generate_lockfile()
+            .unwrap()
+            .generate_ansible_inventory();
+
+        assert!(
+            inventory.contains("[web]\nweb1 ansible_host=10.0.1.10\n"),
+            "{}",
+            inventory
+        );
+        assert!(inventory.contains("[node_exporter]\nweb1 ansible_host=10.0.1.10\n"));
+        assert!(inventory.contains("[ungrouped]\ngw ansible_host=10.0.1.2\n"));
+        assert!(
+            inventory.contains("[monitoring]\nmon ansible_host=10.0.1.11 monitoring_port=9090\n")
+        );
+        assert!(inventory.contains("[routers:vars]\nincus_router=true\n"));
+        assert!(!inventory.contains("[masters]"));
+    }
 
-        assert_eq!(compose.version, "1.0"); // default value
-        assert_eq!(compose.hosts.len(), 1);
-        assert_eq!(compose.subnets.len(), 2);
+    #[test]
+    fn test_host_labels_and_description() {
+        let yaml = r#"
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    description: Public web server
+    labels:
+      team: platform
+      env: prod
+subnets: []
+"#;
 
-        let host = &compose.hosts[0];
-        assert_eq!(host.name, "web_server");
-        assert_eq!(host.roles.len(), 2);
-        assert_eq!(host.roles[0].name(), "web");
-        assert_eq!(host.roles[0].values(), &[] as &[String]);
-        assert_eq!(host.roles[1].name(), "monitoring");
-        assert_eq!(host.roles[1].values(), &["prometheus".to_string()]);
-        assert_eq!(host.subnets, vec!["frontend"]);
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+        assert_eq!(lockfile.hosts[0].labels["team"], "platform");
 
-        // Test subnet formats
-        assert_eq!(compose.subnets[0].name(), "frontend");
-        assert_eq!(compose.subnets[0].cidr(), Some("10.0.1.0/24"));
-        assert_eq!(compose.subnets[1].name(), "backend");
-        assert_eq!(compose.subnets[1].cidr(), None);
+        let commands = lockfile.generate_incus_commands();
+        let user_config: Vec<&String> = commands
+            .iter()
+            .filter(|cmd| cmd.starts_with("incus config set web user."))
+            .collect();
+        assert_eq!(
+            user_config,
+            vec![
+                "incus config set web user.description='Public web server'",
+                "incus config set web user.env=prod",
+                "incus config set web user.team=platform",
+            ]
+        );
     }
 
     #[test]
-    fn test_router_with_multiple_subnets() {
+    fn test_auto_cidr_skips_excluded_blocks() {
         let yaml = r#"
+defaults:
+  excluded_cidrs: [192.168.10.0/24, 192.168.12.0/23]
 hosts:
-  - name: core_router
-    flavor: medium_flavor
-    image: router_image
-    is_router: true
-    roles:
-      - router
-    subnet_list:
-      - frontend
-      - backend
-      - dmz
-
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend, backend]
 subnets:
   - frontend
   - backend
-  - dmz
 "#;
 
-        let mut compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        compose.validate().unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+        let cidrs: Vec<&str> = lockfile.subnets.iter().map(|s| s.cidr.as_str()).collect();
+        assert_eq!(cidrs, vec!["192.168.11.0/24", "192.168.14.0/24"]);
+
+        let exhausted: IncusCompose =
+            serde_yaml::from_str(&yaml.replace("192.168.12.0/23", "192.168.0.0/16")).unwrap();
+        let err = exhausted.generate_lockfile().unwrap_err().to_string();
+        assert!(err.contains("no free /24"), "{}", err);
+
+        let invalid: IncusCompose =
+            serde_yaml::from_str(&yaml.replace("192.168.10.0/24", "192.168.10.0/33")).unwrap();
+        assert!(invalid.validate().is_err());
+    }
 
-        // Normalize subnet fields
-        for host in &mut compose.hosts {
-            host.normalize();
-        }
+    #[test]
+    fn test_management_subnet_joined_implicitly() {
+        let yaml = r#"
+defaults:
+  management_subnet: oob
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend]
+  - name: worker
+    flavor: small_flavor
+    image: base_image
+  - name: appliance
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend]
+    management: false
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+  - name: oob
+    cidr: 10.99.0.0/24
+"#;
 
-        let host = &compose.hosts[0];
-        assert!(host.is_router);
-        assert_eq!(host.subnets.len(), 3);
-        assert_eq!(host.subnets, vec!["frontend", "backend", "dmz"]);
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        compose.validate().unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+        assert_eq!(lockfile.metadata.source_hash, compose.calculate_hash());
+
+        let web = &lockfile.hosts[0];
+        assert_eq!(web.subnets[1].name, "oob");
+        assert_eq!(web.ip_addresses["oob"], "10.99.0.10");
+
+        // No subnets of its own: the management NIC is eth0
+        let worker = &lockfile.hosts[1];
+        assert_eq!(worker.subnets.len(), 1);
+        assert_eq!(worker.ip_addresses["oob"], "10.99.0.11");
+        assert!(lockfile
+            .generate_host_commands(worker)
+            .iter()
+            .any(|c| c.starts_with("incus config device add worker eth0 nic network=oob")));
 
-        // Test shorthand subnet format
-        assert_eq!(compose.subnets[0].name(), "frontend");
-        assert_eq!(compose.subnets[1].name(), "backend");
-        assert_eq!(compose.subnets[2].name(), "dmz");
-        for subnet in &compose.subnets {
-            assert_eq!(subnet.cidr(), None);
-        }
+        assert!(!lockfile.hosts[2].ip_addresses.contains_key("oob"));
+
+        let undefined: IncusCompose =
+            serde_yaml::from_str(&yaml.replace("management_subnet: oob", "management_subnet: bmc"))
+                .unwrap();
+        let err = undefined.validate().unwrap_err().to_string();
+        assert!(err.contains("management_subnet 'bmc'"), "{}", err);
     }
 
     #[test]
-    fn test_defaults_configuration() {
+    fn test_auto_cidr_prefix_must_fit_range() {
         let yaml = r#"
 defaults:
-  host_ip4_ranges:
-    - start: 192.168.10.100
-      end: 192.168.10.200
-  router_ip4_ranges:
-    - start: 192.168.1.100
-      end: 192.168.1.200
   cidr4_ranges:
-    - start: 192.168.20.0/16
-      end: 192.168.80.0/16
-
+    - start: 10.50.0.0/24
+      end: 10.50.0.0/24
 hosts:
-  - name: test_host
+  - name: web
     flavor: small_flavor
     image: base_image
-
+    subnets: [frontend, backend]
 subnets:
-  - test_subnet
+  - name: frontend
+    prefix: 25
+  - name: backend
+    prefix: 25
 "#;
 
         let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        compose.validate().unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+        let cidrs: Vec<&str> = lockfile.subnets.iter().map(|s| s.cidr.as_str()).collect();
+        assert_eq!(cidrs, vec!["10.50.0.0/25", "10.50.0.128/25"]);
+
+        let wide: IncusCompose =
+            serde_yaml::from_str(&yaml.replacen("prefix: 25", "prefix: 22", 1)).unwrap();
+        wide.validate().unwrap();
+        let err = wide.generate_lockfile().unwrap_err().to_string();
+        assert!(
+            err.contains("subnet 'frontend': a /22 block does not fit")
+                && err.contains("10.50.0.0 - 10.50.0.255"),
+            "{}",
+            err
+        );
+    }
 
-        assert_eq!(compose.defaults.host_ip4_ranges.len(), 1);
-        assert_eq!(compose.defaults.host_ip4_ranges[0].start, "192.168.10.100");
-        assert_eq!(compose.defaults.host_ip4_ranges[0].end, "192.168.10.200");
+    #[test]
+    fn test_host_environment() {
+        let yaml = r#"
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    environment:
+      LOG_LEVEL: debug info
+      ENV: prod
+subnets: []
+"#;
 
-        assert_eq!(compose.defaults.router_ip4_ranges.len(), 1);
-        assert_eq!(compose.defaults.router_ip4_ranges[0].start, "192.168.1.100");
-        assert_eq!(compose.defaults.router_ip4_ranges[0].end, "192.168.1.200");
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        compose.validate().unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+        assert_eq!(lockfile.hosts[0].environment["ENV"], "prod");
 
-        assert_eq!(compose.defaults.cidr4_ranges.len(), 1);
-        assert_eq!(compose.defaults.cidr4_ranges[0].start, "192.168.20.0/16");
-        assert_eq!(compose.defaults.cidr4_ranges[0].end, "192.168.80.0/16");
+        let commands = lockfile.generate_incus_commands();
+        let environment: Vec<&String> = commands
+            .iter()
+            .filter(|cmd| cmd.starts_with("incus config set web environment."))
+            .collect();
+        assert_eq!(
+            environment,
+            vec![
+                "incus config set web environment.ENV=prod",
+                "incus config set web environment.LOG_LEVEL='debug info'",
+            ]
+        );
+
+        let invalid: IncusCompose =
+            serde_yaml::from_str(&yaml.replace("ENV: prod", "1ENV: prod")).unwrap();
+        assert!(invalid.validate().is_err());
     }
 
     #[test]
-    fn test_lockfile_generation() {
+    fn test_firewall_role_acl() {
         let yaml = r#"
 hosts:
-  - name: test_host
+  - name: fw
     flavor: small_flavor
     image: base_image
-    subnets: [test_subnet]
-
+    is_router: true
+    subnets: [dmz]
+    roles:
+      - name: firewall
+        values: ["allow tcp port 443 from 0.0.0.0/0"]
 subnets:
-  - name: test_subnet
-    cidr: 192.168.100.0/24
+  - name: dmz
+    cidr: 10.0.9.0/24
 "#;
 
         let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
-        let lockfile = compose.generate_lockfile();
-
-        assert_eq!(lockfile.hosts.len(), 1);
-        assert_eq!(lockfile.subnets.len(), 1);
-
-        let host = &lockfile.hosts[0];
-        assert_eq!(host.name, "test_host");
-        assert_eq!(host.id, "host_001");
-        assert!(host.mac_address.is_some());
-        assert_eq!(host.ip_addresses.len(), 1);
-
-        let subnet = &lockfile.subnets[0];
-        assert_eq!(subnet.name, "test_subnet");
-        assert_eq!(subnet.cidr, "192.168.100.0/24");
-        assert_eq!(subnet.gateway, "192.168.100.1");
+        compose.validate().unwrap();
+        let commands = compose
+            .generate_lockfile()
+            .unwrap()
+            .generate_incus_commands();
+        let acl_commands: Vec<&String> =
+            commands.iter().filter(|cmd| cmd.contains("acl")).collect();
+        assert_eq!(
+            acl_commands,
+            vec![
+                "incus network acl create fw-firewall",
+                "incus network acl rule add fw-firewall ingress action=allow protocol=tcp destination_port=443 source=0.0.0.0/0",
+                "incus network set dmz security.acls=fw-firewall",
+            ]
+        );
+
+        let invalid = yaml.replace("port 443", "port https");
+        let compose: IncusCompose = serde_yaml::from_str(&invalid).unwrap();
+        let err = compose.validate().unwrap_err().to_string();
+        assert!(
+            err.contains("host 'fw'") && err.contains("https"),
+            "{}",
+            err
+        );
     }
 
     #[test]
-    fn test_incus_commands_generation() {
+    fn test_tagged_selection_keeps_shared_acls_and_records() {
         let yaml = r#"
+dns_domain: example.internal
 hosts:
-  - name: web_server
-    flavor: medium_flavor
+  - name: fw-a
+    flavor: small_flavor
     image: base_image
-    subnets: [frontend]
-
+    subnets: [lan]
+    tags: [prod]
+    roles:
+      - name: firewall
+        values: ["allow tcp port 443 from 0.0.0.0/0"]
+  - name: fw-b
+    flavor: small_flavor
+    image: base_image
+    subnets: [lan]
+    tags: [staging]
+    roles:
+      - name: firewall
+        values: ["allow tcp port 22 from 10.0.0.0/8"]
 subnets:
-  - name: frontend
-    cidr: 10.0.1.0/24
+  - name: lan
+    cidr: 10.0.5.0/24
 "#;
 
         let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
-        let lockfile = compose.generate_lockfile();
-        let commands = lockfile.generate_incus_commands();
-
-        assert!(!commands.is_empty());
+        compose.validate().unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+        let prod = lockfile.select_tagged(&["prod".to_string()]);
+        assert_eq!(prod.hosts.len(), 1);
+
+        let commands = prod.generate_incus_commands();
+        assert_eq!(
+            prod.generate_network_commands(),
+            lockfile.generate_network_commands()
+        );
+        assert!(commands.contains(
+            &"incus network set lan security.acls=fw-a-firewall,fw-b-firewall".to_string()
+        ));
+        assert!(commands.contains(
+            &"incus network zone record entry add example.internal fw-b A 10.0.5.11".to_string()
+        ));
+        assert!(!commands.iter().any(|c| c == "incus start fw-b"));
+    }
 
-        // Check that network creation commands are present
-        assert!(commands
-            .iter()
-            .any(|cmd| cmd.contains("incus network create frontend")));
+    #[test]
+    fn test_autostart_config() {
+        let yaml = r#"
+hosts:
+  - name: db
+    flavor: small_flavor
+    image: base_image
+    autostart: true
+    autostart_priority: 10
+  - name: web
+    flavor: small_flavor
+    image: base_image
 
-        // Check that instance creation commands are present
-        assert!(commands
-            .iter()
-            .any(|cmd| cmd.contains("incus create base_image web_server")));
+subnets: []
+"#;
 
-        // Check that start command is present
-        assert!(commands
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        let commands = compose
+            .generate_lockfile()
+            .unwrap()
+            .generate_incus_commands();
+
+        assert!(commands.contains(&"incus config set db boot.autostart=true".to_string()));
+        assert!(commands.contains(&"incus config set db boot.autostart.priority=10".to_string()));
+        assert!(!commands
             .iter()
-            .any(|cmd| cmd.contains("incus start web_server")));
+            .any(|cmd| cmd.contains("boot.autostart.delay")));
+        assert!(!commands.iter().any(|cmd| cmd.contains("web boot.")));
     }
-}
 
-// Add chrono dependency for timestamp generation
-use std::time::{SystemTime, UNIX_EPOCH};
+    #[test]
+    fn test_subnet_utilization() {
+        let yaml = r#"
+hosts:
+  - {name: a, flavor: small_flavor, image: base_image, subnets: [lan]}
+  - {name: b, flavor: small_flavor, image: base_image, subnets: [lan]}
+  - {name: c, flavor: small_flavor, image: base_image, subnets: [lan]}
+  - {name: d, flavor: small_flavor, image: base_image, subnets: [lan]}
+  - {name: e, flavor: small_flavor, image: base_image, subnets: [lan]}
 
-// Simple timestamp implementation since we don't want to add chrono dependency yet
-fn simple_timestamp() -> String {
-    match SystemTime::now().duration_since(UNIX_EPOCH) {
-        Ok(duration) => {
-            let secs = duration.as_secs();
-            format!(
-                "2024-01-01T{:02}:{:02}:{:02}Z",
-                (secs / 3600) % 24,
-                (secs / 60) % 60,
-                secs % 60
-            )
-        }
-        Err(_) => "2024-01-01T00:00:00Z".to_string(),
+subnets:
+  - name: lan
+    cidr: 10.0.1.0/24
+  - name: p2p
+    cidr: 10.0.2.0/31
+"#;
+
+        let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+        let utilization = compose.generate_lockfile().unwrap().subnet_utilization();
+
+        assert_eq!(utilization[0].name, "lan");
+        assert_eq!(utilization[0].used, 5);
+        assert_eq!(utilization[0].capacity, 254);
+        assert_eq!(format!("{:.0}", utilization[0].percent()), "2");
+
+        assert_eq!(utilization[1].used, 0);
+        assert_eq!(utilization[1].capacity, 2);
     }
 }
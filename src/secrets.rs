@@ -0,0 +1,224 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use crate::schema::shell_quote;
+
+/// Prefix of a secret reference, e.g. `${secret:db_password}`
+const SECRET_PREFIX: &str = "${secret:";
+
+/// Resolves `${secret:name}` references from a key=value file or an external command
+///
+/// References stay as placeholders in the compose file and lockfile; they are only
+/// substituted into generated commands.
+#[derive(Debug, Default)]
+pub struct SecretResolver {
+    /// Values loaded from a secrets file
+    values: BTreeMap<String, String>,
+
+    /// Shell command printing a secret; `{name}` stands for the secret name
+    command: Option<String>,
+}
+
+/// Quoting in effect at some point of a shell command line
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Quoting {
+    Unquoted,
+    Single,
+    Double,
+}
+
+/// The quoting open at the end of `prefix`, for a word continuing it
+fn quoting_after(prefix: &str) -> Quoting {
+    let mut quoting = Quoting::Unquoted;
+    let mut chars = prefix.chars();
+    while let Some(c) = chars.next() {
+        quoting = match (quoting, c) {
+            (Quoting::Unquoted, '\\') | (Quoting::Double, '\\') => {
+                chars.next();
+                quoting
+            }
+            (Quoting::Unquoted, '\'') => Quoting::Single,
+            (Quoting::Unquoted, '"') => Quoting::Double,
+            (Quoting::Single, '\'') | (Quoting::Double, '"') => Quoting::Unquoted,
+            _ => quoting,
+        };
+    }
+    quoting
+}
+
+/// `value` as literal text for the shell, given the quoting it is inserted into
+fn quote_for(value: &str, quoting: Quoting) -> String {
+    match quoting {
+        Quoting::Unquoted => shell_quote(value),
+        Quoting::Single => value.replace('\'', "'\\''"),
+        Quoting::Double => value
+            .chars()
+            .flat_map(|c| {
+                let escape = matches!(c, '\\' | '"' | '$' | '`').then_some('\\');
+                escape.into_iter().chain(std::iter::once(c))
+            })
+            .collect(),
+    }
+}
+
+impl SecretResolver {
+    /// Load secrets from a file of `key=value` lines (`#` starts a comment)
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)?;
+        let mut values = BTreeMap::new();
+
+        for (idx, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(format!("{}:{}: expected key=value", path.display(), idx + 1).into());
+            };
+            values.insert(key.trim().to_string(), value.trim().to_string());
+        }
+
+        Ok(SecretResolver {
+            values,
+            command: None,
+        })
+    }
+
+    /// Fall back to running `command` for secrets not found in the file
+    pub fn with_command(mut self, command: &str) -> Self {
+        self.command = Some(command.to_string());
+        self
+    }
+
+    /// Look up a single secret by name
+    pub fn resolve(&self, name: &str) -> Result<String, Box<dyn std::error::Error>> {
+        if let Some(value) = self.values.get(name) {
+            return Ok(value.clone());
+        }
+
+        let Some(ref command) = self.command else {
+            return Err(format!(
+                "secret '{}' is not defined (use --secrets-file or --secrets-command)",
+                name
+            )
+            .into());
+        };
+
+        // The name is passed as `$1` rather than pasted into the script
+        let command = command.replace("{name}", "\"$1\"");
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(&command)
+            .arg("sh")
+            .arg(name)
+            .output()?;
+        if !output.status.success() {
+            return Err(format!(
+                "secrets command for '{}' failed: {}",
+                name,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into());
+        }
+
+        Ok(String::from_utf8(output.stdout)?.trim_end().to_string())
+    }
+
+    /// Replace every `${secret:name}` reference in the shell command `text`
+    ///
+    /// Each value is quoted for where the reference sits, so it reaches the
+    /// command as one literal word however it is spelled.
+    pub fn expand(&self, text: &str) -> Result<String, Box<dyn std::error::Error>> {
+        let mut expanded = String::with_capacity(text.len());
+        let mut rest = text;
+
+        while let Some(start) = rest.find(SECRET_PREFIX) {
+            expanded.push_str(&rest[..start]);
+            let quoting = quoting_after(&text[..text.len() - rest.len() + start]);
+            let after = &rest[start + SECRET_PREFIX.len()..];
+            let end = after
+                .find('}')
+                .ok_or_else(|| format!("unterminated secret reference in '{}'", text))?;
+            expanded.push_str(&quote_for(&self.resolve(&after[..end])?, quoting));
+            rest = &after[end + 1..];
+        }
+
+        expanded.push_str(rest);
+        Ok(expanded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_from_secrets_file() {
+        let path =
+            std::env::temp_dir().join(format!("incus-composer-{}-secrets.env", std::process::id()));
+        fs::write(
+            &path,
+            "# test secrets\ndb_password = hunter2\n\napi_token=abc=123\n",
+        )
+        .unwrap();
+        let resolver = SecretResolver::from_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            resolver
+                .expand("user=admin pass=${secret:db_password} token=${secret:api_token}")
+                .unwrap(),
+            "user=admin pass=hunter2 token=abc=123"
+        );
+        assert_eq!(
+            resolver.expand("no secrets here").unwrap(),
+            "no secrets here"
+        );
+        assert!(resolver.expand("${secret:missing}").is_err());
+        assert!(resolver.expand("${secret:db_password").is_err());
+    }
+
+    #[test]
+    fn test_expand_from_command() {
+        let resolver = SecretResolver::default().with_command("echo value-of-{name}");
+        assert_eq!(
+            resolver.expand("${secret:ssh_key}").unwrap(),
+            "value-of-ssh_key"
+        );
+
+        // The name reaches the command as an argument, not as shell code
+        let name = "x;echo injected";
+        assert_eq!(resolver.resolve(name).unwrap(), "value-of-x;echo injected");
+    }
+
+    #[test]
+    fn test_expanded_values_stay_one_word() {
+        let path = std::env::temp_dir().join(format!(
+            "incus-composer-{}-quoted-secrets.env",
+            std::process::id()
+        ));
+        fs::write(&path, "tricky=a b'c;$(id)\"\n").unwrap();
+        let resolver = SecretResolver::from_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let unquoted = resolver.expand("set pass=${secret:tricky}").unwrap();
+        assert_eq!(unquoted, "set pass='a b'\\''c;$(id)\"'");
+        let single = resolver.expand("set 'pass=${secret:tricky}'").unwrap();
+        assert_eq!(single, "set 'pass=a b'\\''c;$(id)\"'");
+        let double = resolver.expand("set \"pass=${secret:tricky}\"").unwrap();
+        assert_eq!(double, "set \"pass=a b'c;\\$(id)\\\"\"");
+
+        // Each form hands the shell the value unchanged
+        for command in [unquoted, single, double] {
+            let script = command.replacen("set ", "printf %s ", 1);
+            let output = Command::new("sh").arg("-c").arg(&script).output().unwrap();
+            assert_eq!(
+                String::from_utf8(output.stdout).unwrap(),
+                "pass=a b'c;$(id)\""
+            );
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use std::fmt;
+
+use crate::incus::{incus_json, parse_instances, IncusInstance};
+use crate::schema::IncusLockfile;
+
+/// Live state of a lockfile host as reported by incus
+#[derive(Debug, Clone, PartialEq)]
+pub enum HostState {
+    Running,
+    Stopped,
+    /// Present in incus with some other status, e.g. "Frozen" or "Error"
+    Present(String),
+    Missing,
+}
+
+impl fmt::Display for HostState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostState::Running => write!(f, "running"),
+            HostState::Stopped => write!(f, "stopped"),
+            HostState::Present(status) => write!(f, "present ({})", status.to_lowercase()),
+            HostState::Missing => write!(f, "missing"),
+        }
+    }
+}
+
+/// An address that differs between the lockfile and the live instance
+#[derive(Debug, Clone, PartialEq)]
+pub struct IpMismatch {
+    pub subnet: String,
+    pub expected: String,
+    /// Address found on the instance, `None` if the NIC has no address
+    pub actual: Option<String>,
+}
+
+/// Comparison of one lockfile host against incus
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostStatus {
+    pub name: String,
+    pub state: HostState,
+    pub ip_mismatches: Vec<IpMismatch>,
+}
+
+impl HostStatus {
+    /// Whether the host is running with the addresses the lockfile expects
+    pub fn matches(&self) -> bool {
+        self.state == HostState::Running && self.ip_mismatches.is_empty()
+    }
+}
+
+/// Query incus and compare every lockfile host against it
+pub fn status_from_incus(
+    lockfile: &IncusLockfile,
+) -> Result<Vec<HostStatus>, Box<dyn std::error::Error>> {
+    let instances_json = incus_json(&["list", "--format", "json"])?;
+    status_from_json(lockfile, &instances_json)
+}
+
+/// Compare every lockfile host against captured `incus list --format json` output
+pub fn status_from_json(
+    lockfile: &IncusLockfile,
+    instances_json: &str,
+) -> Result<Vec<HostStatus>, Box<dyn std::error::Error>> {
+    let instances = parse_instances(instances_json)?;
+
+    Ok(lockfile
+        .hosts
+        .iter()
+        .map(|host| {
+            let Some(instance) = instances.iter().find(|i| i.name == host.name) else {
+                return HostStatus {
+                    name: host.name.clone(),
+                    state: HostState::Missing,
+                    ip_mismatches: Vec::new(),
+                };
+            };
+
+            let state = match instance.status.as_str() {
+                "Running" => HostState::Running,
+                "Stopped" => HostState::Stopped,
+                other => HostState::Present(other.to_string()),
+            };
+
+            // A stopped instance has no runtime addresses; only compare static ones
+            let ip_mismatches = host
+                .ip_addresses
+                .iter()
+                .filter_map(|(subnet, expected)| {
+                    let actual = live_address(instance, subnet);
+                    if actual.is_none() && state != HostState::Running {
+                        return None;
+                    }
+                    (actual.as_ref() != Some(expected)).then(|| IpMismatch {
+                        subnet: subnet.clone(),
+                        expected: expected.clone(),
+                        actual,
+                    })
+                })
+                .collect();
+
+            HostStatus {
+                name: host.name.clone(),
+                state,
+                ip_mismatches,
+            }
+        })
+        .collect())
+}
+
+/// IPv4 address of the instance's NIC on `subnet`, if any
+fn live_address(instance: &IncusInstance, subnet: &str) -> Option<String> {
+    instance
+        .network_nics()
+        .find(|(_, _, network)| *network == subnet)
+        .and_then(|(device_name, device, _)| instance.nic_ipv4(device_name, device))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::IncusCompose;
+
+    const COMPOSE_YAML: &str = r#"
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend]
+  - name: api
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend]
+  - name: db
+    flavor: small_flavor
+    image: base_image
+    subnets: [backend]
+  - name: cache
+    flavor: small_flavor
+    image: base_image
+    subnets: [backend]
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+  - name: backend
+    cidr: 10.0.2.0/24
+"#;
+
+    const INSTANCES_JSON: &str = r#"[
+  {
+    "name": "web",
+    "type": "container",
+    "status": "Running",
+    "expanded_devices": {
+      "eth0": {"type": "nic", "network": "frontend", "name": "eth0"}
+    },
+    "state": {
+      "network": {
+        "eth0": {"addresses": [{"family": "inet", "address": "10.0.1.10"}]}
+      }
+    }
+  },
+  {
+    "name": "api",
+    "type": "container",
+    "status": "Running",
+    "expanded_devices": {
+      "eth0": {"type": "nic", "network": "frontend", "name": "eth0"}
+    },
+    "state": {
+      "network": {
+        "eth0": {"addresses": [{"family": "inet", "address": "10.0.1.42"}]}
+      }
+    }
+  },
+  {
+    "name": "db",
+    "type": "container",
+    "status": "Stopped",
+    "expanded_devices": {
+      "eth0": {"type": "nic", "network": "backend"}
+    },
+    "state": null
+  }
+]"#;
+
+    #[test]
+    fn test_status_from_json_fixture() {
+        let compose: IncusCompose = serde_yaml::from_str(COMPOSE_YAML).unwrap();
+        let lockfile = compose.generate_lockfile().unwrap();
+        let statuses = status_from_json(&lockfile, INSTANCES_JSON).unwrap();
+
+        let states: Vec<(&str, HostState)> = statuses
+            .iter()
+            .map(|s| (s.name.as_str(), s.state.clone()))
+            .collect();
+        assert_eq!(
+            states,
+            vec![
+                ("web", HostState::Running),
+                ("api", HostState::Running),
+                ("db", HostState::Stopped),
+                ("cache", HostState::Missing),
+            ]
+        );
+
+        assert!(statuses[0].matches());
+        assert_eq!(
+            statuses[1].ip_mismatches,
+            vec![IpMismatch {
+                subnet: "frontend".to_string(),
+                expected: "10.0.1.11".to_string(),
+                actual: Some("10.0.1.42".to_string()),
+            }]
+        );
+        assert!(statuses[2].ip_mismatches.is_empty());
+        assert!(!statuses[2].matches());
+        assert!(!statuses[3].matches());
+    }
+}
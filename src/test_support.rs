@@ -0,0 +1,25 @@
+//! Fixtures shared by the library's and the binary's unit tests
+
+use crate::schema::{IncusCompose, IncusLockfile};
+
+/// Two hosts on one subnet, the starting point most merge tests grow or shrink
+pub const APPEND_BASE: &str = r#"
+hosts:
+  - name: web
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend]
+  - name: api
+    flavor: small_flavor
+    image: base_image
+    subnets: [frontend]
+subnets:
+  - name: frontend
+    cidr: 10.0.1.0/24
+"#;
+
+/// A fresh lockfile for `yaml`, without loading or merging
+pub fn lockfile_from_yaml(yaml: &str) -> IncusLockfile {
+    let compose: IncusCompose = serde_yaml::from_str(yaml).unwrap();
+    compose.generate_lockfile().unwrap()
+}